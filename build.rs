@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // no protoc needed on the build machine
+    // SAFETY: build scripts are single threaded
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    // payloads stay `Bytes` end to end, as in `queue::Message`
+    tonic_prost_build::configure().build_client(false).bytes(".").compile_protos(&["proto/quique.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::LazyLock;
+
+use crate::protocol::valid_namespace;
+
+/// Scheme of the password hashes in a credentials file, see `hash_password`
+const SCHEME: &str = "pbkdf2-sha256";
+
+/// PBKDF2 rounds `hash_password` uses
+const ROUNDS: u32 = 100_000;
+
+/// Who may connect, loaded from a JSON file:
+/// {"users": {"alice": "pbkdf2-sha256$100000$<salt>$<hash>"}, "tokens": {"token": "ci-bot"},
+///  "peer_token": "token", "admins": ["ops"],
+///  "namespaces": {"acme": {"members": ["alice"], "max_topics": 10}}}
+///
+/// Passwords are kept hashed, see `hash_password` and `qq-cli
+/// hash-password`; tokens as is. Secrets cross the network as they are,
+/// serve TLS so they aren't in the clear. Every node of a cluster should
+/// load the same file.
+#[derive(Debug, Default, Deserialize)]
+pub struct Credentials {
+    /// user name -> password hash
+    #[serde(default)]
+    users: HashMap<String, String>,
+    /// token -> identity it authenticates as
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+    /// token this node presents to other nodes, one of `tokens`
+    #[serde(default)]
+    pub peer_token: Option<String>,
+    /// identities that may run the cluster besides the nodes themselves:
+    /// gossip, replicate, add and remove nodes, maintenance mode
+    #[serde(default)]
+    admins: Vec<String>,
+    /// namespace name -> who is confined to it
+    #[serde(default)]
+    namespaces: HashMap<String, Namespace>,
+    /// `users` parsed
+    #[serde(skip)]
+    hashes: HashMap<String, PasswordHash>,
+}
+
+/// A password as `hash_password` keeps it
+#[derive(Debug)]
+struct PasswordHash {
+    rounds: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// `pbkdf2-sha256$<rounds>$<salt hex>$<hash hex>`
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('$');
+        if parts.next()? != SCHEME {
+            return None;
+        }
+        let hash = Self {
+            rounds: parts.next()?.parse().ok()?,
+            salt: unhex(parts.next()?)?,
+            hash: unhex(parts.next()?)?,
+        };
+        (parts.next().is_none() && !hash.hash.is_empty()).then_some(hash)
+    }
+
+    /// Whether `password` is the one hashed, in constant time
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.rounds, &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+/// What a credentials file keeps of `password`: PBKDF2-HMAC-SHA256 with a
+/// random salt, as `pbkdf2-sha256$<rounds>$<salt hex>$<hash hex>`
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; 16];
+    SystemRandom::new().fill(&mut salt).map_err(|_| anyhow::anyhow!("no randomness for a salt"))?;
+    let mut hash = [0u8; 32];
+    let rounds = NonZeroU32::new(ROUNDS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, rounds, &salt, password.as_bytes(), &mut hash);
+    Ok(format!("{}${}${}${}", SCHEME, ROUNDS, hex(&salt), hex(&hash)))
+}
+
+fn hex(v: &[u8]) -> String {
+    v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Tenant whose members only see topics named `<namespace>/...`
+#[derive(Debug, Default, Deserialize)]
+pub struct Namespace {
+    /// identities confined to the namespace
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// most topics the namespace may have on each node, partitions of a
+    /// topic count once (0 = unlimited)
+    #[serde(default)]
+    pub max_topics: usize,
+}
+
+impl Credentials {
+    pub fn load(path: &str) -> Result<Self> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("reading credentials from {}", path))?;
+        let mut creds: Self = serde_json::from_str(&json)?;
+        if let Some(ns) = creds.namespaces.keys().find(|ns| !valid_namespace(ns)) {
+            anyhow::bail!("invalid namespace name {:?} in {}", ns, path);
+        }
+        for (user, password) in &creds.users {
+            let Some(hash) = PasswordHash::parse(password) else {
+                anyhow::bail!("password of {:?} in {} isn't a {} hash, see qq-cli hash-password", user, path, SCHEME);
+            };
+            creds.hashes.insert(user.clone(), hash);
+        }
+        Ok(creds)
+    }
+
+    /// Name of the namespace `identity` is confined to, None if it sees every topic
+    pub fn namespace_of(&self, identity: &str) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .find(|(_, ns)| ns.members.iter().any(|m| m == identity))
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn namespace(&self, name: &str) -> Option<&Namespace> {
+        self.namespaces.get(name)
+    }
+
+    /// Identity of `user` with `secret`, a password, or of the token
+    /// `secret` if `user` is empty. None if they don't match.
+    pub fn check(&self, user: &str, secret: &str) -> Option<String> {
+        if user.is_empty() {
+            return self.tokens.get(secret).cloned();
+        }
+        match self.hashes.get(user) {
+            Some(hash) => hash.verify(secret).then(|| user.to_string()),
+            None => {
+                // as slow as a wrong password, not telling who exists
+                static NOBODY: LazyLock<PasswordHash> = LazyLock::new(|| PasswordHash {
+                    rounds: NonZeroU32::new(ROUNDS).unwrap(),
+                    salt: vec![0; 16],
+                    hash: vec![0; 32],
+                });
+                NOBODY.verify(secret);
+                None
+            }
+        }
+    }
+
+    /// Whether `identity` may run the cluster: one of `admins`, or what the
+    /// nodes authenticate as with `peer_token`
+    pub fn is_admin(&self, identity: &str) -> bool {
+        self.admins.iter().any(|a| a == identity)
+            || self.peer_token.as_ref().and_then(|t| self.tokens.get(t)).is_some_and(|peer| peer == identity)
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{Result, bail};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use tokio::task::JoinHandle;
+
+use crate::cluster::{Cluster, Node};
+use crate::config::Config;
+use crate::gateway::Gateway;
+use crate::handler;
+use crate::hints::Hints;
+use crate::protocol::*;
+use crate::queue::{Message, TopicConfig, TopicRegistry, redeliver_unacked};
+use crate::scheduler;
+use crate::server::{enforce_retention, expire_idle_topics, lock_data_dir, recover_topics};
+use crate::storage::metadata::{LocalMetadataStorage, MetadataStorage, save_topics};
+use crate::webhook;
+
+/// A single node broker in the calling process, for applications and tests
+/// that want quique without a server to connect to. Topics live in
+/// `data_dir` as they would for a server, and are recovered from it on
+/// open. Requests run through the same handlers a server's would; no
+/// socket is opened.
+pub struct Broker {
+    cluster: Cluster,
+    topics: Arc<TopicRegistry>,
+    metadata: Arc<dyn MetadataStorage>,
+    hints: Arc<Hints>,
+    config: Arc<ArcSwap<Config>>,
+    data_dir: String,
+    /// keeps other processes off `data_dir` while open
+    _lock: File,
+    /// idle ttl, retention, webhooks, redelivery and schedules, stopped when the broker is dropped
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Broker {
+    /// Open the broker in `data_dir`, inside a tokio runtime. Fails if
+    /// another broker or server has it open.
+    pub fn open(data_dir: &str) -> Result<Self> {
+        let lock = lock_data_dir(data_dir, false)?;
+        let me = Node {
+            id: "embedded".to_string(),
+            addr: String::new(),
+        };
+        let cluster = Cluster::new(me, Vec::new(), Vec::new());
+        let topics = Arc::new(TopicRegistry::new());
+        let metadata: Arc<dyn MetadataStorage> = Arc::new(LocalMetadataStorage::new(data_dir));
+        recover_topics(data_dir, &cluster, &topics, metadata.as_ref())?;
+        let hints = Arc::new(Hints::open(data_dir)?);
+        let config = Arc::new(ArcSwap::from_pointee(Config::default()));
+        let tasks = vec![
+            tokio::spawn(expire_idle_topics(topics.clone(), metadata.clone())),
+            tokio::spawn(enforce_retention(topics.clone(), config.clone())),
+            tokio::spawn(webhook::run(topics.clone())),
+            tokio::spawn(redeliver_unacked(topics.clone())),
+            tokio::spawn(scheduler::run(cluster.clone(), topics.clone(), hints.clone(), Arc::new(AtomicBool::new(false)))),
+        ];
+        Ok(Self {
+            cluster,
+            topics,
+            metadata,
+            hints,
+            config,
+            data_dir: data_dir.to_string(),
+            _lock: lock,
+            tasks,
+        })
+    }
+
+    /// Create `topic` with `cfg`, all of its partitions
+    pub async fn create_topic(&self, topic: &str, cfg: &TopicConfig) -> Result<()> {
+        let st = handler::create_topic(
+            topic,
+            cfg.clone(),
+            None,
+            &self.cluster,
+            &self.topics,
+            self.metadata.as_ref(),
+            None,
+            None,
+            &self.data_dir,
+        )
+        .await;
+        match st {
+            Status::Ok => Ok(()),
+            st => bail!("create topic {} failed: {:?}", topic, st),
+        }
+    }
+
+    /// Append `payload` to `topic`, once it's written to its log, answering
+    /// the seq it got there
+    pub async fn produce(&self, topic: &str, payload: impl Into<Vec<u8>>) -> Result<u64> {
+        let msg = Message {
+            payload: Bytes::from(payload.into()),
+            envelope: Envelope::default(),
+        };
+        let gateway = self.gateway();
+        let mut session = gateway.session();
+        match gateway.produce(&mut session, topic, &msg, 0, "", Acks::Leader).await {
+            Ok(seq) => Ok(seq),
+            Err(st) => bail!("produce to {} failed: {:?}", topic, st),
+        }
+    }
+
+    /// Take the next message of `topic`'s default group, acked, or None if
+    /// there is none right now
+    pub async fn consume(&self, topic: &str) -> Result<Option<Message>> {
+        let gateway = self.gateway();
+        let mut session = gateway.session();
+        let (tag, msg) = match gateway.next(&mut session, topic, "", 0).await {
+            Ok(Some(m)) => m,
+            Ok(None) => return Ok(None),
+            Err(st) => bail!("consume from {} failed: {:?}", topic, st),
+        };
+        match gateway.settle(&mut session, topic, "", tag, false).await {
+            Status::Ok => Ok(Some(msg)),
+            st => bail!("ack on {} failed: {:?}", topic, st),
+        }
+    }
+
+    /// Everything else a client can do, on sessions of its own: consumer
+    /// groups, acks, waiting for messages, bindings
+    pub fn gateway(&self) -> Gateway {
+        Gateway::new(
+            self.cluster.clone(),
+            self.topics.clone(),
+            self.hints.clone(),
+            self.config.clone(),
+            Arc::new(AtomicBool::new(false)),
+        )
+    }
+
+    /// Flush logs and save topic metadata, as a server does on shutdown
+    pub fn close(self) -> Result<()> {
+        let synced = self.topics.sync().and(self.hints.sync());
+        save_topics(self.metadata.as_ref(), &self.topics)?;
+        synced
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -1,9 +1,27 @@
 use bytes::BytesMut;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
 
 use quique::protocol::*;
+use quique::queue::{SAMPLE_ALL, now_ms};
+use quique::storage::audit_log::AuditEvent;
+use quique::tls::{self, Stream};
+
+/// Set by --tls-ca: every connection goes over TLS
+static TLS: OnceLock<TlsConnector> = OnceLock::new();
+
+/// Set by --user/--password or --token: (user, secret) sent on every connection
+static CREDENTIALS: OnceLock<(String, String)> = OnceLock::new();
+
+/// Set by --output
+static OUTPUT: OnceLock<Output> = OnceLock::new();
 
 #[derive(Parser, Debug)]
 #[command(name = "qq-cli")]
@@ -11,6 +29,26 @@ struct Cli {
     #[arg(long, default_value = "127.0.0.1:7001")]
     server: String,
 
+    /// Connect over TLS, trusting servers whose certificate this PEM CA signed
+    #[arg(long)]
+    tls_ca: Option<String>,
+
+    /// Authenticate as this user, with --password
+    #[arg(long, requires = "password", conflicts_with = "token")]
+    user: Option<String>,
+
+    #[arg(long, requires = "user")]
+    password: Option<String>,
+
+    /// Authenticate with a token instead of a user and password
+    #[arg(long)]
+    token: Option<String>,
+
+    /// text: for people, json: one JSON object per answer (per message for
+    /// tail, per refresh for watch), for jq and scripts
+    #[arg(long, value_enum, global = true, default_value_t = Output::Text)]
+    output: Output,
+
     #[command(subcommand)]
     cmd: Cmd,
 }
@@ -24,6 +62,101 @@ enum Cmd {
 
         #[arg(long, default_value_t = 1024)]
         capacity: u32,
+
+        /// Delete the topic after this many seconds without produce/consume (0 = never)
+        #[arg(long, default_value_t = 0)]
+        idle_ttl_secs: u32,
+
+        /// Expire messages not consumed within this many milliseconds (0 = never)
+        #[arg(long, default_value_t = 0)]
+        message_ttl_ms: u32,
+
+        /// Topic that receives expired messages
+        #[arg(long)]
+        dead_letter: Option<String>,
+
+        /// Number of priority levels above 0 (0 = plain FIFO)
+        #[arg(long, default_value_t = 0)]
+        max_priority: u8,
+
+        /// How produce routes messages to bound consumer groups
+        #[arg(long, value_enum, default_value_t = Kind::Fanout)]
+        kind: Kind,
+
+        /// Delete consumed log segments older than this many seconds (0 = keep)
+        #[arg(long, default_value_t = 0)]
+        retention_secs: u32,
+
+        /// Delete the oldest consumed log segments while the log is larger (0 = unlimited)
+        #[arg(long, default_value_t = 0)]
+        retention_bytes: u64,
+
+        /// Copies of the log kept in the cluster, the leader's included
+        #[arg(long, default_value_t = 1)]
+        replicas: u8,
+
+        /// Partitions of the topic, spread over the cluster
+        #[arg(long, default_value_t = 1)]
+        partitions: u32,
+
+        /// http(s) URL the server POSTs each message to, dead lettering
+        /// those it fails to deliver
+        #[arg(long)]
+        webhook: Option<String>,
+
+        /// Split each priority level into this many queues, for many
+        /// producers and consumers at once, giving up order (0 = one)
+        #[arg(long, default_value_t = 0)]
+        shards: u8,
+
+        /// What to do with messages produced while a consumer group is full:
+        /// reject them with QueueFull, drop the group's oldest, or send them
+        /// to --dead-letter
+        #[arg(long, value_enum, default_value_t = OverflowPolicy::Reject)]
+        overflow: OverflowPolicy,
+
+        /// Deliver a message again if it's not acked within this long
+        /// (like 30s or 5m), rather than once its consumer disconnects
+        #[arg(long, value_parser = parse_interval)]
+        visibility_timeout: Option<Duration>,
+
+        /// Payload bytes each consumer group holds pending at most, on top
+        /// of --capacity messages (0 = unlimited)
+        #[arg(long, default_value_t = 0)]
+        capacity_bytes: u64,
+
+        /// Delete the topic once the last consumer that consumed from it
+        /// disconnects, for temporary reply topics
+        #[arg(long)]
+        auto_delete: bool,
+
+        /// Let only this connection consume from the topic, and delete it
+        /// when the connection closes (which for the CLI is right away)
+        #[arg(long)]
+        exclusive: bool,
+
+        /// Keep messages off disk syncs and out of restarts: faster, but
+        /// the topic and everything in it is gone when the server restarts
+        #[arg(long)]
+        transient: bool,
+
+        /// Record who produced, got and acked each message, see `audit`
+        #[arg(long)]
+        audit: bool,
+    },
+
+    /// List topics led by the server with their depth and capacity
+    ListTopics,
+
+    /// Delete a topic and its log
+    DeleteTopic {
+        #[arg(long)]
+        topic: String,
+
+        /// Delete it even while groups have pending or unacked messages,
+        /// which are dropped
+        #[arg(long)]
+        force: bool,
     },
 
     /// Send value
@@ -31,21 +164,213 @@ enum Cmd {
         #[arg(long)]
         topic: String,
 
+        #[arg(long, required_unless_present = "file", conflicts_with = "file")]
+        data: Option<String>,
+
+        /// Send the contents of this file instead, - for stdin
         #[arg(long)]
-        data: String,
+        file: Option<String>,
+
+        /// Send each line of --data or --file as a message of its own
+        #[arg(long)]
+        line_per_message: bool,
+
+        /// Higher priority messages are consumed first
+        #[arg(long, default_value_t = 0)]
+        priority: u8,
+
+        /// Routing key matched against group bindings on direct/pattern topics
+        #[arg(long, default_value = "")]
+        key: String,
+
+        #[arg(long, default_value = "")]
+        message_id: String,
+
+        #[arg(long, default_value = "")]
+        content_type: String,
+
+        /// Topic an answer to the message should go to
+        #[arg(long, default_value = "")]
+        reply_to: String,
+
+        /// Id an answer carries back to match it to this message
+        #[arg(long, default_value = "")]
+        correlation_id: String,
+
+        /// Message header as key=value, may be repeated
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        /// none: don't wait for an answer, leader: written by the leader,
+        /// quorum: written by a majority of the topic's replicas
+        #[arg(long, value_enum, default_value_t = AckLevel::Leader)]
+        acks: AckLevel,
+
+        /// Wait up to this long (like 500ms or 5s, at most 20s) for room in
+        /// a full topic instead of getting QueueFull right away
+        #[arg(long, value_parser = parse_interval)]
+        wait: Option<Duration>,
+    },
+
+    /// Send messages to one or more topics as a transaction: none is
+    /// delivered unless all are written. The server has to lead every topic.
+    Transaction {
+        /// topic=value, may be repeated, sent in order
+        #[arg(long = "message", value_parser = parse_header, required = true)]
+        messages: Vec<(String, String)>,
+
+        /// Stage the messages, then abort instead of committing
+        #[arg(long)]
+        abort: bool,
     },
 
     /// Fetch from topic
     Consume {
         #[arg(long)]
         topic: String,
+
+        /// Consumer group, each group gets its own copy of every message
+        #[arg(long, default_value = "")]
+        group: String,
+
+        /// Wait up to this long for a message instead of returning Empty right away
+        #[arg(long, default_value_t = 0)]
+        timeout_ms: u32,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        /// Consume up to this many messages, stopping early once there are none
+        #[arg(long, default_value_t = 1)]
+        count: u64,
+
+        /// Consume until there are no messages left
+        #[arg(long, conflicts_with = "count")]
+        drain: bool,
+    },
+
+    /// Print messages of a topic as they arrive, until interrupted. They're
+    /// consumed and acked as `group`, tail with a group of its own to leave
+    /// other consumers' messages alone.
+    Tail {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        /// How payloads are printed
+        #[arg(long, value_enum, default_value_t = Format::Utf8)]
+        format: Format,
+
+        /// Also print each message's envelope
+        #[arg(long)]
+        envelope: bool,
+    },
+
+    /// Write a topic's messages to a file, consuming and acking them as
+    /// `group` until it has none left. A group of its own exports what the
+    /// topic's log still holds without taking messages from consumers.
+    Export {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        #[arg(long)]
+        file: String,
+
+        #[arg(long, value_enum, default_value_t = DumpFormat::Ndjson)]
+        format: DumpFormat,
+    },
+
+    /// Produce every message of a file written by export to a topic, in order
+    Import {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        #[arg(long)]
+        file: String,
+
+        #[arg(long, value_enum, default_value_t = DumpFormat::Ndjson)]
+        format: DumpFormat,
+    },
+
+    /// Fetch up to max-messages / max-bytes from topic in one request
+    Fetch {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+
+        #[arg(long, default_value_t = 100)]
+        max_messages: u32,
+
+        #[arg(long, default_value_t = 1024 * 1024)]
+        max_bytes: u32,
+
+        #[arg(long, default_value_t = 0)]
+        timeout_ms: u32,
+
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
     },
+
     /// Metadata dump
     Metadata {
         #[arg(long)]
         topic: String,
     },
 
+    /// Show where a topic lives, its settings, and its groups with their
+    /// depth, bindings and throughput over a short sample
+    Describe {
+        #[arg(long)]
+        topic: String,
+
+        /// Partition to show the settings and groups of
+        #[arg(long, default_value_t = 0)]
+        partition: u32,
+
+        /// Time to measure throughput over, 0 to skip it
+        #[arg(long, default_value = "1s", value_parser = parse_sample)]
+        sample: Duration,
+    },
+
+    /// Check the server is up and show how it sees the other nodes
+    Ping,
+
+    /// Show the cluster members with the topics each one leads, asking every
+    /// member for its own view: whether it answered, and how many of the
+    /// nodes that did see it alive
+    ClusterStatus,
+
+    /// Bring the node listening on `addr` into the server's cluster
+    AddNode {
+        #[arg(long)]
+        addr: String,
+    },
+
+    /// Take a node out of the cluster, handing its topics to the others
+    RemoveNode {
+        #[arg(long)]
+        id: String,
+    },
+
     /// Read last N messages from a topic (for debugging)
     Read {
         #[arg(long)]
@@ -54,18 +379,391 @@ enum Cmd {
         #[arg(long, default_value_t = 10)]
         size: u32,
     },
+
+    /// Move the consumer position of a topic to the start or end of its log,
+    /// or back (or ahead) to a seq or a time to replay from there
+    ResetOffset {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, value_enum, required_unless_present_any = ["seq", "at"])]
+        to: Option<ResetTo>,
+
+        /// Next seq to deliver, messages from it on are delivered again
+        #[arg(long, conflicts_with_all = ["to", "at"])]
+        seq: Option<u64>,
+
+        /// Messages enqueued from this time on are delivered again: a UTC
+        /// time like 2024-05-01T12:00:00Z, or unix ms
+        #[arg(long, value_parser = parse_time, conflicts_with = "to")]
+        at: Option<u64>,
+
+        #[arg(long, default_value = "")]
+        group: String,
+    },
+
+    /// Show counters and depth of a topic (or one consumer group)
+    Stats {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+    },
+
+    /// Show a topic's (or one consumer group's) depth and rates, refreshed
+    /// every interval until interrupted
+    Watch {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+
+        /// Time between refreshes, like 500ms, 1s or 1m
+        #[arg(long, default_value = "1s", value_parser = parse_interval)]
+        interval: Duration,
+    },
+
+    /// Drop every pending message of a topic (or one consumer group)
+    Purge {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+    },
+
+    /// Bind a consumer group to a routing key (or pattern like `orders.*`)
+    Bind {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        group: String,
+
+        #[arg(long)]
+        key: String,
+
+        /// Only messages with these headers too, like
+        /// `region=eu AND type=refund`
+        #[arg(long)]
+        headers: Option<String>,
+
+        /// Only messages whose headers meet this condition too, like
+        /// `region = 'eu' AND amount > 100`
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Only a sample of what it would get, like `1%` or `0.01`. The
+        /// same messages are picked for groups with the same rate.
+        #[arg(long, value_parser = parse_rate)]
+        sample: Option<u32>,
+    },
+
+    /// Let a bound consumer group get every message again
+    Unbind {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        group: String,
+    },
+
+    /// Copy messages produced to topics matching a pattern (like
+    /// `metrics.*` or `logs.#`) into a topic, from now on
+    BindTopic {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        pattern: String,
+    },
+
+    /// Stop copying messages of topics matching a pattern into a topic
+    UnbindTopic {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        pattern: String,
+    },
+
+    /// Publish a message to a topic at the times a cron expression names,
+    /// like `*/5 * * * *` for every five minutes or `*/10 * * * * *` for
+    /// every ten seconds (UTC), replacing the schedule of that name
+    Schedule {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        name: String,
+
+        #[arg(long)]
+        cron: String,
+
+        #[arg(long)]
+        data: String,
+
+        /// Routing key matched against group bindings on direct/pattern topics
+        #[arg(long, default_value = "")]
+        key: String,
+    },
+
+    /// Stop a schedule of a topic
+    Unschedule {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Show the audit trail of a topic created with --audit: who produced,
+    /// got, acked and nacked its messages, oldest first
+    Audit {
+        #[arg(long)]
+        topic: String,
+
+        /// Only the events of this message, by its id or as `topic:seq`
+        #[arg(long)]
+        message_id: Option<String>,
+
+        /// Only the last N events (0 = all)
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+
+    /// Delete a consumer group, its committed offset and binding
+    DeleteGroup {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        group: String,
+
+        /// Delete it even with pending or unacked messages, which are dropped
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Mark every message up to an offset as consumed by a group
+    CommitOffset {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long)]
+        group: String,
+
+        #[arg(long)]
+        offset: u64,
+    },
+
+    /// Show the offset a group committed, what it resumes after
+    FetchOffset {
+        #[arg(long)]
+        topic: String,
+
+        #[arg(long, default_value = "")]
+        group: String,
+    },
+
+    /// Turn broker maintenance mode on/off (produces, transaction commits,
+    /// topic creation and schedules are refused while on)
+    Maintenance {
+        #[arg(value_enum)]
+        mode: Switch,
+    },
+
+    /// Hash a password read from stdin for the `users` of a credentials
+    /// file (--auth-file), nothing is sent to the broker
+    HashPassword,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Output {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Switch {
+    Off,
+    On,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Kind {
+    Fanout,
+    Direct,
+    Pattern,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OverflowPolicy {
+    Reject,
+    DropHead,
+    DeadLetter,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AckLevel {
+    None,
+    Leader,
+    Quorum,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// as text, invalid UTF-8 replaced
+    Utf8,
+    Hex,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DumpFormat {
+    /// one JSON object per line, see `Record`
+    Ndjson,
+    /// payload(bytes) | envelope, as in a produce request, one after another
+    Binary,
+}
+
+/// A message in an ndjson export. The payload is `payload` if it's UTF-8,
+/// `payload_hex` otherwise.
+#[derive(Serialize, Deserialize, Default)]
+struct Record {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_hex: Option<String>,
+    #[serde(default)]
+    message_id: String,
+    #[serde(default)]
+    content_type: String,
+    #[serde(default)]
+    timestamp_ms: u64,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    reply_to: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    correlation_id: String,
+}
+
+impl Record {
+    /// `payload` as text if it's UTF-8 and `hex` isn't asked for
+    fn new(payload: Vec<u8>, env: Envelope, hex_payload: bool) -> Self {
+        let mut rec = Record {
+            message_id: env.message_id,
+            content_type: env.content_type,
+            timestamp_ms: env.timestamp_ms,
+            headers: env.headers,
+            reply_to: env.reply_to,
+            correlation_id: env.correlation_id,
+            ..Default::default()
+        };
+        match String::from_utf8(payload) {
+            Ok(text) if !hex_payload => rec.payload = Some(text),
+            Ok(text) => rec.payload_hex = Some(hex(text.as_bytes())),
+            Err(e) => rec.payload_hex = Some(hex(e.as_bytes())),
+        }
+        rec
+    }
+}
+
+/// A consumed message as --output json prints it
+#[derive(Serialize)]
+struct Delivery {
+    tag: u64,
+    /// whether the ack that followed it was answered Ok
+    acked: bool,
+    /// handed out before and not acked then
+    redelivered: bool,
+    #[serde(flatten)]
+    record: Record,
+    /// missing from older servers
+    #[serde(flatten)]
+    info: Option<Info>,
+}
+
+/// Delivery info of a consumed message as --output json prints it
+#[derive(Serialize)]
+struct Info {
+    /// message_id, or topic:seq if the producer set none
+    id: String,
+    enqueued_ms: u64,
+    redeliveries: u32,
+    /// topic it was produced to, other than the consumed one if it was dead-lettered
+    origin: String,
+}
+
+impl From<DeliveryInfo> for Info {
+    fn from(i: DeliveryInfo) -> Self {
+        Info {
+            id: i.message_id,
+            enqueued_ms: i.enqueued_ms,
+            redeliveries: i.redeliveries,
+            origin: i.topic,
+        }
+    }
+}
+
+/// A log record as `read --output json` prints it
+#[derive(Serialize)]
+struct Logged {
+    #[serde(flatten)]
+    position: Option<LogPosition>,
+    #[serde(flatten)]
+    record: Record,
+}
+
+/// Where a record read back sits in the log
+#[derive(Serialize)]
+struct LogPosition {
+    seq: u64,
+    /// 0 for records written before enqueue times were logged
+    enqueued_ms: u64,
+    priority: u8,
+    routing_key: String,
+}
+
+/// An event of a topic's audit trail
+#[derive(Serialize)]
+struct Audited {
+    at_ms: u64,
+    event: String,
+    seq: u64,
+    conn: u64,
+    identity: String,
+    group: String,
+    message_id: String,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ResetTo {
+    Earliest,
+    Latest,
 }
 
 #[repr(u16)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Status {
     Ok = 0,
     Redirect = 10,
     Empty = 11,
     TopicExists = 12,
     NotFound = 13,
+    NotEmpty = 14,
+    NotReplicated = 15,
+    QuotaExceeded = 16,
+    TooManyConnections = 17,
+    QueueFull = 18,
+    Unavailable = 19,
     BadRequest = 400,
+    Unauthorized = 401,
+    Throttled = 429,
     ServerError = 500,
+    Maintenance = 503,
 }
 
 // a tiny From for printing convenience
@@ -77,7 +775,16 @@ impl From<u16> for Status {
             11 => Status::Empty,
             12 => Status::TopicExists,
             13 => Status::NotFound,
+            14 => Status::NotEmpty,
+            15 => Status::NotReplicated,
+            16 => Status::QuotaExceeded,
+            17 => Status::TooManyConnections,
+            18 => Status::QueueFull,
+            19 => Status::Unavailable,
             400 => Status::BadRequest,
+            401 => Status::Unauthorized,
+            429 => Status::Throttled,
+            503 => Status::Maintenance,
             _ => Status::ServerError,
         }
     }
@@ -86,6 +793,15 @@ impl From<u16> for Status {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let _ = OUTPUT.set(cli.output);
+    if let Some(ca) = &cli.tls_ca {
+        let _ = TLS.set(tls::connector(ca)?);
+    }
+    if let (Some(user), Some(password)) = (&cli.user, &cli.password) {
+        let _ = CREDENTIALS.set((user.clone(), password.clone()));
+    } else if let Some(token) = &cli.token {
+        let _ = CREDENTIALS.set((String::new(), token.clone()));
+    }
     handle_command(cli.cmd, &cli.server).await
 }
 
@@ -94,94 +810,1742 @@ async fn handle_command(cmd: Cmd, server: &str) -> anyhow::Result<()> {
         Cmd::Create {
             topic,
             capacity,
+            idle_ttl_secs,
+            message_ttl_ms,
+            dead_letter,
+            max_priority,
+            kind,
+            retention_secs,
+            retention_bytes,
+            replicas,
+            partitions,
+            webhook,
+            shards,
+            overflow,
+            visibility_timeout,
+            capacity_bytes,
+            auto_delete,
+            exclusive,
+            transient,
+            audit,
         } => {
-            println!("Create topic {:?} {:?}", topic, capacity);
-            call(&server, Op::CreateTopic, |b| {
+            if !json() {
+                println!("Create topic {:?} {:?}", topic, capacity);
+            }
+            call(server, Op::CreateTopic, |b| {
                 put_str(b, &topic);
                 put_u32(b, capacity);
+                put_u32(b, idle_ttl_secs);
+                put_u32(b, message_ttl_ms);
+                put_str(b, dead_letter.as_deref().unwrap_or(""));
+                put_u8(b, max_priority);
+                put_u8(b, kind as u8);
+                put_u32(b, retention_secs);
+                put_u64(b, retention_bytes);
+                put_u8(b, replicas);
+                put_u32(b, partitions);
+                put_u32(b, ALL_PARTITIONS);
+                put_str(b, webhook.as_deref().unwrap_or(""));
+                put_u8(b, shards);
+                put_u8(b, overflow as u8);
+                put_u32(b, visibility_timeout.map_or(0, |d| d.as_millis() as u32));
+                put_u64(b, capacity_bytes);
+                put_u8(b, auto_delete as u8);
+                put_u8(b, exclusive as u8);
+                put_u8(b, transient as u8);
+                put_u8(b, audit as u8);
             })
             .await?;
         }
-        Cmd::Produce { topic, data } => {
-            let data_bytes = data.as_bytes();
-            let (st, _payload) = redirecting_call_resp(&server, Op::Produce, |b| {
-                put_str(b, &topic);
-                put_bytes(b, data_bytes);
+        Cmd::ListTopics => {
+            let mut s = connect(server).await?;
+            let (st, payload) = rpc(&mut s, Op::ListTopics, &BytesMut::new()).await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            let mut topics = Vec::new();
+            if st == Status::Ok {
+                let mut b = &payload[..];
+                let n = get_u32(&mut b).unwrap_or(0);
+                if !json() {
+                    println!("{:<24} {:>8} {:>8} {:>9}  groups", "topic", "len", "capacity", "in_flight");
+                }
+                for _ in 0..n {
+                    let (Some(name), Some(len), Some(cap), Some(in_flight), Some(m)) = (
+                        get_str(&mut b),
+                        get_u32(&mut b),
+                        get_u32(&mut b),
+                        get_u32(&mut b),
+                        get_u32(&mut b),
+                    ) else {
+                        break;
+                    };
+                    let groups: Vec<String> = (0..m).filter_map(|_| get_str(&mut b)).collect();
+                    if json() {
+                        topics.push(json!({
+                            "topic": name,
+                            "len": len,
+                            "capacity": cap,
+                            "in_flight": in_flight,
+                            "groups": groups,
+                        }));
+                        continue;
+                    }
+                    println!(
+                        "{:<24} {:>8} {:>8} {:>9}  {}",
+                        name,
+                        len,
+                        cap,
+                        in_flight,
+                        groups.join(",")
+                    );
+                }
+            }
+            if json() {
+                emit(json!({ "status": st, "topics": topics }));
+            }
+        }
+        Cmd::DeleteTopic { topic, force } => {
+            call(server, Op::DeleteTopic, |b| {
+                put_str(b, &topic);
+                // if_empty, unless forced
+                put_u8(b, !force as u8);
             })
             .await?;
-            println!("status={:?}", st);
         }
-        Cmd::Consume { topic } => {
-            let (st, payload) = redirecting_call_resp(&server, Op::Consume, |b| {
+        Cmd::Produce {
+            topic,
+            data,
+            file,
+            line_per_message,
+            priority,
+            key,
+            message_id,
+            content_type,
+            reply_to,
+            correlation_id,
+            headers,
+            partition,
+            acks,
+            wait,
+        } => {
+            let topic = partition_name(&topic, partition);
+            let wait_ms = wait.map_or(0, |w| w.as_millis().min(u32::MAX as u128) as u32);
+            let data = match (data, file.as_deref()) {
+                (Some(data), _) => data.into_bytes(),
+                (None, Some("-")) => {
+                    let mut data = Vec::new();
+                    tokio::io::stdin().read_to_end(&mut data).await?;
+                    data
+                }
+                (None, Some(path)) => std::fs::read(path)?,
+                (None, None) => unreachable!("clap requires --data or --file"),
+            };
+            let payloads: Vec<&[u8]> = if line_per_message {
+                let lines = data.split(|&b| b == b'\n').map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+                lines.filter(|l| !l.is_empty()).collect()
+            } else {
+                vec![&data[..]]
+            };
+            let env = Envelope {
+                message_id,
+                content_type,
+                timestamp_ms: 0,
+                headers: headers.into_iter().collect(),
+                reply_to,
+                correlation_id,
+            };
+            let acks = match acks {
+                AckLevel::None => Acks::None,
+                AckLevel::Leader => Acks::Leader,
+                AckLevel::Quorum => Acks::Quorum,
+            };
+            let put_produce = |b: &mut BytesMut, payload: &[u8]| {
                 put_str(b, &topic);
-                put_u32(b, 0);
+                put_bytes(b, payload);
+                put_u8(b, priority);
+                put_str(b, &key);
+                put_envelope(b, &env);
+                put_u8(b, acks as u8);
+                put_u32(b, wait_ms);
+            };
+            let Some((first, rest)) = payloads.split_first() else {
+                if json() {
+                    emit(json!({ "status": Status::Ok, "sent": 0 }));
+                } else {
+                    println!("nothing to send");
+                }
+                return Ok(());
+            };
+            if acks == Acks::None {
+                // no answer, so no redirect either: `server` has to lead the topic
+                let mut s = connect(server).await?;
+                for payload in &payloads {
+                    let mut body = BytesMut::new();
+                    put_produce(&mut body, payload);
+                    send(&mut s, Op::Produce, &body).await?;
+                }
+                s.shutdown().await?;
+                if json() {
+                    // unanswered, so no status
+                    emit(json!({ "sent": payloads.len() }));
+                } else {
+                    println!("sent {}", payloads.len());
+                }
+                return Ok(());
+            }
+            // the rest go to whichever node took the first
+            let (mut s, st, answer) = redirecting_conn(server, Op::Produce, |b| put_produce(b, first)).await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            if st != Status::Ok {
+                print_refused(st, &answer, 0);
+                return Ok(());
+            }
+            // where each went in the log
+            let mut seqs = vec![get_u64(&mut &answer[..]).unwrap_or(0)];
+            for (i, payload) in rest.iter().enumerate() {
+                let mut body = BytesMut::new();
+                put_produce(&mut body, payload);
+                let (st, answer) = rpc(&mut s, Op::Produce, &body).await?;
+                if st != Status::Ok {
+                    if !json() {
+                        println!("status={:?} after {} messages", st, i + 1);
+                    }
+                    print_refused(st, &answer, i + 1);
+                    return Ok(());
+                }
+                seqs.push(get_u64(&mut &answer[..]).unwrap_or(0));
+            }
+            if json() {
+                emit(json!({ "status": st, "sent": payloads.len(), "seqs": seqs }));
+            } else if rest.is_empty() {
+                println!("seq={}", seqs[0]);
+            } else {
+                println!("sent {}, seqs {}..{}", payloads.len(), seqs[0], seqs[seqs.len() - 1]);
+            }
+        }
+        Cmd::Transaction { messages, abort } => {
+            // staged on this connection, closing it early drops them
+            let mut s = connect(server).await?;
+            let (st, _) = rpc(&mut s, Op::Begin, &BytesMut::new()).await?;
+            if st != Status::Ok {
+                print_status(st);
+                return Ok(());
+            }
+            for (i, (topic, value)) in messages.iter().enumerate() {
+                let mut body = BytesMut::new();
+                put_str(&mut body, topic);
+                put_bytes(&mut body, value.as_bytes());
+                put_u8(&mut body, 0);
+                put_str(&mut body, "");
+                put_envelope(&mut body, &Envelope::default());
+                let (st, _) = rpc(&mut s, Op::Produce, &body).await?;
+                if st != Status::Ok {
+                    if json() {
+                        emit(json!({ "status": st, "staged": i, "topic": topic }));
+                    } else {
+                        println!("status={:?} staging message {} to {}", st, i + 1, topic);
+                    }
+                    return Ok(());
+                }
+            }
+            let op = if abort { Op::Abort } else { Op::Commit };
+            let (st, answer) = rpc(&mut s, op, &BytesMut::new()).await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            let mut b = &answer[..];
+            let seqs: Vec<u64> = match (st, abort) {
+                (Status::Ok, false) => (0..get_u32(&mut b).unwrap_or(0)).map_while(|_| get_u64(&mut b)).collect(),
+                _ => Vec::new(),
+            };
+            match st {
+                Status::Ok if json() => emit(json!({ "status": st, "sent": seqs.len(), "seqs": seqs })),
+                Status::Ok if abort => println!("aborted {}", messages.len()),
+                Status::Ok => {
+                    let seqs: Vec<String> = seqs.iter().map(|s| s.to_string()).collect();
+                    println!("committed {}, seqs {}", seqs.len(), seqs.join(", "));
+                }
+                st => print_refused(st, &answer, 0),
+            }
+        }
+        Cmd::Consume {
+            topic,
+            group,
+            timeout_ms,
+            partition,
+            count,
+            drain,
+        } => {
+            let topic = partition_name(&topic, partition);
+            let put_consume = |b: &mut BytesMut| {
+                put_str(b, &topic);
+                put_u32(b, timeout_ms);
+                put_str(b, &group);
+            };
+            let (mut s, mut st, mut payload) = redirecting_conn(server, Op::Consume, put_consume).await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            // the first answer's, or the failure that stopped a run of them
+            let mut status = st;
+            let mut messages = Vec::new();
+            let mut consumed = 0;
+            while st == Status::Ok {
+                let mut b = &payload[..];
+                if let (Some(tag), Some(v), Some(env)) =
+                    (get_u64(&mut b), get_bytes(&mut b), get_envelope(&mut b))
+                {
+                    let redelivered = get_u8(&mut b) == Some(1);
+                    let info = get_delivery_info(&mut b);
+                    if !json() {
+                        println!("value={}", String::from_utf8_lossy(&v));
+                        println!("{}", fmt_envelope(&env));
+                        if let Some(info) = &info {
+                            println!("{}", fmt_info(info));
+                        }
+                        if redelivered {
+                            println!("redelivered");
+                        }
+                    }
+                    // ack on the same connection, closing it would requeue the message
+                    let mut body = BytesMut::new();
+                    put_str(&mut body, &topic);
+                    put_u64(&mut body, tag);
+                    put_str(&mut body, &group);
+                    let (st, _payload) = rpc(&mut s, Op::Ack, &body).await?;
+                    if json() {
+                        let acked = st == Status::Ok;
+                        let (record, info) = (Record::new(v, env, false), info.map(Info::from));
+                        messages.push(Delivery { tag, acked, redelivered, record, info });
+                    } else if st != Status::Ok {
+                        println!("ack status={:?}", st);
+                    }
+                }
+                consumed += 1;
+                if !drain && consumed >= count {
+                    break;
+                }
+                let mut body = BytesMut::new();
+                put_consume(&mut body);
+                (st, payload) = rpc(&mut s, Op::Consume, &body).await?;
+                if st != Status::Ok && st != Status::Empty {
+                    status = st;
+                    if !json() {
+                        println!("status={:?}", st);
+                    }
+                }
+            }
+            if json() {
+                emit(json!({ "status": status, "messages": messages }));
+            } else if drain || count > 1 {
+                println!("consumed {}", consumed);
+            }
+        }
+        Cmd::Tail {
+            topic,
+            group,
+            partition,
+            format,
+            envelope,
+        } => {
+            let topic = partition_name(&topic, partition);
+            tokio::select! {
+                res = tail(server, &topic, &group, format, envelope) => res?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Cmd::Export {
+            topic,
+            group,
+            partition,
+            file,
+            format,
+        } => {
+            let topic = partition_name(&topic, partition);
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&file)?);
+            let n = export(server, &topic, &group, format, &mut out).await?;
+            out.flush()?;
+            if json() {
+                emit(json!({ "exported": n, "file": file }));
+            } else {
+                println!("exported {} messages to {}", n, file);
+            }
+        }
+        Cmd::Import {
+            topic,
+            partition,
+            file,
+            format,
+        } => {
+            let topic = partition_name(&topic, partition);
+            let data = std::fs::read(&file)?;
+            let n = import(server, &topic, format, &data).await?;
+            if json() {
+                emit(json!({ "imported": n, "file": file }));
+            } else {
+                println!("imported {} messages from {}", n, file);
+            }
+        }
+        Cmd::Fetch {
+            topic,
+            group,
+            max_messages,
+            max_bytes,
+            timeout_ms,
+            partition,
+        } => {
+            let topic = partition_name(&topic, partition);
+            let (mut s, st, payload) = redirecting_conn(server, Op::Fetch, |b| {
+                put_str(b, &topic);
+                put_u32(b, timeout_ms);
+                put_str(b, &group);
+                put_u32(b, max_messages);
+                put_u32(b, max_bytes);
             })
             .await?;
-            println!("status={:?}", st);
+            if !json() {
+                println!("status={:?}", st);
+            }
+            let mut messages = Vec::new();
             if st == Status::Ok {
-                if payload.len() >= 4 {
-                    let n = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
-                        as usize;
-                    let v = &payload[4..4 + n];
-                    println!("value={}", String::from_utf8_lossy(v));
+                let mut b = &payload[..];
+                let n = get_u32(&mut b).unwrap_or(0);
+                let mut fetched = Vec::new();
+                for _ in 0..n {
+                    let (Some(tag), Some(v), Some(env)) =
+                        (get_u64(&mut b), get_bytes(&mut b), get_envelope(&mut b))
+                    else {
+                        break;
+                    };
+                    fetched.push((tag, v, env));
+                }
+                // flags follow the messages, missing from older servers
+                let redelivered: Vec<bool> = fetched.iter().map(|_| get_u8(&mut b) == Some(1)).collect();
+                let infos: Vec<Option<DeliveryInfo>> = fetched.iter().map(|_| get_delivery_info(&mut b)).collect();
+                for (((tag, v, env), redelivered), info) in fetched.into_iter().zip(redelivered).zip(infos) {
+                    if !json() {
+                        let again = if redelivered { "  redelivered" } else { "" };
+                        let info_text = info.as_ref().map(|i| format!("  {}", fmt_info(i))).unwrap_or_default();
+                        println!("[{}] {}  {}{}{}", tag, String::from_utf8_lossy(&v), fmt_envelope(&env), info_text, again);
+                    }
+                    let mut body = BytesMut::new();
+                    put_str(&mut body, &topic);
+                    put_u64(&mut body, tag);
+                    put_str(&mut body, &group);
+                    let (st, _payload) = rpc(&mut s, Op::Ack, &body).await?;
+                    if json() {
+                        let acked = st == Status::Ok;
+                        let (record, info) = (Record::new(v, env, false), info.map(Info::from));
+                        messages.push(Delivery { tag, acked, redelivered, record, info });
+                    } else if st != Status::Ok {
+                        println!("ack status={:?}", st);
+                    }
                 }
             }
+            if json() {
+                emit(json!({ "status": st, "messages": messages }));
+            }
         }
         Cmd::Metadata { topic } => {
-            let mut s = connect(&server).await?;
-            let mut body = BytesMut::new();
-            put_str(&mut body, &topic);
-            let (st, payload) = rpc(&mut s, Op::Metadata, &body).await?;
-            println!("status={:?}", st);
+            let (st, payload) = redirecting_call_resp(server, Op::Metadata, |b| {
+                put_str(b, &topic);
+            })
+            .await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            let mut partitions = Vec::new();
             if st == Status::Ok {
                 let mut b = &payload[..];
                 if let Some(n) = get_u32(&mut b) {
                     for _ in 0..n {
                         let p = get_u32(&mut b).unwrap();
                         let addr = get_str(&mut b).unwrap();
-                        println!("partition {} -> {}", p, addr);
+                        if json() {
+                            partitions.push(json!({ "partition": p, "leader": addr }));
+                        } else {
+                            println!("partition {} -> {}", p, addr);
+                        }
                     }
                 }
             }
+            if json() {
+                emit(json!({ "status": st, "partitions": partitions }));
+            }
+        }
+        Cmd::Describe { topic, partition, sample } => describe(server, &topic, partition, sample).await?,
+        Cmd::Ping => {
+            let mut s = connect(server).await?;
+            let (st, payload) = rpc(&mut s, Op::Ping, &BytesMut::new()).await?;
+            let mut b = &payload[..];
+            let (Status::Ok, Some(id), Some(heartbeat)) = (st, get_str(&mut b), get_u64(&mut b)) else {
+                print_status(st);
+                return Ok(());
+            };
+            if !json() {
+                println!("status={:?}", st);
+                println!("node {} heartbeat {}", id, heartbeat);
+                println!("{:<16} {:<22} {:<8} {:>12} {:>10}", "node", "addr", "status", "last_seen_ms", "rtt_us");
+            }
+            let mut peers = Vec::new();
+            let n = get_u32(&mut b).unwrap_or(0);
+            for _ in 0..n {
+                let (Some(id), Some(addr), Some(status), Some(last_seen), Some(rtt)) = (
+                    get_str(&mut b),
+                    get_str(&mut b),
+                    get_u8(&mut b).and_then(|v| NodeStatus::try_from(v).ok()),
+                    get_u64(&mut b),
+                    get_u64(&mut b),
+                ) else {
+                    break;
+                };
+                if json() {
+                    peers.push(json!({
+                        "node": id,
+                        "addr": addr,
+                        "status": format!("{:?}", status),
+                        "last_seen_ms": last_seen,
+                        // 0 until it's been measured
+                        "rtt_us": rtt,
+                    }));
+                    continue;
+                }
+                let rtt = if rtt == 0 { "-".to_string() } else { rtt.to_string() };
+                println!("{:<16} {:<22} {:<8} {:>12} {:>10}", id, addr, format!("{:?}", status), last_seen, rtt);
+            }
+            if json() {
+                emit(json!({ "status": st, "node": id, "heartbeat": heartbeat, "peers": peers }));
+            }
+        }
+        Cmd::ClusterStatus => {
+            let (st, view) = cluster_info(server).await?;
+            let Some((me, members)) = view else {
+                print_status(st);
+                return Ok(());
+            };
+            if !json() {
+                println!("status={:?}", st);
+            }
+            // every member's own view, the server's included
+            let mut views: HashMap<String, Option<Vec<Member>>> = HashMap::new();
+            views.insert(me.clone(), Some(members.clone()));
+            for m in members.iter().filter(|m| m.id != me && m.status != NodeStatus::Left) {
+                let view = match tokio::time::timeout(MEMBER_TIMEOUT, cluster_info(&m.addr)).await {
+                    Ok(Ok((Status::Ok, Some((id, members))))) if id == m.id => Some(members),
+                    _ => None,
+                };
+                views.insert(m.id.clone(), view);
+            }
+            let answered = views.values().flatten().count();
+            if !json() {
+                println!(
+                    "{:<16} {:<22} {:<8} {:>12} {:<8} {:>8}  leads",
+                    "node", "addr", "status", "last_seen_ms", "answered", "alive_by"
+                );
+            }
+            let mut rows = Vec::new();
+            for m in &members {
+                let own = views.get(&m.id).and_then(Option::as_ref);
+                let alive_by = views
+                    .values()
+                    .flatten()
+                    .filter(|v| v.iter().any(|o| o.id == m.id && o.status == NodeStatus::Alive))
+                    .count();
+                // what a node leads is best known by the node itself
+                let leads = own.and_then(|v| v.iter().find(|o| o.id == m.id)).map_or(&m.leads, |o| &o.leads);
+                if json() {
+                    rows.push(json!({
+                        "node": m.id,
+                        "addr": m.addr,
+                        "status": format!("{:?}", m.status),
+                        "last_seen_ms": m.last_seen_ms,
+                        "answered": own.is_some(),
+                        "alive_by": alive_by,
+                        "leads": leads,
+                    }));
+                    continue;
+                }
+                let id = if m.id == me { format!("{} *", m.id) } else { m.id.clone() };
+                println!(
+                    "{:<16} {:<22} {:<8} {:>12} {:<8} {:>8}  {}",
+                    id,
+                    m.addr,
+                    format!("{:?}", m.status),
+                    m.last_seen_ms,
+                    if own.is_some() { "yes" } else { "no" },
+                    format!("{}/{}", alive_by, answered),
+                    leads.join(",")
+                );
+            }
+            if json() {
+                emit(json!({ "status": st, "node": me, "answered": answered, "members": rows }));
+            }
+        }
+        Cmd::AddNode { addr } => {
+            call(server, Op::AddNode, |b| put_str(b, &addr)).await?;
+        }
+        Cmd::RemoveNode { id } => {
+            call(server, Op::RemoveNode, |b| put_str(b, &id)).await?;
         }
         Cmd::Read {
             topic,
             size,
         } => {
-            let (st, payload) = redirecting_call_resp(&server, Op::Read, |b| {
+            let (st, payload) = redirecting_call_resp(server, Op::Read, |b| {
                 put_str(b, &topic);
                 put_u32(b, size);
             })
             .await?;
+            let mut b = &payload[..];
+            let n = if st == Status::Ok { get_u32(&mut b).unwrap_or(0) } else { 0 };
+            let payloads: Vec<Vec<u8>> = (0..n).map_while(|_| get_bytes(&mut b)).collect();
+            // where each record sits in the log follows the payloads, missing from older servers
+            let logged: Vec<Option<(LogPosition, Envelope)>> = payloads
+                .iter()
+                .map(|_| {
+                    let (seq, enqueued_ms, priority) = (get_u64(&mut b)?, get_u64(&mut b)?, get_u8(&mut b)?);
+                    let routing_key = get_str(&mut b)?;
+                    Some((LogPosition { seq, enqueued_ms, priority, routing_key }, get_envelope(&mut b)?))
+                })
+                .collect();
+            if json() {
+                let messages: Vec<_> = payloads
+                    .into_iter()
+                    .zip(logged)
+                    .map(|(v, logged)| match logged {
+                        Some((position, env)) => Logged { position: Some(position), record: Record::new(v, env, false) },
+                        None => Logged { position: None, record: Record::new(v, Envelope::default(), false) },
+                    })
+                    .collect();
+                emit(json!({ "status": st, "messages": messages }));
+                return Ok(());
+            }
             println!("status={:?}", st);
-            if st == Status::Ok {
-                let mut b = &payload[..];
-                if let Some(n) = get_u32(&mut b) {
-                    if n == 0 {
-                        println!(
-                            "No messages found in topic '{}'.",
-                            topic
-                        );
-                        return Ok(());
+            if st != Status::Ok {
+                return Ok(());
+            }
+            if payloads.is_empty() {
+                println!("No messages found in topic '{}'.", topic);
+                return Ok(());
+            }
+            println!("Found {} messages in topic '{}':", payloads.len(), topic);
+            for (i, (v, logged)) in payloads.iter().zip(&logged).enumerate() {
+                match logged {
+                    Some((p, env)) => println!(
+                        "[{}] {}  enqueued={} priority={} routing_key={}  {}",
+                        p.seq,
+                        String::from_utf8_lossy(v),
+                        fmt_time(p.enqueued_ms),
+                        p.priority,
+                        p.routing_key,
+                        fmt_envelope(env)
+                    ),
+                    None => println!("[{}] {}", i, String::from_utf8_lossy(v)),
+                }
+            }
+        }
+        Cmd::ResetOffset { topic, to, seq, at, group } => {
+            let (st, payload) = redirecting_call_resp(server, Op::ResetOffset, |b| {
+                put_str(b, &topic);
+                // 2: to the seq that follows the group, 3: to the time
+                put_u8(b, to.map(|to| to as u8).unwrap_or(if at.is_some() { 3 } else { 2 }));
+                put_str(b, &group);
+                if let Some(n) = seq.or(at) {
+                    put_u64(b, n);
+                }
+            })
+            .await?;
+            let mut b = &payload[..];
+            let pending = if st == Status::Ok { get_u32(&mut b) } else { None };
+            if json() {
+                emit(json!({ "status": st, "pending": pending }));
+                return Ok(());
+            }
+            println!("status={:?}", st);
+            if let Some(n) = pending {
+                println!("{} messages pending in topic '{}'", n, topic);
+            }
+        }
+        Cmd::Stats { topic, group } => {
+            let (st, payload) = redirecting_call_resp(server, Op::Stats, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+            })
+            .await?;
+            if !json() {
+                println!("status={:?}", st);
+            }
+            let mut b = &payload[..];
+            let stats = match (
+                st,
+                get_u64(&mut b),
+                get_u64(&mut b),
+                get_u32(&mut b),
+                get_u32(&mut b),
+                get_u32(&mut b),
+                get_u64(&mut b),
+            ) {
+                (Status::Ok, Some(enq), Some(deq), Some(depth), Some(peak), Some(in_flight), Some(age)) => {
+                    Some((enq, deq, depth, peak, in_flight, age))
+                }
+                _ => None,
+            };
+            // (depth_bytes, memory_used, memory_limit), missing from older servers
+            let memory = (get_u64(&mut b), get_u64(&mut b), get_u64(&mut b));
+            match stats {
+                _ if json() => {
+                    let mut v = json!({ "status": st });
+                    if let Some((enq, deq, depth, peak, in_flight, age)) = stats {
+                        v["enqueued"] = enq.into();
+                        v["delivered"] = deq.into();
+                        v["depth"] = depth.into();
+                        v["peak_depth"] = peak.into();
+                        v["in_flight"] = in_flight.into();
+                        v["oldest_age_ms"] = age.into();
                     }
-                    println!(
-                        "Found {} messages in topic '{}':",
-                        n, topic
-                    );
-                    for i in 0..n {
-                        if let Some(msg) = get_bytes(&mut b) {
-                            println!("[{}] {}", i, String::from_utf8_lossy(&msg));
+                    if let (Some(bytes), Some(used), Some(limit)) = memory {
+                        v["depth_bytes"] = bytes.into();
+                        v["memory_used"] = used.into();
+                        v["memory_limit"] = limit.into();
+                    }
+                    emit(v);
+                }
+                Some((enq, deq, depth, peak, in_flight, age)) => {
+                    println!("enqueued={} delivered={}", enq, deq);
+                    println!("depth={} peak_depth={} in_flight={}", depth, peak, in_flight);
+                    println!("oldest_age_ms={}", age);
+                    if let (Some(bytes), Some(used), Some(limit)) = memory {
+                        println!("depth_bytes={} memory_used={} memory_limit={}", bytes, used, limit);
+                    }
+                }
+                None => {}
+            }
+        }
+        Cmd::Watch { topic, group, interval } => {
+            tokio::select! {
+                res = watch(server, &topic, &group, interval) => res?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Cmd::Purge { topic, group } => {
+            let (st, payload) = redirecting_call_resp(server, Op::Purge, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+            })
+            .await?;
+            let mut b = &payload[..];
+            let purged = if st == Status::Ok { get_u32(&mut b) } else { None };
+            if json() {
+                emit(json!({ "status": st, "purged": purged }));
+                return Ok(());
+            }
+            println!("status={:?}", st);
+            if let Some(n) = purged {
+                println!("purged {} messages from topic '{}'", n, topic);
+            }
+        }
+        Cmd::Bind {
+            topic,
+            group,
+            key,
+            headers,
+            filter,
+            sample,
+        } => {
+            let (st, payload) = redirecting_call_resp(server, Op::Bind, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+                put_str(b, &key);
+                put_str(b, headers.as_deref().unwrap_or(""));
+                put_str(b, filter.as_deref().unwrap_or(""));
+                put_u32(b, sample.unwrap_or(0));
+            })
+            .await?;
+            // what's wrong with the filter, if that's why
+            let error = get_str(&mut &payload[..]).filter(|_| st == Status::BadRequest);
+            if json() {
+                emit(json!({ "status": st, "error": error }));
+            } else {
+                println!("status={:?}", st);
+                if let Some(e) = error {
+                    println!("filter: {}", e);
+                }
+            }
+        }
+        Cmd::Unbind { topic, group } => {
+            call(server, Op::Unbind, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+            })
+            .await?;
+        }
+        Cmd::BindTopic { topic, pattern } => {
+            let (st, _payload) = redirecting_call_resp(server, Op::BindTopic, |b| {
+                put_str(b, &topic);
+                put_str(b, &pattern);
+            })
+            .await?;
+            print_status(st);
+        }
+        Cmd::UnbindTopic { topic, pattern } => {
+            let (st, _payload) = redirecting_call_resp(server, Op::UnbindTopic, |b| {
+                put_str(b, &topic);
+                put_str(b, &pattern);
+            })
+            .await?;
+            print_status(st);
+        }
+        Cmd::Schedule { topic, name, cron, data, key } => {
+            let (st, payload) = redirecting_call_resp(server, Op::Schedule, |b| {
+                put_str(b, &topic);
+                put_str(b, &name);
+                put_str(b, &cron);
+                put_bytes(b, data.as_bytes());
+                put_str(b, &key);
+            })
+            .await?;
+            // what's wrong with the cron expression, if that's why
+            let error = get_str(&mut &payload[..]).filter(|_| st == Status::BadRequest);
+            if json() {
+                emit(json!({ "status": st, "error": error }));
+            } else {
+                println!("status={:?}", st);
+                if let Some(e) = error {
+                    println!("cron: {}", e);
+                }
+            }
+        }
+        Cmd::Unschedule { topic, name } => {
+            let (st, _payload) = redirecting_call_resp(server, Op::Unschedule, |b| {
+                put_str(b, &topic);
+                put_str(b, &name);
+            })
+            .await?;
+            print_status(st);
+        }
+        Cmd::Audit { topic, message_id, limit } => {
+            let (st, payload) = redirecting_call_resp(server, Op::Audit, |b| {
+                put_str(b, &topic);
+                put_str(b, message_id.as_deref().unwrap_or(""));
+                put_u32(b, limit);
+            })
+            .await?;
+            let mut b = &payload[..];
+            let n = if st == Status::Ok { get_u32(&mut b).unwrap_or(0) } else { 0 };
+            let events: Vec<Audited> = (0..n)
+                .map_while(|_| {
+                    let (at_ms, event, seq, conn) = (get_u64(&mut b)?, get_u8(&mut b)?, get_u64(&mut b)?, get_u64(&mut b)?);
+                    Some(Audited {
+                        at_ms,
+                        event: match AuditEvent::try_from(event) {
+                            Ok(e) => format!("{:?}", e).to_lowercase(),
+                            Err(v) => v.to_string(),
+                        },
+                        seq,
+                        conn,
+                        identity: get_str(&mut b)?,
+                        group: get_str(&mut b)?,
+                        message_id: get_str(&mut b)?,
+                    })
+                })
+                .collect();
+            if json() {
+                emit(json!({ "status": st, "events": events }));
+                return Ok(());
+            }
+            println!("status={:?}", st);
+            for e in &events {
+                println!(
+                    "{} {:<7} [{}] conn={} identity={} group={} message_id={}",
+                    fmt_time(e.at_ms),
+                    e.event,
+                    e.seq,
+                    e.conn,
+                    e.identity,
+                    e.group,
+                    e.message_id
+                );
+            }
+        }
+        Cmd::DeleteGroup { topic, group, force } => {
+            call(server, Op::DeleteGroup, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+                put_u8(b, force as u8);
+            })
+            .await?;
+        }
+        Cmd::CommitOffset {
+            topic,
+            group,
+            offset,
+        } => {
+            call(server, Op::CommitOffset, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+                put_u64(b, offset);
+            })
+            .await?;
+        }
+        Cmd::FetchOffset { topic, group } => {
+            let (st, payload) = redirecting_call_resp(server, Op::FetchOffset, |b| {
+                put_str(b, &topic);
+                put_str(b, &group);
+            })
+            .await?;
+            let mut b = &payload[..];
+            let offsets = if st == Status::Ok { get_u64(&mut b).zip(get_u64(&mut b)) } else { None };
+            if json() {
+                let (committed, last_seq) = offsets.unzip();
+                emit(json!({ "status": st, "committed": committed, "last_seq": last_seq }));
+                return Ok(());
+            }
+            println!("status={:?}", st);
+            if let Some((committed, last_seq)) = offsets {
+                println!("committed={} last_seq={} lag={}", committed, last_seq, last_seq.saturating_sub(committed));
+            }
+        }
+        Cmd::Maintenance { mode } => {
+            let mut s = connect(server).await?;
+            let mut body = BytesMut::new();
+            put_u8(&mut body, mode as u8);
+            let (st, _payload) = rpc(&mut s, Op::Maintenance, &body).await?;
+            print_status(st);
+        }
+        Cmd::HashPassword => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            println!("{}", quique::auth::hash_password(password.trim_end_matches(['\r', '\n']))?);
+        }
+    }
+    Ok(())
+}
+
+/// Print why a produce was refused after `sent` messages went through,
+/// naming the full groups of a QueueFull
+fn print_refused(st: Status, answer: &[u8], sent: usize) {
+    let mut b = answer;
+    let full: Vec<String> = match st {
+        Status::QueueFull => (0..get_u32(&mut b).unwrap_or(0)).map_while(|_| get_str(&mut b)).collect(),
+        _ => Vec::new(),
+    };
+    if json() {
+        let mut v = json!({ "status": st, "sent": sent });
+        if st == Status::QueueFull {
+            v["full"] = json!(full);
+        }
+        emit(v);
+    } else if st == Status::QueueFull {
+        let full: Vec<&str> = full.iter().map(|g| if g.is_empty() { "(default)" } else { g.as_str() }).collect();
+        println!("full: {}", full.join(", "));
+    }
+}
+
+/// How long each consume of `tail` waits for a message
+const TAIL_POLL_MS: u32 = 1000;
+
+/// Consume `topic` as `group` and print what comes, acking it, until a
+/// consume fails. The connection is kept, only the first consume follows
+/// redirects.
+async fn tail(server: &str, topic: &str, group: &str, format: Format, envelope: bool) -> anyhow::Result<()> {
+    let put_consume = |b: &mut BytesMut| {
+        put_str(b, topic);
+        put_u32(b, TAIL_POLL_MS);
+        put_str(b, group);
+    };
+    let (mut s, mut st, mut payload) = redirecting_conn(server, Op::Consume, put_consume).await?;
+    loop {
+        match st {
+            Status::Ok => {
+                let mut b = &payload[..];
+                let (Some(tag), Some(v), Some(env)) = (get_u64(&mut b), get_bytes(&mut b), get_envelope(&mut b)) else {
+                    anyhow::bail!("malformed consume answer");
+                };
+                let redelivered = get_u8(&mut b) == Some(1);
+                let info = get_delivery_info(&mut b);
+                if !json() {
+                    let value = match format {
+                        Format::Utf8 => String::from_utf8_lossy(&v).into_owned(),
+                        Format::Hex => hex(&v),
+                    };
+                    let again = if redelivered { "  (redelivered)" } else { "" };
+                    println!("{} [{}] {}{}", fmt_time(env.timestamp_ms), tag, value, again);
+                    if envelope {
+                        println!("    {}", fmt_envelope(&env));
+                        if let Some(info) = &info {
+                            println!("    {}", fmt_info(info));
                         }
                     }
                 }
+                let mut body = BytesMut::new();
+                put_str(&mut body, topic);
+                put_u64(&mut body, tag);
+                put_str(&mut body, group);
+                let (st, _payload) = rpc(&mut s, Op::Ack, &body).await?;
+                if json() {
+                    // the envelope always comes along, --envelope is for text
+                    let record = Record::new(v, env, matches!(format, Format::Hex));
+                    emit(Delivery { tag, acked: st == Status::Ok, redelivered, record, info: info.map(Info::from) });
+                } else if st != Status::Ok {
+                    println!("ack status={:?}", st);
+                }
             }
+            Status::Empty => {}
+            st => anyhow::bail!("consume from {} failed: {:?}", topic, st),
         }
+        let mut body = BytesMut::new();
+        put_consume(&mut body);
+        (st, payload) = rpc(&mut s, Op::Consume, &body).await?;
+    }
+}
+
+/// A topic's part of a `Metadata` answer, what `describe` shows
+struct TopicInfo {
+    capacity: u32,
+    idle_ttl_secs: u32,
+    message_ttl_ms: u32,
+    dead_letter: String,
+    max_priority: u8,
+    kind: u8,
+    retention_secs: u32,
+    retention_bytes: u64,
+    replicas: u8,
+    webhook: String,
+    shards: u8,
+    overflow: u8,
+    visibility_timeout_ms: u32,
+    capacity_bytes: u64,
+    auto_delete: bool,
+    exclusive: bool,
+    transient: bool,
+    audit: bool,
+    /// (group, binding key, header conditions, filter, sample rate), "" or
+    /// 0 = none, the default group first
+    groups: Vec<(String, String, String, String, u32)>,
+    /// topic patterns it's bound to
+    patterns: Vec<String>,
+    /// topics bound to a pattern matching it
+    copied_to: Vec<String>,
+    /// (name, cron, next_ms) of its schedules
+    schedules: Vec<(String, String, u64)>,
+}
+
+impl TopicInfo {
+    /// Settings and groups, after the partitions of a `Metadata` answer
+    fn decode(b: &mut &[u8]) -> Option<Self> {
+        let mut info = TopicInfo {
+            capacity: get_u32(b)?,
+            idle_ttl_secs: get_u32(b)?,
+            message_ttl_ms: get_u32(b)?,
+            dead_letter: get_str(b)?,
+            max_priority: get_u8(b)?,
+            kind: get_u8(b)?,
+            retention_secs: get_u32(b)?,
+            retention_bytes: get_u64(b)?,
+            replicas: get_u8(b)?,
+            webhook: get_str(b)?,
+            shards: get_u8(b)?,
+            overflow: get_u8(b)?,
+            visibility_timeout_ms: get_u32(b)?,
+            capacity_bytes: get_u64(b)?,
+            auto_delete: get_u8(b)? != 0,
+            exclusive: get_u8(b)? != 0,
+            transient: get_u8(b)? != 0,
+            audit: get_u8(b)? != 0,
+            groups: Vec::new(),
+            patterns: Vec::new(),
+            copied_to: Vec::new(),
+            schedules: Vec::new(),
+        };
+        for _ in 0..get_u32(b)? {
+            info.groups.push((get_str(b)?, get_str(b)?, String::new(), String::new(), 0));
+        }
+        for _ in 0..get_u32(b)? {
+            info.patterns.push(get_str(b)?);
+        }
+        for g in &mut info.groups {
+            g.2 = get_str(b)?;
+        }
+        for g in &mut info.groups {
+            g.3 = get_str(b)?;
+        }
+        for _ in 0..get_u32(b)? {
+            info.copied_to.push(get_str(b)?);
+        }
+        for _ in 0..get_u32(b)? {
+            info.schedules.push((get_str(b)?, get_str(b)?, get_u64(b)?));
+        }
+        // servers from before sample rates don't send them
+        for g in &mut info.groups {
+            g.4 = get_u32(b).unwrap_or(0);
+        }
+        Some(info)
+    }
+}
+
+/// (enqueued, delivered, depth, in_flight, oldest_age_ms) of a group of
+/// `topic`, asked on `s`
+async fn group_stats(s: &mut Stream, topic: &str, group: &str) -> anyhow::Result<(u64, u64, u32, u32, u64)> {
+    let mut body = BytesMut::new();
+    put_str(&mut body, topic);
+    put_str(&mut body, group);
+    let (st, payload) = rpc(s, Op::Stats, &body).await?;
+    if st != Status::Ok {
+        anyhow::bail!("stats of {} failed: {:?}", topic, st);
+    }
+    let mut b = &payload[..];
+    let (Some(enq), Some(deq), Some(depth), Some(_peak), Some(in_flight), Some(age)) = (
+        get_u64(&mut b),
+        get_u64(&mut b),
+        get_u32(&mut b),
+        get_u32(&mut b),
+        get_u32(&mut b),
+        get_u64(&mut b),
+    ) else {
+        anyhow::bail!("malformed stats answer");
+    };
+    Ok((enq, deq, depth, in_flight, age))
+}
+
+/// Print where `topic` is led, the settings and groups of one of its
+/// partitions, and each group's stats, with rates over `sample`
+async fn describe(server: &str, topic: &str, partition: u32, sample: Duration) -> anyhow::Result<()> {
+    let (st, payload) = redirecting_call_resp(server, Op::Metadata, |b| put_str(b, topic)).await?;
+    if st != Status::Ok {
+        print_status(st);
+        return Ok(());
+    }
+    let mut b = &payload[..];
+    let n = get_u32(&mut b).unwrap_or(0);
+    let mut leaders = Vec::new();
+    for _ in 0..n {
+        let (Some(p), Some(addr)) = (get_u32(&mut b), get_str(&mut b)) else {
+            break;
+        };
+        leaders.push((p, addr));
+    }
+
+    // the partition's own leader has its settings, and its stats
+    let name = partition_name(topic, partition);
+    let (mut s, st, payload) = redirecting_conn(server, Op::Metadata, |b| put_str(b, &name)).await?;
+    let mut b = &payload[..];
+    for _ in 0..get_u32(&mut b).unwrap_or(0) {
+        let _ = (get_u32(&mut b), get_str(&mut b));
+    }
+    let info = match (st, TopicInfo::decode(&mut b)) {
+        (Status::Ok, Some(info)) => info,
+        (Status::Ok, None) => {
+            print_status(Status::NotFound);
+            return Ok(());
+        }
+        (st, _) => {
+            print_status(st);
+            return Ok(());
+        }
+    };
+    let kind = match info.kind {
+        0 => "fanout",
+        1 => "direct",
+        2 => "pattern",
+        _ => "unknown",
+    };
+    let overflow = match info.overflow {
+        0 => "reject",
+        1 => "drop-head",
+        2 => "dead-letter",
+        _ => "unknown",
+    };
+
+    let mut before = Vec::new();
+    for (g, ..) in &info.groups {
+        before.push(group_stats(&mut s, &name, g).await?);
+    }
+    let sampled = !sample.is_zero();
+    if sampled {
+        tokio::time::sleep(sample).await;
+    }
+    let secs = sample.as_secs_f64();
+    // counted for the topic, every group reports the same
+    let enqueued_before = before.first().map_or(0, |b| b.0);
+    let mut enqueued = enqueued_before;
+    // (group, binding, stats now, delivered per second)
+    let mut groups = Vec::new();
+    for ((g, binding, headers, filter, sample), before) in info.groups.iter().zip(&before) {
+        let now = if sampled { group_stats(&mut s, &name, g).await? } else { *before };
+        enqueued = now.0;
+        let rate = sampled.then(|| now.1.saturating_sub(before.1) as f64 / secs);
+        groups.push((g, binding, headers, filter, *sample, now, rate));
+    }
+    let in_rate = sampled.then(|| enqueued.saturating_sub(enqueued_before) as f64 / secs);
+
+    if json() {
+        let or_null = |v: &str| (!v.is_empty()).then(|| v.to_string());
+        let partitions: Vec<_> = leaders.iter().map(|(p, addr)| json!({ "partition": p, "leader": addr })).collect();
+        let groups: Vec<_> = groups
+            .iter()
+            .map(|(g, binding, headers, filter, sample, now, rate)| {
+                json!({
+                    "group": g,
+                    "binding": or_null(binding),
+                    "headers": or_null(headers),
+                    "filter": or_null(filter),
+                    "sample": (*sample > 0).then(|| *sample as f64 / SAMPLE_ALL as f64),
+                    "depth": now.2,
+                    "in_flight": now.3,
+                    "oldest_age_ms": now.4,
+                    "delivered": now.1,
+                    "out_per_sec": rate,
+                })
+            })
+            .collect();
+        let schedules: Vec<_> = info
+            .schedules
+            .iter()
+            .map(|(name, cron, next_ms)| json!({ "name": name, "cron": cron, "next_ms": (*next_ms > 0).then_some(*next_ms) }))
+            .collect();
+        emit(json!({
+            "status": st,
+            "topic": topic,
+            "partitions": partitions,
+            "partition": name,
+            "capacity": info.capacity,
+            "capacity_bytes": info.capacity_bytes,
+            "auto_delete": info.auto_delete,
+            "exclusive": info.exclusive,
+            "transient": info.transient,
+            "audit": info.audit,
+            "kind": kind,
+            "max_priority": info.max_priority,
+            "shards": info.shards.max(1),
+            "replicas": info.replicas.max(1),
+            "idle_ttl_secs": info.idle_ttl_secs,
+            "message_ttl_ms": info.message_ttl_ms,
+            "dead_letter": or_null(&info.dead_letter),
+            "overflow": overflow,
+            "visibility_timeout_ms": info.visibility_timeout_ms,
+            "retention_secs": info.retention_secs,
+            "retention_bytes": info.retention_bytes,
+            "webhook": or_null(&info.webhook),
+            "groups": groups,
+            "bound_to": info.patterns,
+            "copied_to": info.copied_to,
+            "schedules": schedules,
+            "enqueued": enqueued,
+            "in_per_sec": in_rate,
+        }));
+        return Ok(());
+    }
+
+    let or_never = |v: u64, unit: &str| if v == 0 { "never".to_string() } else { format!("{}{}", v, unit) };
+    let or_default = |v: u64, unit: &str| if v == 0 { "server default".to_string() } else { format!("{}{}", v, unit) };
+    let or_none = |v: &str| if v.is_empty() { "-".to_string() } else { v.to_string() };
+    let or_unlimited = |v: u64| if v == 0 { "unlimited".to_string() } else { v.to_string() };
+    println!("topic        {}", topic);
+    println!("partitions   {}", n);
+    for (p, addr) in &leaders {
+        println!("  {:>3} -> {}", p, addr);
+    }
+    println!();
+    println!("partition    {}", name);
+    println!(
+        "capacity {}  capacity_bytes {}  kind {}  max_priority {}  shards {}  replicas {}",
+        info.capacity,
+        or_unlimited(info.capacity_bytes),
+        kind,
+        info.max_priority,
+        info.shards.max(1),
+        info.replicas.max(1)
+    );
+    println!(
+        "idle_ttl {}  auto_delete {}  exclusive {}  transient {}  audit {}  message_ttl {}  dead_letter {}  overflow {}",
+        or_never(info.idle_ttl_secs as u64, "s"),
+        info.auto_delete,
+        info.exclusive,
+        info.transient,
+        info.audit,
+        or_never(info.message_ttl_ms as u64, "ms"),
+        or_none(&info.dead_letter),
+        overflow
+    );
+    println!(
+        "retention {}  retention_bytes {}  visibility_timeout {}",
+        or_default(info.retention_secs as u64, "s"),
+        or_default(info.retention_bytes, ""),
+        or_never(info.visibility_timeout_ms as u64, "ms")
+    );
+    println!("webhook {}", or_none(&info.webhook));
+    if !info.patterns.is_empty() {
+        println!("bound to {}", info.patterns.join(", "));
+    }
+    if !info.copied_to.is_empty() {
+        println!("copied to {}", info.copied_to.join(", "));
+    }
+    for (name, cron, next_ms) in &info.schedules {
+        let next = if *next_ms == 0 { "never".to_string() } else { fmt_time(*next_ms) };
+        println!("schedule {}  {}  next {}", name, cron, next);
+    }
+    println!();
+    println!(
+        "{:<20} {:<16} {:>8} {:>9} {:>10} {:>10} {:>8}  headers",
+        "group", "binding", "depth", "in_flight", "oldest_ms", "delivered", "out/s"
+    );
+    for (g, binding, headers, filter, sample, now, rate) in &groups {
+        let g = if g.is_empty() { "(default)" } else { g.as_str() };
+        let rate = rate.map_or("-".to_string(), |r| format!("{:.1}", r));
+        println!(
+            "{:<20} {:<16} {:>8} {:>9} {:>10} {:>10} {:>8}  {}",
+            g,
+            or_none(binding),
+            now.2,
+            now.3,
+            now.4,
+            now.1,
+            rate,
+            or_none(headers)
+        );
+        if !filter.is_empty() {
+            println!("{:<20} filter {}", "", filter);
+        }
+        if *sample > 0 {
+            println!("{:<20} sample {}%", "", *sample as f64 * 100.0 / SAMPLE_ALL as f64);
+        }
+    }
+    println!();
+    match in_rate {
+        Some(rate) => println!("enqueued {} since open, {:.1}/s over the last {:?}", enqueued, rate, sample),
+        None => println!("enqueued {} since open", enqueued),
     }
     Ok(())
 }
 
-async fn connect(addr: &str) -> anyhow::Result<TcpStream> {
-    Ok(TcpStream::connect(addr).await?)
+fn parse_sample(s: &str) -> Result<Duration, String> {
+    match s {
+        "0" => Ok(Duration::ZERO),
+        s => parse_interval(s),
+    }
+}
+
+/// How long cluster-status waits for each member to answer
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A cluster member as a node sees it, from `ClusterInfo`
+#[derive(Clone)]
+struct Member {
+    id: String,
+    addr: String,
+    status: NodeStatus,
+    last_seen_ms: u64,
+    /// topics it leads that the node asked leads or follows
+    leads: Vec<String>,
+}
+
+/// Ask the node at `addr` how it sees the cluster: its own id and every
+/// member, if it answered Ok
+async fn cluster_info(addr: &str) -> anyhow::Result<(Status, Option<(String, Vec<Member>)>)> {
+    let mut s = connect(addr).await?;
+    let (st, payload) = rpc(&mut s, Op::ClusterInfo, &BytesMut::new()).await?;
+    if st != Status::Ok {
+        return Ok((st, None));
+    }
+    let mut b = &payload[..];
+    let Some(me) = get_str(&mut b) else {
+        return Ok((st, None));
+    };
+    let n = get_u32(&mut b).unwrap_or(0);
+    let mut members = Vec::new();
+    for _ in 0..n {
+        let (Some(id), Some(addr), Some(status), Some(last_seen_ms), Some(m)) = (
+            get_str(&mut b),
+            get_str(&mut b),
+            get_u8(&mut b).and_then(|v| NodeStatus::try_from(v).ok()),
+            get_u64(&mut b),
+            get_u32(&mut b),
+        ) else {
+            break;
+        };
+        let leads = (0..m).filter_map(|_| get_str(&mut b)).collect();
+        members.push(Member {
+            id,
+            addr,
+            status,
+            last_seen_ms,
+            leads,
+        });
+    }
+    Ok((st, Some((me, members))))
+}
+
+/// Depth samples the sparkline of `watch` shows
+const WATCH_HISTORY: usize = 60;
+
+/// Ask for the stats of `topic` every `interval` and redraw them, with the
+/// rates since the last ask and the depth over the last `WATCH_HISTORY` asks
+async fn watch(server: &str, topic: &str, group: &str, interval: Duration) -> anyhow::Result<()> {
+    let put_stats = |b: &mut BytesMut| {
+        put_str(b, topic);
+        put_str(b, group);
+    };
+    let (mut s, mut st, mut payload) = redirecting_conn(server, Op::Stats, put_stats).await?;
+    let mut depths = VecDeque::with_capacity(WATCH_HISTORY);
+    let mut last: Option<(Instant, u64, u64)> = None;
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        if st != Status::Ok {
+            anyhow::bail!("stats of {} failed: {:?}", topic, st);
+        }
+        let mut b = &payload[..];
+        let (Some(enq), Some(deq), Some(depth), Some(peak), Some(in_flight), Some(age)) = (
+            get_u64(&mut b),
+            get_u64(&mut b),
+            get_u32(&mut b),
+            get_u32(&mut b),
+            get_u32(&mut b),
+            get_u64(&mut b),
+        ) else {
+            anyhow::bail!("malformed stats answer");
+        };
+        let now = Instant::now();
+        let (in_rate, out_rate) = match last {
+            Some((at, last_enq, last_deq)) => {
+                let secs = now.duration_since(at).as_secs_f64().max(f64::EPSILON);
+                (enq.saturating_sub(last_enq) as f64 / secs, deq.saturating_sub(last_deq) as f64 / secs)
+            }
+            None => (0.0, 0.0),
+        };
+        last = Some((now, enq, deq));
+        if depths.len() == WATCH_HISTORY {
+            depths.pop_front();
+        }
+        depths.push_back(depth);
+
+        if json() {
+            // a line per refresh, rates are 0 on the first
+            emit(json!({
+                "depth": depth,
+                "peak_depth": peak,
+                "in_flight": in_flight,
+                "oldest_age_ms": age,
+                "in_per_sec": in_rate,
+                "out_per_sec": out_rate,
+            }));
+        } else {
+            // clear the screen and start at the top
+            print!("\x1b[2J\x1b[H");
+            let name = if group.is_empty() { topic.to_string() } else { format!("{} ({})", topic, group) };
+            println!("{}  every {:?}, ctrl-c to stop", name, interval);
+            println!();
+            println!("{:>10} {:>10} {:>10} {:>12} {:>10} {:>10}", "depth", "peak", "in_flight", "oldest_ms", "in/s", "out/s");
+            println!(
+                "{:>10} {:>10} {:>10} {:>12} {:>10.1} {:>10.1}",
+                depth, peak, in_flight, age, in_rate, out_rate
+            );
+            println!();
+            println!("depth {}", sparkline(&depths));
+        }
+        std::io::stdout().flush()?;
+
+        tick.tick().await;
+        let mut body = BytesMut::new();
+        put_stats(&mut body);
+        (st, payload) = rpc(&mut s, Op::Stats, &body).await?;
+    }
+}
+
+/// `values` as a line of block characters, scaled to the largest
+fn sparkline(values: &VecDeque<u32>) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1) as usize;
+    values.iter().map(|&v| BARS[v as usize * (BARS.len() - 1) / max]).collect()
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = n.parse().map_err(|_| format!("expected a number with a unit, like 1s: {}", s))?;
+    let d = match unit {
+        "ms" => Duration::from_millis(n),
+        "s" | "" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        _ => return Err(format!("unknown unit {:?}, expected ms, s or m", unit)),
+    };
+    if d.is_zero() {
+        return Err("interval can't be 0".to_string());
+    }
+    Ok(d)
+}
+
+/// Unix ms, or an ISO 8601 UTC time as `fmt_time` prints them, the
+/// fraction optional
+fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(ms) = s.parse() {
+        return Ok(ms);
+    }
+    let bad = || format!("expected unix ms or a UTC time like 2024-05-01T12:00:00Z: {}", s);
+    let (date, time) = s.strip_suffix('Z').and_then(|s| s.split_once('T')).ok_or_else(bad)?;
+    let (time, frac) = time.split_once('.').unwrap_or((time, "0"));
+    let fields = |s: &str, sep: char| -> Result<Vec<i64>, String> {
+        let v: Vec<i64> = s.split(sep).map(|f| f.parse().map_err(|_| bad())).collect::<Result<_, _>>()?;
+        if v.len() == 3 { Ok(v) } else { Err(bad()) }
+    };
+    let (d, t) = (fields(date, '-')?, fields(time, ':')?);
+    let (y, m, day) = (d[0], d[1], d[2]);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&day) || !(0..24).contains(&t[0]) || !(0..60).contains(&t[1]) || !(0..60).contains(&t[2]) {
+        return Err(bad());
+    }
+    if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let ms: i64 = format!("{:0<3}", frac)[..3].parse().unwrap();
+    // days since 1970-01-01, after Howard Hinnant's days_from_civil
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    u64::try_from(((days * 24 + t[0]) * 60 + t[1]) * 60_000 + t[2] * 1000 + ms).map_err(|_| bad())
+}
+
+/// A sample rate as parts of `SAMPLE_ALL`, from a fraction like `0.01` or a
+/// percentage like `1%`
+fn parse_rate(s: &str) -> Result<u32, String> {
+    let (v, scale) = match s.strip_suffix('%') {
+        Some(p) => (p, 100.0),
+        None => (s, 1.0),
+    };
+    let rate: f64 = v.trim().parse().map_err(|_| format!("expected a fraction like 0.01 or a percentage like 1%: {}", s))?;
+    let parts = (rate / scale * SAMPLE_ALL as f64).round();
+    if !(1.0..=SAMPLE_ALL as f64).contains(&parts) {
+        return Err(format!("sample rate has to be above 0 and at most 100%: {}", s));
+    }
+    Ok(parts as u32)
+}
+
+/// Messages an export fetches at once
+const EXPORT_BATCH: u32 = 100;
+
+/// Fetch `topic` as `group` until it's empty, writing each message to `out`
+/// as `format` and acking it once written. Returns how many were exported.
+async fn export(server: &str, topic: &str, group: &str, format: DumpFormat, out: &mut impl std::io::Write) -> anyhow::Result<u64> {
+    let put_fetch = |b: &mut BytesMut| {
+        put_str(b, topic);
+        put_u32(b, 0);
+        put_str(b, group);
+        put_u32(b, EXPORT_BATCH);
+        put_u32(b, MAX_FRAME / 2);
+    };
+    let (mut s, mut st, mut payload) = redirecting_conn(server, Op::Fetch, put_fetch).await?;
+    let mut exported = 0;
+    loop {
+        match st {
+            Status::Ok => {}
+            Status::Empty => return Ok(exported),
+            st => anyhow::bail!("fetch from {} failed: {:?}", topic, st),
+        }
+        let mut b = &payload[..];
+        let n = get_u32(&mut b).unwrap_or(0);
+        if n == 0 {
+            return Ok(exported);
+        }
+        for _ in 0..n {
+            let (Some(tag), Some(v), Some(env)) = (get_u64(&mut b), get_bytes(&mut b), get_envelope(&mut b)) else {
+                anyhow::bail!("malformed fetch answer");
+            };
+            match format {
+                DumpFormat::Ndjson => {
+                    serde_json::to_writer(&mut *out, &Record::new(v, env, false))?;
+                    out.write_all(b"\n")?;
+                }
+                DumpFormat::Binary => {
+                    let mut rec = BytesMut::new();
+                    put_bytes(&mut rec, &v);
+                    put_envelope(&mut rec, &env);
+                    out.write_all(&rec)?;
+                }
+            }
+            let mut body = BytesMut::new();
+            put_str(&mut body, topic);
+            put_u64(&mut body, tag);
+            put_str(&mut body, group);
+            let (st, _payload) = rpc(&mut s, Op::Ack, &body).await?;
+            if st != Status::Ok {
+                anyhow::bail!("ack of {} failed: {:?}", tag, st);
+            }
+            exported += 1;
+        }
+        let mut body = BytesMut::new();
+        put_fetch(&mut body);
+        (st, payload) = rpc(&mut s, Op::Fetch, &body).await?;
+    }
+}
+
+/// Produce the messages of `data`, an export as `format`, to `topic` one
+/// at a time. Returns how many were imported.
+async fn import(server: &str, topic: &str, format: DumpFormat, data: &[u8]) -> anyhow::Result<u64> {
+    let mut messages = Vec::new();
+    match format {
+        DumpFormat::Ndjson => {
+            for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let rec: Record = serde_json::from_slice(line).map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+                let payload = match (rec.payload, rec.payload_hex) {
+                    (Some(text), _) => text.into_bytes(),
+                    (None, Some(h)) => unhex(&h).ok_or_else(|| anyhow::anyhow!("line {}: payload_hex isn't hex", i + 1))?,
+                    (None, None) => Vec::new(),
+                };
+                let env = Envelope {
+                    message_id: rec.message_id,
+                    content_type: rec.content_type,
+                    timestamp_ms: rec.timestamp_ms,
+                    headers: rec.headers,
+                    reply_to: rec.reply_to,
+                    correlation_id: rec.correlation_id,
+                };
+                messages.push((payload, env));
+            }
+        }
+        DumpFormat::Binary => {
+            let mut b = data;
+            while !b.is_empty() {
+                let (Some(v), Some(env)) = (get_bytes(&mut b), get_envelope(&mut b)) else {
+                    anyhow::bail!("truncated record after {} messages", messages.len());
+                };
+                messages.push((v, env));
+            }
+        }
+    }
+    let put_produce = |b: &mut BytesMut, (v, env): &(Vec<u8>, Envelope)| {
+        put_str(b, topic);
+        put_bytes(b, v);
+        put_u8(b, 0);
+        put_str(b, "");
+        put_envelope(b, env);
+        put_u8(b, Acks::Leader as u8);
+    };
+    let Some(first) = messages.first() else {
+        return Ok(0);
+    };
+    // the rest go to whichever node took the first
+    let (mut s, st, _payload) = redirecting_conn(server, Op::Produce, |b| put_produce(b, first)).await?;
+    if st != Status::Ok {
+        anyhow::bail!("produce to {} failed: {:?}", topic, st);
+    }
+    for (i, msg) in messages.iter().enumerate().skip(1) {
+        let mut body = BytesMut::new();
+        put_produce(&mut body, msg);
+        let (st, _payload) = rpc(&mut s, Op::Produce, &body).await?;
+        if st != Status::Ok {
+            anyhow::bail!("produce to {} failed after {} messages: {:?}", topic, i, st);
+        }
+    }
+    Ok(messages.len() as u64)
+}
+
+fn hex(v: &[u8]) -> String {
+    v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// `ms` since the epoch as an ISO 8601 UTC time, like 2024-05-01T12:00:00.000Z
+fn fmt_time(ms: u64) -> String {
+    let (days, ms) = ((ms / 86_400_000) as i64, ms % 86_400_000);
+    // civil date from days since 1970-01-01, after Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (k, v) = s.split_once('=').ok_or("expected key=value")?;
+    Ok((k.to_string(), v.to_string()))
+}
+
+fn json() -> bool {
+    matches!(OUTPUT.get(), Some(Output::Json))
+}
+
+/// Print `v` on a line of its own, for --output json
+fn emit(v: impl Serialize) {
+    println!("{}", json!(v));
+}
+
+/// Print the status of an answer that has nothing else to show
+fn print_status(st: Status) {
+    if json() {
+        emit(json!({ "status": st }));
+    } else {
+        println!("status={:?}", st);
+    }
+}
+
+fn fmt_envelope(env: &Envelope) -> String {
+    let headers: Vec<String> = env.headers.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let mut s = format!(
+        "message_id={} content_type={} timestamp_ms={} headers={{{}}}",
+        env.message_id,
+        env.content_type,
+        env.timestamp_ms,
+        headers.join(",")
+    );
+    if !env.reply_to.is_empty() {
+        s += &format!(" reply_to={}", env.reply_to);
+    }
+    if !env.correlation_id.is_empty() {
+        s += &format!(" correlation_id={}", env.correlation_id);
+    }
+    s
+}
+
+/// `info` with how long ago the message was enqueued
+fn fmt_info(info: &DeliveryInfo) -> String {
+    format!(
+        "id={} enqueued={} age_ms={} redeliveries={} origin={}",
+        info.message_id,
+        fmt_time(info.enqueued_ms),
+        now_ms().saturating_sub(info.enqueued_ms),
+        info.redeliveries,
+        info.topic
+    )
+}
+
+async fn connect(addr: &str) -> anyhow::Result<Stream> {
+    let mut s = tls::connect(addr, TLS.get()).await?;
+    if let Some((user, secret)) = CREDENTIALS.get() {
+        let mut body = BytesMut::new();
+        put_str(&mut body, user);
+        put_str(&mut body, secret);
+        let (st, _) = rpc(&mut s, Op::Auth, &body).await?;
+        if st != Status::Ok {
+            anyhow::bail!("authentication with {} failed: {:?}", addr, st);
+        }
+    }
+    Ok(s)
 }
 
 async fn call<F>(server: &str, op: Op, f: F) -> anyhow::Result<()>
@@ -189,16 +2553,27 @@ where
     F: Fn(&mut BytesMut) + Copy,
 {
     let (st, _payload) = redirecting_call_resp(server, op, f).await?;
-    println!("status={:?}", st);
+    print_status(st);
     Ok(())
 }
 
 async fn redirecting_call_resp<F>(server: &str, op: Op, f: F) -> anyhow::Result<(Status, Vec<u8>)>
+where
+    F: Fn(&mut BytesMut) + Copy,
+{
+    let (_s, st, payload) = redirecting_conn(server, op, f).await?;
+    Ok((st, payload))
+}
+
+/// Like `redirecting_call_resp`, but keeps the connection to the node that answered
+async fn redirecting_conn<F>(server: &str, op: Op, f: F) -> anyhow::Result<(Stream, Status, Vec<u8>)>
 where
     F: Fn(&mut BytesMut) + Copy,
 {
     let mut current = server.to_string();
-    println!("Current {:?}", current);
+    if !json() {
+        println!("Current {:?}", current);
+    }
     for _ in 0..5 {
         let mut s = connect(&current).await?;
         let mut body = BytesMut::new();
@@ -210,12 +2585,12 @@ where
             current = addr;
             continue;
         }
-        return Ok((st, payload));
+        return Ok((s, st, payload));
     }
     anyhow::bail!("too many redirects")
 }
 
-async fn rpc(s: &mut TcpStream, op: Op, body: &BytesMut) -> anyhow::Result<(Status, Vec<u8>)> {
+async fn send(s: &mut Stream, op: Op, body: &BytesMut) -> anyhow::Result<()> {
     let hdr = Header {
         magic: MAGIC,
         version: VERSION,
@@ -226,8 +2601,13 @@ async fn rpc(s: &mut TcpStream, op: Op, body: &BytesMut) -> anyhow::Result<(Stat
     };
     let mut buf = BytesMut::with_capacity(16 + body.len());
     hdr.encode(&mut buf);
-    buf.extend_from_slice(&body);
+    buf.extend_from_slice(body);
     s.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn rpc(s: &mut Stream, op: Op, body: &BytesMut) -> anyhow::Result<(Status, Vec<u8>)> {
+    send(s, op, body).await?;
 
     let mut hb = [0u8; 16];
     s.read_exact(&mut hb).await?;
@@ -0,0 +1,921 @@
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::TlsConnector;
+
+use crate::protocol::*;
+use crate::queue::Message;
+use crate::tls::{self, Stream};
+
+/// Redirects followed for one message before it fails
+const MAX_REDIRECTS: u32 = 5;
+
+/// How long a consumer waits on the leader for a message per request
+const CONSUME_POLL_MS: u32 = 30_000;
+
+/// Time allowed to connect and authenticate
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for answers, on top of what the request waits for
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answers a `Producer::request` reply topic holds before it's full
+const REPLY_CAPACITY: u32 = 1024;
+
+/// Number of the next `Producer::request` of the process, names its reply topic
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// What a client request can fail with
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// the connection couldn't be opened or broke
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// the node answered something that isn't QBUS
+    #[error("protocol error: {0}")]
+    Protocol(#[from] ProtoError),
+    /// the node answered with this status, not the one asked for
+    #[error("server answered {0:?}")]
+    ServerStatus(Status),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    /// no answer in time, see `REQUEST_TIMEOUT`
+    #[error("timed out")]
+    Timeout,
+}
+
+impl ClientError {
+    /// Whether trying again later may succeed, see `RetryPolicy`
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Io(_) | ClientError::Timeout => true,
+            ClientError::ServerStatus(st) => retryable(*st),
+            ClientError::Protocol(_) | ClientError::TooManyRedirects => false,
+        }
+    }
+
+    /// Whether the connection it happened on can't be used anymore
+    fn breaks_connection(&self) -> bool {
+        matches!(self, ClientError::Io(_) | ClientError::Protocol(_) | ClientError::Timeout)
+    }
+
+    /// The same error again, for each of the requests it failed
+    fn duplicate(&self) -> Self {
+        match self {
+            ClientError::Io(e) => ClientError::Io(io::Error::new(e.kind(), e.to_string())),
+            ClientError::Protocol(e) => ClientError::Protocol(*e),
+            ClientError::ServerStatus(st) => ClientError::ServerStatus(*st),
+            ClientError::TooManyRedirects => ClientError::TooManyRedirects,
+            ClientError::Timeout => ClientError::Timeout,
+        }
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for ClientError {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        ClientError::Timeout
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// How a client rides out failures that may pass, see
+/// `ClientError::is_transient`: a connection that can't be opened, breaks
+/// or times out, and answers asking to come back later (Throttled,
+/// TooManyConnections, Maintenance). Retries wait `backoff`, doubled for
+/// each one after the first, up to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// tries of a request before its failure is returned, the first one
+    /// included, 0 = keep trying
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request that failed `failures` times gets another try
+    fn allows(&self, failures: u32) -> bool {
+        self.attempts == 0 || failures < self.attempts
+    }
+
+    /// Wait before retry `n`, the first being 1
+    fn delay(&self, n: u32) -> Duration {
+        self.backoff.saturating_mul(1 << n.saturating_sub(1).min(16)).min(self.max_backoff)
+    }
+}
+
+/// Answers that ask the client to try again later
+fn retryable(st: Status) -> bool {
+    matches!(st, Status::Throttled | Status::TooManyConnections | Status::QueueFull | Status::Maintenance | Status::Unavailable)
+}
+
+/// Where and how to reach a cluster: any node's address, TLS and
+/// credentials. Producers and consumers are made from it.
+#[derive(Clone)]
+pub struct Client {
+    addr: String,
+    tls: Option<TlsConnector>,
+    /// (user, secret) sent in an `Op::Auth` on every new connection
+    credentials: Option<(String, String)>,
+    retry: RetryPolicy,
+    /// overrides of `retry` for some ops
+    op_retry: Vec<(Op, RetryPolicy)>,
+    /// topic (partition) -> address of its leader, from Metadata answers and
+    /// redirects, shared by every producer and consumer of the client
+    leaders: Arc<RwLock<HashMap<String, String>>>,
+    /// address -> connection to it, shared by every producer of the client
+    /// and its metadata lookups
+    conns: Arc<Mutex<HashMap<String, Conn>>>,
+}
+
+impl Client {
+    /// A client of the cluster `addr` is a node of
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            tls: None,
+            credentials: None,
+            retry: RetryPolicy::default(),
+            // a consumer is there to wait for messages, through outages too
+            op_retry: vec![(
+                Op::Consume,
+                RetryPolicy {
+                    attempts: 0,
+                    ..Default::default()
+                },
+            )],
+            leaders: Arc::default(),
+            conns: Arc::default(),
+        }
+    }
+
+    /// Connect over TLS, see `tls::connector`
+    pub fn tls(mut self, connector: TlsConnector) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Authenticate as `user` with password `secret`, or with the token
+    /// `secret` if `user` is empty
+    pub fn credentials(mut self, user: &str, secret: &str) -> Self {
+        self.credentials = Some((user.to_string(), secret.to_string()));
+        self
+    }
+
+    /// Retry failed requests as `policy` says, `RetryPolicy::default()`
+    /// unless set
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Retry failed requests of `op` as `policy` says, whatever `retry` is.
+    /// Producers go by `Op::Produce` and consumers by `Op::Consume`, which
+    /// keeps trying unless set.
+    pub fn retry_op(mut self, op: Op, policy: RetryPolicy) -> Self {
+        self.op_retry.retain(|(o, _)| *o != op);
+        self.op_retry.push((op, policy));
+        self
+    }
+
+    fn policy(&self, op: Op) -> RetryPolicy {
+        self.op_retry.iter().find(|(o, _)| *o == op).map_or(self.retry, |(_, p)| *p)
+    }
+
+    /// A producer, inside a tokio runtime. Producers of a client share its
+    /// connections.
+    pub fn producer(&self) -> Producer {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_producer(self.clone(), rx));
+        Producer {
+            tx,
+            client: self.clone(),
+            id: producer_id(),
+            next: Arc::default(),
+        }
+    }
+
+    /// Messages of `topic` for consumer `group` ("" = the default group),
+    /// see `Consumer`
+    pub fn consumer(&self, topic: &str, group: &str) -> Consumer {
+        let fetcher = Fetcher {
+            client: self.clone(),
+            topic: topic.to_string(),
+            group: group.to_string(),
+            addr: String::new(),
+            conn: None,
+            last: None,
+            redirects: 0,
+            failures: 0,
+        };
+        let inner = stream::unfold(Some(fetcher), |fetcher| async move {
+            let mut fetcher = fetcher?;
+            match fetcher.next().await {
+                Ok(m) => Some((Ok(m), Some(fetcher))),
+                // the stream ends after an error
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        Consumer { inner: Box::pin(inner) }
+    }
+
+    /// Address of `topic`'s leader. A topic not seen before is looked up
+    /// with `Op::Metadata`, which answers for all partitions of it at once;
+    /// if that fails the request goes to the node the client was given
+    /// and its redirect says where the leader is.
+    async fn leader(&self, topic: &str) -> String {
+        if let Some(addr) = self.leaders.read().unwrap().get(topic) {
+            return addr.clone();
+        }
+        let base = split_partition(topic).0;
+        match self.metadata(base).await {
+            Ok(partitions) => {
+                let mut leaders = self.leaders.write().unwrap();
+                for (p, addr) in partitions {
+                    leaders.insert(partition_name(base, p), addr);
+                }
+            }
+            Err(e) => tracing::debug!("metadata of {} failed: {}", base, e),
+        }
+        self.leaders.read().unwrap().get(topic).cloned().unwrap_or_else(|| self.addr.clone())
+    }
+
+    /// (partition, leader address) of each partition of `topic`
+    async fn metadata(&self, topic: &str) -> Result<Vec<(u32, String)>> {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        let mut addr = self.addr.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let reply = self.conn(&addr).await.map(|c| c.request(Op::Metadata, &body));
+            let (st, rest) = answer(reply, REQUEST_TIMEOUT).await?;
+            let b = &mut &rest[..];
+            match st {
+                Status::Ok => {
+                    let n = get_u32(b).ok_or(ProtoError::Short)?;
+                    return (0..n)
+                        .map(|_| match (get_u32(b), get_str(b)) {
+                            (Some(p), Some(addr)) => Ok((p, addr)),
+                            _ => Err(ProtoError::Short.into()),
+                        })
+                        .collect();
+                }
+                Status::Redirect => addr = get_str(b).ok_or(ProtoError::Short)?,
+                st => return Err(ClientError::ServerStatus(st)),
+            }
+        }
+        Err(ClientError::TooManyRedirects)
+    }
+
+    /// `topic` is led by the node at `addr`, as a redirect said
+    fn moved(&self, topic: &str, addr: &str) {
+        self.leaders.write().unwrap().insert(topic.to_string(), addr.to_string());
+    }
+
+    /// The node at `addr` can't be reached: the topics it led are looked
+    /// up again, it may be gone for good
+    fn unreachable(&self, addr: &str) {
+        self.leaders.write().unwrap().retain(|_, a| a != addr);
+    }
+
+    /// Send `op` with `body` to the leader of `topic` on the shared
+    /// connection to it: the leader's address, and the answer to come
+    async fn request(&self, topic: &str, op: Op, body: &[u8]) -> (String, Result<Reply>) {
+        let addr = self.leader(topic).await;
+        let reply = self.conn(&addr).await.map(|c| c.request(op, body));
+        (addr, reply)
+    }
+
+    /// The shared connection to `addr`, a new one if there's none yet or
+    /// the last one failed
+    async fn conn(&self, addr: &str) -> Result<Conn> {
+        if let Some(c) = self.conns.lock().unwrap().get(addr)
+            && !c.is_closed()
+        {
+            return Ok(c.clone());
+        }
+        let c = self.connect(addr).await?;
+        self.conns.lock().unwrap().insert(addr.to_string(), c.clone());
+        Ok(c)
+    }
+
+    /// A new connection to `addr`, its version and features agreed on and
+    /// authenticated. A node refusing either fails it with the status it
+    /// answered.
+    async fn connect(&self, addr: &str) -> Result<Conn> {
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let s = tls::connect(addr, self.tls.as_ref()).await.map_err(|e| match e.downcast::<io::Error>() {
+                Ok(e) => ClientError::Io(e),
+                // a host name the certificate can't be checked against
+                Err(e) => ClientError::Io(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())),
+            })?;
+            let mut conn = Conn::new(s);
+            let mut body = BytesMut::new();
+            put_u8(&mut body, VERSION);
+            put_u32(&mut body, FEATURES);
+            put_u32(&mut body, MAX_FRAME);
+            match conn.request(Op::Hello, &body).await? {
+                (Status::Ok, rest) => {
+                    let b = &mut &rest[..];
+                    let (Some(_version), Some(features), Some(max_frame)) = (get_u8(b), get_u32(b), get_u32(b)) else {
+                        return Err(ProtoError::Short.into());
+                    };
+                    conn.features = features;
+                    conn.max_frame = max_frame;
+                }
+                (st, _) => return Err(ClientError::ServerStatus(st)),
+            }
+            if let Some((user, secret)) = &self.credentials {
+                let mut body = BytesMut::new();
+                put_str(&mut body, user);
+                put_str(&mut body, secret);
+                match conn.request(Op::Auth, &body).await? {
+                    (Status::Ok, _) => {}
+                    (st, _) => return Err(ClientError::ServerStatus(st)),
+                }
+            }
+            Ok(conn)
+        })
+        .await?
+    }
+}
+
+/// A connection carrying any number of requests at once. Each request gets
+/// a stream_id of its own, which its answer carries back, so answers reach
+/// their requests in whatever order they come. A writer task writes what
+/// was requested meanwhile at once, a reader task hands out the answers;
+/// once either fails, so does every request waiting on the connection and
+/// every one made on it after. Dropping the last handle closes it.
+#[derive(Clone)]
+struct Conn {
+    /// frames for the writer task
+    frames: mpsc::UnboundedSender<BytesMut>,
+    inflight: Arc<Mutex<Inflight>>,
+    /// features agreed on with the node, see `Op::Hello`
+    features: u32,
+    /// largest request body the node takes
+    max_frame: u32,
+}
+
+/// An answer as (status, rest of body)
+type Answer = (Status, Vec<u8>);
+
+/// Requests of a `Conn` waiting for their answers
+#[derive(Default)]
+struct Inflight {
+    next_id: u32,
+    waiting: HashMap<u32, oneshot::Sender<Result<Answer>>>,
+    /// why the connection failed, once it has
+    failed: Option<ClientError>,
+}
+
+impl Inflight {
+    /// Fail the requests waiting and any made after with `e`
+    fn fail(&mut self, e: ClientError) {
+        for (_, w) in self.waiting.drain() {
+            let _ = w.send(Err(e.duplicate()));
+        }
+        self.failed.get_or_insert(e);
+    }
+}
+
+impl Conn {
+    fn new(s: Stream) -> Self {
+        let (r, w) = tokio::io::split(s);
+        let (frames, rx) = mpsc::unbounded_channel();
+        let inflight = Arc::new(Mutex::new(Inflight::default()));
+        tokio::spawn(write_frames(w, rx, inflight.clone()));
+        tokio::spawn(read_answers(r, inflight.clone()));
+        Self {
+            frames,
+            inflight,
+            features: 0,
+            max_frame: MAX_FRAME,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inflight.lock().unwrap().failed.is_some()
+    }
+
+    /// Send `op` with `body`, written after the requests made before it.
+    /// The answer is waited for on the `Reply`, no need to for the next
+    /// request to go out. A body larger than the node takes fails at once.
+    fn request(&self, op: Op, body: &[u8]) -> Reply {
+        let (tx, rx) = oneshot::channel();
+        if body.len() > self.max_frame as usize {
+            let _ = tx.send(Err(ProtoError::TooLarge(body.len() as u32).into()));
+            return Reply(rx);
+        }
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(e) = &inflight.failed {
+            let _ = tx.send(Err(e.duplicate()));
+            return Reply(rx);
+        }
+        let id = inflight.next_id;
+        inflight.next_id = id.wrapping_add(1);
+        inflight.waiting.insert(id, tx);
+        // what's produced is compressed if the node takes it so
+        let packed = match op {
+            Op::Produce if self.features & FEATURE_COMPRESSION != 0 => compress(body),
+            _ => None,
+        };
+        let (mut flags, body) = match &packed {
+            Some(packed) => (FLAG_COMPRESSED, &packed[..]),
+            None => (0, body),
+        };
+        let mut buf = BytesMut::with_capacity(Header::LEN + body.len() + 4);
+        if self.features & FEATURE_CHECKSUM != 0 {
+            flags |= FLAG_CHECKSUM;
+            put_frame(&mut buf, op, flags, id, &[body, &checksum(body)].concat());
+        } else {
+            put_frame(&mut buf, op, flags, id, body);
+        }
+        // a writer that's gone failed the connection, `tx` with it
+        let _ = self.frames.send(buf);
+        Reply(rx)
+    }
+}
+
+/// Answer to a `Conn::request`
+struct Reply(oneshot::Receiver<Result<Answer>>);
+
+impl Future for Reply {
+    type Output = Result<Answer>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed").into())))
+    }
+}
+
+/// `reply`, unless it takes longer than `wait`
+async fn answer(reply: Result<Reply>, wait: Duration) -> Result<Answer> {
+    tokio::time::timeout(wait, reply?).await?
+}
+
+/// Write the frames of a `Conn`, those requested meanwhile at once, until
+/// every handle to it is dropped
+async fn write_frames(mut w: WriteHalf<Stream>, mut rx: mpsc::UnboundedReceiver<BytesMut>, inflight: Arc<Mutex<Inflight>>) {
+    while let Some(mut buf) = rx.recv().await {
+        while let Ok(more) = rx.try_recv() {
+            buf.extend_from_slice(&more);
+        }
+        if let Err(e) = w.write_all(&buf).await {
+            inflight.lock().unwrap().fail(e.into());
+            return;
+        }
+    }
+    // the node closes its end once it has answered what's left
+    let _ = w.shutdown().await;
+}
+
+/// Hand the answers read on a `Conn` to their requests until it fails
+async fn read_answers(mut r: ReadHalf<Stream>, inflight: Arc<Mutex<Inflight>>) {
+    let e = loop {
+        match recv(&mut r).await {
+            Ok((id, answer)) => {
+                // the request may have stopped waiting for it
+                if let Some(w) = inflight.lock().unwrap().waiting.remove(&id) {
+                    let _ = w.send(Ok(answer));
+                }
+            }
+            Err(e) => break e,
+        }
+    };
+    inflight.lock().unwrap().fail(e);
+}
+
+/// Sends messages to the leaders of their topics, see `Client::leader`,
+/// each topic's in the order sent. Messages don't wait for the answers to
+/// the ones before: all of them go out on the one connection to the node,
+/// what was sent meanwhile in one write. A connection that fails is
+/// replaced and what it didn't answer sent again. Every message carries
+/// the producer's id and a number of its own, so the leader writes it once
+/// however often it's sent, unless leadership moved meanwhile. Messages retried
+/// after a backoff, see `RetryPolicy`, or redirected to another node, may
+/// land after ones sent later.
+///
+/// A partition of a partitioned topic is sent to as
+/// `partition_name(topic, p)`. Dropping the producer leaves the messages
+/// already sent to be delivered. Clones share the producer's id.
+#[derive(Clone)]
+pub struct Producer {
+    tx: mpsc::UnboundedSender<Pending>,
+    /// for the reply topics of `request`
+    client: Client,
+    /// random, tells this producer's messages apart from others' on retry
+    id: u64,
+    /// number of the next message sent, see `ProducerSeq`
+    next: Arc<AtomicU64>,
+}
+
+impl Producer {
+    /// Send `payload` to `topic`, done once the leader wrote it with the
+    /// seq it got in the topic's log, 0 if the leader couldn't be reached
+    /// and the node kept it to produce later
+    pub fn send(&self, topic: &str, payload: impl Into<Vec<u8>>) -> SendFuture {
+        let msg = Message {
+            payload: Bytes::from(payload.into()),
+            envelope: Envelope::default(),
+        };
+        self.send_message(topic, &msg, 0, "")
+    }
+
+    /// Send `msg` to `topic` with `priority` and `routing_key`
+    pub fn send_message(&self, topic: &str, msg: &Message, priority: u8, routing_key: &str) -> SendFuture {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        put_bytes(&mut body, &msg.payload);
+        put_u8(&mut body, priority);
+        put_str(&mut body, routing_key);
+        put_envelope(&mut body, &msg.envelope);
+        put_u8(&mut body, Acks::Leader as u8);
+        put_u32(&mut body, 0);
+        put_u64(&mut body, self.id);
+        put_u64(&mut body, self.next.fetch_add(1, Ordering::Relaxed));
+        let (done, rx) = oneshot::channel();
+        // a producer task that's gone drops `done`, failing the future
+        let _ = self.tx.send(Pending {
+            topic: topic.to_string(),
+            body: body.freeze(),
+            done,
+            redirects: 0,
+            failures: 0,
+        });
+        SendFuture(rx)
+    }
+
+    /// Send `msg` to `topic` and wait up to `wait` for the answer to it. The
+    /// answer goes to a reply topic of its own, exclusive and transient, on
+    /// a connection that's closed, and the topic deleted with it, once the
+    /// answer came or the wait is over. The message gets the topic as its
+    /// `reply_to`, and a `correlation_id` unless it has one; answers not
+    /// carrying it are dropped. The one answering sends with `reply`.
+    pub async fn request(&self, topic: &str, msg: &Message, wait: Duration) -> Result<Message> {
+        let n = REQUESTS.fetch_add(1, Ordering::Relaxed);
+        let name = format!("reply-{:016x}-{}", self.id, n);
+        let reply_to = match split_namespace(topic).0 {
+            // answers stay in the namespace the request is in
+            Some(ns) => format!("{}/{}", ns, name),
+            None => name,
+        };
+        tokio::time::timeout(wait, async {
+            let conn = self.reply_topic(&reply_to).await?;
+            let mut msg = msg.clone();
+            if msg.envelope.correlation_id.is_empty() {
+                msg.envelope.correlation_id = format!("{:016x}-{}", self.id, n);
+            }
+            msg.envelope.reply_to = reply_to.clone();
+            self.send_message(topic, &msg, 0, "").await?;
+            loop {
+                let mut body = BytesMut::new();
+                put_str(&mut body, &reply_to);
+                put_u32(&mut body, CONSUME_POLL_MS);
+                put_str(&mut body, "");
+                let wait = Duration::from_millis(CONSUME_POLL_MS as u64) + REQUEST_TIMEOUT;
+                let (st, rest) = answer(Ok(conn.request(Op::Consume, &body)), wait).await?;
+                match st {
+                    Status::Ok => {}
+                    Status::Empty => continue,
+                    st => return Err(ClientError::ServerStatus(st)),
+                }
+                let rest = Bytes::from(rest);
+                let r = &mut &rest[..];
+                let (Some(tag), Some(payload)) = (get_u64(r), get_slice(r)) else {
+                    return Err(ProtoError::Short.into());
+                };
+                let payload = rest.slice_ref(payload);
+                let envelope = get_envelope(r).unwrap_or_default();
+                let mut body = BytesMut::new();
+                put_str(&mut body, &reply_to);
+                put_u64(&mut body, tag);
+                put_str(&mut body, "");
+                // the topic goes with the connection, acked or not
+                drop(conn.request(Op::Ack, &body));
+                if envelope.correlation_id == msg.envelope.correlation_id {
+                    return Ok(Message { payload, envelope });
+                }
+                tracing::debug!("answer on {} for {:?} dropped", reply_to, envelope.correlation_id);
+            }
+        })
+        .await?
+    }
+
+    /// Send `payload` as the answer to `request`, to its `reply_to` with its
+    /// `correlation_id`. A request that wants no answer fails it with
+    /// BadRequest.
+    pub fn reply(&self, request: &Message, payload: impl Into<Vec<u8>>) -> SendFuture {
+        if request.envelope.reply_to.is_empty() {
+            let (done, rx) = oneshot::channel();
+            let _ = done.send(Err(ClientError::ServerStatus(Status::BadRequest)));
+            return SendFuture(rx);
+        }
+        let msg = Message {
+            payload: Bytes::from(payload.into()),
+            envelope: Envelope {
+                correlation_id: request.envelope.correlation_id.clone(),
+                ..Default::default()
+            },
+        };
+        self.send_message(&request.envelope.reply_to, &msg, 0, "")
+    }
+
+    /// A connection of its own that created, and so owns, the exclusive
+    /// topic `name`, to the node leading it
+    async fn reply_topic(&self, name: &str) -> Result<Conn> {
+        let mut body = BytesMut::new();
+        put_str(&mut body, name);
+        put_u32(&mut body, REPLY_CAPACITY);
+        // no ttls, dead letter, priorities, retention; fanout, one replica and partition
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_str(&mut body, "");
+        put_u8(&mut body, 0);
+        put_u8(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u64(&mut body, 0);
+        put_u8(&mut body, 1);
+        put_u32(&mut body, 1);
+        put_u32(&mut body, ALL_PARTITIONS);
+        // no webhook, shards, overflow, visibility timeout, byte capacity or auto delete
+        put_str(&mut body, "");
+        put_u8(&mut body, 0);
+        put_u8(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u64(&mut body, 0);
+        put_u8(&mut body, 0);
+        // exclusive, transient
+        put_u8(&mut body, 1);
+        put_u8(&mut body, 1);
+        let mut addr = self.client.leader(name).await;
+        for _ in 0..=MAX_REDIRECTS {
+            let conn = self.client.connect(&addr).await?;
+            let (st, rest) = answer(Ok(conn.request(Op::CreateTopic, &body)), REQUEST_TIMEOUT).await?;
+            match st {
+                Status::Ok => return Ok(conn),
+                Status::Redirect => addr = get_str(&mut &rest[..]).ok_or(ProtoError::Short)?,
+                st => return Err(ClientError::ServerStatus(st)),
+            }
+        }
+        Err(ClientError::TooManyRedirects)
+    }
+}
+
+/// A random producer id, never 0 which means none
+fn producer_id() -> u64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    RandomState::new().hash_one(now.as_nanos()).max(1)
+}
+
+/// Outcome of a `Producer::send`, the message's seq
+pub struct SendFuture(oneshot::Receiver<Result<u64>>);
+
+impl Future for SendFuture {
+    type Output = Result<u64>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "producer stopped").into())))
+    }
+}
+
+/// A message waiting for its leader's answer
+struct Pending {
+    topic: String,
+    /// Produce request body
+    body: Bytes,
+    done: oneshot::Sender<Result<u64>>,
+    redirects: u32,
+    /// failed tries, see `RetryPolicy`
+    failures: u32,
+}
+
+/// Send what producers hand over, in order, until they're all dropped.
+/// Each message's answer is waited for by a task of its own.
+async fn run_producer(client: Client, mut rx: mpsc::UnboundedReceiver<Pending>) {
+    while let Some(p) = rx.recv().await {
+        let sent = client.request(&p.topic, Op::Produce, &p.body).await;
+        tokio::spawn(deliver(client.clone(), p, sent));
+    }
+}
+
+/// Wait for the answer to `p`, sending it again if it's redirected or
+/// fails in a way `RetryPolicy` rides out
+async fn deliver(client: Client, mut p: Pending, mut sent: (String, Result<Reply>)) {
+    let policy = client.policy(Op::Produce);
+    let res = loop {
+        let (addr, reply) = sent;
+        let err = match answer(reply, REQUEST_TIMEOUT).await {
+            Ok((Status::Ok, rest)) => break Ok(get_u64(&mut &rest[..]).unwrap_or(0)),
+            Ok((Status::Redirect, rest)) => match get_str(&mut &rest[..]) {
+                Some(leader) if p.redirects < MAX_REDIRECTS => {
+                    p.redirects += 1;
+                    client.moved(&p.topic, &leader);
+                    sent = client.request(&p.topic, Op::Produce, &p.body).await;
+                    continue;
+                }
+                _ => break Err(ClientError::TooManyRedirects),
+            },
+            Ok((st, _)) => ClientError::ServerStatus(st),
+            Err(e) => e,
+        };
+        if err.breaks_connection() {
+            client.unreachable(&addr);
+        }
+        if !err.is_transient() || !policy.allows(p.failures + 1) {
+            break Err(err);
+        }
+        p.failures += 1;
+        tracing::debug!("producing to {} on {} failed, retrying: {}", p.topic, addr, err);
+        tokio::time::sleep(policy.delay(p.failures)).await;
+        sent = client.request(&p.topic, Op::Produce, &p.body).await;
+    };
+    let _ = p.done.send(res);
+}
+
+/// Read one answer off `r`, with the stream_id of its request
+async fn recv<R: AsyncRead + Unpin>(r: &mut R) -> Result<(u32, Answer)> {
+    let mut hb = BytesMut::zeroed(Header::LEN);
+    r.read_exact(&mut hb).await?;
+    let hdr = Header::decode(&mut hb)?.ok_or(ProtoError::Short)?;
+    if hdr.body_len > MAX_FRAME {
+        return Err(ProtoError::TooLarge(hdr.body_len).into());
+    }
+    let mut resp = vec![0u8; hdr.body_len as usize];
+    r.read_exact(&mut resp).await?;
+    if hdr.flags & FLAG_CHECKSUM != 0 {
+        resp.truncate(verify_checksum(&resp)?);
+    }
+    if hdr.flags & FLAG_COMPRESSED != 0 {
+        resp = decompress(&resp)?;
+    }
+    if resp.len() < 2 {
+        return Err(ProtoError::Short.into());
+    }
+    let st = Status::try_from(u16::from_be_bytes([resp[0], resp[1]]))?;
+    Ok((hdr.stream_id, (st, resp.split_off(2))))
+}
+
+/// Request frame for `op` with `body` on `stream_id`, appended to `buf`
+fn put_frame(buf: &mut BytesMut, op: Op, flags: u8, stream_id: u32, body: &[u8]) {
+    let hdr = Header {
+        magic: MAGIC,
+        version: VERSION,
+        op,
+        flags,
+        stream_id,
+        body_len: body.len() as u32,
+    };
+    hdr.encode(buf);
+    buf.extend_from_slice(body);
+}
+
+/// Messages of one topic for one consumer group, as a `Stream`:
+/// `while let Some(msg) = consumer.next().await` with
+/// `futures_util::StreamExt`. A message is acked once the next one is
+/// asked for, so one whose handling was cut short, by a crash or by
+/// dropping the consumer, is delivered again. The consumer waits on the
+/// topic's leader for messages and reconnects if the connection fails;
+/// an answer other than a message, such as NotFound or Unauthorized, is
+/// yielded as an error and ends the stream.
+pub struct Consumer {
+    inner: Pin<Box<dyn stream::Stream<Item = Result<Message>> + Send>>,
+}
+
+impl stream::Stream for Consumer {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// State of a `Consumer` between messages
+struct Fetcher {
+    client: Client,
+    topic: String,
+    group: String,
+    /// node `conn` is to
+    addr: String,
+    /// a connection of the consumer's own, not the client's shared one: the
+    /// node gives the messages it didn't ack back to the topic once it's gone
+    conn: Option<Conn>,
+    /// delivery tag of the message handed out last, acked with the next request
+    last: Option<u64>,
+    /// redirects since the last answer that wasn't one
+    redirects: u32,
+    /// failed tries since the last answer, see `RetryPolicy`
+    failures: u32,
+}
+
+impl Fetcher {
+    /// Next message, acking the one before
+    async fn next(&mut self) -> Result<Message> {
+        let policy = self.client.policy(Op::Consume);
+        loop {
+            let err = match self.poll().await {
+                Ok(m) => {
+                    self.failures = 0;
+                    match m {
+                        Some(m) => return Ok(m),
+                        None => continue,
+                    }
+                }
+                Err(e) if !e.is_transient() => return Err(e),
+                Err(e) => e,
+            };
+            if err.breaks_connection() {
+                self.client.unreachable(&self.addr);
+            }
+            self.failures += 1;
+            if !policy.allows(self.failures) {
+                return Err(err);
+            }
+            tracing::warn!("consuming {} from {} failed, reconnecting: {}", self.topic, self.addr, err);
+            // the message handed out last goes back to the topic with the connection
+            self.conn = None;
+            self.last = None;
+            tokio::time::sleep(policy.delay(self.failures)).await;
+        }
+    }
+
+    /// One long poll, sent along with the ack of the last message. None if
+    /// it timed out or was redirected.
+    async fn poll(&mut self) -> Result<Option<Message>> {
+        if self.conn.is_none() {
+            self.addr = self.client.leader(&self.topic).await;
+            self.conn = Some(self.client.connect(&self.addr).await?);
+        }
+        let conn = self.conn.as_ref().unwrap();
+        let acked = self.last.take().map(|tag| {
+            let mut body = BytesMut::new();
+            put_str(&mut body, &self.topic);
+            put_u64(&mut body, tag);
+            put_str(&mut body, &self.group);
+            (tag, conn.request(Op::Ack, &body))
+        });
+        let mut body = BytesMut::new();
+        put_str(&mut body, &self.topic);
+        put_u32(&mut body, CONSUME_POLL_MS);
+        put_str(&mut body, &self.group);
+        let consumed = conn.request(Op::Consume, &body);
+        if let Some((tag, reply)) = acked {
+            let (st, _) = answer(Ok(reply), REQUEST_TIMEOUT).await?;
+            if st != Status::Ok {
+                tracing::warn!("ack of {} on {} answered {:?}", tag, self.topic, st);
+            }
+        }
+        let wait = Duration::from_millis(CONSUME_POLL_MS as u64) + REQUEST_TIMEOUT;
+        let (st, rest) = answer(Ok(consumed), wait).await?;
+        if st == Status::Redirect {
+            self.redirects += 1;
+            if self.redirects > MAX_REDIRECTS {
+                return Err(ClientError::TooManyRedirects);
+            }
+            let leader = get_str(&mut &rest[..]).ok_or(ProtoError::Short)?;
+            self.client.moved(&self.topic, &leader);
+            self.conn = None;
+            return Ok(None);
+        }
+        self.redirects = 0;
+        match st {
+            Status::Ok => {
+                let rest = Bytes::from(rest);
+                let r = &mut &rest[..];
+                let (Some(tag), Some(payload)) = (get_u64(r), get_slice(r)) else {
+                    return Err(ProtoError::Short.into());
+                };
+                let payload = rest.slice_ref(payload);
+                let envelope = get_envelope(r).unwrap_or_default();
+                self.last = Some(tag);
+                Ok(Some(Message { payload, envelope }))
+            }
+            Status::Empty => Ok(None),
+            st => Err(ClientError::ServerStatus(st)),
+        }
+    }
+}
@@ -1,6 +1,32 @@
+use bytes::BytesMut;
 use seahash::hash;
 use serde::Deserialize;
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::peer;
+use crate::protocol::*;
+use crate::queue::now_ms;
+
+/// How often this node bumps its heartbeat and gossips with one peer
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often this node pings every other member directly
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A member whose heartbeat hasn't moved for this long is suspect
+const SUSPECT_AFTER: Duration = Duration::from_secs(2);
+
+/// A member whose heartbeat hasn't moved for this long is down: it keeps its
+/// place in the view but leads nothing until it heartbeats again
+const FAIL_AFTER: Duration = Duration::from_secs(5);
+
+/// A member whose heartbeat hasn't moved for this long is dropped from the view
+const FORGET_AFTER: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Node {
@@ -8,48 +34,447 @@ pub struct Node {
     pub addr: String, // "host:port"
 }
 
+/// What this node knows about one cluster member
+#[derive(Debug, Clone)]
+struct Member {
+    node: Node,
+    /// counter bumped by the member itself every gossip round
+    heartbeat: u64,
+    /// when `heartbeat` last went up here
+    seen: Instant,
+    /// set by `tick` once `seen` is older than FAIL_AFTER
+    down: bool,
+    /// removed by an operator: leads nothing and hands its topics off.
+    /// Cleared if the node comes back with a newer heartbeat.
+    left: bool,
+    /// whether it answered the last ping from this node
+    reachable: bool,
+    /// round trip of the last answered ping
+    rtt: Option<Duration>,
+}
+
+impl Member {
+    fn new(node: Node, heartbeat: u64, seen: Instant) -> Self {
+        Self {
+            node,
+            heartbeat,
+            seen,
+            down: false,
+            left: false,
+            reachable: true,
+            rtt: None,
+        }
+    }
+
+    fn status(&self) -> NodeStatus {
+        if self.left {
+            NodeStatus::Left
+        } else if self.down {
+            NodeStatus::Down
+        } else if !self.reachable || self.seen.elapsed() >= SUSPECT_AFTER {
+            NodeStatus::Suspect
+        } else {
+            NodeStatus::Alive
+        }
+    }
+}
+
+/// One member as this node sees it
+#[derive(Debug, Clone)]
+pub struct NodeHealth {
+    pub node: Node,
+    pub status: NodeStatus,
+    /// since its heartbeat last moved
+    pub last_seen: Duration,
+    pub rtt: Option<Duration>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Cluster {
     pub me: Node,
-    pub nodes: Arc<Vec<Node>>,
+    /// membership view by node id, including this node
+    members: Arc<RwLock<BTreeMap<String, Member>>>,
+    /// addresses to gossip with until their nodes show up in the view
+    seeds: Arc<Vec<String>>,
+    /// round-robin position over gossip targets
+    next_target: Arc<AtomicUsize>,
+    /// kept-open connections to other nodes
+    peers: Arc<peer::Pool>,
 }
 
 impl Cluster {
     /// env:
     /// QBUS_NODE_ID="node-a"
-    /// QBUS_NODES='[{"id":"node-a","addr":"127.0.0.1:7001"},{"id":"node-b","addr":"127.0.0.1:7002"}]'
-    pub fn from_env() -> anyhow::Result<Self> {
+    /// QBUS_NODES='[{"id":"node-a","addr":"127.0.0.1:7001"},{"id":"node-b","addr":"127.0.0.1:7002"}]' (optional, initial members)
+    /// QBUS_SEEDS="127.0.0.1:7002,127.0.0.1:7003" (optional, nodes to join through)
+    ///
+    /// `addr` is advertised to other nodes unless QBUS_NODES lists this node.
+    pub fn from_env(addr: &str) -> anyhow::Result<Self> {
         let me_id = std::env::var("QBUS_NODE_ID").unwrap_or_else(|_| "node-a".to_string());
-        let nodes_json = std::env::var("QBUS_NODES").unwrap_or_else(|_| {
-            r#"[{"id":"node-a","addr":"127.0.0.1:7001"},{"id":"node-b","addr":"127.0.0.1:7002"}]"#
-                .to_string()
+        let nodes: Vec<Node> = match std::env::var("QBUS_NODES") {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(_) => Vec::new(),
+        };
+        let seeds: Vec<String> = std::env::var("QBUS_SEEDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+        let me = nodes.iter().find(|n| n.id == me_id).cloned().unwrap_or(Node {
+            id: me_id,
+            addr: addr.to_string(),
         });
-        let nodes: Vec<Node> = serde_json::from_str(&nodes_json)?;
-        let me = nodes
-            .iter()
-            .find(|n| n.id == me_id)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("me id not in QBUS_NODES"))?;
-        Ok(Self {
+        Ok(Self::new(me, nodes, seeds))
+    }
+
+    pub fn new(me: Node, nodes: Vec<Node>, seeds: Vec<String>) -> Self {
+        let now = Instant::now();
+        let mut members = BTreeMap::new();
+        for node in nodes {
+            members.insert(node.id.clone(), Member::new(node, 0, now));
+        }
+        // start from the clock so peers see a restarted node as newer
+        members.insert(me.id.clone(), Member::new(me.clone(), now_ms(), now));
+        Self {
             me,
-            nodes: Arc::new(nodes),
-        })
+            members: Arc::new(RwLock::new(members)),
+            seeds: Arc::new(seeds),
+            next_target: Arc::new(AtomicUsize::new(0)),
+            peers: Arc::new(peer::Pool::new(None, None)),
+        }
     }
 
-    /// Rendezvous hashing: 가장 큰 hash(node, topic)
+    /// Talk to other nodes through `pool`, for TLS or authentication
+    pub fn with_peers(mut self, pool: peer::Pool) -> Self {
+        self.peers = Arc::new(pool);
+        self
+    }
+
+    pub fn peers(&self) -> &peer::Pool {
+        &self.peers
+    }
+
+    /// Current members, sorted by id
+    pub fn nodes(&self) -> Vec<Node> {
+        self.members
+            .read()
+            .unwrap()
+            .values()
+            .map(|m| m.node.clone())
+            .collect()
+    }
+
+    /// Rendezvous hashing: 가장 큰 hash(node, topic), over members that are up.
+    /// Topics of a down node move to the next best node until it is back.
     pub fn leader_of(&self, topic: &str) -> Node {
-        let mut best: Option<(&Node, u64)> = None;
-        for n in self.nodes.iter() {
-            let key = format!("{}:{}", n.id, topic);
-            let score = hash(key.as_bytes());
-            if best.map(|(_, s)| score > s).unwrap_or(true) {
-                best = Some((n, score));
-            }
+        self.replicas_of(topic, 1).remove(0)
+    }
+
+    /// The `n` members that are up with the highest hash(node, topic), best
+    /// first. The first one leads the topic, the rest keep copies of its log,
+    /// so the best follower is also the node that takes over from the leader.
+    pub fn replicas_of(&self, topic: &str, n: usize) -> Vec<Node> {
+        let members = self.members.read().unwrap();
+        let mut ranked: Vec<(u64, &Node)> = members
+            .values()
+            .filter(|m| !m.down && !m.left)
+            .map(|m| (hash(format!("{}:{}", m.node.id, topic).as_bytes()), &m.node))
+            .collect();
+        ranked.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+        if ranked.is_empty() {
+            // the last node standing leads everything, left or not
+            return vec![self.me.clone()];
         }
-        best.unwrap().0.clone()
+        ranked.into_iter().take(n.max(1)).map(|(_, n)| n.clone()).collect()
     }
 
     pub fn is_leader(&self, topic: &str) -> bool {
         self.leader_of(topic).id == self.me.id
     }
+
+    /// digest: n(u32) | n * (id(str) | addr(str) | heartbeat(u64))
+    ///         | m(u32) | m * id(str) of the members above that left
+    pub fn put_digest(&self, out: &mut BytesMut) {
+        let members = self.members.read().unwrap();
+        put_u32(out, members.len() as u32);
+        for m in members.values() {
+            put_str(out, &m.node.id);
+            put_str(out, &m.node.addr);
+            put_u64(out, m.heartbeat);
+        }
+        let left: Vec<&Member> = members.values().filter(|m| m.left).collect();
+        put_u32(out, left.len() as u32);
+        for m in left {
+            put_str(out, &m.node.id);
+        }
+    }
+
+    /// Fold a peer's digest into the view, newer heartbeats win. A member
+    /// that left wins over the same heartbeat still marked live.
+    /// Returns false if the digest is malformed.
+    pub fn merge_digest(&self, b: &mut &[u8]) -> bool {
+        let Some(n) = get_u32(b) else {
+            return false;
+        };
+        let mut entries = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            let (Some(id), Some(addr), Some(heartbeat)) = (get_str(b), get_str(b), get_u64(b)) else {
+                return false;
+            };
+            entries.push((id, addr, heartbeat));
+        }
+        let mut left = Vec::new();
+        for _ in 0..get_u32(b).unwrap_or(0) {
+            let Some(id) = get_str(b) else {
+                return false;
+            };
+            left.push(id);
+        }
+        let now = Instant::now();
+        let mut members = self.members.write().unwrap();
+        for (id, addr, heartbeat) in entries {
+            // only this node speaks for itself
+            if id == self.me.id {
+                continue;
+            }
+            let gone = left.contains(&id);
+            match members.get_mut(&id) {
+                Some(m) if gone && heartbeat >= m.heartbeat && !m.left => {
+                    tracing::info!("node {} at {} left the cluster", id, m.node.addr);
+                    m.heartbeat = heartbeat;
+                    m.left = true;
+                }
+                Some(m) if heartbeat <= m.heartbeat => {}
+                Some(m) => {
+                    if m.left && !gone {
+                        tracing::info!("node {} at {} rejoined", id, addr);
+                    }
+                    m.heartbeat = heartbeat;
+                    m.node.addr = addr;
+                    m.seen = now;
+                    m.left = gone;
+                }
+                // no point learning about a node that is already gone
+                None if gone => {}
+                None => {
+                    tracing::info!("node {} at {} joined", id, addr);
+                    let node = Node { id: id.clone(), addr };
+                    members.insert(id, Member::new(node, heartbeat, now));
+                }
+            }
+        }
+        true
+    }
+
+    /// Every member with its status, sorted by id
+    pub fn health(&self) -> Vec<NodeHealth> {
+        self.members
+            .read()
+            .unwrap()
+            .values()
+            .map(|m| NodeHealth {
+                node: m.node.clone(),
+                status: m.status(),
+                last_seen: m.seen.elapsed(),
+                rtt: m.rtt,
+            })
+            .collect()
+    }
+
+    /// Whether `id` is a member that answers pings and heartbeats
+    pub fn is_reachable(&self, id: &str) -> bool {
+        self.members
+            .read()
+            .unwrap()
+            .get(id)
+            .is_some_and(|m| m.status() == NodeStatus::Alive)
+    }
+
+    /// health: n(u32) | n * (id(str) | addr(str) | status(u8) | last_seen_ms(u64) | rtt_us(u64), 0 if unknown)
+    pub fn put_health(&self, out: &mut BytesMut) {
+        let health = self.health();
+        put_u32(out, health.len() as u32);
+        for h in health {
+            put_str(out, &h.node.id);
+            put_str(out, &h.node.addr);
+            put_u8(out, h.status as u8);
+            put_u64(out, h.last_seen.as_millis() as u64);
+            put_u64(out, h.rtt.map_or(0, |d| d.as_micros() as u64));
+        }
+    }
+
+    /// Our heartbeat, as answered to a ping
+    pub fn heartbeat(&self) -> u64 {
+        self.members.read().unwrap().get(&self.me.id).map_or(0, |m| m.heartbeat)
+    }
+
+    /// Take this node out of the cluster: it stops heartbeating and leads
+    /// nothing from now on, so its topics move to the other nodes
+    pub fn leave(&self) {
+        let mut members = self.members.write().unwrap();
+        if let Some(me) = members.get_mut(&self.me.id) {
+            me.left = true;
+        }
+    }
+
+    pub fn has_left(&self) -> bool {
+        self.members.read().unwrap().get(&self.me.id).is_some_and(|m| m.left)
+    }
+
+    /// Mark another member as left, for a node that is gone and can't be
+    /// told to leave itself. Returns false if it isn't a member.
+    pub fn remove(&self, id: &str) -> bool {
+        let mut members = self.members.write().unwrap();
+        match members.get_mut(id) {
+            Some(m) if id != self.me.id => {
+                m.left = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn addr_of(&self, id: &str) -> Option<String> {
+        self.members.read().unwrap().get(id).map(|m| m.node.addr.clone())
+    }
+
+    /// Exchange digests with the node at `addr` right away, so it joins the
+    /// view without waiting to be picked by gossip
+    pub async fn join(&self, addr: &str) -> anyhow::Result<()> {
+        let mut body = BytesMut::new();
+        self.put_digest(&mut body);
+        match self.peers.call_once(addr, Op::Gossip, &body).await? {
+            (Status::Ok, resp) if self.merge_digest(&mut &resp[..]) => Ok(()),
+            (st, _) => Err(anyhow::anyhow!("{} answered gossip with {:?}", addr, st)),
+        }
+    }
+
+    /// Bump our heartbeat, mark quiet members down and drop long gone ones
+    fn tick(&self) {
+        let mut members = self.members.write().unwrap();
+        if let Some(me) = members.get_mut(&self.me.id) {
+            // a node that left stays quiet so nobody takes it back
+            if !me.left {
+                me.heartbeat += 1;
+            }
+            me.seen = Instant::now();
+        }
+        for (id, m) in members.iter_mut() {
+            let down = m.seen.elapsed() >= FAIL_AFTER;
+            if down != m.down {
+                if down {
+                    tracing::warn!("node {} at {} is down, its topics move to other nodes", id, m.node.addr);
+                } else {
+                    tracing::info!("node {} at {} is back up", id, m.node.addr);
+                }
+                m.down = down;
+            }
+        }
+        members.retain(|id, m| {
+            let keep = m.seen.elapsed() < FORGET_AFTER;
+            if !keep {
+                tracing::warn!("node {} at {} left, no heartbeat for {:?}", id, m.node.addr, FORGET_AFTER);
+            }
+            keep
+        });
+    }
+
+    /// Ping every other member once a round, forever. An answer counts as a
+    /// heartbeat from that member, no answer makes it suspect.
+    pub async fn probe(self) {
+        let mut tick = tokio::time::interval(PING_INTERVAL);
+        loop {
+            tick.tick().await;
+            let peers: Vec<Node> = self.nodes().into_iter().filter(|n| n.id != self.me.id).collect();
+            let mut pings = JoinSet::new();
+            for node in peers {
+                let pool = self.peers.clone();
+                pings.spawn(async move {
+                    let start = Instant::now();
+                    let res = pool.call_once(&node.addr, Op::Ping, &[]).await;
+                    (node, res, start.elapsed())
+                });
+            }
+            while let Some(Ok((node, res, rtt))) = pings.join_next().await {
+                let pong = match res {
+                    Ok((Status::Ok, resp)) => {
+                        let mut b = &resp[..];
+                        match (get_str(&mut b), get_u64(&mut b)) {
+                            // another node took over the address
+                            (Some(id), Some(heartbeat)) if id == node.id => Some(heartbeat),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                self.pong(&node.id, pong, rtt);
+            }
+        }
+    }
+
+    /// Record the answer to a ping of `id`: its heartbeat, or None if it didn't answer
+    fn pong(&self, id: &str, heartbeat: Option<u64>, rtt: Duration) {
+        let mut members = self.members.write().unwrap();
+        let Some(m) = members.get_mut(id) else {
+            return;
+        };
+        match heartbeat {
+            Some(heartbeat) => {
+                m.reachable = true;
+                m.rtt = Some(rtt);
+                if heartbeat > m.heartbeat {
+                    m.heartbeat = heartbeat;
+                    m.seen = Instant::now();
+                }
+            }
+            None => {
+                if m.reachable && !m.left {
+                    tracing::debug!("node {} at {} missed a ping", id, m.node.addr);
+                }
+                m.reachable = false;
+                m.rtt = None;
+            }
+        }
+    }
+
+    /// Peers and seeds not in the view yet, in a stable order
+    fn gossip_targets(&self) -> Vec<String> {
+        let members = self.members.read().unwrap();
+        let mut addrs: Vec<String> = members
+            .values()
+            .filter(|m| m.node.id != self.me.id)
+            .map(|m| m.node.addr.clone())
+            .collect();
+        for s in self.seeds.iter() {
+            if !addrs.contains(s) && *s != self.me.addr {
+                addrs.push(s.clone());
+            }
+        }
+        addrs
+    }
+
+    /// Gossip with one peer per round, forever
+    pub async fn gossip(self) {
+        let mut tick = tokio::time::interval(GOSSIP_INTERVAL);
+        loop {
+            tick.tick().await;
+            self.tick();
+            let targets = self.gossip_targets();
+            if targets.is_empty() {
+                continue;
+            }
+            let i = self.next_target.fetch_add(1, Ordering::Relaxed) % targets.len();
+            let mut body = BytesMut::new();
+            self.put_digest(&mut body);
+            match self.peers.call_once(&targets[i], Op::Gossip, &body).await {
+                Ok((Status::Ok, resp)) => {
+                    self.merge_digest(&mut &resp[..]);
+                }
+                Ok((st, _)) => tracing::debug!("gossip with {} answered {:?}", targets[i], st),
+                Err(e) => tracing::debug!("gossip with {} failed: {}", targets[i], e),
+            }
+        }
+    }
 }
@@ -0,0 +1,49 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::Credentials;
+use crate::limit::RateLimit;
+
+/// Settings a running broker can take new values of, see `Server::live_config`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// connections have to authenticate before anything else if set
+    pub auth: Option<Arc<Credentials>>,
+    /// how fast each connection may send requests
+    pub rate_limit: RateLimit,
+    /// retention of topics that don't set their own
+    pub retention: Option<Duration>,
+    pub retention_bytes: Option<u64>,
+}
+
+/// Settings in a JSON file, each one overriding its command line flag when
+/// set. All but `otlp_endpoint` are reloaded on SIGHUP.
+/// {"log_level": "quique=debug", "auth_file": "creds.json", "max_requests_per_sec": 1000,
+///  "max_bytes_per_sec": 1048576, "retention_secs": 86400, "retention_bytes": 1073741824,
+///  "max_memory_bytes": 268435456, "otlp_endpoint": "http://localhost:4318/v1/traces"}
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// tracing filter directives, like RUST_LOG
+    pub log_level: Option<String>,
+    pub auth_file: Option<String>,
+    pub max_requests_per_sec: Option<u32>,
+    pub max_bytes_per_sec: Option<u64>,
+    /// 0 = forever
+    pub retention_secs: Option<u32>,
+    /// 0 = unlimited
+    pub retention_bytes: Option<u64>,
+    /// 0 = unlimited, see `queue::MEMORY`
+    pub max_memory_bytes: Option<u64>,
+    /// where request spans are exported to over OTLP/HTTP
+    pub otlp_endpoint: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<Self> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("reading config from {}", path))?;
+        serde_json::from_str(&json).with_context(|| format!("parsing config {}", path))
+    }
+}
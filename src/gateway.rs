@@ -0,0 +1,191 @@
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::handler::{self, Session};
+use crate::hints::Hints;
+use crate::protocol::*;
+use crate::queue::{Message, TopicRegistry};
+
+/// Produce/consume for the listeners that don't speak QBUS themselves (gRPC,
+/// WebSocket): requests are turned into the QBUS bodies they stand for and
+/// run through the same handlers, forwarded to the topic's leader when that's
+/// another node, as in proxy mode.
+#[derive(Clone)]
+pub struct Gateway {
+    cluster: Cluster,
+    topics: Arc<TopicRegistry>,
+    hints: Arc<Hints>,
+    config: Arc<ArcSwap<Config>>,
+    maintenance: Arc<AtomicBool>,
+}
+
+impl Gateway {
+    pub fn new(
+        cluster: Cluster,
+        topics: Arc<TopicRegistry>,
+        hints: Arc<Hints>,
+        config: Arc<ArcSwap<Config>>,
+        maintenance: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            cluster,
+            topics,
+            hints,
+            config,
+            maintenance,
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        Session::new(self.topics.clone())
+    }
+
+    /// Whether requests on a session need it to authenticate first
+    pub fn auth_required(&self) -> bool {
+        self.config.load().auth.is_some()
+    }
+
+    /// Authenticate `session` as `user` with password `secret`, or with the
+    /// token `secret` if `user` is empty
+    pub async fn authenticate(&self, session: &mut Session, user: &str, secret: &str) -> Status {
+        let mut body = BytesMut::new();
+        put_str(&mut body, user);
+        put_str(&mut body, secret);
+        self.call(session, Op::Auth, body.freeze()).await.0
+    }
+
+    /// Serve a QBUS request on `session` as handle_conn would in proxy mode,
+    /// for Produce, Consume, Ack, Nack, Bind and Auth. Returns its status and the
+    /// rest of the answer.
+    pub async fn call(&self, session: &mut Session, op: Op, body: Bytes) -> (Status, Bytes) {
+        let cfg = self.config.load_full();
+        if op == Op::Auth {
+            let mut out = BytesMut::new();
+            if let Err(e) = handler::handle_auth(&mut &body[..], cfg.auth.as_deref(), session, &mut out).await {
+                tracing::warn!("authentication failed: {}", e);
+                return (Status::ServerError, Bytes::new());
+            }
+            return answer(out);
+        }
+        if cfg.auth.is_some() && session.identity.is_none() {
+            return (Status::Unauthorized, Bytes::new());
+        }
+        if !matches!(op, Op::Produce | Op::Consume | Op::Ack | Op::Nack | Op::Bind) {
+            return (Status::BadRequest, Bytes::new());
+        }
+        let body = match session.namespace.as_deref() {
+            Some(ns) => match handler::scope_request(&body, ns) {
+                Some(body) => body,
+                None => return (Status::BadRequest, Bytes::new()),
+            },
+            None => body,
+        };
+        if op.is_write() && self.maintenance.load(Ordering::SeqCst) {
+            return (Status::Maintenance, Bytes::new());
+        }
+        let leader = get_str(&mut &body[..])
+            .map(|topic| self.cluster.leader_of(&topic))
+            .filter(|l| l.id != self.cluster.me.id && (op != Op::Produce || self.cluster.is_reachable(&l.id)));
+        let mut out = BytesMut::new();
+        let req = &mut &body[..];
+        let res = match op {
+            _ if let Some(leader) = &leader => {
+                handler::forward(session, self.cluster.peers(), &leader.addr, op, &body, &mut out).await
+            }
+            Op::Produce => handler::handle_produce(&body, &self.cluster, &self.topics, &self.hints, session, &mut out).await,
+            Op::Consume => handler::handle_consume(req, &self.cluster, &self.topics, session, &mut out).await,
+            Op::Bind => handler::handle_bind(req, &self.cluster, &self.topics, &mut out).await,
+            _ => handler::handle_settle(req, op, &self.cluster, &self.topics, session, &mut out).await,
+        };
+        if let Err(e) = res {
+            tracing::warn!("{:?} failed: {}", op, e);
+            return (Status::ServerError, Bytes::new());
+        }
+        answer(out)
+    }
+
+    /// Produce `msg` to `topic`, answering the seq it got: 0 with
+    /// `Acks::None`, where it's not known
+    pub async fn produce(
+        &self,
+        session: &mut Session,
+        topic: &str,
+        msg: &Message,
+        priority: u8,
+        routing_key: &str,
+        acks: Acks,
+    ) -> Result<u64, Status> {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        put_bytes(&mut body, &msg.payload);
+        put_u8(&mut body, priority);
+        put_str(&mut body, routing_key);
+        put_envelope(&mut body, &msg.envelope);
+        put_u8(&mut body, acks as u8);
+        match self.call(session, Op::Produce, body.freeze()).await {
+            (Status::Ok, rest) => Ok(get_u64(&mut &rest[..]).unwrap_or(0)),
+            (st, _) => Err(st),
+        }
+    }
+
+    /// Next message of `topic` for `group` and its delivery tag, waiting up
+    /// to `timeout_ms`. It's redelivered unless acked before `session` ends.
+    pub async fn next(
+        &self,
+        session: &mut Session,
+        topic: &str,
+        group: &str,
+        timeout_ms: u32,
+    ) -> Result<Option<(u64, Message)>, Status> {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        put_u32(&mut body, timeout_ms);
+        put_str(&mut body, group);
+        let rest = match self.call(session, Op::Consume, body.freeze()).await {
+            (Status::Ok, rest) => rest,
+            (Status::Empty, _) => return Ok(None),
+            (st, _) => return Err(st),
+        };
+        let r = &mut &rest[..];
+        let (Some(tag), Some(payload)) = (get_u64(r), get_slice(r)) else {
+            return Err(Status::ServerError);
+        };
+        let payload = rest.slice_ref(payload);
+        let envelope = get_envelope(r).unwrap_or_default();
+        Ok(Some((tag, Message { payload, envelope })))
+    }
+
+    /// Ack, or with `requeue` nack, delivery `tag` of `topic` to `group`
+    pub async fn settle(&self, session: &mut Session, topic: &str, group: &str, tag: u64, requeue: bool) -> Status {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        put_u64(&mut body, tag);
+        put_str(&mut body, group);
+        let op = if requeue { Op::Nack } else { Op::Ack };
+        self.call(session, op, body.freeze()).await.0
+    }
+
+    /// Route messages of `topic` to `group` by `key`, see `Topic::bind`
+    pub async fn bind(&self, session: &mut Session, topic: &str, group: &str, key: &str) -> Status {
+        let mut body = BytesMut::new();
+        put_str(&mut body, topic);
+        put_str(&mut body, group);
+        put_str(&mut body, key);
+        self.call(session, Op::Bind, body.freeze()).await.0
+    }
+}
+
+/// Status an answer starts with and the rest of it. Nothing at all is the
+/// answer to a fire and forget produce, which is as good as Ok.
+fn answer(out: BytesMut) -> (Status, Bytes) {
+    let st = match &out[..] {
+        [a, b, ..] => Status::try_from(u16::from_be_bytes([*a, *b])).unwrap_or(Status::ServerError),
+        _ => Status::Ok,
+    };
+    let skip = out.len().min(2);
+    (st, out.freeze().slice(skip..))
+}
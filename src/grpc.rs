@@ -0,0 +1,149 @@
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response};
+
+use crate::gateway::Gateway;
+use crate::handler::Session;
+use crate::protocol::*;
+use crate::queue::Message;
+
+pub mod pb {
+    tonic::include_proto!("quique.v1");
+}
+
+/// How long a Subscribe stream waits for a message before checking the
+/// client is still there
+const SUBSCRIBE_POLL_MS: u32 = 30_000;
+
+/// Messages a Subscribe stream has taken, and acked, that haven't gone out
+/// yet. They're lost if the client leaves, so only the one.
+const SUBSCRIBE_BUFFER: usize = 1;
+
+/// The gRPC `Broker` service of proto/quique.proto. Each call gets a
+/// session of its own, as a QBUS connection would.
+#[derive(Clone)]
+pub struct Service {
+    gateway: Gateway,
+}
+
+impl Service {
+    pub fn new(gateway: Gateway) -> Self {
+        Self { gateway }
+    }
+
+    /// A session authenticated with the `quique-token`, or `quique-user`
+    /// and `quique-password`, of the call's metadata
+    async fn session(&self, metadata: &MetadataMap) -> Result<Session, tonic::Status> {
+        let mut session = self.gateway.session();
+        if !self.gateway.auth_required() {
+            return Ok(session);
+        }
+        let get = |key: &str| metadata.get(key).and_then(|v| v.to_str().ok()).unwrap_or("");
+        let (user, secret) = match get("quique-token") {
+            "" => (get("quique-user"), get("quique-password")),
+            token => ("", token),
+        };
+        match self.gateway.authenticate(&mut session, user, secret).await {
+            Status::Ok => Ok(session),
+            _ => Err(tonic::Status::unauthenticated("invalid credentials")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl pb::broker_server::Broker for Service {
+    async fn produce(&self, request: Request<pb::ProduceRequest>) -> Result<Response<pb::ProduceResponse>, tonic::Status> {
+        let mut session = self.session(request.metadata()).await?;
+        let req = request.into_inner();
+        let acks = match req.acks() {
+            pb::Acks::Leader => Acks::Leader,
+            pb::Acks::Quorum => Acks::Quorum,
+            pb::Acks::None => Acks::None,
+        };
+        let msg = req.message.unwrap_or_default();
+        let msg = Message {
+            payload: msg.payload,
+            envelope: Envelope {
+                message_id: msg.message_id,
+                content_type: msg.content_type,
+                timestamp_ms: msg.timestamp_ms,
+                headers: msg.headers.into_iter().collect(),
+                ..Default::default()
+            },
+        };
+        let priority = req.priority.min(u8::MAX as u32) as u8;
+        match self.gateway.produce(&mut session, &req.topic, &msg, priority, &req.routing_key, acks).await {
+            Ok(seq) => Ok(Response::new(pb::ProduceResponse { seq })),
+            Err(st) => Err(to_grpc(st)),
+        }
+    }
+
+    async fn consume(&self, request: Request<pb::ConsumeRequest>) -> Result<Response<pb::ConsumeResponse>, tonic::Status> {
+        let mut session = self.session(request.metadata()).await?;
+        let req = request.into_inner();
+        let message = match self.gateway.next(&mut session, &req.topic, &req.group, req.timeout_ms).await {
+            Ok(Some((tag, msg))) => match self.gateway.settle(&mut session, &req.topic, &req.group, tag, false).await {
+                Status::Ok => Some(to_pb(msg)),
+                st => return Err(to_grpc(st)),
+            },
+            Ok(None) => None,
+            Err(st) => return Err(to_grpc(st)),
+        };
+        Ok(Response::new(pb::ConsumeResponse { message }))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<pb::Message, tonic::Status>>;
+
+    async fn subscribe(&self, request: Request<pb::SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, tonic::Status> {
+        let mut session = self.session(request.metadata()).await?;
+        let req = request.into_inner();
+        let (tx, rx) = mpsc::channel(SUBSCRIBE_BUFFER);
+        let gateway = self.gateway.clone();
+        tokio::spawn(async move {
+            // a permit first: nothing is taken off the topic for a client that's gone
+            while let Ok(permit) = tx.reserve().await {
+                let res = match gateway.next(&mut session, &req.topic, &req.group, SUBSCRIBE_POLL_MS).await {
+                    // the client left while waiting: the message goes back with the session
+                    Ok(Some(_)) if tx.is_closed() => return,
+                    Ok(Some((tag, msg))) => match gateway.settle(&mut session, &req.topic, &req.group, tag, false).await {
+                        Status::Ok => Ok(to_pb(msg)),
+                        st => Err(to_grpc(st)),
+                    },
+                    Ok(None) => continue,
+                    Err(st) => Err(to_grpc(st)),
+                };
+                let done = res.is_err();
+                permit.send(res);
+                if done {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn to_pb(msg: Message) -> pb::Message {
+    let mut env = msg.envelope;
+    pb::Message {
+        payload: msg.payload,
+        message_id: std::mem::take(&mut env.message_id),
+        content_type: std::mem::take(&mut env.content_type),
+        timestamp_ms: env.timestamp_ms,
+        headers: env.into_headers().into_iter().collect(),
+    }
+}
+
+fn to_grpc(st: Status) -> tonic::Status {
+    let msg = format!("{:?}", st);
+    match st {
+        Status::NotFound => tonic::Status::not_found(msg),
+        Status::TopicExists => tonic::Status::already_exists(msg),
+        Status::BadRequest => tonic::Status::invalid_argument(msg),
+        Status::Unauthorized => tonic::Status::permission_denied(msg),
+        Status::Throttled | Status::QuotaExceeded | Status::TooManyConnections | Status::QueueFull => tonic::Status::resource_exhausted(msg),
+        Status::Maintenance | Status::NotReplicated | Status::Unavailable => tonic::Status::unavailable(msg),
+        _ => tonic::Status::internal(msg),
+    }
+}
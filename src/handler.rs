@@ -1,157 +1,2182 @@
 use anyhow::Result;
-use bytes::{BufMut, BytesMut};
-use std::sync::Arc;
+use bytes::{BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
+use crate::auth::Credentials;
 use crate::cluster::Cluster;
+use crate::hints::Hints;
+use crate::peer;
 use crate::protocol::*;
-use crate::queue::{Topic, TopicRegistry};
+use crate::queue::{
+    self, Binding, Delivery, Duplicate, HeaderMatch, Message, OffsetReset, Overflow, ProducerSeq, QueueFull, Replica, SAMPLE_ALL, Staged, Topic, TopicConfig, TopicKind,
+    TopicRegistry, UnknownGroup,
+};
+use crate::replication;
+use crate::scheduler::{Cron, Schedule};
+use crate::selector::Selector;
+use crate::storage::audit_log::AuditEvent;
+use crate::storage::disk_log::LogEntry;
+use crate::storage::metadata::{MetadataStorage, save_topics};
+use crate::webhook;
 
-pub async fn handle_metadata(body: &mut &[u8], cluster: &Cluster, out: &mut BytesMut) -> Result<()> {
+/// How long a quorum produce waits for followers before answering NotReplicated
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a produce waits for room in a full topic, longer waits asked for
+/// are cut to it so the answer comes before a client gives up on it
+const MAX_PRODUCE_WAIT: Duration = Duration::from_secs(20);
+
+/// Most shards a topic's priority levels can be split into
+const MAX_SHARDS: u8 = 64;
+
+/// Messages and payload bytes a transaction may stage, past either the
+/// produce is answered QuotaExceeded
+const MAX_TRANSACTION_MESSAGES: usize = 10_000;
+const MAX_TRANSACTION_BYTES: usize = 64 << 20;
+
+/// Per-connection state
+pub struct Session {
+    topics: Arc<TopicRegistry>,
+    /// handed out on this connection and not yet settled, shared with forks
+    unacked: Arc<Unacked>,
+    /// proxy mode: connections to the leaders requests were forwarded to, by
+    /// address, shared with forks. Each is locked only while it's opened.
+    upstream: Arc<DashMap<String, Arc<tokio::sync::Mutex<Option<peer::Mux>>>>>,
+    /// who authenticated on this connection, see handle_auth
+    pub identity: Option<String>,
+    /// namespace the identity is confined to, topics it names are scoped to it
+    pub namespace: Option<String>,
+    /// protocol version and features agreed on with `Op::Hello`, the oldest
+    /// and none for a client that didn't say
+    pub version: u8,
+    pub features: u32,
+    /// produces staged since `Op::Begin`, None outside a transaction.
+    /// Shared with forks, dropped with the connection.
+    transaction: Arc<Mutex<Option<Transaction>>>,
+    /// the same for forks, owner of the exclusive topics it creates
+    id: u64,
+    /// topics consumed from or owned, shared with forks
+    attached: Arc<Mutex<Attached>>,
+}
+
+/// Ids of sessions, unique in the process
+static SESSIONS: AtomicU64 = AtomicU64::new(1);
+
+/// Messages of a session's open transaction, see `handle_commit`
+#[derive(Default)]
+struct Transaction {
+    staged: Vec<Staged>,
+    /// payload bytes staged
+    bytes: usize,
+}
+
+impl Session {
+    pub fn new(topics: Arc<TopicRegistry>) -> Self {
+        let id = SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Self {
+            unacked: Arc::new(Unacked {
+                topics: topics.clone(),
+                conn: id,
+                tags: Mutex::new(HashSet::new()),
+            }),
+            topics,
+            upstream: Arc::default(),
+            identity: None,
+            namespace: None,
+            version: MIN_VERSION,
+            features: 0,
+            transaction: Arc::default(),
+            id,
+            attached: Arc::default(),
+        }
+    }
+
+    /// Who to put in `t`'s audit trail for a request of this session
+    fn audit(&self, t: &Topic, event: AuditEvent, seq: u64, message_id: &str, group: &str) {
+        t.audit(event, seq, message_id, self.id, self.identity.as_deref().unwrap_or(""), group);
+    }
+
+    /// Whether produces on this session are staged for a commit
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.lock().unwrap().is_some()
+    }
+
+    /// Another session of the same client, authenticated as this one is.
+    /// Deliveries on each are settled, or requeued, separately.
+    pub fn sibling(&self) -> Self {
+        let mut other = Self::new(self.topics.clone());
+        other.identity = self.identity.clone();
+        other.namespace = self.namespace.clone();
+        other.version = self.version;
+        other.features = self.features;
+        other
+    }
+
+    /// The session again, for a request of the connection running alongside
+    /// others. Deliveries and upstream connections are shared: a tag handed
+    /// out on one fork can be settled on another.
+    pub fn fork(&self) -> Self {
+        Self {
+            topics: self.topics.clone(),
+            unacked: self.unacked.clone(),
+            upstream: self.upstream.clone(),
+            identity: self.identity.clone(),
+            namespace: self.namespace.clone(),
+            version: self.version,
+            features: self.features,
+            transaction: self.transaction.clone(),
+            id: self.id,
+            attached: self.attached.clone(),
+        }
+    }
+
+    /// Count the session as a consumer of `t` until it ends, if `t` is
+    /// deleted once its consumers are gone. False if `t` is exclusive to
+    /// another session.
+    fn consume_from(&self, t: &Arc<Topic>) -> bool {
+        if !t.may_consume(self.id) {
+            return false;
+        }
+        if !t.config().auto_delete {
+            return true;
+        }
+        let mut attached = self.attached.lock().unwrap();
+        if !attached.consuming.iter().any(|a| Arc::ptr_eq(a, t)) {
+            t.attach();
+            attached.consuming.push(t.clone());
+        }
+        true
+    }
+
+    /// Make the session the owner of exclusive topic `t`, deleted when the
+    /// session ends
+    fn own(&self, t: &Arc<Topic>) {
+        t.claim(self.id);
+        self.attached.lock().unwrap().owned.push(t.clone());
+    }
+}
+
+/// Topics a session consumes from or owns, let go of when it ends
+#[derive(Default)]
+struct Attached {
+    /// auto-delete topics, see `Topic::attach`
+    consuming: Vec<Arc<Topic>>,
+    /// exclusive topics it created
+    owned: Vec<Arc<Topic>>,
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        for t in self.consuming.drain(..) {
+            t.detach();
+        }
+        for t in self.owned.drain(..) {
+            t.disown();
+        }
+    }
+}
+
+/// Deliveries of a session not yet settled
+struct Unacked {
+    topics: Arc<TopicRegistry>,
+    /// id of the session, see `Session`
+    conn: u64,
+    /// (topic, group, delivery tag)
+    tags: Mutex<HashSet<(String, String, u64)>>,
+}
+
+impl Drop for Unacked {
+    // the consumer is gone: whatever it didn't ack gets redelivered
+    fn drop(&mut self) {
+        for (topic, group, tag) in self.tags.get_mut().unwrap().drain() {
+            let Some(t) = self.topics.get(&topic) else {
+                continue;
+            };
+            match t.nack(&group, tag) {
+                Ok(true) => t.audit(AuditEvent::Nack, tag, "", self.conn, "", &group),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("failed to requeue {}#{}: {}", topic, tag, e),
+            }
+        }
+    }
+}
+
+/// Proxy mode: pass a request on to the topic's leader and its answer back
+/// as is. Every session gets its own connection to each leader, so whatever
+/// a client consumed through it is requeued by the leader once it hangs up.
+/// Requests share that connection, each on a stream_id of its own, so a
+/// long poll doesn't hold up the others.
+pub async fn forward(
+    session: &Session,
+    peers: &peer::Pool,
+    addr: &str,
+    op: Op,
+    body: &[u8],
+    out: &mut BytesMut,
+) -> Result<()> {
+    let slot = session.upstream.entry(addr.to_string()).or_default().clone();
+    let conn = {
+        let mut conn = slot.lock().await;
+        match conn.as_ref() {
+            Some(c) if !c.is_closed() => c.clone(),
+            // unacked deliveries on a closed one are requeued by the leader
+            _ => match peers.connect(addr).await {
+                Ok(s) => conn.insert(peer::Mux::new(s)).clone(),
+                Err(e) => {
+                    tracing::warn!("failed to connect to leader {} for forwarding: {}", addr, e);
+                    put_status(out, Status::ServerError);
+                    return Ok(());
+                }
+            },
+        }
+    };
+    // the leader doesn't answer a fire and forget produce, neither do we
+    if op == Op::Produce && produce_acks(body) == Acks::None {
+        if let Err(e) = conn.send(op, body) {
+            tracing::warn!("forwarding {:?} to {} failed: {}", op, addr, e);
+        }
+        return Ok(());
+    }
+    match conn.rpc(op, body).await {
+        Ok((st, rest)) => {
+            put_status(out, st);
+            out.extend_from_slice(&rest);
+        }
+        Err(e) => {
+            tracing::warn!("forwarding {:?} to {} failed: {}", op, addr, e);
+            put_status(out, Status::ServerError);
+        }
+    }
+    Ok(())
+}
+
+/// A request that starts with a topic, with the topic moved into namespace
+/// `ns`. None if there's no topic or it already names a namespace: tenants
+/// can't reach outside of theirs.
+pub fn scope_request(body: &[u8], ns: &str) -> Option<Bytes> {
+    let mut rest = body;
+    let topic = get_str(&mut rest)?;
+    if split_namespace(&topic).0.is_some() {
+        return None;
+    }
+    let mut out = BytesMut::with_capacity(body.len() + ns.len() + 1);
+    put_str(&mut out, &namespaced(ns, &topic));
+    out.extend_from_slice(rest);
+    Some(out.freeze())
+}
+
+/// Ack level of a produce request body, see handle_produce
+pub fn produce_acks(mut body: &[u8]) -> Acks {
+    let b = &mut body;
+    let (Some(_), Some(_), Some(_), Some(_), Some(_), Some(acks)) =
+        (get_str(b), get_bytes(b), get_u8(b), get_str(b), get_envelope(b), get_u8(b))
+    else {
+        return Acks::Leader;
+    };
+    Acks::try_from(acks).unwrap_or(Acks::Leader)
+}
+
+pub async fn handle_metadata(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
     // req: topic(str)
+    // resp: n(u32) | n * (partition(u32) | leader_addr(str)), partition p is
+    //       addressed as topic `partition_name(topic, p)` in other requests
+    //       then, if the topic is here, its settings and groups:
+    //       | capacity(u32) | idle_ttl_secs(u32) | message_ttl_ms(u32) | dead_letter(str)
+    //       | max_priority(u8) | kind(u8) | retention_secs(u32) | retention_bytes(u64)
+    //       | replicas(u8) | webhook(str) | shards(u8) | overflow(u8) | visibility_timeout_ms(u32)
+    //       | capacity_bytes(u64) | auto_delete(u8) | exclusive(u8) | transient(u8) | audit(u8)
+    //       | m(u32) | m * (group(str) | binding(str, "" = none)), the default group first
+    //       | k(u32) | k * pattern(str), topic patterns it's bound to, see BindTopic
+    //       | m * headers(str, "" = none), header conditions of each group's binding
+    //       | m * filter(str, "" = none), and its filter
+    //       | j(u32) | j * topic(str), topics here bound to a pattern matching
+    //         this one, which get copies of its messages
+    //       | s(u32) | s * (name(str) | cron(str) | next_ms(u64, 0 = never)), its schedules
+    //       | m * sample(u32, 0 = every message), the sample rate of each
+    //         group's binding, see Bind
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    // the partition count is known where partition 0 lives
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    // unknown topics are reported as one partition, led by whoever would create it
+    let n = topics.get(&topic).map(|t| t.config().partitions.max(1)).unwrap_or(1);
+    put_status(out, Status::Ok);
+    put_u32(out, n);
+    for p in 0..n {
+        out.put_u32(p);
+        put_str(out, &cluster.leader_of(&partition_name(&topic, p)).addr);
+    }
+    let Some(t) = topics.get(&topic) else {
+        return Ok(());
+    };
+    let cfg = t.config();
+    put_u32(out, cfg.capacity as u32);
+    put_u32(out, cfg.idle_ttl.map(|d| d.as_secs() as u32).unwrap_or(0));
+    put_u32(out, cfg.message_ttl.map(|d| d.as_millis() as u32).unwrap_or(0));
+    put_str(out, cfg.dead_letter.as_deref().unwrap_or(""));
+    put_u8(out, cfg.max_priority);
+    put_u8(out, cfg.kind as u8);
+    put_u32(out, cfg.retention.map(|d| d.as_secs() as u32).unwrap_or(0));
+    put_u64(out, cfg.retention_bytes.unwrap_or(0));
+    put_u8(out, cfg.replicas);
+    put_str(out, cfg.webhook.as_deref().unwrap_or(""));
+    put_u8(out, cfg.shards);
+    put_u8(out, cfg.overflow as u8);
+    put_u32(out, cfg.visibility_timeout.map(|d| d.as_millis() as u32).unwrap_or(0));
+    put_u64(out, cfg.capacity_bytes.unwrap_or(0));
+    put_u8(out, cfg.auto_delete as u8);
+    put_u8(out, cfg.exclusive as u8);
+    put_u8(out, cfg.transient as u8);
+    put_u8(out, cfg.audit as u8);
+    let bindings: HashMap<String, Binding> = t.bindings().into_iter().collect();
+    let groups: Vec<String> = std::iter::once(String::new()).chain(t.group_names()).collect();
+    put_u32(out, groups.len() as u32);
+    for g in &groups {
+        put_str(out, g);
+        put_str(out, bindings.get(g).map_or("", |b| b.key.as_str()));
+    }
+    let patterns = topics.subscriptions(&topic);
+    put_u32(out, patterns.len() as u32);
+    for p in patterns {
+        put_str(out, &p);
+    }
+    for g in &groups {
+        put_str(out, &bindings.get(g).map(|b| b.headers.to_string()).unwrap_or_default());
+    }
+    for g in &groups {
+        put_str(out, &bindings.get(g).and_then(|b| b.filter.as_ref()).map(|f| f.to_string()).unwrap_or_default());
+    }
+    let copied_to = topics.subscribers(&topic);
+    put_u32(out, copied_to.len() as u32);
+    for s in copied_to {
+        put_str(out, &s.name);
+    }
+    let schedules = topics.schedules(&topic);
+    put_u32(out, schedules.len() as u32);
+    let now = queue::now_ms();
+    for (name, s) in schedules {
+        put_str(out, &name);
+        put_str(out, &s.cron);
+        put_u64(out, Cron::parse(&s.cron).ok().and_then(|c| c.next_after(now)).unwrap_or(0));
+    }
+    for g in &groups {
+        put_u32(out, bindings.get(g).and_then(|b| b.sample).unwrap_or(0));
+    }
+    Ok(())
+}
+
+pub async fn handle_gossip(body: &mut &[u8], cluster: &Cluster, out: &mut BytesMut) -> Result<()> {
+    // req : digest of the sender's view, see Cluster::put_digest
+    // resp: digest of ours, after merging theirs
+    if !cluster.merge_digest(body) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    put_status(out, Status::Ok);
+    cluster.put_digest(out);
+    Ok(())
+}
+
+pub async fn handle_replicate(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    data_dir: &str,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | op(u8) | op specific fields, see ReplicaOp
+    // resp: status, NotFound if this node doesn't follow the topic (yet)
+    let (Some(topic), Some(op)) = (get_str(body), get_u8(body).and_then(|v| ReplicaOp::try_from(v).ok())) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if op == ReplicaOp::Open {
+        // still led here as far as this node knows, the roles sync sorts it out
+        if topics.get(&topic).is_some() {
+            put_status(out, Status::TopicExists);
+            return Ok(());
+        }
+        let Some(cfg) = get_bytes(body).and_then(|b| serde_json::from_slice::<TopicConfig>(&b).ok()) else {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        };
+        let handoff = get_u8(body).unwrap_or(0) != 0;
+        let r = match topics.get_replica(&topic) {
+            Some(r) => r,
+            None => {
+                let r = Arc::new(Replica::open(data_dir, &topic, cfg)?);
+                topics.insert_replica(r.clone());
+                tracing::info!("following topic {}", topic);
+                if let Err(e) = save_topics(metadata, topics) {
+                    tracing::warn!("failed to save metadata for replica {}: {}", topic, e);
+                }
+                r
+            }
+        };
+        if handoff {
+            tracing::info!("receiving topic {}", topic);
+            r.start_receiving(replication::HANDOFF_TIMEOUT);
+        }
+        put_status(out, Status::Ok);
+        return Ok(());
+    }
+    if op == ReplicaOp::Drop {
+        if let Some(r) = topics.remove_replica(&topic) {
+            tracing::info!("dropping replica of deleted topic {}", topic);
+            if let Err(e) = save_topics(metadata, topics) {
+                tracing::warn!("failed to save metadata after dropping replica {}: {}", topic, e);
+            }
+            r.destroy()?;
+        }
+        put_status(out, Status::Ok);
+        return Ok(());
+    }
+    // took over on its own already, nothing left to hand off
+    if op == ReplicaOp::Promote && topics.get(&topic).is_some() {
+        put_status(out, Status::Ok);
+        return Ok(());
+    }
+    let Some(r) = topics.get_replica(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    let res = match op {
+        ReplicaOp::Append => {
+            let (Some(seq), Some(at_ms), Some(priority), Some(routing_key), Some(envelope), Some(payload)) = (
+                get_u64(body),
+                get_u64(body),
+                get_u8(body),
+                get_str(body),
+                get_bytes(body),
+                get_bytes(body),
+            ) else {
+                put_status(out, Status::BadRequest);
+                return Ok(());
+            };
+            r.append(&LogEntry {
+                seq,
+                at_ms,
+                priority,
+                routing_key,
+                envelope,
+                payload: payload.into(),
+            })
+            .map(|_| ())
+        }
+        ReplicaOp::Commit => {
+            let (Some(group), Some(seq)) = (get_str(body), get_u64(body)) else {
+                put_status(out, Status::BadRequest);
+                return Ok(());
+            };
+            r.commit(&group, seq)
+        }
+        ReplicaOp::Bind => {
+            let (Some(group), Some(key)) = (get_str(body), get_str(body)) else {
+                put_status(out, Status::BadRequest);
+                return Ok(());
+            };
+            let headers = get_str(body).and_then(|h| HeaderMatch::parse(&h)).unwrap_or_default();
+            let filter = get_str(body).filter(|f| !f.is_empty()).and_then(|f| Selector::parse(&f).ok());
+            let sample = get_u32(body).filter(|&s| s > 0 && s < SAMPLE_ALL);
+            r.bind(&group, &Binding { key, headers, filter, sample })
+        }
+        ReplicaOp::Unbind | ReplicaOp::DeleteGroup => {
+            let Some(group) = get_str(body) else {
+                put_status(out, Status::BadRequest);
+                return Ok(());
+            };
+            if op == ReplicaOp::Unbind {
+                r.unbind(&group)
+            } else {
+                r.delete_group(&group)
+            }
+        }
+        ReplicaOp::Promote => {
+            r.stop_receiving();
+            replication::promote(cluster, topics, &r).map(|t| {
+                tracing::info!("took over handed off topic {} with {} pending messages", topic, t.len());
+                if let Err(e) = save_topics(metadata, topics) {
+                    tracing::warn!("failed to save metadata after taking over {}: {}", topic, e);
+                }
+            })
+        }
+        ReplicaOp::Open | ReplicaOp::Drop => unreachable!(),
+    };
+    match res {
+        Ok(()) => put_status(out, Status::Ok),
+        Err(e) => {
+            tracing::warn!("failed to apply {:?} to replica of {}: {}", op, topic, e);
+            put_status(out, Status::ServerError);
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_auth(
+    body: &mut &[u8],
+    auth: Option<&Credentials>,
+    session: &mut Session,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : user(str) | secret(str), a password, or a token if user is empty
+    // resp: status, Unauthorized if they don't match
+    let (Some(user), Some(secret)) = (get_str(body), get_str(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let Some(auth) = auth else {
+        // nothing to check against, anyone is welcome
+        put_status(out, Status::Ok);
+        return Ok(());
+    };
+    match auth.check(&user, &secret) {
+        Some(identity) => {
+            tracing::debug!("connection authenticated as {}", identity);
+            session.namespace = auth.namespace_of(&identity).map(str::to_string);
+            session.identity = Some(identity);
+            put_status(out, Status::Ok);
+        }
+        None => {
+            tracing::warn!("failed authentication as {:?}", if user.is_empty() { "<token>" } else { &user });
+            put_status(out, Status::Unauthorized);
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_hello(body: &mut &[u8], session: &mut Session, out: &mut BytesMut) -> Result<()> {
+    // req : version(u8) | features(u32) | max_frame(u32), the newest version
+    //       the client speaks, what it supports and the largest body it takes
+    // resp: status | version(u8) | features(u32) | max_frame(u32), what both
+    //       sides speak and support and the largest body taken here.
+    //       BadRequest if the client is older than MIN_VERSION.
+    let (Some(version), Some(features), Some(_max_frame)) = (get_u8(body), get_u32(body), get_u32(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if version < MIN_VERSION {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    session.version = version.min(VERSION);
+    session.features = features & FEATURES;
+    put_status(out, Status::Ok);
+    put_u8(out, session.version);
+    put_u32(out, session.features);
+    put_u32(out, MAX_FRAME);
+    Ok(())
+}
+
+pub async fn handle_ping(cluster: &Cluster, out: &mut BytesMut) -> Result<()> {
+    // req : (empty)
+    // resp: status | id(str) | heartbeat(u64) | health view, see Cluster::put_health
+    put_status(out, Status::Ok);
+    put_str(out, &cluster.me.id);
+    put_u64(out, cluster.heartbeat());
+    cluster.put_health(out);
+    Ok(())
+}
+
+pub async fn handle_cluster_info(cluster: &Cluster, topics: &TopicRegistry, out: &mut BytesMut) -> Result<()> {
+    // req : (empty)
+    // resp: status | id(str) of this node
+    //       | n(u32) | n * (id(str) | addr(str) | status(u8) | last_seen_ms(u64) | m(u32) | m * topic(str))
+    // Topics are the ones this node leads or follows, under their leader. Topics
+    // without a copy here don't show up, ask their nodes for those.
+    let mut names: Vec<String> = topics.list().iter().map(|t| t.name.clone()).collect();
+    names.extend(topics.list_replicas().iter().map(|r| r.name.clone()));
+    names.sort();
+    names.dedup();
+    let mut led: HashMap<String, Vec<String>> = HashMap::new();
+    for name in names {
+        led.entry(cluster.leader_of(&name).id).or_default().push(name);
+    }
+
+    put_status(out, Status::Ok);
+    put_str(out, &cluster.me.id);
+    let health = cluster.health();
+    put_u32(out, health.len() as u32);
+    for h in health {
+        put_str(out, &h.node.id);
+        put_str(out, &h.node.addr);
+        put_u8(out, h.status as u8);
+        put_u64(out, h.last_seen.as_millis() as u64);
+        let names = led.remove(&h.node.id).unwrap_or_default();
+        put_u32(out, names.len() as u32);
+        for name in names {
+            put_str(out, &name);
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_add_node(body: &mut &[u8], cluster: &Cluster, out: &mut BytesMut) -> Result<()> {
+    // req : addr(str) of a node to bring into the cluster
+    let Some(addr) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    match cluster.join(&addr).await {
+        Ok(()) => put_status(out, Status::Ok),
+        Err(e) => {
+            tracing::warn!("failed to add node at {}: {}", addr, e);
+            put_status(out, Status::ServerError);
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_remove_node(body: &mut &[u8], cluster: &Cluster, out: &mut BytesMut) -> Result<()> {
+    // req : id(str) of the node to take out of the cluster
+    // A live node is asked to leave itself and hands its topics off. A node
+    // that can't be reached is only marked as left.
+    let Some(id) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if id == cluster.me.id {
+        tracing::info!("leaving the cluster, handing off topics");
+        cluster.leave();
+        put_status(out, Status::Ok);
+        return Ok(());
+    }
+    let Some(addr) = cluster.addr_of(&id) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    let mut fwd = BytesMut::new();
+    put_str(&mut fwd, &id);
+    match cluster.peers().call(&addr, Op::RemoveNode, &fwd).await {
+        Ok((st, _)) => put_status(out, st),
+        Err(e) => {
+            tracing::info!("node {} unreachable ({}), marking it as left", id, e);
+            cluster.remove(&id);
+            put_status(out, Status::Ok);
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_list_topics(topics: &TopicRegistry, namespace: Option<&str>, out: &mut BytesMut) -> Result<()> {
+    // req : (empty), only topics led by this node are listed, and only
+    //       those of the session's namespace if it has one, without it
+    // resp: n(u32) | n * (topic(str) | len(u32) | capacity(u32) | in_flight(u32) | groups(u32 m, m * str))
+    let all: Vec<(String, Arc<Topic>)> = topics
+        .list()
+        .into_iter()
+        .filter_map(|t| match (namespace, split_namespace(&t.name)) {
+            (None, _) => Some((t.name.clone(), t)),
+            (Some(ns), (Some(of), name)) if of == ns => Some((name.to_string(), t)),
+            _ => None,
+        })
+        .collect();
+    put_status(out, Status::Ok);
+    put_u32(out, all.len() as u32);
+    for (name, t) in all {
+        put_str(out, &name);
+        put_u32(out, t.len() as u32);
+        put_u32(out, t.capacity() as u32);
+        put_u32(out, t.in_flight() as u32);
+        let groups = t.group_names();
+        put_u32(out, groups.len() as u32);
+        for g in groups {
+            put_str(out, &g);
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_create_topic(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    auth: Option<&Credentials>,
+    owner: Option<&Session>,
+    data_dir: &str,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req: topic(str) | capacity(u32) | idle_ttl_secs(u32, optional, 0 = never)
+    //      | message_ttl_ms(u32, optional, 0 = never) | dead_letter(str, optional, "" = drop)
+    //      | max_priority(u8, optional, 0 = plain FIFO)
+    //      | kind(u8, optional, 0 = fanout, 1 = direct, 2 = pattern)
+    //      | retention_secs(u32, optional, 0 = forever) | retention_bytes(u64, optional, 0 = unlimited)
+    //      | replicas(u8, optional, copies of the log including the leader's, default 1)
+    //      | partitions(u32, optional, default 1)
+    //      | partition(u32, optional, only create this one, sent between nodes, ALL_PARTITIONS = all)
+    //      | webhook(str, optional, http(s) URL messages are POSTed to, "" = none)
+    //      | shards(u8, optional, queues each priority level is split into, 0 = 1, up to MAX_SHARDS)
+    //      | overflow(u8, optional, 0 = reject, 1 = drop head, 2 = dead letter, see Overflow)
+    //      | visibility_timeout_ms(u32, optional, 0 = in flight until the consumer disconnects)
+    //      | capacity_bytes(u64, optional, payload bytes pending per group, 0 = unlimited)
+    //      | auto_delete(u8, optional, 1 = delete once the last consumer disconnects)
+    //      | exclusive(u8, optional, 1 = only this connection consumes, deleted when it closes)
+    //      | transient(u8, optional, 1 = log not synced, dropped on restart)
+    //      | audit(u8, optional, 1 = keep an audit trail, see Op::Audit)
+    // Partitions led by other nodes are created by forwarding the request to them.
+    // A topic in a namespace dead letters within it, and counts against its
+    // max_topics on every node holding one of its partitions.
     let Some(topic) = get_str(body) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
-    put_status(out, Status::Ok);
-    // resp: [u32 1] then {u32 0 | str leader_addr}
-    // We pretend there is 1 partition (0) for compatibility if needed, or just simplify protocol.
-    // Let's simplify: just return 1 partition always.
-    put_u32(out, 1);
+    let Some(cap) = get_u32(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let idle_ttl = match get_u32(body).unwrap_or(0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    };
+    let message_ttl = match get_u32(body).unwrap_or(0) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    };
+    let dead_letter = match (split_namespace(&topic).0, get_str(body).filter(|s| !s.is_empty())) {
+        (Some(ns), Some(dl)) => match split_namespace(&dl).0 {
+            None => Some(namespaced(ns, &dl)),
+            Some(of) if of == ns => Some(dl),
+            Some(_) => {
+                put_status(out, Status::BadRequest);
+                return Ok(());
+            }
+        },
+        (_, dl) => dl,
+    };
+    let max_priority = get_u8(body).unwrap_or(0);
+    let kind = match get_u8(body).unwrap_or(0) {
+        0 => TopicKind::Fanout,
+        1 => TopicKind::Direct,
+        2 => TopicKind::Pattern,
+        _ => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
+    let retention = match get_u32(body).unwrap_or(0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    };
+    let retention_bytes = get_u64(body).filter(|&b| b > 0);
+    let replicas = get_u8(body).unwrap_or(1).max(1);
+    let partitions = get_u32(body).unwrap_or(1).max(1);
+    let only = get_u32(body).filter(|&p| p != ALL_PARTITIONS);
+    let webhook = get_str(body).filter(|s| !s.is_empty());
+    let shards = get_u8(body).unwrap_or(0);
+    let overflow = match get_u8(body).unwrap_or(0) {
+        0 => Overflow::Reject,
+        1 => Overflow::DropHead,
+        2 => Overflow::DeadLetter,
+        _ => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
+    let visibility_timeout = match get_u32(body).unwrap_or(0) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    };
+    let capacity_bytes = get_u64(body).filter(|&b| b > 0);
+    let auto_delete = get_u8(body).unwrap_or(0) != 0;
+    let exclusive = get_u8(body).unwrap_or(0) != 0;
+    let transient = get_u8(body).unwrap_or(0) != 0;
+    let audit = get_u8(body).unwrap_or(0) != 0;
+    let cfg = TopicConfig {
+        capacity: cap as usize,
+        max_priority,
+        idle_ttl,
+        message_ttl,
+        dead_letter,
+        kind,
+        retention,
+        retention_bytes,
+        replicas,
+        partitions,
+        webhook,
+        shards,
+        overflow,
+        visibility_timeout,
+        capacity_bytes,
+        auto_delete,
+        exclusive,
+        transient,
+        audit,
+    };
+    let st = create_topic(&topic, cfg, only, cluster, topics, metadata, auth, owner, data_dir).await;
+    put_status(out, st);
+    if st == Status::Redirect {
+        let name = only.map_or(topic.clone(), |p| partition_name(&topic, p));
+        put_str(out, &cluster.leader_of(&name).addr);
+    }
+    Ok(())
+}
+
+/// Create `topic` with `cfg`, every partition of it, or with `only` just
+/// the one of that number, as asked for by an `Op::CreateTopic`. Partitions
+/// led by other nodes are created by forwarding the request to them. An
+/// exclusive topic is owned by `owner`, and can't be created without one.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_topic(
+    topic: &str,
+    cfg: TopicConfig,
+    only: Option<u32>,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    auth: Option<&Credentials>,
+    owner: Option<&Session>,
+    data_dir: &str,
+) -> Status {
+    if !valid_topic(topic)
+        || cfg.webhook.as_deref().is_some_and(|url| !webhook::valid_url(url))
+        || cfg.shards > MAX_SHARDS
+        // nowhere to send them otherwise
+        || (cfg.overflow == Overflow::DeadLetter && cfg.dead_letter.is_none())
+        || only.is_some_and(|p| p >= cfg.partitions)
+    {
+        return Status::BadRequest;
+    }
+    if let Some(ns) = split_namespace(topic).0
+        && let Some(max) = auth.and_then(|a| a.namespace(ns)).map(|n| n.max_topics).filter(|&m| m > 0)
+        && namespace_topics(topics, ns, topic) >= max
+    {
+        return Status::QuotaExceeded;
+    }
+
+    // owned by the connection creating it, which has to be to its leader
+    if cfg.exclusive {
+        let Some(owner) = owner.filter(|_| cfg.partitions == 1 && cfg.replicas == 1 && only.is_none()) else {
+            return Status::BadRequest;
+        };
+        if !cluster.is_leader(topic) {
+            return Status::Redirect;
+        }
+        let st = create_partition(topic, cfg, cluster, topics, metadata, data_dir);
+        if st == Status::Ok
+            && let Some(t) = topics.get(topic)
+        {
+            owner.own(&t);
+        }
+        return st;
+    }
+
+    if let Some(p) = only {
+        return create_partition(&partition_name(topic, p), cfg, cluster, topics, metadata, data_dir);
+    }
+
+    // the first failure is reported, partitions created before it stay
+    let mut st = Status::Ok;
+    for p in 0..cfg.partitions {
+        let name = partition_name(topic, p);
+        let leader = cluster.leader_of(&name);
+        let res = if leader.id == cluster.me.id {
+            create_partition(&name, cfg.clone(), cluster, topics, metadata, data_dir)
+        } else {
+            let mut fwd = BytesMut::new();
+            put_create_topic(&mut fwd, topic, &cfg, p);
+            match cluster.peers().call(&leader.addr, Op::CreateTopic, &fwd).await {
+                Ok((res, _)) => res,
+                Err(e) => {
+                    tracing::warn!("failed to create {} on {}: {}", name, leader.id, e);
+                    Status::ServerError
+                }
+            }
+        };
+        if st == Status::Ok {
+            st = res;
+        }
+    }
+    st
+}
+
+/// Topics of namespace `ns` this node leads or follows a partition of,
+/// other than `except`
+fn namespace_topics(topics: &TopicRegistry, ns: &str, except: &str) -> usize {
+    let names = topics.list().into_iter().map(|t| t.name.clone());
+    let names = names.chain(topics.list_replicas().into_iter().map(|r| r.name.clone()));
+    let mut bases: HashSet<String> = HashSet::new();
+    for name in names {
+        let base = split_partition(&name).0;
+        if split_namespace(base).0 == Some(ns) && base != except {
+            bases.insert(base.to_string());
+        }
+    }
+    bases.len()
+}
+
+/// Open one partition led by this node, as a topic of its own
+fn create_partition(
+    name: &str,
+    cfg: TopicConfig,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    data_dir: &str,
+) -> Status {
+    if topics.get(name).is_some() {
+        return Status::TopicExists;
+    }
+    let t = match Topic::open(data_dir, name, cfg, || cluster.is_leader(name)) {
+        Ok(t) => Arc::new(t),
+        Err(e) if cluster.is_leader(name) => {
+            tracing::warn!("failed to open new topic {}: {}", name, e);
+            return Status::ServerError;
+        }
+        Err(_) => return Status::Redirect,
+    };
+    topics.insert(t.clone());
+    // a topic that wouldn't survive a restart is not created
+    if let Err(e) = save_topics(metadata, topics) {
+        tracing::warn!("failed to save metadata for new topic {}: {}", name, e);
+        topics.remove(name);
+        let _ = t.destroy();
+        return Status::ServerError;
+    }
+    replication::start(cluster, &t);
+    Status::Ok
+}
+
+/// CreateTopic request body for partition `p` of `topic` with `cfg`, as
+/// parsed by `handle_create_topic`
+fn put_create_topic(out: &mut BytesMut, topic: &str, cfg: &TopicConfig, p: u32) {
+    put_str(out, topic);
+    put_u32(out, cfg.capacity as u32);
+    put_u32(out, cfg.idle_ttl.map(|d| d.as_secs() as u32).unwrap_or(0));
+    put_u32(out, cfg.message_ttl.map(|d| d.as_millis() as u32).unwrap_or(0));
+    put_str(out, cfg.dead_letter.as_deref().unwrap_or(""));
+    put_u8(out, cfg.max_priority);
+    put_u8(out, cfg.kind as u8);
+    put_u32(out, cfg.retention.map(|d| d.as_secs() as u32).unwrap_or(0));
+    put_u64(out, cfg.retention_bytes.unwrap_or(0));
+    put_u8(out, cfg.replicas);
+    put_u32(out, cfg.partitions);
+    put_u32(out, p);
+    put_str(out, cfg.webhook.as_deref().unwrap_or(""));
+    put_u8(out, cfg.shards);
+    put_u8(out, cfg.overflow as u8);
+    put_u32(out, cfg.visibility_timeout.map(|d| d.as_millis() as u32).unwrap_or(0));
+    put_u64(out, cfg.capacity_bytes.unwrap_or(0));
+    put_u8(out, cfg.auto_delete as u8);
+    put_u8(out, cfg.exclusive as u8);
+    put_u8(out, cfg.transient as u8);
+    put_u8(out, cfg.audit as u8);
+}
+
+pub async fn handle_delete_topic(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | if_empty(u8, optional, 1 = refuse while messages are pending)
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let if_empty = get_u8(body).unwrap_or(0) != 0;
+
+    // only the leader holds the topic, so check leadership before lookup
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // deleting partition 0 deletes the whole topic. It goes last, once the
+    // others are gone: a partition that refuses, or can't be reached,
+    // leaves partition 0 and the topic's config in place for another try,
+    // and the ones deleted before it were empty if that was asked for.
+    if split_partition(&topic).1 == 0 {
+        for p in 1..t.config().partitions {
+            let name = partition_name(&topic, p);
+            let mut fwd = BytesMut::new();
+            put_str(&mut fwd, &name);
+            put_u8(&mut fwd, if_empty as u8);
+            let leader = cluster.leader_of(&name);
+            let res = match cluster.peers().call(&leader.addr, Op::DeleteTopic, &fwd).await {
+                Ok((res, _)) => res,
+                Err(e) => {
+                    tracing::warn!("failed to delete {} on {}: {}", name, leader.id, e);
+                    Status::ServerError
+                }
+            };
+            // gone already on an earlier try
+            if res != Status::Ok && res != Status::NotFound {
+                put_status(out, res);
+                return Ok(());
+            }
+        }
+    }
+    let Some(t) = topics.remove_if(&topic, |t| !if_empty || t.is_drained()) else {
+        let st = if topics.get(&topic).is_some() {
+            Status::NotEmpty
+        } else {
+            Status::NotFound
+        };
+        put_status(out, st);
+        return Ok(());
+    };
+    topics.unsubscribe_all(&topic);
+    if let Err(e) = save_topics(metadata, topics) {
+        tracing::warn!("failed to save metadata after deleting {}: {}", topic, e);
+        put_status(out, Status::ServerError);
+        return Ok(());
+    }
+    let st = match t.destroy() {
+        Ok(()) => Status::Ok,
+        Err(e) => {
+            tracing::warn!("failed to remove log of deleted topic {}: {}", topic, e);
+            Status::ServerError
+        }
+    };
+    put_status(out, st);
+    Ok(())
+}
+
+pub async fn handle_produce(
+    req: &Bytes,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    hints: &Hints,
+    session: &Session,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | bytes | priority(u8, optional) | routing_key(str, optional)
+    //      | envelope(optional) | acks(u8, optional, default Leader), see Acks
+    //      | wait_ms(u32, optional, 0 = don't): how long to wait for room in a
+    //        full topic before answering QueueFull, up to MAX_PRODUCE_WAIT
+    //      | producer_id(u64, optional, 0 = none) | producer_seq(u64): sent
+    //        again with every retry, a message the topic took already is
+    //        answered Ok and not written twice, see ProducerSeq
+    // resp: Ok and NotReplicated are followed by seq(u64), the message's
+    //       place in the topic's log. A duplicate gets the seq of the first.
+    //       QueueFull is followed by
+    //       n(u32) | n * group(str, "" = default), the groups that had no room for it
+    // A leader that can't be reached doesn't get a redirect: an acks=none
+    // message without a producer id is kept here as a hint and delivered
+    // once it is back, anything else is answered Unavailable.
+    // The payload stays in `req`, the frame body, see server::OWN_BUFFER.
+    // In a transaction the message is only staged, see handle_commit.
+    let body = &mut &req[..];
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let Some(data) = get_slice(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let priority = get_u8(body).unwrap_or(0);
+    let routing_key = get_str(body).unwrap_or_default();
+    let envelope = get_envelope(body).unwrap_or_default();
+    let Ok(acks) = get_u8(body).map_or(Ok(Acks::Leader), Acks::try_from) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let wait = Duration::from_millis(get_u32(body).unwrap_or(0) as u64).min(MAX_PRODUCE_WAIT);
+    let id = match (get_u64(body).unwrap_or(0), get_u64(body)) {
+        (0, _) => None,
+        (producer, Some(seq)) => Some(ProducerSeq { producer, seq }),
+        (_, None) => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
+    if session.in_transaction() {
+        let msg = Message {
+            // kept past this request, not holding on to the read buffer
+            payload: Bytes::copy_from_slice(data),
+            envelope,
+        };
+        stage(session, cluster, topics, &topic, msg, priority, routing_key, out);
+        if acks == Acks::None {
+            out.clear();
+        }
+        return Ok(());
+    }
+    let msg = Message {
+        payload: req.slice_ref(data),
+        envelope,
+    };
+    produce(cluster, topics, hints, session, &topic, msg, priority, &routing_key, acks, wait, id, out).await;
+    // fire and forget: the producer doesn't read an answer, not even an error
+    if acks == Acks::None {
+        out.clear();
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn produce(
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    hints: &Hints,
+    session: &Session,
+    topic: &str,
+    msg: Message,
+    priority: u8,
+    routing_key: &str,
+    acks: Acks,
+    wait: Duration,
+    id: Option<ProducerSeq>,
+    out: &mut BytesMut,
+) {
+    let leader = cluster.leader_of(topic);
+    if leader.id != cluster.me.id && cluster.is_reachable(&leader.id) {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return;
+    }
+    if leader.id != cluster.me.id {
+        // a hint has no seq to answer, and the leader wouldn't know its id
+        if acks != Acks::None || id.is_some() {
+            put_status(out, Status::Unavailable);
+            return;
+        }
+        match hints.add(topic, priority, routing_key, &msg) {
+            Ok(()) => {
+                tracing::debug!("leader {} of {} unreachable, kept a hint", leader.id, topic);
+                put_status(out, Status::Ok);
+            }
+            Err(e) => {
+                tracing::warn!("failed to keep a hint for {}: {}", topic, e);
+                put_status(out, Status::ServerError);
+            }
+        }
+        return;
+    }
+    let Some(t) = topics.get(topic) else {
+        put_status(out, Status::NotFound);
+        return;
+    };
+    // copies besides the leader's own that make a majority
+    let needed = t.config().replicas as usize / 2;
+    let quorum = acks == Acks::Quorum && needed > 0;
+    let deadline = tokio::time::Instant::now() + wait;
+    let (seq, copies) = loop {
+        let on_overflow = |m| dead_letter(topics, &t, m);
+        let res = if quorum {
+            t.enqueue_replicated(msg.clone(), priority, routing_key, id, on_overflow)
+                .map(|(seq, copies)| (seq, Some(copies)))
+        } else {
+            t.enqueue(msg.clone(), priority, routing_key, id, on_overflow).map(|seq| (seq, None))
+        };
+        let e = match res {
+            Ok(written) => break written,
+            Err(e) => e,
+        };
+        // written by an earlier try, whose answer didn't make it
+        if let Some(d) = e.downcast_ref::<Duplicate>() {
+            put_status(out, Status::Ok);
+            put_u64(out, d.seq);
+            return;
+        }
+        // the groups were full, try again once they all have room
+        if let Some(full) = e.downcast_ref::<QueueFull>()
+            && !wait.is_zero()
+            && t.wait_for_room(&full.groups, deadline).await
+        {
+            continue;
+        }
+        put_enqueue_error(out, &e);
+        return;
+    };
+    session.audit(&t, AuditEvent::Produce, seq, &msg.envelope.message_id, "");
+    copy_to_subscribers(topics, &t, &msg, priority, routing_key);
+    let Some(copies) = copies else {
+        put_status(out, Status::Ok);
+        put_u64(out, seq);
+        return;
+    };
+    match tokio::time::timeout(QUORUM_TIMEOUT, copies).await {
+        Ok(Ok(n)) if n >= needed => put_status(out, Status::Ok),
+        _ => put_status(out, Status::NotReplicated),
+    }
+    put_u64(out, seq);
+}
+
+/// Add a produce to `session`'s transaction. Its topic has to be led here,
+/// a transaction doesn't span nodes.
+#[allow(clippy::too_many_arguments)]
+fn stage(
+    session: &Session,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    topic: &str,
+    msg: Message,
+    priority: u8,
+    routing_key: String,
+    out: &mut BytesMut,
+) {
+    if cluster.leader_of(topic).id != cluster.me.id {
+        put_status(out, Status::BadRequest);
+        return;
+    }
+    let Some(t) = topics.get(topic) else {
+        put_status(out, Status::NotFound);
+        return;
+    };
+    let mut transaction = session.transaction.lock().unwrap();
+    let Some(tx) = transaction.as_mut() else {
+        // aborted meanwhile
+        put_status(out, Status::BadRequest);
+        return;
+    };
+    if tx.staged.len() >= MAX_TRANSACTION_MESSAGES || tx.bytes + msg.payload.len() > MAX_TRANSACTION_BYTES {
+        put_status(out, Status::QuotaExceeded);
+        return;
+    }
+    tx.bytes += msg.payload.len();
+    tx.staged.push(Staged {
+        topic: t,
+        msg,
+        priority,
+        routing_key,
+    });
+    put_status(out, Status::Ok);
+}
+
+/// Start staging the session's produces, see handle_commit
+pub async fn handle_begin(session: &Session, out: &mut BytesMut) -> Result<()> {
+    // req : (empty)
+    // resp: Ok, or BadRequest if a transaction is open already
+    let mut transaction = session.transaction.lock().unwrap();
+    if transaction.is_some() {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    *transaction = Some(Transaction::default());
+    put_status(out, Status::Ok);
+    Ok(())
+}
+
+/// Enqueue what the session's transaction staged, all of it or none
+pub async fn handle_commit(topics: &TopicRegistry, session: &Session, out: &mut BytesMut) -> Result<()> {
+    // req : (empty)
+    // resp: n(u32) | n * seq(u64), the messages written in the order staged
+    //       and their seqs, once every one is in its topic's log. BadRequest if no transaction is open, NotFound if
+    //       a topic went away meanwhile, QueueFull as for Produce. The
+    //       transaction is over either way, none of it is written on failure.
+    let Some(tx) = session.transaction.lock().unwrap().take() else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if tx.staged.iter().any(|s| !topics.get(&s.topic.name).is_some_and(|t| Arc::ptr_eq(&t, &s.topic))) {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    }
+    let produced: Vec<_> = tx.staged.iter().map(|s| (s.topic.clone(), s.msg.clone(), s.priority, s.routing_key.clone())).collect();
+    match queue::enqueue_all(tx.staged, |t, m| dead_letter(topics, t, m)) {
+        Ok(seqs) => {
+            for ((t, msg, priority, routing_key), &seq) in produced.iter().zip(&seqs) {
+                session.audit(t, AuditEvent::Produce, seq, &msg.envelope.message_id, "");
+                copy_to_subscribers(topics, t, msg, *priority, routing_key);
+            }
+            put_status(out, Status::Ok);
+            put_u32(out, seqs.len() as u32);
+            for seq in seqs {
+                put_u64(out, seq);
+            }
+        }
+        Err(e) => put_enqueue_error(out, &e),
+    }
+    Ok(())
+}
+
+/// Drop what the session's transaction staged
+pub async fn handle_abort(session: &Session, out: &mut BytesMut) -> Result<()> {
+    // req : (empty)
+    // resp: Ok, or BadRequest if no transaction is open
+    match session.transaction.lock().unwrap().take() {
+        Some(_) => put_status(out, Status::Ok),
+        None => put_status(out, Status::BadRequest),
+    }
+    Ok(())
+}
+
+/// Answer a failed enqueue, with the groups that were full if that's why
+fn put_enqueue_error(out: &mut BytesMut, e: &anyhow::Error) {
+    let Some(full) = e.downcast_ref::<QueueFull>() else {
+        put_status(out, Status::ServerError);
+        return;
+    };
+    put_status(out, Status::QueueFull);
+    put_u32(out, full.groups.len() as u32);
+    for g in &full.groups {
+        put_str(out, g);
+    }
+}
+
+pub async fn handle_consume(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    session: &mut Session,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | timeout_ms(u32, optional) | group(str, optional, "" = default)
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let timeout = Duration::from_millis(get_u32(body).unwrap_or(0) as u64);
+    let group = get_str(body).unwrap_or_default();
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    if !session.consume_from(&t) {
+        put_status(out, Status::Unauthorized);
+        return Ok(());
+    }
+    // resp : tag(u64) | bytes | envelope | redelivered(u8) | delivery info, settle the tag with Ack/Nack
+    // long-poll: wait up to timeout_ms for a message before answering Empty
+    match t.dequeue_wait(&group, timeout, |v| dead_letter(topics, &t, v)).await {
+        Ok(Some(d)) => {
+            session.audit(&t, AuditEvent::Deliver, d.tag, &d.msg.envelope.message_id, &group);
+            session.unacked.tags.lock().unwrap().insert((topic, group, d.tag));
+            put_status(out, Status::Ok);
+            put_u64(out, d.tag);
+            put_bytes(out, &d.msg.payload);
+            put_envelope(out, &d.msg.envelope);
+            put_u8(out, d.redelivered() as u8);
+            put_delivery_info(out, &delivery_info(&t, &d));
+        }
+        Ok(None) => put_status(out, Status::Empty),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
+    Ok(())
+}
+
+pub async fn handle_fetch(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    session: &mut Session,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | timeout_ms(u32) | group(str) | max_messages(u32) | max_bytes(u32)
+    let (Some(topic), Some(timeout), Some(group), Some(max_messages), Some(max_bytes)) = (
+        get_str(body),
+        get_u32(body),
+        get_str(body),
+        get_u32(body),
+        get_u32(body),
+    ) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if !valid_group(&group) || max_messages == 0 {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    if !session.consume_from(&t) {
+        put_status(out, Status::Unauthorized);
+        return Ok(());
+    }
+    let timeout = Duration::from_millis(timeout as u64);
+    let fetched = t
+        .fetch(&group, timeout, max_messages as usize, max_bytes as usize, |v| {
+            dead_letter(topics, &t, v)
+        })
+        .await;
+    // resp : n(u32) | n * (tag(u64) | bytes | envelope) | n * redelivered(u8) | n * delivery info
+    match fetched {
+        Ok(msgs) if msgs.is_empty() => put_status(out, Status::Empty),
+        Ok(msgs) => {
+            put_status(out, Status::Ok);
+            put_u32(out, msgs.len() as u32);
+            for d in &msgs {
+                session.audit(&t, AuditEvent::Deliver, d.tag, &d.msg.envelope.message_id, &group);
+                session.unacked.tags.lock().unwrap().insert((topic.clone(), group.clone(), d.tag));
+                put_u64(out, d.tag);
+                put_bytes(out, &d.msg.payload);
+                put_envelope(out, &d.msg.envelope);
+            }
+            for d in &msgs {
+                put_u8(out, d.redelivered() as u8);
+            }
+            for d in &msgs {
+                put_delivery_info(out, &delivery_info(&t, d));
+            }
+        }
+        Err(_) => put_status(out, Status::ServerError),
+    }
+    Ok(())
+}
+
+/// What the consumer of `d` from `t` is told about it besides its envelope
+fn delivery_info(t: &Topic, d: &Delivery) -> DeliveryInfo {
+    let env = &d.msg.envelope;
+    DeliveryInfo {
+        message_id: match env.message_id.as_str() {
+            "" => format!("{}:{}", t.name, d.tag),
+            id => id.to_string(),
+        },
+        enqueued_ms: d.enqueued_ms,
+        redeliveries: d.deliveries.saturating_sub(1),
+        topic: env.headers.get(ORIGIN_HEADER).cloned().unwrap_or_else(|| t.name.clone()),
+    }
+}
+
+/// Group names become file names, keep them to a single path component
+fn valid_group(group: &str) -> bool {
+    !group.contains(['/', '\\']) && group != "." && group != ".."
+}
+
+/// Move an expired message to the dead letter topic of `from`, if it has a
+/// local one, noting `from` as its origin unless it came from further back
+pub fn dead_letter(topics: &TopicRegistry, from: &Topic, mut m: Message) {
+    let Some(dlq) = from.dead_letter() else {
+        return;
+    };
+    let Some(d) = topics.get(dlq) else {
+        tracing::warn!("dead letter topic {} of {} not found here, dropping", dlq, from.name);
+        return;
+    };
+    m.envelope.headers.entry(ORIGIN_HEADER.to_string()).or_insert_with(|| from.name.clone());
+    let overflow = |_| tracing::warn!("dead letter topic {} of {} is full, dropping", dlq, from.name);
+    if let Err(e) = d.enqueue(m, 0, "", None, overflow) {
+        tracing::warn!("dead letter to {} failed: {}", dlq, e);
+    }
+}
+
+/// Copy a message produced to `from` into the topics here bound to a
+/// pattern matching its name, noting `from` as its origin unless it came
+/// from further back. Copies are best effort, a full topic drops its copy.
+fn copy_to_subscribers(topics: &TopicRegistry, from: &Topic, msg: &Message, priority: u8, routing_key: &str) {
+    for s in topics.subscribers(&from.name) {
+        let mut m = msg.clone();
+        m.envelope.headers.entry(ORIGIN_HEADER.to_string()).or_insert_with(|| from.name.clone());
+        if let Err(e) = s.enqueue(m, priority, routing_key, None, |m| dead_letter(topics, &s, m)) {
+            tracing::warn!("copy of a message of {} to {} failed: {}", from.name, s.name, e);
+        }
+    }
+}
+
+pub async fn handle_read(body: &mut &[u8], cluster: &Cluster, topics: &TopicRegistry, out: &mut BytesMut) -> Result<()> {
+    // req : topic(str) | size(u32)
+    let (Some(topic), Some(size)) = (get_str(body), get_u32(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // resp : n(u32) | n * bytes | n * (seq(u64) | at_ms(u64) | priority(u8) | routing_key(str) | envelope)
+    let records = match t.read_last_n(size as usize) {
+        Ok(records) => records,
+        Err(e) => {
+            tracing::warn!("read of {} failed: {}", topic, e);
+            put_status(out, Status::ServerError);
+            return Ok(());
+        }
+    };
+    put_status(out, Status::Ok);
+    put_u32(out, records.len() as u32);
+    for r in &records {
+        put_bytes(out, &r.payload);
+    }
+    for r in &records {
+        put_u64(out, r.seq);
+        put_u64(out, r.at_ms);
+        put_u8(out, r.priority);
+        put_str(out, &r.routing_key);
+        // records older than envelopes have none
+        put_envelope(out, &get_envelope(&mut &r.envelope[..]).unwrap_or_default());
+    }
+
+    Ok(())
+}
+
+pub async fn handle_reset_offset(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | to(u8, 0 = earliest, 1 = latest, 2 = seq, 3 = time) | group(str, optional, "" = default)
+    //      | seq(u64, with to = 2, the first seq to deliver again)
+    //      | at_ms(u64, with to = 3, unix ms: messages enqueued from then on are delivered again)
+    let (Some(topic), Some(to)) = (get_str(body), get_u8(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let group = get_str(body).unwrap_or_default();
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    let to = match (to, get_u64(body)) {
+        (0, _) => OffsetReset::Earliest,
+        (1, _) => OffsetReset::Latest,
+        (2, Some(seq)) => OffsetReset::Seq(seq),
+        (3, Some(ms)) => OffsetReset::At(ms),
+        _ => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // resp : pending(u32)
+    match t.reset_offset(&group, to) {
+        Ok(pending) => {
+            put_status(out, Status::Ok);
+            put_u32(out, pending as u32);
+        }
+        Err(e) if e.is::<UnknownGroup>() => put_status(out, Status::NotFound),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
+    Ok(())
+}
+
+pub async fn handle_settle(
+    body: &mut &[u8],
+    op: Op,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    session: &mut Session,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | tag(u64) | group(str, optional, "" = default)
+    let (Some(topic), Some(tag)) = (get_str(body), get_u64(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let group = get_str(body).unwrap_or_default();
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+
+    let res = if op == Op::Ack {
+        t.ack(&group, tag)
+    } else {
+        t.nack(&group, tag)
+    };
+    match res {
+        Ok(true) => {
+            let event = if op == Op::Ack { AuditEvent::Ack } else { AuditEvent::Nack };
+            session.audit(&t, event, tag, "", &group);
+            session.unacked.tags.lock().unwrap().remove(&(topic, group, tag));
+            put_status(out, Status::Ok);
+        }
+        // unknown or already settled tag
+        Ok(false) => put_status(out, Status::NotFound),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+    Ok(())
+}
+
+pub async fn handle_purge(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str, optional, "" = default)
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let group = get_str(body).unwrap_or_default();
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // resp : purged(u32)
+    match t.purge(&group) {
+        Ok(n) => {
+            put_status(out, Status::Ok);
+            put_u32(out, n as u32);
+        }
+        Err(e) if e.is::<UnknownGroup>() => put_status(out, Status::NotFound),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
+    Ok(())
+}
+
+pub async fn handle_bind(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str) | key(str, binding key or pattern)
+    //      | headers(str, optional): header conditions messages have to
+    //        meet too, `name=value` terms joined by `AND`, see HeaderMatch
+    //      | filter(str, optional, "" = none): and a filter on their headers,
+    //        see Selector. One that doesn't parse is answered BadRequest
+    //        followed by error(str), what's wrong with it and where.
+    //      | sample(u32, optional, 0 = every message): messages out of every
+    //        SAMPLE_ALL it gets that the group gets, picked by seq
+    let (Some(topic), Some(group), Some(key)) = (get_str(body), get_str(body), get_str(body))
+    else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if group.is_empty() || !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    let Some(headers) = HeaderMatch::parse(&get_str(body).unwrap_or_default()) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let filter = match get_str(body).filter(|f| !f.is_empty()).map(|f| Selector::parse(&f)) {
+        None => None,
+        Some(Ok(f)) => Some(f),
+        Some(Err(e)) => {
+            put_status(out, Status::BadRequest);
+            put_str(out, &e.to_string());
+            return Ok(());
+        }
+    };
+    let sample = match get_u32(body).unwrap_or(0) {
+        0 | SAMPLE_ALL => None,
+        s if s < SAMPLE_ALL => Some(s),
+        _ => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
 
     let leader = cluster.leader_of(&topic);
-    out.put_u32(0);
-    put_str(out, &leader.addr);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    match t.bind(&group, &Binding { key, headers, filter, sample }) {
+        Ok(()) => put_status(out, Status::Ok),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
     Ok(())
 }
 
-pub async fn handle_create_topic(
+pub async fn handle_unbind(
     body: &mut &[u8],
     cluster: &Cluster,
     topics: &TopicRegistry,
-    data_dir: &str,
     out: &mut BytesMut,
 ) -> Result<()> {
-    // req: topic(str) | capacity(u32)
-    let Some(topic) = get_str(body) else {
+    // req : topic(str) | group(str)
+    let (Some(topic), Some(group)) = (get_str(body), get_str(body)) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
-    let Some(cap) = get_u32(body) else {
+    if group.is_empty() || !valid_group(&group) {
         put_status(out, Status::BadRequest);
         return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic).filter(|t| t.has_group(&group)) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
     };
+    match t.unbind(&group) {
+        Ok(()) => put_status(out, Status::Ok),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
+    Ok(())
+}
 
-    if topics.get(&topic).is_some() {
-        put_status(out, Status::TopicExists);
+pub async fn handle_bind_topic(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | pattern(str): messages produced from now on to topics
+    //       of the same namespace whose name matches pattern, like `metrics.*`
+    //       or `logs.#`, are copied into topic, see TopicRegistry::subscribe.
+    //       Only topics led by topic's leader are matched.
+    let (Some(topic), Some(pattern)) = (get_str(body), get_str(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    if !valid_topic_pattern(&pattern) {
+        put_status(out, Status::BadRequest);
         return Ok(());
     }
 
-    match Topic::open(data_dir, &topic, cap as usize, || {
-            cluster.is_leader(&topic)
-    }) {
-        Ok(t) => {
-        topics.insert(Arc::new(t));
-        put_status(out, Status::Ok);
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
     }
-        Err(_) => {
-            // Not a leader for this topic, but another node might be.
-            // For simplicity, we just say OK, assuming the client will get redirected
-            // on produce/consume if this node isn't the leader.
-            put_status(out, Status::Ok);
-        }
+    if topics.get(&topic).is_none() {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    }
+    if topics.subscribe(&topic, &pattern)
+        && let Err(e) = save_topics(metadata, topics)
+    {
+        tracing::warn!("failed to save metadata after binding {} to {}: {}", topic, pattern, e);
+        put_status(out, Status::ServerError);
+        return Ok(());
     }
+    put_status(out, Status::Ok);
     Ok(())
 }
 
-pub async fn handle_produce(
+pub async fn handle_unbind_topic(
     body: &mut &[u8],
     cluster: &Cluster,
     topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
     out: &mut BytesMut,
 ) -> Result<()> {
-    // req : topic(str) | bytes
-    let Some(topic) = get_str(body) else {
+    // req : topic(str) | pattern(str)
+    // resp: Ok, NotFound if topic wasn't bound to pattern
+    let (Some(topic), Some(pattern)) = (get_str(body), get_str(body)) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
-    let Some(data) = get_bytes(body) else {
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    if !topics.unsubscribe(&topic, &pattern) {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    }
+    if let Err(e) = save_topics(metadata, topics) {
+        tracing::warn!("failed to save metadata after unbinding {} from {}: {}", topic, pattern, e);
+        put_status(out, Status::ServerError);
+        return Ok(());
+    }
+    put_status(out, Status::Ok);
+    Ok(())
+}
+
+/// Dot separated words, `*` and `#` among them, naming topics of the
+/// namespace of the topic bound to it
+fn valid_topic_pattern(pattern: &str) -> bool {
+    !pattern.contains('/') && pattern.split('.').all(|w| !w.is_empty())
+}
+
+pub async fn handle_schedule(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | name(str) | cron(str) | bytes | routing_key(str, optional)
+    //       payload is produced to topic at the times cron names, see
+    //       scheduler::Cron, by the node leading it. A schedule of the same
+    //       name is replaced.
+    // resp: Ok, BadRequest | error(str) if cron is malformed
+    let (Some(topic), Some(name), Some(cron), Some(payload)) = (get_str(body), get_str(body), get_str(body), get_bytes(body)) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
+    let routing_key = get_str(body).unwrap_or_default();
+    if name.is_empty() {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+    if let Err(e) = Cron::parse(&cron) {
+        put_status(out, Status::BadRequest);
+        put_str(out, &e.to_string());
+        return Ok(());
+    }
 
-    let Some(t) = topics.get(&topic) else {
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    if topics.get(&topic).is_none() {
         put_status(out, Status::NotFound);
         return Ok(());
+    }
+    let s = Schedule { cron, payload, routing_key };
+    if topics.schedule(&topic, &name, s)
+        && let Err(e) = save_topics(metadata, topics)
+    {
+        tracing::warn!("failed to save metadata after scheduling {} on {}: {}", name, topic, e);
+        put_status(out, Status::ServerError);
+        return Ok(());
+    }
+    put_status(out, Status::Ok);
+    Ok(())
+}
+
+pub async fn handle_unschedule(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | name(str)
+    // resp: Ok, NotFound if topic has no schedule of that name
+    let (Some(topic), Some(name)) = (get_str(body), get_str(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
     };
+
     let leader = cluster.leader_of(&topic);
     if leader.id != cluster.me.id {
         put_status(out, Status::Redirect);
         put_str(out, &leader.addr);
-    } else {
-        match t.enqueue(data) {
-            Ok(_seq) => put_status(out, Status::Ok),
-            Err(_) => put_status(out, Status::ServerError),
-        }
+        return Ok(());
+    }
+    if !topics.unschedule(&topic, &name) {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    }
+    if let Err(e) = save_topics(metadata, topics) {
+        tracing::warn!("failed to save metadata after unscheduling {} on {}: {}", name, topic, e);
+        put_status(out, Status::ServerError);
+        return Ok(());
     }
+    put_status(out, Status::Ok);
     Ok(())
 }
 
-pub async fn handle_consume(
+pub async fn handle_audit(
     body: &mut &[u8],
     cluster: &Cluster,
     topics: &TopicRegistry,
     out: &mut BytesMut,
 ) -> Result<()> {
-    // req : topic(str) | timeout_ms(u32, optional)
+    // req : topic(str) | message_id(str, optional, "" = every message) | limit(u32, optional, 0 = no limit)
+    //       a message id is the producer's, or `topic:seq` for a message
+    //       produced without one, as consumers are told
+    // resp: n(u32) | n * (at_ms(u64) | event(u8) | seq(u64) | conn(u64) | identity(str)
+    //       | group(str) | message_id(str)), the last `limit` records, oldest
+    //       first. BadRequest if the topic keeps no audit trail.
     let Some(topic) = get_str(body) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
-    let _timeout = get_u32(body).unwrap_or(0);
+    let message_id = get_str(body).unwrap_or_default();
+    let limit = get_u32(body).unwrap_or(0) as usize;
 
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
     let Some(t) = topics.get(&topic) else {
         put_status(out, Status::NotFound);
         return Ok(());
     };
+    let mut records = match t.audit_trail() {
+        Some(Ok(records)) => records,
+        Some(Err(e)) => {
+            tracing::warn!("failed to read the audit trail of {}: {}", topic, e);
+            put_status(out, Status::ServerError);
+            return Ok(());
+        }
+        None => {
+            put_status(out, Status::BadRequest);
+            return Ok(());
+        }
+    };
+    if !message_id.is_empty() {
+        // settles only carry the seq, find the message's by its id
+        let mut seqs: HashSet<u64> = records.iter().filter(|r| r.message_id == message_id).map(|r| r.seq).collect();
+        if let Some(seq) = message_id.strip_prefix(&format!("{}:", t.name)).and_then(|s| s.parse().ok()) {
+            seqs.insert(seq);
+        }
+        records.retain(|r| seqs.contains(&r.seq));
+    }
+    if limit > 0 && records.len() > limit {
+        records.drain(..records.len() - limit);
+    }
+    put_status(out, Status::Ok);
+    put_u32(out, records.len() as u32);
+    for r in &records {
+        put_u64(out, r.at_ms);
+        put_u8(out, r.event as u8);
+        put_u64(out, r.seq);
+        put_u64(out, r.conn);
+        put_str(out, &r.identity);
+        put_str(out, &r.group);
+        put_str(out, &r.message_id);
+    }
+    Ok(())
+}
+
+pub async fn handle_delete_group(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str) | force(u8, optional, 1 = even with pending or unacked messages)
+    let (Some(topic), Some(group)) = (get_str(body), get_str(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let force = get_u8(body).unwrap_or(0) != 0;
+    if group.is_empty() || !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
     let leader = cluster.leader_of(&topic);
     if leader.id != cluster.me.id {
         put_status(out, Status::Redirect);
         put_str(out, &leader.addr);
-    } else {
-        match t.dequeue() {
-            Ok(Some(v)) => {
-                put_status(out, Status::Ok);
-                put_bytes(out, &v);
-            }
-            Ok(None) => put_status(out, Status::Empty),
-            Err(_) => put_status(out, Status::ServerError),
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic).filter(|t| t.has_group(&group)) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    match t.delete_group(&group, !force) {
+        Ok(true) => put_status(out, Status::Ok),
+        Ok(false) => put_status(out, Status::NotEmpty),
+        Err(e) => {
+            tracing::warn!("failed to delete group {} of {}: {}", group, topic, e);
+            put_status(out, Status::ServerError);
         }
     }
+
     Ok(())
 }
 
-pub async fn handle_read(body: &mut &[u8], cluster: &Cluster, topics: &TopicRegistry, out: &mut BytesMut) -> Result<()> {
-    // req : topic(str) | size(u32)
-    let (Some(topic), Some(size)) = (get_str(body), get_u32(body)) else {
+pub async fn handle_stats(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str, optional, "" = default)
+    let Some(topic) = get_str(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let group = get_str(body).unwrap_or_default();
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
+
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // resp : enqueued(u64) | delivered(u64) | depth(u32) | peak_depth(u32)
+    //        | in_flight(u32) | oldest_age_ms(u64) | depth_bytes(u64)
+    //        | memory_used(u64) | memory_limit(u64, 0 = unlimited), the last two for this node
+    match t.stats(&group) {
+        Ok(st) => {
+            put_status(out, Status::Ok);
+            put_u64(out, st.enqueued);
+            put_u64(out, st.delivered);
+            put_u32(out, st.depth as u32);
+            put_u32(out, st.peak_depth as u32);
+            put_u32(out, st.in_flight as u32);
+            put_u64(out, st.oldest_age_ms);
+            put_u64(out, st.depth_bytes);
+            put_u64(out, queue::MEMORY.used());
+            put_u64(out, queue::MEMORY.limit().unwrap_or(0));
+        }
+        Err(e) if e.is::<UnknownGroup>() => put_status(out, Status::NotFound),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+    Ok(())
+}
+
+pub async fn handle_commit_offset(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str) | offset(u64), everything <= offset is done
+    let (Some(topic), Some(group), Some(offset)) = (get_str(body), get_str(body), get_u64(body)) else {
         put_status(out, Status::BadRequest);
         return Ok(());
     };
+    if !valid_group(&group) {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    }
 
+    let leader = cluster.leader_of(&topic);
+    if leader.id != cluster.me.id {
+        put_status(out, Status::Redirect);
+        put_str(out, &leader.addr);
+        return Ok(());
+    }
     let Some(t) = topics.get(&topic) else {
         put_status(out, Status::NotFound);
         return Ok(());
     };
+    match t.commit_offset(&group, offset) {
+        Ok(()) => put_status(out, Status::Ok),
+        Err(e) if e.is::<UnknownGroup>() => put_status(out, Status::NotFound),
+        Err(_) => put_status(out, Status::ServerError),
+    }
+
+    Ok(())
+}
+
+pub async fn handle_fetch_offset(
+    body: &mut &[u8],
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    out: &mut BytesMut,
+) -> Result<()> {
+    // req : topic(str) | group(str)
+    let (Some(topic), Some(group)) = (get_str(body), get_str(body)) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+
     let leader = cluster.leader_of(&topic);
     if leader.id != cluster.me.id {
         put_status(out, Status::Redirect);
         put_str(out, &leader.addr);
-    } else {
-        let messages = t.read_last_n(size as usize).unwrap_or_default();
-        put_status(out, Status::Ok);
-        put_u32(out, messages.len() as u32);
-        for msg in messages {
-            put_bytes(out, &msg);
+        return Ok(());
+    }
+    let Some(t) = topics.get(&topic) else {
+        put_status(out, Status::NotFound);
+        return Ok(());
+    };
+    // resp : committed(u64) | last_seq(u64), everything <= committed is done
+    match t.committed_offset(&group) {
+        Some(committed) => {
+            put_status(out, Status::Ok);
+            put_u64(out, committed);
+            put_u64(out, t.last_seq());
         }
+        None => put_status(out, Status::NotFound),
+    }
+
+    Ok(())
+}
+
+pub async fn handle_maintenance(body: &mut &[u8], maintenance: &AtomicBool, out: &mut BytesMut) -> Result<()> {
+    // req : on(u8, 0 = leave, 1 = enter)
+    let Some(on) = get_u8(body) else {
+        put_status(out, Status::BadRequest);
+        return Ok(());
+    };
+    let was = maintenance.swap(on != 0, Ordering::SeqCst);
+    if was != (on != 0) {
+        tracing::info!("maintenance mode {}", if on != 0 { "on" } else { "off" });
     }
+    put_status(out, Status::Ok);
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,152 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cluster::Cluster;
+use crate::handler;
+use crate::protocol::*;
+use crate::queue::{Message, TopicRegistry, now_ms};
+use crate::storage::disk_log::DiskLog;
+
+/// How often hinted messages are offered to their topic's leader
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Messages produced while their topic's leader couldn't be reached, one
+/// log per topic under `<data_dir>/hints.d`. The default group's committed
+/// offset tracks what has been delivered.
+pub struct Hints {
+    dir: PathBuf,
+    logs: Mutex<HashMap<String, Arc<DiskLog>>>,
+}
+
+impl Hints {
+    /// Reopen hints left over from a previous run
+    pub fn open(data_dir: &str) -> Result<Self> {
+        // not a valid namespace name, so never listed as topics
+        let dir = PathBuf::from(data_dir).join("hints.d");
+        let mut logs = HashMap::new();
+        for topic in DiskLog::list(&dir)? {
+            let log = DiskLog::open(&dir, &topic)?;
+            tracing::info!("{} hinted messages for topic {} to deliver", pending(&log)?, topic);
+            logs.insert(topic, Arc::new(log));
+        }
+        Ok(Self {
+            dir,
+            logs: Mutex::new(logs),
+        })
+    }
+
+    /// Keep a message for `topic` until its leader takes it. Its envelope
+    /// is stamped with the time it was produced here, unless it has one.
+    pub fn add(&self, topic: &str, priority: u8, routing_key: &str, msg: &Message) -> Result<()> {
+        if !valid_topic(topic) {
+            return Err(anyhow::anyhow!("invalid topic name {:?}", topic));
+        }
+        let mut logs = self.logs.lock().unwrap();
+        let log = match logs.get(topic) {
+            Some(log) => log.clone(),
+            None => {
+                std::fs::create_dir_all(&self.dir)?;
+                let log = Arc::new(DiskLog::open(&self.dir, topic)?);
+                logs.insert(topic.to_string(), log.clone());
+                log
+            }
+        };
+        let at_ms = now_ms();
+        let mut envelope = msg.envelope.clone();
+        if envelope.timestamp_ms == 0 {
+            envelope.timestamp_ms = at_ms;
+        }
+        let mut env = BytesMut::new();
+        put_envelope(&mut env, &envelope);
+        log.append(at_ms, priority, routing_key, &env, &msg.payload)?;
+        Ok(())
+    }
+
+    /// Get every hint log onto disk
+    pub fn sync(&self) -> Result<()> {
+        for log in self.logs.lock().unwrap().values() {
+            log.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Deliver hinted messages in order once their topic's leader is
+    /// reachable again, or has moved, forever. A log is dropped once empty.
+    pub async fn replay(self: Arc<Self>, cluster: Cluster, topics: Arc<TopicRegistry>) {
+        let mut tick = tokio::time::interval(REPLAY_INTERVAL);
+        loop {
+            tick.tick().await;
+            let logs: Vec<(String, Arc<DiskLog>)> =
+                self.logs.lock().unwrap().iter().map(|(t, l)| (t.clone(), l.clone())).collect();
+            for (topic, log) in logs {
+                match deliver(&cluster, &topics, &topic, &log).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("delivered {} hinted messages for topic {}", n, topic),
+                    Err(e) => tracing::debug!("hinted messages for topic {} not delivered yet: {}", topic, e),
+                }
+                // nothing can be added meanwhile, add takes the same lock
+                let mut logs = self.logs.lock().unwrap();
+                if pending(&log).is_ok_and(|n| n == 0) {
+                    logs.remove(&topic);
+                    if let Err(e) = log.remove() {
+                        tracing::warn!("failed to remove hint log of {}: {}", topic, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn pending(log: &DiskLog) -> Result<u64> {
+    Ok(log.last_seq().saturating_sub(log.read_acked("")?))
+}
+
+/// Hand pending messages of `topic` to its leader, stopping at the first
+/// one it doesn't take for now. Messages for a topic the leader doesn't
+/// have are dropped, they never will be taken. Returns how many were
+/// delivered.
+async fn deliver(cluster: &Cluster, topics: &TopicRegistry, topic: &str, log: &DiskLog) -> Result<usize> {
+    let leader = cluster.leader_of(topic);
+    if leader.id != cluster.me.id && !cluster.is_reachable(&leader.id) {
+        return Ok(0);
+    }
+    let mut delivered = 0;
+    for entry in log.replay_unacked("")? {
+        if leader.id == cluster.me.id {
+            let Some(t) = topics.get(topic) else {
+                tracing::warn!("topic {} doesn't exist, dropping its hinted message #{}", topic, entry.seq);
+                log.write_acked("", entry.seq)?;
+                continue;
+            };
+            let envelope = get_envelope(&mut &entry.envelope[..]).unwrap_or_default();
+            let msg = Message {
+                payload: entry.payload,
+                envelope,
+            };
+            t.enqueue(msg, entry.priority, &entry.routing_key, None, |m| handler::dead_letter(topics, &t, m))?;
+        } else {
+            let mut body = BytesMut::new();
+            put_str(&mut body, topic);
+            put_bytes(&mut body, &entry.payload);
+            put_u8(&mut body, entry.priority);
+            put_str(&mut body, &entry.routing_key);
+            body.extend_from_slice(&entry.envelope);
+            match cluster.peers().call(&leader.addr, Op::Produce, &body).await? {
+                (Status::Ok, _) => {}
+                (Status::NotFound, _) => {
+                    tracing::warn!("{} has no topic {}, dropping its hinted message #{}", leader.id, topic, entry.seq);
+                    log.write_acked("", entry.seq)?;
+                    continue;
+                }
+                (st, _) => return Err(anyhow::anyhow!("{} answered {:?}", leader.id, st)),
+            }
+        }
+        log.write_acked("", entry.seq)?;
+        delivered += 1;
+    }
+    Ok(delivered)
+}
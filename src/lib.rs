@@ -1,6 +1,28 @@
+pub mod auth;
+pub mod broker;
+pub mod client;
 pub mod cluster;
+pub mod config;
+pub mod gateway;
+pub mod grpc;
 pub mod protocol;
 pub mod handler;
+pub mod hints;
+pub mod limit;
+pub mod mqtt;
+pub mod peer;
+pub mod pool;
 pub mod queue;
+pub mod replication;
+pub mod resp;
+pub mod scheduler;
+pub mod selector;
 pub mod server;
+pub mod stomp;
 pub mod storage;
+pub mod telemetry;
+pub mod tls;
+pub mod webhook;
+pub mod ws;
+
+pub use broker::Broker;
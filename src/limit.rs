@@ -0,0 +1,80 @@
+use std::time::Instant;
+
+/// How fast a single connection may send, 0 = unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests_per_sec: u32,
+    pub bytes_per_sec: u64,
+}
+
+/// Token bucket holding up to a second's worth of `rate`, refilled as time
+/// passes. A request is let through while the bucket isn't empty and takes
+/// what it costs, going into debt if it's bigger than what's left: a frame
+/// larger than a second's worth still gets through, then waits it off.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Whether there's anything left, after topping up for the time passed
+    fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens > 0.0
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost;
+    }
+}
+
+/// The buckets of one connection
+#[derive(Debug)]
+pub struct Limiter {
+    limit: RateLimit,
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            requests: (limit.requests_per_sec > 0).then(|| TokenBucket::new(limit.requests_per_sec as f64)),
+            bytes: (limit.bytes_per_sec > 0).then(|| TokenBucket::new(limit.bytes_per_sec as f64)),
+        }
+    }
+
+    /// Whether a request of `len` bytes may go through now. One that
+    /// doesn't costs nothing. The buckets start over full if `limit`
+    /// changed since the last request.
+    pub fn admit(&mut self, limit: RateLimit, len: usize) -> bool {
+        if limit != self.limit {
+            *self = Self::new(limit);
+        }
+        let requests = self.requests.as_mut().is_none_or(|b| b.ready());
+        let bytes = self.bytes.as_mut().is_none_or(|b| b.ready());
+        if !(requests && bytes) {
+            return false;
+        }
+        if let Some(b) = &mut self.requests {
+            b.take(1.0);
+        }
+        if let Some(b) = &mut self.bytes {
+            b.take(len as f64);
+        }
+        true
+    }
+}
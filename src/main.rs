@@ -1,16 +1,21 @@
-mod cluster;
-mod handler;
-mod protocol;
-mod queue;
-mod server;
-mod storage;
-
 use clap::Parser;
-use cluster::Cluster;
-use server::Server;
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use opentelemetry::trace::TracerProvider as _;
+use quique::auth::Credentials;
+use quique::cluster::Cluster;
+use quique::config::{Config, ConfigFile};
+use quique::limit::RateLimit;
+use quique::peer::Pool;
+use quique::queue::MEMORY;
+use quique::server::Server;
+use quique::{telemetry, tls};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, fmt, reload};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct Args {
     /// listen addr
     #[arg(long, default_value = "127.0.0.1:7001")]
@@ -18,21 +23,202 @@ struct Args {
     /// data dir
     #[arg(long, default_value = "./data")]
     data_dir: String,
+    /// bind with SO_REUSEPORT so a new broker can start next to a draining one
+    #[arg(long)]
+    reuse_port: bool,
+    /// forward produce/consume for topics led by other nodes instead of
+    /// redirecting the client there
+    #[arg(long)]
+    proxy: bool,
+    /// leave Nagle's algorithm on for client connections: small answers
+    /// are held back to fill segments, more throughput for more latency
+    #[arg(long)]
+    nagle: bool,
+    /// serve TLS with this PEM certificate chain, other nodes must too
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// PEM private key of `tls_cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// PEM CA that signed the other nodes' certificates (default: tls_cert,
+    /// which then has to end with the CA certificate)
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<String>,
+    /// require clients to authenticate against the users and tokens in this
+    /// JSON file, see `Credentials`
+    #[arg(long)]
+    auth_file: Option<String>,
+    /// most requests a connection may send per second, 0 = unlimited
+    #[arg(long, default_value_t = 0)]
+    max_requests_per_sec: u32,
+    /// most bytes a connection may send per second, 0 = unlimited
+    #[arg(long, default_value_t = 0)]
+    max_bytes_per_sec: u64,
+    /// most connections served at once, 0 = unlimited; more wait to be
+    /// accepted
+    #[arg(long, default_value_t = 0)]
+    max_connections: usize,
+    /// at max_connections, answer new connections with TooManyConnections
+    /// instead of letting them wait
+    #[arg(long, requires = "max_connections")]
+    reject_when_full: bool,
+    /// delete log segments of topics without a retention of their own once
+    /// older than this, 0 = keep
+    #[arg(long, default_value_t = 0)]
+    retention_secs: u32,
+    /// delete log segments of topics without a retention of their own past
+    /// this size, 0 = unlimited
+    #[arg(long, default_value_t = 0)]
+    retention_bytes: u64,
+    /// payload bytes held pending in all topics at most, 0 = unlimited;
+    /// past it every group is full and produces get their topic's overflow
+    /// policy
+    #[arg(long, default_value_t = 0)]
+    max_memory_bytes: u64,
+    /// JSON file with settings that override the flags above, read again on
+    /// SIGHUP, see `ConfigFile`
+    #[arg(long)]
+    config: Option<String>,
+    /// export a span per request over OTLP/HTTP to this URL, like
+    /// http://localhost:4318/v1/traces
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// also serve produce/consume over gRPC on this addr, without TLS, see
+    /// proto/quique.proto
+    #[arg(long)]
+    grpc_addr: Option<String>,
+    /// also serve WebSocket clients on this addr, JSON or QBUS frames, over
+    /// TLS if --tls-cert is set
+    #[arg(long)]
+    ws_addr: Option<String>,
+    /// also serve MQTT 3.1.1 clients on this addr, over TLS if --tls-cert is set
+    #[arg(long)]
+    mqtt_addr: Option<String>,
+    /// also serve STOMP 1.2 clients on this addr, over TLS if --tls-cert is set
+    #[arg(long)]
+    stomp_addr: Option<String>,
+    /// also serve Redis streams commands (XADD, XREAD, XREADGROUP, XACK) on
+    /// this addr, over TLS if --tls-cert is set
+    #[arg(long)]
+    resp_addr: Option<String>,
+}
+
+/// Reloadable settings: the flags, overridden by the config file if there
+/// is one. Also returns the file, for the settings `Config` doesn't hold.
+/// The memory limit is process-wide and set right away, once the rest loaded.
+fn load_config(args: &Args) -> anyhow::Result<(Config, ConfigFile)> {
+    let file = args.config.as_deref().map(ConfigFile::load).transpose()?.unwrap_or_default();
+    let auth_file = file.auth_file.as_deref().or(args.auth_file.as_deref());
+    let config = Config {
+        auth: auth_file.map(Credentials::load).transpose()?.map(Arc::new),
+        rate_limit: RateLimit {
+            requests_per_sec: file.max_requests_per_sec.unwrap_or(args.max_requests_per_sec),
+            bytes_per_sec: file.max_bytes_per_sec.unwrap_or(args.max_bytes_per_sec),
+        },
+        retention: match file.retention_secs.unwrap_or(args.retention_secs) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        },
+        retention_bytes: Some(file.retention_bytes.unwrap_or(args.retention_bytes)).filter(|&b| b > 0),
+    };
+    MEMORY.set_limit(Some(file.max_memory_bytes.unwrap_or(args.max_memory_bytes)).filter(|&b| b > 0));
+    Ok((config, file))
+}
+
+/// `level` as given in the config file, or RUST_LOG with quique at info
+fn log_filter(level: Option<&str>) -> anyhow::Result<EnvFilter> {
+    Ok(match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::from_default_env().add_directive("quique=info".parse()?),
+    })
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Define tracing subscriber for structured logging
-    let subscriber = FmtSubscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env().add_directive("quique=info".parse()?))
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
-
     let args = Args::parse();
-    let cluster = Cluster::from_env()?;
+    let (config, file) = load_config(&args)?;
+
+    // Define tracing subscriber for structured logging, and exporting spans
+    let (filter, log_reload) = reload::Layer::new(log_filter(file.log_level.as_deref())?);
+    let tracer = match file.otlp_endpoint.as_ref().or(args.otlp_endpoint.as_ref()) {
+        Some(endpoint) => Some(telemetry::tracer_provider(endpoint)?),
+        None => None,
+    };
+    let otel = tracer.as_ref().map(|t| OpenTelemetryLayer::new(t.tracer("quique")));
+    tracing_subscriber::registry().with(filter).with(fmt::layer()).with(otel).init();
+
+    let mut acceptor = None;
+    let mut connector = None;
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        acceptor = Some(tls::acceptor(cert, key)?);
+        connector = Some(tls::connector(args.tls_ca.as_ref().unwrap_or(cert))?);
+    }
+    // not reloaded, other nodes would have to change theirs at the same time
+    let peer_token = config.auth.as_ref().and_then(|c| c.peer_token.clone());
+    let cluster = Cluster::from_env(&args.addr)?.with_peers(Pool::new(connector, peer_token));
 
     // start host server
-    let srv = Server::new(args.addr, args.data_dir, cluster);
+    let mut srv = Server::new(args.addr.clone(), args.data_dir.clone(), cluster)
+        .reuse_port(args.reuse_port)
+        .proxy(args.proxy)
+        .nodelay(!args.nagle)
+        .max_connections(args.max_connections, args.reject_when_full)
+        .config(config);
+    if let Some(acceptor) = acceptor {
+        srv = srv.tls(acceptor);
+    }
+    if let Some(addr) = &args.grpc_addr {
+        srv = srv.grpc(addr.clone());
+    }
+    if let Some(addr) = &args.ws_addr {
+        srv = srv.websocket(addr.clone());
+    }
+    if let Some(addr) = &args.mqtt_addr {
+        srv = srv.mqtt(addr.clone());
+    }
+    if let Some(addr) = &args.stomp_addr {
+        srv = srv.stomp(addr.clone());
+    }
+    if let Some(addr) = &args.resp_addr {
+        srv = srv.resp(addr.clone());
+    }
+
+    // SIGHUP: read the config file (and auth file) again. A config that
+    // doesn't load leaves the current one in place.
+    let live = srv.live_config();
+    let mut hup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            let reloaded = load_config(&args).and_then(|(config, file)| Ok((config, log_filter(file.log_level.as_deref())?)));
+            match reloaded {
+                Ok((config, filter)) => {
+                    live.store(Arc::new(config));
+                    if let Err(e) = log_reload.reload(filter) {
+                        tracing::warn!("failed to change the log level: {}", e);
+                    }
+                    tracing::info!("configuration reloaded");
+                }
+                Err(e) => tracing::warn!("configuration not reloaded: {:#}", e),
+            }
+        }
+    });
 
-    srv.run().await
+    // SIGTERM or Ctrl-C: stop accepting and drain, so a replacement process
+    // can take over, then sync logs and save metadata
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let res = srv
+        .run_until(async move {
+            tokio::select! {
+                _ = term.recv() => tracing::info!("SIGTERM received, shutting down"),
+                _ = int.recv() => tracing::info!("SIGINT received, shutting down"),
+            }
+        })
+        .await;
+    if let Some(tracer) = tracer
+        && let Err(e) = tracer.shutdown()
+    {
+        tracing::warn!("failed to export the last spans: {}", e);
+    }
+    res
 }
@@ -0,0 +1,624 @@
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::gateway::Gateway;
+use crate::handler::Session;
+use crate::protocol::*;
+use crate::queue::{Message, now_ms};
+
+/// Header a message published over MQTT carries its MQTT topic name in,
+/// subscribers get it back under that name
+pub const TOPIC_HEADER: &str = "mqtt-topic";
+
+/// How long a new connection has to send CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a subscription waits for a message before settling what the
+/// client acked in the meantime
+const SUBSCRIBE_POLL_MS: u32 = 1_000;
+
+/// QoS 1 messages a subscription sends before waiting for PUBACKs
+const MAX_INFLIGHT: usize = 32;
+
+/// Packets waiting to be written to a client
+const OUTBOX: usize = 64;
+
+// packet types, the high nibble of the first byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+// CONNACK return codes
+const ACCEPTED: u8 = 0;
+const BAD_PROTOCOL: u8 = 1;
+const BAD_CLIENT_ID: u8 = 2;
+const BAD_CREDENTIALS: u8 = 4;
+const NOT_AUTHORIZED: u8 = 5;
+
+/// SUBACK return code of a filter that can't be subscribed to
+const SUBSCRIBE_FAILED: u8 = 0x80;
+
+/// Client ids handed out to clients that connect without one
+static ANONYMOUS: AtomicU64 = AtomicU64::new(0);
+
+/// One MQTT 3.1.1 client. Topic names map onto quique topics by their first
+/// level, the rest is the routing key with `.` for `/`: publishing to
+/// `sensors/kitchen/temp` produces to `sensors` with key `kitchen.temp`. A
+/// subscription is a consumer group of that topic named after the client
+/// (and the filter, if it goes past the first level), bound to the filter
+/// with `+` as `*`, so it keeps queueing while the client is away.
+pub async fn serve<S>(sock: S, gateway: Gateway, mut drain: watch::Receiver<bool>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut rd, mut wr) = tokio::io::split(sock);
+    let mut buf = BytesMut::with_capacity(4096);
+    let Ok(first) = tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut rd, &mut buf)).await else {
+        return Ok(());
+    };
+    let Some(p) = first? else {
+        return Ok(());
+    };
+    if p.kind != CONNECT {
+        bail!("expected CONNECT, got packet type {}", p.kind);
+    }
+    let mut session = gateway.session();
+    let conn = match connect(&gateway, &mut session, &p.body).await {
+        Ok(conn) => conn,
+        Err(rc) => {
+            wr.write_all(&encode(CONNACK, 0, &[0, rc])).await?;
+            return Ok(());
+        }
+    };
+    wr.write_all(&encode(CONNACK, 0, &[0, ACCEPTED])).await?;
+
+    let (tx, mut rx) = mpsc::channel::<Bytes>(OUTBOX);
+    let writer = tokio::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            if wr.write_all(&packet).await.is_err() {
+                return;
+            }
+        }
+        let _ = wr.shutdown().await;
+    });
+
+    let mut client = Client {
+        gateway,
+        session,
+        id: conn.client_id,
+        out: tx,
+        inflight: Arc::new(Mutex::new(Inflight::default())),
+        next_sub: 0,
+        subs: HashMap::new(),
+    };
+    // the keep alive is a promise to send something that often, give it half again
+    let idle = match conn.keep_alive {
+        0 => Duration::MAX,
+        secs => Duration::from_millis(secs as u64 * 1500),
+    };
+    let res = client.run(&mut rd, &mut buf, &mut drain, idle).await;
+    for (_, sub) in client.subs.drain() {
+        sub.stop().await;
+    }
+    if !matches!(res, Ok(true))
+        && let Some(will) = conn.will
+    {
+        will.publish(&client.gateway, &mut client.session).await;
+    }
+    drop(client);
+    let _ = writer.await;
+    res.map(|_| ())
+}
+
+/// What a CONNECT asked for
+struct Connect {
+    client_id: String,
+    keep_alive: u16,
+    will: Option<Will>,
+}
+
+/// Message published for a client that goes away without DISCONNECT
+struct Will {
+    topic: String,
+    payload: Vec<u8>,
+    qos: u8,
+}
+
+impl Will {
+    async fn publish(self, gateway: &Gateway, session: &mut Session) {
+        let st = publish(gateway, session, &self.topic, self.payload, self.qos).await;
+        if st != Status::Ok {
+            tracing::warn!("will message to {} failed: {:?}", self.topic, st);
+        }
+    }
+}
+
+/// Check a CONNECT and authenticate `session` with its username and
+/// password. A password without a username is taken as a token. Returns
+/// the CONNACK code it's refused with otherwise.
+async fn connect(gateway: &Gateway, session: &mut Session, body: &[u8]) -> Result<Connect, u8> {
+    let b = &mut &body[..];
+    let (Some(name), Some(level), Some(flags), Some(keep_alive)) = (get_str(b), get_u8(b), get_u8(b), get_u16(b)) else {
+        return Err(BAD_PROTOCOL);
+    };
+    if name != "MQTT" || level != 4 {
+        return Err(BAD_PROTOCOL);
+    }
+    let clean_session = flags & 0x02 != 0;
+    let Some(mut client_id) = get_str(b) else {
+        return Err(BAD_PROTOCOL);
+    };
+    let will = if flags & 0x04 != 0 {
+        let (Some(topic), Some(payload)) = (get_str(b), get_data(b)) else {
+            return Err(BAD_PROTOCOL);
+        };
+        Some(Will {
+            topic,
+            payload,
+            qos: (flags >> 3) & 0x03,
+        })
+    } else {
+        None
+    };
+    let user = if flags & 0x80 != 0 { get_str(b) } else { None };
+    let password = if flags & 0x40 != 0 { get_data(b) } else { None };
+
+    if client_id.is_empty() {
+        // only a client that keeps nothing between connections may go unnamed
+        if !clean_session {
+            return Err(BAD_CLIENT_ID);
+        }
+        client_id = format!("mqtt-{:x}-{}", now_ms(), ANONYMOUS.fetch_add(1, Ordering::Relaxed));
+    }
+    // it names consumer groups
+    if client_id.contains(['/', '\\', ':']) || client_id.starts_with('.') {
+        return Err(BAD_CLIENT_ID);
+    }
+    if gateway.auth_required() {
+        let Some(password) = password else {
+            return Err(NOT_AUTHORIZED);
+        };
+        let password = String::from_utf8_lossy(&password);
+        if gateway.authenticate(session, &user.unwrap_or_default(), &password).await != Status::Ok {
+            return Err(BAD_CREDENTIALS);
+        }
+    }
+    Ok(Connect {
+        client_id,
+        keep_alive,
+        will,
+    })
+}
+
+/// QoS 1 messages sent to a client and not yet acked, by packet id, as the
+/// subscription that sent them and the delivery tag
+#[derive(Default)]
+struct Inflight {
+    next_id: u16,
+    waiting: HashMap<u16, (u64, u64)>,
+}
+
+impl Inflight {
+    /// A packet id for delivery `tag` of subscription `sub`
+    fn add(&mut self, sub: u64, tag: u64) -> u16 {
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if self.next_id != 0 && !self.waiting.contains_key(&self.next_id) {
+                break;
+            }
+        }
+        self.waiting.insert(self.next_id, (sub, tag));
+        self.next_id
+    }
+}
+
+/// A subscription as the connection knows it
+struct Sub {
+    seq: u64,
+    /// delivery tags the client acked
+    acks: mpsc::UnboundedSender<u64>,
+    task: JoinHandle<()>,
+}
+
+impl Sub {
+    /// Stop delivering once the acks already in are settled, the rest is
+    /// requeued
+    async fn stop(self) {
+        drop(self.acks);
+        let _ = self.task.await;
+    }
+}
+
+/// A connected client
+struct Client {
+    gateway: Gateway,
+    session: Session,
+    id: String,
+    out: mpsc::Sender<Bytes>,
+    inflight: Arc<Mutex<Inflight>>,
+    next_sub: u64,
+    /// by filter
+    subs: HashMap<String, Sub>,
+}
+
+impl Client {
+    /// Serve packets until the client leaves, returns whether it said
+    /// DISCONNECT first, or the server is shutting down
+    async fn run<R: AsyncRead + Unpin>(
+        &mut self,
+        rd: &mut R,
+        buf: &mut BytesMut,
+        drain: &mut watch::Receiver<bool>,
+        idle: Duration,
+    ) -> Result<bool> {
+        loop {
+            let p = tokio::select! {
+                p = tokio::time::timeout(idle, read_packet(rd, buf)) => match p {
+                    Ok(p) => p?,
+                    Err(_) => {
+                        tracing::debug!("MQTT client {} missed its keep alive", self.id);
+                        None
+                    }
+                },
+                _ = drain.wait_for(|d| *d) => return Ok(true),
+            };
+            let Some(p) = p else {
+                return Ok(false);
+            };
+            let reply = match p.kind {
+                PUBLISH => self.publish(p.flags, &p.body).await?,
+                PUBACK => {
+                    let acked = get_u16(&mut &p.body[..]).and_then(|id| self.inflight.lock().unwrap().waiting.remove(&id));
+                    // the subscription may be gone, and with it what it didn't get acked
+                    if let Some((seq, tag)) = acked
+                        && let Some(sub) = self.subs.values().find(|s| s.seq == seq)
+                    {
+                        let _ = sub.acks.send(tag);
+                    }
+                    None
+                }
+                // QoS 2 is done with once PUBREC went out, the rest is a formality
+                PUBREL => Some(encode(PUBCOMP, 0, &p.body)),
+                SUBSCRIBE => Some(self.subscribe(&p.body).await?),
+                UNSUBSCRIBE => Some(self.unsubscribe(&p.body)?),
+                PINGREQ => Some(encode(PINGRESP, 0, &[])),
+                DISCONNECT => return Ok(true),
+                kind => bail!("unexpected packet type {}", kind),
+            };
+            if let Some(reply) = reply
+                && self.out.send(reply).await.is_err()
+            {
+                return Ok(false);
+            }
+        }
+    }
+
+    async fn publish(&mut self, flags: u8, body: &[u8]) -> Result<Option<Bytes>> {
+        let b = &mut &body[..];
+        let qos = (flags >> 1) & 0x03;
+        let Some(topic) = get_str(b) else {
+            bail!("malformed PUBLISH");
+        };
+        let id = if qos > 0 { get_u16(b) } else { Some(0) };
+        let Some(id) = id else {
+            bail!("malformed PUBLISH");
+        };
+        let st = publish(&self.gateway, &mut self.session, &topic, b.to_vec(), qos).await;
+        // MQTT 3.1.1 has no way to refuse a publish: dropping the connection
+        // is the only way to tell a QoS 1 or 2 client it didn't go through
+        if st != Status::Ok {
+            if qos > 0 {
+                bail!("publish to {} failed: {:?}", topic, st);
+            }
+            tracing::debug!("dropped QoS 0 publish to {}: {:?}", topic, st);
+        }
+        Ok(match qos {
+            0 => None,
+            1 => Some(encode(PUBACK, 0, &id.to_be_bytes())),
+            _ => Some(encode(PUBREC, 0, &id.to_be_bytes())),
+        })
+    }
+
+    async fn subscribe(&mut self, body: &[u8]) -> Result<Bytes> {
+        let b = &mut &body[..];
+        let Some(id) = get_u16(b) else {
+            bail!("malformed SUBSCRIBE");
+        };
+        let mut reply = BytesMut::new();
+        reply.put_u16(id);
+        while !b.is_empty() {
+            let (Some(filter), Some(qos)) = (get_str(b), get_u8(b)) else {
+                bail!("malformed SUBSCRIBE");
+            };
+            // QoS 2 is delivered as QoS 1
+            let qos = qos.min(1);
+            reply.put_u8(match self.start(&filter, qos).await {
+                Status::Ok => qos,
+                st => {
+                    tracing::debug!("MQTT client {} can't subscribe to {}: {:?}", self.id, filter, st);
+                    SUBSCRIBE_FAILED
+                }
+            });
+        }
+        Ok(encode(SUBACK, 0, &reply))
+    }
+
+    /// Start delivering what matches `filter`, instead of what an earlier
+    /// subscription to it took and didn't send
+    async fn start(&mut self, filter: &str, qos: u8) -> Status {
+        let Some((topic, key)) = parse_filter(filter) else {
+            return Status::BadRequest;
+        };
+        let group = match &key {
+            Some(key) => format!("{}:{}", self.id, key),
+            None => self.id.clone(),
+        };
+        if let Some(key) = &key {
+            let st = self.gateway.bind(&mut self.session, topic, &group, key).await;
+            if st != Status::Ok {
+                return st;
+            }
+        }
+        if let Some(old) = self.subs.remove(filter) {
+            tokio::spawn(old.stop());
+        }
+        self.next_sub += 1;
+        let (acks, rx) = mpsc::unbounded_channel();
+        let sub = Subscription {
+            gateway: self.gateway.clone(),
+            session: self.session.sibling(),
+            seq: self.next_sub,
+            topic: topic.to_string(),
+            group,
+            qos,
+            out: self.out.clone(),
+            inflight: self.inflight.clone(),
+        };
+        let sub = Sub {
+            seq: self.next_sub,
+            acks,
+            task: tokio::spawn(sub.run(rx)),
+        };
+        self.subs.insert(filter.to_string(), sub);
+        Status::Ok
+    }
+
+    fn unsubscribe(&mut self, body: &[u8]) -> Result<Bytes> {
+        let b = &mut &body[..];
+        let Some(id) = get_u16(b) else {
+            bail!("malformed UNSUBSCRIBE");
+        };
+        while !b.is_empty() {
+            let Some(filter) = get_str(b) else {
+                bail!("malformed UNSUBSCRIBE");
+            };
+            // its group stays
+            if let Some(sub) = self.subs.remove(&filter) {
+                tokio::spawn(sub.stop());
+            }
+        }
+        Ok(encode(UNSUBACK, 0, &id.to_be_bytes()))
+    }
+}
+
+/// Produce `payload` to where MQTT topic `name` maps to, see `serve`
+async fn publish(gateway: &Gateway, session: &mut Session, name: &str, payload: Vec<u8>, qos: u8) -> Status {
+    let Some((topic, key)) = parse_topic(name) else {
+        return Status::BadRequest;
+    };
+    let msg = Message {
+        payload: payload.into(),
+        envelope: Envelope {
+            headers: BTreeMap::from([(TOPIC_HEADER.to_string(), name.to_string())]),
+            ..Default::default()
+        },
+    };
+    let acks = if qos == 0 { Acks::None } else { Acks::Leader };
+    match gateway.produce(session, topic, &msg, 0, &key, acks).await {
+        Ok(_seq) => Status::Ok,
+        Err(st) => st,
+    }
+}
+
+/// Deliveries of one filter, on a session of their own
+struct Subscription {
+    gateway: Gateway,
+    session: Session,
+    seq: u64,
+    topic: String,
+    group: String,
+    qos: u8,
+    out: mpsc::Sender<Bytes>,
+    inflight: Arc<Mutex<Inflight>>,
+}
+
+impl Subscription {
+    async fn run(mut self, mut acks: mpsc::UnboundedReceiver<u64>) {
+        let out = self.out.clone();
+        let mut unacked = 0;
+        loop {
+            // settle what's in before stopping, see `Sub::stop`
+            loop {
+                match acks.try_recv() {
+                    Ok(tag) => {
+                        self.settle(tag).await;
+                        unacked -= 1;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            if unacked >= MAX_INFLIGHT {
+                let Some(tag) = acks.recv().await else {
+                    return;
+                };
+                self.settle(tag).await;
+                unacked -= 1;
+                continue;
+            }
+            // a permit first: nothing is taken off the topic for a client that's gone
+            let Ok(permit) = out.reserve().await else {
+                return;
+            };
+            let (tag, msg) = match self.gateway.next(&mut self.session, &self.topic, &self.group, SUBSCRIBE_POLL_MS).await {
+                // stopped or gone while waiting: the message goes back with the session
+                Ok(Some(_)) if out.is_closed() || acks.is_closed() => return,
+                Ok(Some(m)) => m,
+                Ok(None) => continue,
+                Err(st) => {
+                    tracing::warn!("MQTT delivery from {} to {} failed: {:?}", self.topic, self.group, st);
+                    return;
+                }
+            };
+            let name = match msg.envelope.headers.get(TOPIC_HEADER) {
+                Some(name) => name.clone(),
+                None => self.topic.clone(),
+            };
+            let mut body = BytesMut::new();
+            put_str(&mut body, &name);
+            if self.qos == 0 {
+                self.settle(tag).await;
+            } else {
+                body.put_u16(self.inflight.lock().unwrap().add(self.seq, tag));
+                unacked += 1;
+            }
+            body.extend_from_slice(&msg.payload);
+            permit.send(encode(PUBLISH, self.qos << 1, &body));
+        }
+    }
+
+    async fn settle(&mut self, tag: u64) {
+        let st = self.gateway.settle(&mut self.session, &self.topic, &self.group, tag, false).await;
+        if st != Status::Ok {
+            tracing::debug!("ack of {}#{} for {} failed: {:?}", self.topic, tag, self.group, st);
+        }
+    }
+}
+
+/// Quique topic and routing key MQTT topic `name` maps to
+fn parse_topic(name: &str) -> Option<(&str, String)> {
+    if name.contains(['+', '#']) {
+        return None;
+    }
+    let (topic, rest) = name.split_once('/').unwrap_or((name, ""));
+    Some((topic, rest.replace('/', ".")))
+}
+
+/// Quique topic and binding pattern MQTT `filter` maps to, no pattern if it
+/// takes the whole topic (`sensors` or `sensors/#`)
+fn parse_filter(filter: &str) -> Option<(&str, Option<String>)> {
+    let (topic, rest) = filter.split_once('/').unwrap_or((filter, ""));
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return None;
+    }
+    let levels: Vec<&str> = rest.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard = level.contains(['+', '#']);
+        if wildcard && (level.len() > 1 || (*level == "#" && i + 1 != levels.len())) {
+            return None;
+        }
+    }
+    if rest.is_empty() || rest == "#" {
+        return Some((topic, None));
+    }
+    Some((topic, Some(rest.replace('/', ".").replace('+', "*"))))
+}
+
+struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Bytes,
+}
+
+/// Next packet from `rd`, None once it's closed. Whatever was read is kept
+/// in `buf`, so it can be cancelled and called again.
+async fn read_packet<R: AsyncRead + Unpin>(rd: &mut R, buf: &mut BytesMut) -> Result<Option<Packet>> {
+    loop {
+        if let Some(p) = decode(buf)? {
+            return Ok(Some(p));
+        }
+        if rd.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Take a whole packet off `buf`: type and flags, the body length in one to
+/// four bytes of 7 bits, low first, then the body
+fn decode(buf: &mut BytesMut) -> Result<Option<Packet>> {
+    let mut len = 0;
+    for i in 0..4 {
+        let Some(&b) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+        len |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            if buf.len() < 2 + i + len {
+                return Ok(None);
+            }
+            let first = buf[0];
+            buf.advance(2 + i);
+            return Ok(Some(Packet {
+                kind: first >> 4,
+                flags: first & 0x0f,
+                body: buf.split_to(len).freeze(),
+            }));
+        }
+    }
+    bail!("malformed packet length")
+}
+
+fn encode(kind: u8, flags: u8, body: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(5 + body.len());
+    out.put_u8(kind << 4 | flags);
+    let mut len = body.len();
+    loop {
+        let b = (len % 128) as u8;
+        len /= 128;
+        out.put_u8(if len > 0 { b | 0x80 } else { b });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out.freeze()
+}
+
+fn get_u16(b: &mut &[u8]) -> Option<u16> {
+    if b.len() < 2 {
+        return None;
+    }
+    let v = u16::from_be_bytes([b[0], b[1]]);
+    *b = &b[2..];
+    Some(v)
+}
+
+/// Binary data: a u16 length, then the bytes
+fn get_data(b: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = get_u16(b)? as usize;
+    if b.len() < len {
+        return None;
+    }
+    let v = b[..len].to_vec();
+    *b = &b[len..];
+    Some(v)
+}
@@ -0,0 +1,253 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio_rustls::TlsConnector;
+
+use crate::protocol::*;
+use crate::tls::{self, Stream};
+
+/// A peer that doesn't answer within this is treated as unreachable
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send one request on `s` without waiting for an answer
+pub async fn send<S: AsyncWrite + Unpin>(s: &mut S, op: Op, body: &[u8]) -> Result<()> {
+    s.write_all(&frame(op, 0, body)).await?;
+    Ok(())
+}
+
+/// Request frame for `op` with `body` on `stream_id`
+fn frame(op: Op, stream_id: u32, body: &[u8]) -> BytesMut {
+    let hdr = Header {
+        magic: MAGIC,
+        version: VERSION,
+        op,
+        flags: 0,
+        stream_id,
+        body_len: body.len() as u32,
+    };
+    let mut buf = BytesMut::with_capacity(Header::LEN + body.len());
+    hdr.encode(&mut buf);
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Send one request on `s` and read its answer as (status, rest of body)
+pub async fn rpc<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S, op: Op, body: &[u8]) -> Result<(Status, Vec<u8>)> {
+    send(s, op, body).await?;
+    recv(s).await
+}
+
+/// Read the answer to the oldest request sent on `s` that has none yet
+pub async fn recv<S: AsyncRead + Unpin>(s: &mut S) -> Result<(Status, Vec<u8>)> {
+    Ok(recv_answer(s).await?.1)
+}
+
+/// Read one answer off `s`, with the stream_id of its request
+async fn recv_answer<S: AsyncRead + Unpin>(s: &mut S) -> Result<(u32, (Status, Vec<u8>))> {
+    let mut hb = [0u8; Header::LEN];
+    s.read_exact(&mut hb).await?;
+    let stream_id = u32::from_be_bytes([hb[8], hb[9], hb[10], hb[11]]);
+    let body_len = u32::from_be_bytes([hb[12], hb[13], hb[14], hb[15]]);
+    if body_len > MAX_FRAME {
+        return Err(ProtoError::TooLarge(body_len).into());
+    }
+    let mut resp = vec![0u8; body_len as usize];
+    s.read_exact(&mut resp).await?;
+    if resp.len() < 2 {
+        return Err(ProtoError::Short.into());
+    }
+    let st = Status::try_from(u16::from_be_bytes([resp[0], resp[1]]))?;
+    Ok((stream_id, (st, resp.split_off(2))))
+}
+
+/// A connection to another node carrying any number of requests at once,
+/// like `client::Conn`: each request goes out on a stream_id of its own and
+/// its answer is handed back by it, whatever order answers come in. Once
+/// the connection fails, so does every request waiting on it and every one
+/// made on it after. Dropping the last handle closes it, after the node
+/// answered what's left.
+#[derive(Clone)]
+pub struct Mux {
+    /// frames for the writer task
+    frames: mpsc::UnboundedSender<BytesMut>,
+    inflight: Arc<std::sync::Mutex<Inflight>>,
+}
+
+/// Requests of a `Mux` waiting for their answers
+#[derive(Default)]
+struct Inflight {
+    next_id: u32,
+    waiting: HashMap<u32, oneshot::Sender<(Status, Vec<u8>)>>,
+    failed: bool,
+}
+
+impl Inflight {
+    fn fail(&mut self) {
+        // dropping the senders fails their requests
+        self.waiting.clear();
+        self.failed = true;
+    }
+}
+
+impl Mux {
+    pub fn new(s: Stream) -> Self {
+        let (r, w) = tokio::io::split(s);
+        let (frames, rx) = mpsc::unbounded_channel();
+        let inflight = Arc::new(std::sync::Mutex::new(Inflight::default()));
+        tokio::spawn(write_frames(w, rx, inflight.clone()));
+        tokio::spawn(read_answers(r, inflight.clone()));
+        Self { frames, inflight }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inflight.lock().unwrap().failed
+    }
+
+    /// Send one request without waiting for an answer, after the ones
+    /// made before it
+    pub fn send(&self, op: Op, body: &[u8]) -> Result<()> {
+        if self.is_closed() {
+            anyhow::bail!("connection closed");
+        }
+        self.frames.send(frame(op, 0, body)).map_err(|_| anyhow::anyhow!("connection closed"))
+    }
+
+    /// Send one request and wait for its answer as (status, rest of body),
+    /// while others on the connection go out and get theirs
+    pub async fn rpc(&self, op: Op, body: &[u8]) -> Result<(Status, Vec<u8>)> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut inflight = self.inflight.lock().unwrap();
+            if inflight.failed {
+                anyhow::bail!("connection closed");
+            }
+            // 0 is left to requests that aren't answered
+            let id = inflight.next_id.wrapping_add(1).max(1);
+            inflight.next_id = id;
+            inflight.waiting.insert(id, tx);
+            // a writer that's gone failed the connection, `tx` with it
+            let _ = self.frames.send(frame(op, id, body));
+        }
+        rx.await.map_err(|_| anyhow::anyhow!("connection closed"))
+    }
+}
+
+/// Write the frames of a `Mux`, those requested meanwhile at once, until
+/// every handle to it is dropped
+async fn write_frames(mut w: WriteHalf<Stream>, mut rx: mpsc::UnboundedReceiver<BytesMut>, inflight: Arc<std::sync::Mutex<Inflight>>) {
+    while let Some(mut buf) = rx.recv().await {
+        while let Ok(more) = rx.try_recv() {
+            buf.extend_from_slice(&more);
+        }
+        if w.write_all(&buf).await.is_err() {
+            inflight.lock().unwrap().fail();
+            return;
+        }
+    }
+    // the node closes its end once it has answered what's left
+    let _ = w.shutdown().await;
+}
+
+/// Hand the answers read on a `Mux` to their requests until it fails
+async fn read_answers(mut r: ReadHalf<Stream>, inflight: Arc<std::sync::Mutex<Inflight>>) {
+    while let Ok((id, answer)) = recv_answer(&mut r).await {
+        // the request may have stopped waiting for it
+        if let Some(w) = inflight.lock().unwrap().waiting.remove(&id) {
+            let _ = w.send(answer);
+        }
+    }
+    inflight.lock().unwrap().fail();
+}
+
+/// One kept-open connection per peer address, for node to node traffic
+/// that is too frequent to connect for every request
+pub struct Pool {
+    conns: DashMap<String, Arc<Mutex<Option<Stream>>>>,
+    /// nodes talk TLS to each other if their listeners do
+    tls: Option<TlsConnector>,
+    /// sent in an `Op::Auth` on every new connection, if other nodes want one
+    token: Option<String>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("conns", &self.conns.len())
+            .field("tls", &self.tls.is_some())
+            .field("token", &self.token.is_some())
+            .finish()
+    }
+}
+
+impl Pool {
+    pub fn new(tls: Option<TlsConnector>, token: Option<String>) -> Self {
+        Self {
+            conns: DashMap::new(),
+            tls,
+            token,
+        }
+    }
+
+    /// A new connection to another node, not kept in the pool
+    pub async fn connect(&self, addr: &str) -> Result<Stream> {
+        tokio::time::timeout(PEER_TIMEOUT, self.open(addr)).await?
+    }
+
+    /// Connect to another node, make one request and hang up
+    pub async fn call_once(&self, addr: &str, op: Op, body: &[u8]) -> Result<(Status, Vec<u8>)> {
+        tokio::time::timeout(PEER_TIMEOUT, async {
+            let mut s = self.open(addr).await?;
+            rpc(&mut s, op, body).await
+        })
+        .await?
+    }
+
+    async fn open(&self, addr: &str) -> Result<Stream> {
+        let mut s = tls::connect(addr, self.tls.as_ref()).await?;
+        if let Some(token) = &self.token {
+            let mut body = BytesMut::new();
+            put_str(&mut body, "");
+            put_str(&mut body, token);
+            match rpc(&mut s, Op::Auth, &body).await? {
+                (Status::Ok, _) => {}
+                (st, _) => return Err(anyhow::anyhow!("{} answered auth with {:?}", addr, st)),
+            }
+        }
+        Ok(s)
+    }
+
+    /// Like `call_once`, over the pooled connection to `addr`. Requests to the
+    /// same peer go one at a time. A kept connection that turns out broken
+    /// is dropped and the request retried on a new one.
+    pub async fn call(&self, addr: &str, op: Op, body: &[u8]) -> Result<(Status, Vec<u8>)> {
+        let slot = self.conns.entry(addr.to_string()).or_default().clone();
+        let mut conn = slot.lock().await;
+        loop {
+            let reused = conn.is_some();
+            let res = tokio::time::timeout(PEER_TIMEOUT, async {
+                if conn.is_none() {
+                    *conn = Some(self.open(addr).await?);
+                }
+                rpc(conn.as_mut().unwrap(), op, body).await
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+            match res {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    // half a frame may be in flight, the stream can't be reused
+                    *conn = None;
+                    if !reused {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
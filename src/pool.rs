@@ -0,0 +1,51 @@
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+/// Read buffers of connections, taken while a frame is coming in and given
+/// back once it's all been handled
+pub static READS: Pool = Pool::new(64 * 1024, 256);
+
+/// Answers being built and the frames they're written in
+pub static ANSWERS: Pool = Pool::new(1024, 1024);
+
+/// Buffers grown past this many times their size are dropped rather than
+/// kept, a big consume answer shouldn't stay around for a ping
+const MAX_GROWTH: usize = 64;
+
+/// Free buffers of one size, so connections don't each hold on to their
+/// own while idle or allocate a new one for every request
+pub struct Pool {
+    free: Mutex<Vec<BytesMut>>,
+    size: usize,
+    /// free buffers kept at most, the rest are dropped
+    max: usize,
+}
+
+impl Pool {
+    pub const fn new(size: usize, max: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            size,
+            max,
+        }
+    }
+
+    /// An empty buffer with room for at least `size` bytes
+    pub fn take(&self) -> BytesMut {
+        self.free.lock().unwrap().pop().unwrap_or_else(|| BytesMut::with_capacity(self.size))
+    }
+
+    /// Give `buf` back for reuse. Kept only if nothing else still points
+    /// into it, frames split off it and not dropped yet, and it's not grown
+    /// too big.
+    pub fn give(&self, mut buf: BytesMut) {
+        buf.clear();
+        if buf.capacity() > self.size * MAX_GROWTH || !buf.try_reclaim(self.size) {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max {
+            free.push(buf);
+        }
+    }
+}
@@ -1,8 +1,36 @@
 use bytes::{Buf, BufMut, BytesMut};
+use std::collections::BTreeMap;
 use thiserror::Error;
 
 pub const MAGIC: u32 = 0x51425553; // 'QBUS'
 pub const VERSION: u8 = 1;
+/// Oldest protocol version still taken, frames of older ones are refused
+pub const MIN_VERSION: u8 = 1;
+
+/// Largest frame body taken, bigger ones end the connection
+pub const MAX_FRAME: u32 = 64 << 20;
+
+/// Bits of the features in an `Op::Hello`
+/// produce bodies and consume answers compressed, see `FLAG_COMPRESSED`
+pub const FEATURE_COMPRESSION: u32 = 1 << 0;
+/// many messages per request, see `Op::Fetch`
+pub const FEATURE_BATCHING: u32 = 1 << 1;
+/// requests answered out of order by stream_id, see `Header`
+pub const FEATURE_STREAMING: u32 = 1 << 2;
+/// every body checksummed, see `FLAG_CHECKSUM`
+pub const FEATURE_CHECKSUM: u32 = 1 << 3;
+/// Features this build supports
+pub const FEATURES: u32 = FEATURE_COMPRESSION | FEATURE_BATCHING | FEATURE_STREAMING | FEATURE_CHECKSUM;
+
+/// Header flag: the body is lz4 compressed, see `compress`
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Header flag: the body ends with a CRC32 (u32) of what comes before it,
+/// counted in body_len, see `checksum`
+pub const FLAG_CHECKSUM: u8 = 0x02;
+
+/// Bodies smaller than this are sent as they are
+pub const COMPRESS_MIN: usize = 1024;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -12,6 +40,36 @@ pub enum Op {
     Consume = 0x03,
     Metadata = 0x04,
     Read = 0x05,
+    ResetOffset = 0x06,
+    Maintenance = 0x07,
+    Ack = 0x08,
+    Nack = 0x09,
+    CommitOffset = 0x0a,
+    Fetch = 0x0b,
+    DeleteTopic = 0x0c,
+    Purge = 0x0d,
+    ListTopics = 0x0e,
+    Stats = 0x0f,
+    Bind = 0x10,
+    Gossip = 0x11, // node to node, exchanges membership
+    Replicate = 0x12, // node to node, leader ships a topic's log to a follower
+    AddNode = 0x13,
+    RemoveNode = 0x14,
+    Ping = 0x15, // node to node health check, also answers with the health view
+    ClusterInfo = 0x16,
+    Auth = 0x17, // first request on a connection to a broker that wants credentials
+    Hello = 0x18, // first of all on a connection, agrees on the version and features to use
+    DeleteGroup = 0x19,
+    Unbind = 0x1a,
+    Begin = 0x1b, // starts a transaction, the connection's produces are staged until Commit or Abort
+    Commit = 0x1c,
+    Abort = 0x1d,
+    FetchOffset = 0x1e,
+    BindTopic = 0x1f, // copies messages produced to topics matching a pattern into a topic
+    UnbindTopic = 0x20,
+    Schedule = 0x21, // publishes a fixed message to a topic at the times a cron expression names
+    Unschedule = 0x22,
+    Audit = 0x23, // reads a topic's audit trail, see TopicConfig::audit
 }
 
 impl TryFrom<u8> for Op {
@@ -23,6 +81,135 @@ impl TryFrom<u8> for Op {
             0x03 => Op::Consume,
             0x04 => Op::Metadata,
             0x05 => Op::Read,
+            0x06 => Op::ResetOffset,
+            0x07 => Op::Maintenance,
+            0x08 => Op::Ack,
+            0x09 => Op::Nack,
+            0x0a => Op::CommitOffset,
+            0x0b => Op::Fetch,
+            0x0c => Op::DeleteTopic,
+            0x0d => Op::Purge,
+            0x0e => Op::ListTopics,
+            0x0f => Op::Stats,
+            0x10 => Op::Bind,
+            0x11 => Op::Gossip,
+            0x12 => Op::Replicate,
+            0x13 => Op::AddNode,
+            0x14 => Op::RemoveNode,
+            0x15 => Op::Ping,
+            0x16 => Op::ClusterInfo,
+            0x17 => Op::Auth,
+            0x18 => Op::Hello,
+            0x19 => Op::DeleteGroup,
+            0x1a => Op::Unbind,
+            0x1b => Op::Begin,
+            0x1c => Op::Commit,
+            0x1d => Op::Abort,
+            0x1e => Op::FetchOffset,
+            0x1f => Op::BindTopic,
+            0x20 => Op::UnbindTopic,
+            0x21 => Op::Schedule,
+            0x22 => Op::Unschedule,
+            0x23 => Op::Audit,
+            _ => return Err(ProtoError::InvalidOpcode(v)),
+        })
+    }
+}
+
+impl Op {
+    /// Whether the op brings new messages or topics onto the node, refused
+    /// with `Status::Maintenance` while the node is in maintenance mode.
+    /// A transaction's produces are staged, `Commit` is what writes them.
+    pub fn is_write(self) -> bool {
+        matches!(self, Op::Produce | Op::Commit | Op::CreateTopic | Op::Schedule)
+    }
+}
+
+/// What an `Op::Replicate` request carries, first byte of its body after the topic
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaOp {
+    /// config (json bytes) | handoff(u8, optional): start following the topic.
+    /// With handoff set the copy is not taken over until `Promote`.
+    Open = 0,
+    /// seq u64 | at_ms u64 | priority u8 | routing_key str | envelope bytes | payload bytes
+    Append = 1,
+    /// group str | committed u64
+    Commit = 2,
+    /// group str | key str | headers str | filter str, the binding's conditions
+    Bind = 3,
+    /// the topic was deleted, drop the copy
+    Drop = 4,
+    /// handoff done, the copy becomes the topic
+    Promote = 5,
+    /// group str
+    Unbind = 6,
+    /// group str
+    DeleteGroup = 7,
+}
+
+impl TryFrom<u8> for ReplicaOp {
+    type Error = ProtoError;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        Ok(match v {
+            0 => ReplicaOp::Open,
+            1 => ReplicaOp::Append,
+            2 => ReplicaOp::Commit,
+            3 => ReplicaOp::Bind,
+            4 => ReplicaOp::Drop,
+            5 => ReplicaOp::Promote,
+            6 => ReplicaOp::Unbind,
+            7 => ReplicaOp::DeleteGroup,
+            _ => return Err(ProtoError::InvalidOpcode(v)),
+        })
+    }
+}
+
+/// How far a produce has to get before it is answered, the last field of its body
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acks {
+    /// not answered at all
+    None = 0,
+    /// answered once the leader wrote it to its log
+    Leader = 1,
+    /// answered once a majority of the topic's replicas have it
+    Quorum = 2,
+}
+
+impl TryFrom<u8> for Acks {
+    type Error = ProtoError;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        Ok(match v {
+            0 => Acks::None,
+            1 => Acks::Leader,
+            2 => Acks::Quorum,
+            _ => return Err(ProtoError::InvalidOpcode(v)),
+        })
+    }
+}
+
+/// How one node sees another, as reported in a health view
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Alive = 0,
+    /// missed its last ping or heartbeat, still leads its topics
+    Suspect = 1,
+    /// quiet for too long, its topics moved to other nodes
+    Down = 2,
+    /// removed from the cluster
+    Left = 3,
+}
+
+impl TryFrom<u8> for NodeStatus {
+    type Error = ProtoError;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        Ok(match v {
+            0 => NodeStatus::Alive,
+            1 => NodeStatus::Suspect,
+            2 => NodeStatus::Down,
+            3 => NodeStatus::Left,
             _ => return Err(ProtoError::InvalidOpcode(v)),
         })
     }
@@ -36,11 +223,45 @@ pub enum Status {
     Empty = 11,
     TopicExists = 12,
     NotFound = 13,
+    NotEmpty = 14,
+    NotReplicated = 15, // written by the leader, too few replicas took it in time
+    QuotaExceeded = 16, // the namespace already has as many topics as it may, or the transaction messages
+    TooManyConnections = 17, // the broker is at its connection limit, try later or elsewhere
+    QueueFull = 18, // the topic is at capacity and rejects new messages, back off
+    Unavailable = 19, // the topic's leader can't be reached and nothing was written, try later
     BadRequest = 400,
+    Unauthorized = 401,
+    Throttled = 429, // the connection goes over its rate limit, slow down
     ServerError = 500,
+    Maintenance = 503, // broker is draining, produce elsewhere
 }
 
-#[derive(Debug, Error)]
+impl TryFrom<u16> for Status {
+    type Error = ProtoError;
+    fn try_from(v: u16) -> Result<Self, Self::Error> {
+        Ok(match v {
+            0 => Status::Ok,
+            10 => Status::Redirect,
+            11 => Status::Empty,
+            12 => Status::TopicExists,
+            13 => Status::NotFound,
+            14 => Status::NotEmpty,
+            15 => Status::NotReplicated,
+            16 => Status::QuotaExceeded,
+            17 => Status::TooManyConnections,
+            18 => Status::QueueFull,
+            19 => Status::Unavailable,
+            400 => Status::BadRequest,
+            401 => Status::Unauthorized,
+            429 => Status::Throttled,
+            500 => Status::ServerError,
+            503 => Status::Maintenance,
+            _ => return Err(ProtoError::InvalidStatus(v)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Error)]
 pub enum ProtoError {
     #[error("invalid magic: {0:#x}")]
     InvalidMagic(u32),
@@ -48,8 +269,16 @@ pub enum ProtoError {
     InvalidVersion(u8),
     #[error("invalid opcode: {0}")]
     InvalidOpcode(u8),
+    #[error("invalid status: {0}")]
+    InvalidStatus(u16),
     #[error("short frame")]
     Short,
+    #[error("frame too large: {0} bytes")]
+    TooLarge(u32),
+    #[error("corrupt compressed body")]
+    Corrupt,
+    #[error("checksum mismatch")]
+    Checksum,
 }
 
 /// 16B header: magic:u32 | ver:u8 | op:u8 | flags:u8 | rsvd:u8 | stream_id:u32 | body_len:u32
@@ -83,7 +312,7 @@ impl Header {
             return Err(ProtoError::InvalidMagic(magic));
         }
         let ver = cur.get_u8();
-        if ver != VERSION {
+        if !(MIN_VERSION..=VERSION).contains(&ver) {
             return Err(ProtoError::InvalidVersion(ver));
         }
         let op = Op::try_from(cur.get_u8())?;
@@ -103,6 +332,152 @@ impl Header {
     }
 }
 
+/// `body` lz4 compressed, its length first, if it's worth it: large enough
+/// and smaller for it
+pub fn compress(body: &[u8]) -> Option<Vec<u8>> {
+    if body.len() < COMPRESS_MIN {
+        return None;
+    }
+    let packed = lz4_flex::compress_prepend_size(body);
+    (packed.len() < body.len()).then_some(packed)
+}
+
+/// A body sent with `FLAG_COMPRESSED`, as it was before `compress`
+pub fn decompress(body: &[u8]) -> Result<Vec<u8>, ProtoError> {
+    let Some(len) = body.get(..4) else {
+        return Err(ProtoError::Short);
+    };
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]);
+    if len > MAX_FRAME {
+        return Err(ProtoError::TooLarge(len));
+    }
+    lz4_flex::decompress_size_prepended(body).map_err(|_| ProtoError::Corrupt)
+}
+
+/// What a body sent with `FLAG_CHECKSUM` ends with
+pub fn checksum(body: &[u8]) -> [u8; 4] {
+    crc32fast::hash(body).to_be_bytes()
+}
+
+/// Length of a body sent with `FLAG_CHECKSUM` without the checksum, if it
+/// matches. One that doesn't means the connection can't be trusted anymore.
+pub fn verify_checksum(body: &[u8]) -> Result<usize, ProtoError> {
+    let Some(len) = body.len().checked_sub(4) else {
+        return Err(ProtoError::Short);
+    };
+    if checksum(&body[..len]) != body[len..] {
+        return Err(ProtoError::Checksum);
+    }
+    Ok(len)
+}
+
+/// `partition` of a CreateTopic request that creates all of them, for
+/// requests with fields after it
+pub const ALL_PARTITIONS: u32 = u32::MAX;
+
+/// Name partition `p` of `topic` goes by in requests and on disk, `topic#p`.
+/// Partition 0 is the topic itself, so single-partition topics keep their name.
+pub fn partition_name(topic: &str, p: u32) -> String {
+    if p == 0 {
+        topic.to_string()
+    } else {
+        format!("{}#{}", topic, p)
+    }
+}
+
+/// Inverse of `partition_name`: (topic, partition)
+pub fn split_partition(name: &str) -> (&str, u32) {
+    match name.rsplit_once('#') {
+        Some((topic, p)) => match p.parse() {
+            Ok(p) if p > 0 => (topic, p),
+            _ => (name, 0),
+        },
+        None => (name, 0),
+    }
+}
+
+/// Name `topic` of namespace `ns` goes by in the registry and on disk, `ns/topic`
+pub fn namespaced(ns: &str, topic: &str) -> String {
+    format!("{}/{}", ns, topic)
+}
+
+/// Inverse of `namespaced`: (namespace, topic), no namespace for plain names
+pub fn split_namespace(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('/') {
+        Some((ns, topic)) => (Some(ns), topic),
+        None => (None, name),
+    }
+}
+
+/// Namespace names: letters, digits, `-` and `_`
+pub fn valid_namespace(ns: &str) -> bool {
+    !ns.is_empty() && ns.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Topic names are used as file names: letters, digits and `-_.#`, not
+/// starting with a dot, optionally after a namespace and a `/`
+pub fn valid_topic(name: &str) -> bool {
+    let (ns, topic) = split_namespace(name);
+    ns.is_none_or(valid_namespace)
+        && !topic.is_empty()
+        && !topic.starts_with('.')
+        && topic.chars().all(|c| c.is_ascii_alphanumeric() || "-_.#".contains(c))
+}
+
+/// Message properties carried alongside the payload from produce to consume
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// producer-chosen id, "" if none
+    pub message_id: String,
+    pub content_type: String,
+    /// unix ms, set to the enqueue time if the producer sends 0
+    pub timestamp_ms: u64,
+    pub headers: BTreeMap<String, String>,
+    /// topic an answer to the message goes to, "" if none
+    pub reply_to: String,
+    /// id an answer carries back to match it to its request, "" if none
+    pub correlation_id: String,
+}
+
+/// Header a dead-lettered message gets, naming the topic it was produced to
+pub const ORIGIN_HEADER: &str = "quique-origin-topic";
+
+/// Header a message published by a schedule gets, naming the schedule
+pub const SCHEDULE_HEADER: &str = "quique-schedule";
+
+/// Headers `Envelope::reply_to` and `Envelope::correlation_id` travel as,
+/// so logs and nodes that predate them keep them
+pub const REPLY_TO_HEADER: &str = "quique-reply-to";
+pub const CORRELATION_ID_HEADER: &str = "quique-correlation-id";
+
+impl Envelope {
+    /// `headers` with `reply_to` and `correlation_id` among them as they
+    /// travel, for protocols with nowhere else to put them
+    pub fn into_headers(mut self) -> BTreeMap<String, String> {
+        if !self.reply_to.is_empty() {
+            self.headers.insert(REPLY_TO_HEADER.to_string(), self.reply_to);
+        }
+        if !self.correlation_id.is_empty() {
+            self.headers.insert(CORRELATION_ID_HEADER.to_string(), self.correlation_id);
+        }
+        self.headers
+    }
+}
+
+/// What a consumer is told about a message besides its envelope, for retry
+/// and latency decisions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryInfo {
+    /// the envelope's message_id, or `<topic>:<seq>` if the producer set none
+    pub message_id: String,
+    /// unix ms the message was appended to the consumed topic
+    pub enqueued_ms: u64,
+    /// times it was handed out before and not acked
+    pub redeliveries: u32,
+    /// topic the message was produced to, the consumed one unless it was dead-lettered
+    pub topic: String,
+}
+
 // TLV helpers (string, bytes, u32)
 pub fn put_str(buf: &mut BytesMut, s: &str) {
     buf.put_u16(s.len() as u16);
@@ -126,6 +501,10 @@ pub fn put_bytes(buf: &mut BytesMut, v: &[u8]) {
     buf.extend_from_slice(v);
 }
 pub fn get_bytes(b: &mut &[u8]) -> Option<Vec<u8>> {
+    get_slice(b).map(<[u8]>::to_vec)
+}
+/// `get_bytes` without the copy, for `Bytes::slice_ref`
+pub fn get_slice<'a>(b: &mut &'a [u8]) -> Option<&'a [u8]> {
     if b.len() < 4 {
         return None;
     }
@@ -134,8 +513,8 @@ pub fn get_bytes(b: &mut &[u8]) -> Option<Vec<u8>> {
     if b.len() < n {
         return None;
     }
-    let v = b[..n].to_vec();
-    *b = &b[n..];
+    let (v, rest) = b.split_at(n);
+    *b = rest;
     Some(v)
 }
 pub fn put_u32(buf: &mut BytesMut, v: u32) {
@@ -149,6 +528,78 @@ pub fn get_u32(b: &mut &[u8]) -> Option<u32> {
     *b = &b[4..];
     Some(v)
 }
+pub fn put_u64(buf: &mut BytesMut, v: u64) {
+    buf.put_u64(v);
+}
+pub fn get_u64(b: &mut &[u8]) -> Option<u64> {
+    if b.len() < 8 {
+        return None;
+    }
+    let v = u64::from_be_bytes(b[..8].try_into().unwrap());
+    *b = &b[8..];
+    Some(v)
+}
+pub fn put_u8(buf: &mut BytesMut, v: u8) {
+    buf.put_u8(v);
+}
+pub fn get_u8(b: &mut &[u8]) -> Option<u8> {
+    let (&v, rest) = b.split_first()?;
+    *b = rest;
+    Some(v)
+}
 pub fn put_status(buf: &mut BytesMut, st: Status) {
     buf.put_u16(st as u16);
 }
+
+/// envelope: message_id(str) | content_type(str) | timestamp_ms(u64) | n(u32) | (key(str) | value(str))*
+/// with reply_to and correlation_id among the headers, see `REPLY_TO_HEADER`
+pub fn put_envelope(buf: &mut BytesMut, env: &Envelope) {
+    put_str(buf, &env.message_id);
+    put_str(buf, &env.content_type);
+    put_u64(buf, env.timestamp_ms);
+    let fields = [(REPLY_TO_HEADER, env.reply_to.as_str()), (CORRELATION_ID_HEADER, env.correlation_id.as_str())];
+    let fields: Vec<_> = fields.into_iter().filter(|(_, v)| !v.is_empty()).collect();
+    // a field that's set wins over a header of the same name
+    let headers: Vec<_> = env.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).filter(|(k, _)| !fields.iter().any(|(f, _)| f == k)).collect();
+    put_u32(buf, (headers.len() + fields.len()) as u32);
+    for (k, v) in headers.into_iter().chain(fields) {
+        put_str(buf, k);
+        put_str(buf, v);
+    }
+}
+pub fn get_envelope(b: &mut &[u8]) -> Option<Envelope> {
+    let message_id = get_str(b)?;
+    let content_type = get_str(b)?;
+    let timestamp_ms = get_u64(b)?;
+    let n = get_u32(b)?;
+    let mut headers = BTreeMap::new();
+    for _ in 0..n {
+        headers.insert(get_str(b)?, get_str(b)?);
+    }
+    let reply_to = headers.remove(REPLY_TO_HEADER).unwrap_or_default();
+    let correlation_id = headers.remove(CORRELATION_ID_HEADER).unwrap_or_default();
+    Some(Envelope {
+        message_id,
+        content_type,
+        timestamp_ms,
+        headers,
+        reply_to,
+        correlation_id,
+    })
+}
+
+/// delivery info: message_id(str) | enqueued_ms(u64) | redeliveries(u32) | topic(str)
+pub fn put_delivery_info(buf: &mut BytesMut, info: &DeliveryInfo) {
+    put_str(buf, &info.message_id);
+    put_u64(buf, info.enqueued_ms);
+    put_u32(buf, info.redeliveries);
+    put_str(buf, &info.topic);
+}
+pub fn get_delivery_info(b: &mut &[u8]) -> Option<DeliveryInfo> {
+    Some(DeliveryInfo {
+        message_id: get_str(b)?,
+        enqueued_ms: get_u64(b)?,
+        redeliveries: get_u32(b)?,
+        topic: get_str(b)?,
+    })
+}
@@ -1,20 +1,710 @@
-use crate::storage::disk_log::DiskLog;
+use crate::protocol::{Envelope, get_envelope, namespaced, put_envelope, split_namespace, split_partition};
+use crate::scheduler::Schedule;
+use crate::selector::Selector;
+use crate::storage::audit_log::{AuditEvent, AuditLog, AuditRecord};
+use crate::storage::disk_log::{DiskLog, LogEntry, StoredBinding};
+use crate::storage::metadata::BrokerMetadata;
 use anyhow::Result;
-use crossbeam_queue::ArrayQueue;
+use bytes::{Bytes, BytesMut};
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 // use seahash::hash;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::{Notify, oneshot};
+use tokio::time::Instant;
+
+/// Where `Topic::reset_offset` moves the consumer position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetReset {
+    /// redeliver everything still in the log
+    Earliest,
+    /// skip everything, only new messages are delivered
+    Latest,
+    /// redeliver what's still in the log from this seq on
+    Seq(u64),
+    /// redeliver what's still in the log from the first message enqueued
+    /// at or after this unix ms on
+    At(u64),
+}
+
+/// How produce picks the consumer groups that get a copy of a message.
+/// The default group always gets every message, as do groups never bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TopicKind {
+    /// every group, routing keys are ignored
+    #[default]
+    Fanout,
+    /// groups whose binding key equals the routing key
+    Direct,
+    /// groups whose binding pattern matches the routing key, dot separated
+    /// words with `*` for exactly one word and `#` for zero or more
+    Pattern,
+}
+
+/// What a named group is bound to, see `Topic::bind`
+#[derive(Debug, Clone, Default)]
+pub struct Binding {
+    /// routing key, or pattern of a `TopicKind::Pattern` topic
+    pub key: String,
+    /// conditions on headers a message has to meet as well
+    pub headers: HeaderMatch,
+    /// and a filter on them, if any
+    pub filter: Option<Selector>,
+    /// messages out of every `SAMPLE_ALL` the group gets, None for all
+    pub sample: Option<u32>,
+}
+
+/// Parts a binding's sample rate is given in, one million
+pub const SAMPLE_ALL: u32 = 1_000_000;
+
+impl Binding {
+    /// Whether a message with `headers` meets the conditions, whatever its key
+    fn selects(&self, headers: &BTreeMap<String, String>) -> bool {
+        self.headers.matches(headers) && self.filter.as_ref().is_none_or(|f| f.matches(headers))
+    }
+
+    /// Whether the message of `seq` is in the sample. Picked by a hash of
+    /// the seq, so a reload from the log picks the same ones, and a lower
+    /// rate picks some of those a higher one does.
+    fn samples(&self, seq: u64) -> bool {
+        let Some(rate) = self.sample else {
+            return true;
+        };
+        // splitmix64's finalizer
+        let mut h = seq;
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        h % u64::from(SAMPLE_ALL) < u64::from(rate)
+    }
+
+    fn stored(&self) -> StoredBinding {
+        StoredBinding {
+            key: self.key.clone(),
+            headers: self.headers.to_string(),
+            filter: self.filter.as_ref().map(|f| f.to_string()).unwrap_or_default(),
+            sample: self.sample.map(|s| s.to_string()).unwrap_or_default(),
+        }
+    }
+
+    /// Conditions were checked before they were stored, any that don't
+    /// parse anymore are dropped
+    fn from_stored(b: StoredBinding) -> Self {
+        Self {
+            key: b.key,
+            headers: HeaderMatch::parse(&b.headers).unwrap_or_default(),
+            filter: Some(b.filter).filter(|f| !f.is_empty()).and_then(|f| Selector::parse(&f).ok()),
+            sample: b.sample.parse().ok().filter(|&s| s < SAMPLE_ALL),
+        }
+    }
+}
+
+/// Header conditions of a binding: `name=value` terms joined by `AND`, like
+/// `region=eu AND type=refund`, met by messages that have every one of
+/// those headers with that value. No terms matches every message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMatch(Vec<(String, String)>);
+
+impl HeaderMatch {
+    /// None unless `s` is empty or terms as above. Terms have no spaces,
+    /// `AND` may be in any case.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut terms = Vec::new();
+        let mut words = s.split_whitespace().peekable();
+        while let Some(term) = words.next() {
+            let (name, value) = term.split_once('=').filter(|(n, _)| !n.is_empty())?;
+            terms.push((name.to_string(), value.to_string()));
+            if let Some(and) = words.next()
+                && (!and.eq_ignore_ascii_case("and") || words.peek().is_none())
+            {
+                return None;
+            }
+        }
+        Some(Self(terms))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, headers: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|(name, value)| headers.get(name) == Some(value))
+    }
+}
+
+impl std::fmt::Display for HeaderMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" AND ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What produce does with a message a consumer group has no room for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Overflow {
+    /// refuse it with `QueueFull` if any group that would get it is full
+    #[default]
+    Reject,
+    /// drop the group's next pending message to make room for it
+    DropHead,
+    /// hand it to the topic's dead letter topic, full groups miss it
+    DeadLetter,
+}
+
+/// Produce refused by a topic that is full, see `Overflow::Reject`
+#[derive(Debug, Error)]
+#[error("queue full: {groups:?}")]
+pub struct QueueFull {
+    /// groups that had no room, "" is the default group
+    pub groups: Vec<String>,
+}
+
+/// Produce of a message the topic already took, see `ProducerSeq`
+#[derive(Debug, Error)]
+#[error("duplicate of #{seq}")]
+pub struct Duplicate {
+    /// log seq the message got the first time
+    pub seq: u64,
+}
+
+/// Operation on a consumer group the topic doesn't have, see `Topic::has_group`
+#[derive(Debug, Error)]
+#[error("no group {0:?}")]
+pub struct UnknownGroup(pub String);
+
+/// Producer id and number of a message, the same on every retry of it.
+/// A topic takes each pair once among the last `DEDUP_WINDOW` it saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProducerSeq {
+    pub producer: u64,
+    pub seq: u64,
+}
+
+/// Producer seqs a topic remembers, retries of older ones are written again
+const DEDUP_WINDOW: usize = 100_000;
+
+/// Recent producer seqs of a topic and the log seqs they got
+#[derive(Default)]
+struct Dedup {
+    seen: HashMap<ProducerSeq, u64>,
+    /// oldest first
+    order: VecDeque<ProducerSeq>,
+}
+
+impl Dedup {
+    fn record(&mut self, id: ProducerSeq, seq: u64) {
+        if self.order.len() >= DEDUP_WINDOW
+            && let Some(old) = self.order.pop_front()
+        {
+            self.seen.remove(&old);
+        }
+        self.seen.insert(id, seq);
+        self.order.push_back(id);
+    }
+}
+
+/// Settings fixed at topic creation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicConfig {
+    pub capacity: usize,
+    /// highest priority accepted on produce, 0 = plain FIFO
+    pub max_priority: u8,
+    /// drop the topic after this long without any produce/consume
+    pub idle_ttl: Option<Duration>,
+    /// messages older than this are never delivered
+    pub message_ttl: Option<Duration>,
+    /// topic that receives expired messages, dropped if unset
+    pub dead_letter: Option<String>,
+    pub kind: TopicKind,
+    /// log segments older than this are deleted once consumed
+    pub retention: Option<Duration>,
+    /// oldest log segments are deleted once consumed while the log is larger
+    pub retention_bytes: Option<u64>,
+    /// copies of the log kept in the cluster, the leader's included.
+    /// 0 and 1 both mean the leader's only.
+    pub replicas: u8,
+    /// partitions of the topic this one is part of, 0 and 1 mean unpartitioned
+    pub partitions: u32,
+    /// URL the leader POSTs messages of the default group to, see `webhook`
+    pub webhook: Option<String>,
+    /// queues each priority level is split into, for many producers and
+    /// consumers at once. 0 and 1 mean one, the only way order is kept.
+    pub shards: u8,
+    /// what happens to messages produced while a group is at capacity
+    pub overflow: Overflow,
+    /// messages delivered but not acked for this long are queued again,
+    /// None leaves them in flight until the consumer's connection closes
+    pub visibility_timeout: Option<Duration>,
+    /// payload bytes each group holds pending at most, on top of `capacity`
+    /// messages. None counts messages only.
+    pub capacity_bytes: Option<u64>,
+    /// delete the topic once the last consumer that consumed from it is
+    /// gone, see `Topic::attach`
+    pub auto_delete: bool,
+    /// only the connection that created the topic consumes from it, and it's
+    /// deleted when that connection closes, see `Topic::claim`
+    pub exclusive: bool,
+    /// keep the log out of the way of a restart: not synced to disk, not
+    /// saved in metadata, and dropped on startup, see `DiskLog::open_transient`
+    pub transient: bool,
+    /// record what happens to each message in an audit trail next to the
+    /// log, see `AuditLog`
+    pub audit: bool,
+}
+
+/// Snapshot returned by `Topic::stats`, counters start at topic open
+#[derive(Debug, Clone, Copy)]
+pub struct TopicStats {
+    pub enqueued: u64,
+    pub delivered: u64,
+    pub depth: usize,
+    /// payload bytes of the pending messages
+    pub depth_bytes: u64,
+    pub peak_depth: usize,
+    pub in_flight: usize,
+    /// age of the oldest pending or in-flight message, 0 if there is none
+    pub oldest_age_ms: u64,
+}
+
+/// Payload and envelope, as produced and as delivered
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub payload: Bytes,
+    pub envelope: Envelope,
+}
+
+/// Message handed out by `Topic::dequeue`, in flight until settled by its tag
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// log seq of the message
+    pub tag: u64,
+    pub msg: Message,
+    /// unix ms the message was appended to the topic's log
+    pub enqueued_ms: u64,
+    /// times the message was handed out since load, this one included
+    pub deliveries: u32,
+}
+
+impl Delivery {
+    /// Whether the message was handed out before, and not acked
+    pub fn redelivered(&self) -> bool {
+        self.deliveries > 1
+    }
+}
+
+/// Change to a topic's log that its followers have to repeat
+pub enum ReplicaEvent {
+    /// answered with how many followers took the record, if someone waits for it
+    Append(LogEntry, Option<oneshot::Sender<usize>>),
+    Commit { group: String, seq: u64 },
+    Bind { group: String, binding: Binding },
+    Unbind { group: String },
+    DeleteGroup { group: String },
+    Drop,
+}
+
+/// Message held in memory: log seq, enqueue time, priority and message.
+/// The message is shared by every group it was pushed to.
+struct Entry {
+    seq: u64,
+    at_ms: u64,
+    priority: u8,
+    msg: Arc<Message>,
+    /// when it was last handed out, 0 if never
+    delivered_ms: u64,
+    /// times it was handed out, kept when it's queued again
+    deliveries: u32,
+}
+
+impl Entry {
+    /// What the entry counts against byte limits
+    fn size(&self) -> u64 {
+        self.msg.payload.len() as u64
+    }
+}
+
+/// Message appended to a topic's log by `Topic::write`, not queued yet
+struct Written {
+    seq: u64,
+    at_ms: u64,
+    priority: u8,
+    msg: Message,
+}
+
+/// Bounded queue with one FIFO per priority level, highest level pops first.
+/// A level split into shards is a FIFO per shard: messages go to the shard of
+/// their seq, and a pop starts at the next shard in turn, taking from the
+/// others if it's empty. Producers and consumers mostly lock different
+/// shards then, at the cost of order across them.
+struct Levels {
+    /// shards of each level
+    levels: Vec<Vec<Mutex<VecDeque<Entry>>>>,
+    /// shard the next pop starts at
+    next: AtomicUsize,
+    len: AtomicUsize,
+    cap: usize,
+    /// payload bytes of the pending messages, also counted in `MEMORY`
+    bytes: AtomicU64,
+    /// u64::MAX if the topic has no byte capacity
+    cap_bytes: u64,
+    /// high-water mark of `len`
+    peak: AtomicUsize,
+    /// signalled on every push, wakes long-polling consumers
+    ready: Notify,
+    /// signalled whenever a slot frees up, wakes producers waiting for room
+    room: Notify,
+}
+
+impl Levels {
+    fn new(cap: usize, cap_bytes: Option<u64>, max_priority: u8, shards: u8) -> Self {
+        let shards = shards.max(1) as usize;
+        Self {
+            levels: (0..=max_priority)
+                .map(|_| (0..shards).map(|_| Mutex::new(VecDeque::new())).collect())
+                .collect(),
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            cap,
+            bytes: AtomicU64::new(0),
+            cap_bytes: cap_bytes.unwrap_or(u64::MAX),
+            peak: AtomicUsize::new(0),
+            ready: Notify::new(),
+            room: Notify::new(),
+        }
+    }
+
+    fn push(&self, e: Entry) -> Result<(), Box<Entry>> {
+        // reserve a slot first so concurrent pushes can't overshoot cap
+        if !self.reserve(e.size()) {
+            return Err(Box::new(e));
+        }
+        self.place(e, false);
+        Ok(())
+    }
+
+    /// Put a delivered message back at the head of its level, for
+    /// redelivery. It was let in once, so it goes back even past the
+    /// group's and the broker's limits, which then hold off new messages.
+    fn requeue(&self, e: Entry) {
+        let size = e.size();
+        let prev = self.len.fetch_add(1, Ordering::AcqRel);
+        self.bytes.fetch_add(size, Ordering::AcqRel);
+        MEMORY.take_anyway(size);
+        self.peak.fetch_max(prev + 1, Ordering::Relaxed);
+        self.place(e, true);
+    }
+
+    /// Take a slot for a message of `size` payload bytes `place`d later,
+    /// false if there is none. Bytes are taken while below the group's and
+    /// the broker's limit, so one message may go past them.
+    fn reserve(&self, size: u64) -> bool {
+        let Ok(prev) = self
+            .len
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < self.cap).then_some(n + 1))
+        else {
+            return false;
+        };
+        let bytes = self
+            .bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |b| (b < self.cap_bytes).then_some(b + size));
+        if bytes.is_err() || !MEMORY.take(size) {
+            if bytes.is_ok() {
+                self.bytes.fetch_sub(size, Ordering::AcqRel);
+            }
+            self.len.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        self.peak.fetch_max(prev + 1, Ordering::Relaxed);
+        true
+    }
+
+    /// Give back a slot `reserve` took for nothing
+    fn release(&self, size: u64) {
+        self.len.fetch_sub(1, Ordering::AcqRel);
+        self.bytes.fetch_sub(size, Ordering::AcqRel);
+        MEMORY.give(size);
+        self.room.notify_waiters();
+    }
+
+    /// Queue `e` in a slot `reserve` took
+    fn place(&self, e: Entry, front: bool) {
+        let shards = &self.levels[(e.priority as usize).min(self.levels.len() - 1)];
+        let mut q = shards[e.seq as usize % shards.len()].lock().unwrap();
+        if front {
+            q.push_front(e);
+        } else {
+            q.push_back(e);
+        }
+        drop(q);
+        self.ready.notify_one();
+    }
+
+    fn pop(&self) -> Option<Entry> {
+        let start = match self.levels[0].len() {
+            1 => 0,
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let e = self.levels.iter().rev().find_map(|shards| {
+            (0..shards.len()).find_map(|i| shards[(start + i) % shards.len()].lock().unwrap().pop_front())
+        })?;
+        self.len.fetch_sub(1, Ordering::AcqRel);
+        self.bytes.fetch_sub(e.size(), Ordering::AcqRel);
+        MEMORY.give(e.size());
+        self.room.notify_waiters();
+        Some(e)
+    }
+
+    /// Enqueue time of the oldest pending message
+    fn oldest_at_ms(&self) -> Option<u64> {
+        self.levels
+            .iter()
+            .flatten()
+            .filter_map(|q| q.lock().unwrap().front().map(|e| e.at_ms))
+            .min()
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() >= self.cap || self.bytes.load(Ordering::Acquire) >= self.cap_bytes || MEMORY.is_exhausted()
+    }
+}
+
+impl Drop for Levels {
+    fn drop(&mut self) {
+        // pending messages of a deleted topic or group no longer take memory
+        MEMORY.give(*self.bytes.get_mut());
+    }
+}
+
+/// Payload bytes pending in the consumer groups of every topic in the
+/// process, a message counted once per group it's queued for, against the
+/// broker-wide limit. A group with room left is still full while the
+/// broker is at its limit.
+pub struct MemoryBudget {
+    used: AtomicU64,
+    /// 0 = unlimited
+    limit: AtomicU64,
+    /// signalled whenever bytes are given back, wakes producers waiting for room
+    freed: Notify,
+}
+
+/// The process' memory budget, a server sets its limit from `--max-memory-bytes`
+pub static MEMORY: MemoryBudget = MemoryBudget::new();
+
+impl MemoryBudget {
+    const fn new() -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            limit: AtomicU64::new(0),
+            freed: Notify::const_new(),
+        }
+    }
+
+    /// None lifts the limit. Lowering it below what's used drops nothing,
+    /// produces get room again once consumers took enough.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        self.freed.notify_waiters();
+    }
+
+    /// Payload bytes pending now
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|&l| l > 0)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.limit().is_some_and(|l| self.used() >= l)
+    }
+
+    fn take(&self, size: u64) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |b| (limit == 0 || b < limit).then_some(b + size))
+            .is_ok()
+    }
+
+    /// Count `size` bytes whatever the limit
+    fn take_anyway(&self, size: u64) {
+        self.used.fetch_add(size, Ordering::AcqRel);
+    }
+
+    fn give(&self, size: u64) {
+        if size > 0 {
+            self.used.fetch_sub(size, Ordering::AcqRel);
+            self.freed.notify_waiters();
+        }
+    }
+}
 
 pub struct Topic {
     pub name: String,
-    mem: Arc<ArrayQueue<(u64, Vec<u8>)>>,
     wal: Arc<DiskLog>,
+    cfg: TopicConfig,
+    last_active_ms: AtomicU64,
+    /// messages accepted by enqueue since open
+    enqueued: AtomicU64,
+    /// consumer groups by name, "" is the default group. Every group gets
+    /// its own copy of each message, consumers within a group split them.
+    groups: RwLock<HashMap<String, Arc<Group>>>,
+    /// set once the topic has followers, gets every log change in log order
+    followers: Mutex<Option<UnboundedSender<ReplicaEvent>>>,
+    /// in memory only, a new leader starts with an empty window
+    dedup: Mutex<Dedup>,
+    /// sessions attached as consumers, for `auto_delete`
+    consumers: AtomicUsize,
+    /// set once the last consumer of an `auto_delete` topic detached, or
+    /// the owner of an `exclusive` one is gone
+    abandoned: AtomicBool,
+    /// session id of the owner of an `exclusive` topic, 0 = none
+    owner: AtomicU64,
+    /// set if the topic is created with `audit`
+    audit: Option<AuditLog>,
 }
+
+/// Delivery state of one consumer group
+struct Group {
+    name: String,
+    mem: Levels,
+    inflight: Mutex<Inflight>,
+    /// messages handed out by dequeue since load, redeliveries included
+    delivered: AtomicU64,
+    /// set by `Topic::bind`, None receives everything
+    binding: RwLock<Option<Binding>>,
+}
+
+impl Group {
+    /// Load everything past the group's committed offset from the log
+    fn load(wal: &DiskLog, cfg: &TopicConfig, name: &str) -> Result<Self> {
+        let binding = if name.is_empty() {
+            None
+        } else {
+            wal.read_binding(name)?.map(Binding::from_stored)
+        };
+        let mem = Levels::new(cfg.capacity, cfg.capacity_bytes, cfg.max_priority, cfg.shards);
+        let mut inflight = Inflight {
+            committed: wal.read_acked(name)?,
+            ..Default::default()
+        };
+        load_unacked(wal, &mem, &mut inflight, |seq, key, headers| {
+            routes(cfg.kind, binding.as_ref(), key, headers) && binding.as_ref().is_none_or(|b| b.samples(seq))
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            mem,
+            inflight: Mutex::new(inflight),
+            delivered: AtomicU64::new(0),
+            binding: RwLock::new(binding),
+        })
+    }
+
+    fn accepts(&self, kind: TopicKind, routing_key: &str, headers: &BTreeMap<String, String>) -> bool {
+        routes(kind, self.binding.read().unwrap().as_ref(), routing_key, headers)
+    }
+
+    /// Whether the message of `seq` is in the sample of its binding, if it
+    /// has one
+    fn samples(&self, seq: u64) -> bool {
+        self.binding.read().unwrap().as_ref().is_none_or(|b| b.samples(seq))
+    }
+
+    /// Whether records the group had no room for are left in the log
+    fn is_behind(&self) -> bool {
+        self.inflight.lock().unwrap().backlog.is_some()
+    }
+}
+
+/// Delivered-but-unacked messages and the committed (acked) offset
+#[derive(Default)]
+struct Inflight {
+    /// by delivery tag (= log seq), kept for redelivery
+    msgs: BTreeMap<u64, Entry>,
+    /// every seq <= committed is acked and persisted in the ack file
+    committed: u64,
+    /// acked seqs above `committed`, waiting for the gap below to close
+    acked: BTreeSet<u64>,
+    /// last seq read from the log, set while the records past it didn't
+    /// fit in memory. They are read again once the queue drained, and new
+    /// messages wait in the log behind them.
+    backlog: Option<u64>,
+}
+
+impl Inflight {
+    fn reset(&mut self, committed: u64) {
+        self.msgs.clear();
+        self.acked.clear();
+        self.committed = committed;
+        self.backlog = None;
+    }
+
+    /// Mark `seq` done, returns true if the committed offset moved
+    fn settle(&mut self, seq: u64) -> bool {
+        if seq <= self.committed {
+            return false;
+        }
+        self.acked.insert(seq);
+        let before = self.committed;
+        while self.acked.remove(&(self.committed + 1)) {
+            self.committed += 1;
+        }
+        self.committed != before
+    }
+
+    /// Mark `from..to` done, for seqs that are missing from the log
+    fn settle_gap(&mut self, from: u64, to: u64) {
+        if from >= to {
+            return;
+        }
+        if self.committed + 1 >= from {
+            self.settle_through(to - 1);
+        } else {
+            for seq in from..to {
+                self.settle(seq);
+            }
+        }
+    }
+
+    /// Mark everything up to `seq` done, returns true if the committed offset moved
+    fn settle_through(&mut self, seq: u64) -> bool {
+        if seq <= self.committed {
+            return false;
+        }
+        self.msgs = self.msgs.split_off(&(seq + 1));
+        self.acked = self.acked.split_off(&(seq + 1));
+        self.committed = seq;
+        while self.acked.remove(&(self.committed + 1)) {
+            self.committed += 1;
+        }
+        true
+    }
+}
+
 impl Topic {
     pub fn open(
         data_dir: &str,
         name: &str,
-        cap: usize,
+        cfg: TopicConfig,
         is_leader_fn: impl Fn() -> bool,
     ) -> Result<Self> {
         // We still need to check if this node is a leader for the topic.
@@ -24,66 +714,1559 @@ impl Topic {
             // but for this simplification, we tie the Topic's storage to leadership.
             return Err(anyhow::anyhow!("Not a leader for this topic"));
         }
+        let wal = open_log(data_dir, name, &cfg)?;
+        Self::load(name, cfg, Arc::new(wal))
+    }
 
-        let wal = Arc::new(DiskLog::open(data_dir, name)?);
-        let mem = Arc::new(ArrayQueue::new(cap));
+    /// Take over a topic this node followed, from its copy of the log
+    pub fn promote(replica: &Replica) -> Result<Self> {
+        Self::load(&replica.name, replica.cfg.clone(), Arc::new(replica.wal.clone()))
+    }
 
-        let mut entries = wal.replay_unacked()?;
-        entries.sort_by_key(|(s, _)| *s);
-        for (seq, payload) in entries {
-            if mem.push((seq, payload)).is_err() {
-                break;
-            }
+    fn load(name: &str, cfg: TopicConfig, wal: Arc<DiskLog>) -> Result<Self> {
+        let default = Group::load(&wal, &cfg, "")?;
+        let mut groups = HashMap::from([(String::new(), Arc::new(default))]);
+        // groups known from a previous run get their backlog right away
+        for name in wal.group_names()? {
+            let g = Group::load(&wal, &cfg, &name)?;
+            groups.insert(name, Arc::new(g));
         }
+        let audit = if cfg.audit { Some(wal.open_audit()?) } else { None };
 
         Ok(Self {
             name: name.to_string(),
-            mem,
             wal,
+            cfg,
+            last_active_ms: AtomicU64::new(now_ms()),
+            enqueued: AtomicU64::new(0),
+            groups: RwLock::new(groups),
+            followers: Mutex::new(None),
+            dedup: Mutex::new(Dedup::default()),
+            consumers: AtomicUsize::new(0),
+            abandoned: AtomicBool::new(false),
+            owner: AtomicU64::new(0),
+            audit,
         })
     }
 
-    pub fn enqueue(&self, val: Vec<u8>) -> Result<u64> {
-        let seq = self.wal.append(&val)?; // durable
-        self.mem
-            .push((seq, val))
-            .map_err(|_| anyhow::anyhow!("Queue full"))?;
+    /// Start recording log changes for followers. Changes made before this
+    /// are not replayed.
+    pub fn replicate(&self) -> UnboundedReceiver<ReplicaEvent> {
+        let (tx, rx) = unbounded_channel();
+        *self.followers.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    fn to_followers(&self, ev: ReplicaEvent) {
+        if let Some(tx) = &*self.followers.lock().unwrap() {
+            let _ = tx.send(ev);
+        }
+    }
+
+    /// Persist the committed offset of `group` and pass it on to followers
+    fn write_acked(&self, group: &str, seq: u64) -> Result<()> {
+        self.wal.write_acked(group, seq)?;
+        self.to_followers(ReplicaEvent::Commit {
+            group: group.to_string(),
+            seq,
+        });
+        Ok(())
+    }
+
+    /// `priority` above the topic's max priority is clamped to it.
+    /// Named groups only get a copy if they accept `routing_key`.
+    /// A message some group had no room for goes to `on_overflow` with the
+    /// dead letter overflow policy, and fails with `QueueFull` with reject.
+    /// A message with the `id` of one taken before fails with `Duplicate`.
+    pub fn enqueue(
+        &self,
+        msg: Message,
+        priority: u8,
+        routing_key: &str,
+        id: Option<ProducerSeq>,
+        on_overflow: impl FnOnce(Message),
+    ) -> Result<u64> {
+        self.push(msg, priority, routing_key, id, None, on_overflow)
+    }
+
+    /// Like `enqueue`, also answers how many followers took the message.
+    /// The receiver fails right away if the topic isn't replicated.
+    pub fn enqueue_replicated(
+        &self,
+        msg: Message,
+        priority: u8,
+        routing_key: &str,
+        id: Option<ProducerSeq>,
+        on_overflow: impl FnOnce(Message),
+    ) -> Result<(u64, oneshot::Receiver<usize>)> {
+        let (tx, rx) = oneshot::channel();
+        let seq = self.push(msg, priority, routing_key, id, Some(tx), on_overflow)?;
+        Ok((seq, rx))
+    }
+
+    fn push(
+        &self,
+        msg: Message,
+        priority: u8,
+        routing_key: &str,
+        id: Option<ProducerSeq>,
+        copies: Option<oneshot::Sender<usize>>,
+        on_overflow: impl FnOnce(Message),
+    ) -> Result<u64> {
+        self.touch();
+        // held until the append, so a retry racing the first try isn't written too
+        let mut dedup = match id {
+            Some(id) => {
+                let dedup = self.dedup.lock().unwrap();
+                if let Some(&seq) = dedup.seen.get(&id) {
+                    return Err(Duplicate { seq }.into());
+                }
+                Some(dedup)
+            }
+            None => None,
+        };
+        // read lock: a group being loaded from the log must not miss this append
+        let groups = self.groups.read().unwrap();
+        let (targets, skipped) = self.route(&groups, routing_key, &msg.envelope.headers);
+        let size = msg.payload.len() as u64;
+        let reserved = self.reserve_room(&targets, size)?;
+        let w = match self.write(msg, priority, routing_key, copies) {
+            Ok(w) => w,
+            Err(e) => {
+                if reserved {
+                    release_room(&targets, size);
+                }
+                return Err(e);
+            }
+        };
+        let seq = w.seq;
+        if let (Some(dedup), Some(id)) = (&mut dedup, id) {
+            dedup.record(id, seq);
+        }
+        drop(dedup);
+        let overflowed = self.queue(w, &targets, &skipped, reserved)?;
+        // the dead letter topic may be this one
+        drop(groups);
+        if let Some(m) = overflowed {
+            on_overflow(m);
+        }
         Ok(seq)
     }
 
-    pub fn dequeue(&self) -> Result<Option<Vec<u8>>> {
-        if let Some((seq, v)) = self.mem.pop() {
-            self.wal.write_acked(seq)?;
-            return Ok(Some(v));
+    /// (groups that get a message with `routing_key` and `headers`, groups
+    /// that don't). Groups behind the log are in neither, they read the
+    /// message from it once they caught up.
+    fn route<'g>(
+        &self,
+        groups: &'g HashMap<String, Arc<Group>>,
+        routing_key: &str,
+        headers: &BTreeMap<String, String>,
+    ) -> (Vec<&'g Arc<Group>>, Vec<&'g Arc<Group>>) {
+        groups
+            .values()
+            .filter(|g| !g.is_behind())
+            .partition(|g| g.name.is_empty() || g.accepts(self.cfg.kind, routing_key, headers))
+    }
+
+    /// With `Overflow::Reject`, take a slot in each of `targets` for a
+    /// message of `size` payload bytes, or none and fail with `QueueFull`.
+    /// Returns whether it did.
+    fn reserve_room(&self, targets: &[&Arc<Group>], size: u64) -> Result<bool> {
+        // rejected before it's written: every group that gets it needs room
+        if self.cfg.overflow != Overflow::Reject {
+            return Ok(false);
+        }
+        let full: Vec<String> = targets.iter().filter(|g| !g.mem.reserve(size)).map(|g| g.name.clone()).collect();
+        if !full.is_empty() {
+            for g in targets.iter().filter(|g| !full.contains(&g.name)) {
+                g.mem.release(size);
+            }
+            return Err(QueueFull { groups: full }.into());
+        }
+        Ok(true)
+    }
+
+    /// Append a message to the log and pass it on to followers, not queued yet
+    fn write(
+        &self,
+        mut msg: Message,
+        priority: u8,
+        routing_key: &str,
+        copies: Option<oneshot::Sender<usize>>,
+    ) -> Result<Written> {
+        let at_ms = now_ms();
+        let priority = priority.min(self.cfg.max_priority);
+        if msg.envelope.timestamp_ms == 0 {
+            msg.envelope.timestamp_ms = at_ms;
+        }
+        let mut env = BytesMut::new();
+        put_envelope(&mut env, &msg.envelope);
+        // held across the append so followers get records in seq order
+        let followers = self.followers.lock().unwrap();
+        let seq = self.wal.append(at_ms, priority, routing_key, &env, &msg.payload)?; // durable
+        if let Some(tx) = &*followers {
+            let entry = LogEntry {
+                seq,
+                at_ms,
+                priority,
+                routing_key: routing_key.to_string(),
+                envelope: env.to_vec(),
+                payload: msg.payload.clone(),
+            };
+            let _ = tx.send(ReplicaEvent::Append(entry, copies));
+        }
+        drop(followers);
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
+        Ok(Written {
+            seq,
+            at_ms,
+            priority,
+            msg,
+        })
+    }
+
+    /// Queue a written message on `targets`, in the slots `reserve_room`
+    /// took if `reserved`. Returns it if a group had no room for it and the
+    /// topic dead letters overflow.
+    fn queue(&self, w: Written, targets: &[&Arc<Group>], skipped: &[&Arc<Group>], reserved: bool) -> Result<Option<Message>> {
+        // sampled once the seq is known, room was taken for the whole message
+        let (targets, unsampled): (Vec<&Arc<Group>>, Vec<&Arc<Group>>) = targets.iter().partition(|g| g.samples(w.seq));
+        if reserved {
+            release_room(&unsampled, w.msg.payload.len() as u64);
+        }
+        for g in skipped.iter().chain(&unsampled) {
+            // not persisted: a reload from the log skips it again
+            g.inflight.lock().unwrap().settle(w.seq);
+        }
+        // one copy of the message however many groups it fans out to
+        let shared = Arc::new(w.msg);
+        let mut overflowed = false;
+        for g in &targets {
+            let e = Entry {
+                seq: w.seq,
+                at_ms: w.at_ms,
+                priority: w.priority,
+                msg: shared.clone(),
+                delivered_ms: 0,
+                deliveries: 0,
+            };
+            if reserved {
+                g.mem.place(e, false);
+            } else if !self.admit(g, e)? {
+                tracing::debug!("group {} of {} is full, skipping #{}", g.name, self.name, w.seq);
+                overflowed = true;
+            }
+        }
+        Ok((overflowed && self.cfg.overflow == Overflow::DeadLetter).then(|| Arc::unwrap_or_clone(shared)))
+    }
+
+    /// Give up on a written message that won't be queued: it stays in the
+    /// log, settled for every group
+    fn discard(&self, w: &Written, targets: &[&Arc<Group>], skipped: &[&Arc<Group>], reserved: bool) -> Result<()> {
+        if reserved {
+            release_room(targets, w.msg.payload.len() as u64);
+        }
+        for g in targets.iter().chain(skipped) {
+            self.settle(g, w.seq)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until each of `groups`, named by a `QueueFull`, has room for a
+    /// message. False if `deadline` passed first. Another producer may take
+    /// the room before the message is produced again.
+    pub async fn wait_for_room(&self, groups: &[String], deadline: Instant) -> bool {
+        for name in groups {
+            // a group deleted meanwhile has no limit anymore
+            let Some(g) = self.groups.read().unwrap().get(name).cloned() else {
+                continue;
+            };
+            loop {
+                // register before checking, so a pop in between isn't missed
+                let room = g.mem.room.notified();
+                let freed = MEMORY.freed.notified();
+                tokio::pin!(room, freed);
+                room.as_mut().enable();
+                freed.as_mut().enable();
+                if !g.mem.is_full() {
+                    break;
+                }
+                let either = async {
+                    tokio::select! {
+                        _ = room => {}
+                        _ = freed => {}
+                    }
+                };
+                if tokio::time::timeout_at(deadline, either).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Queue `e` on `g`, dropping its next pending messages for room with
+    /// `Overflow::DropHead`. Returns false if `e` was left out, settled.
+    fn admit(&self, g: &Group, e: Entry) -> Result<bool> {
+        let seq = e.seq;
+        let mut e = match g.mem.push(e) {
+            Ok(()) => return Ok(true),
+            Err(e) => e,
+        };
+        if self.cfg.overflow == Overflow::DropHead {
+            // a concurrent push can take the slot freed, then drop another
+            while let Some(head) = g.mem.pop() {
+                tracing::debug!("group {} of {} is full, dropping #{}", g.name, self.name, head.seq);
+                self.settle(g, head.seq)?;
+                match g.mem.push(*e) {
+                    Ok(()) => return Ok(true),
+                    Err(back) => e = back,
+                }
+            }
+        }
+        self.settle(g, seq)?;
+        Ok(false)
+    }
+
+    /// Get a consumer group, loading it from the log on first use
+    fn group(&self, name: &str) -> Result<Arc<Group>> {
+        if let Some(g) = self.groups.read().unwrap().get(name) {
+            return Ok(g.clone());
+        }
+        let mut groups = self.groups.write().unwrap();
+        if let Some(g) = groups.get(name) {
+            return Ok(g.clone());
+        }
+        let g = Arc::new(Group::load(&self.wal, &self.cfg, name)?);
+        groups.insert(name.to_string(), g.clone());
+        Ok(g)
+    }
+
+    /// Get a consumer group that exists, failing with `UnknownGroup` instead
+    /// of loading it
+    fn existing_group(&self, name: &str) -> Result<Arc<Group>> {
+        let g = self.groups.read().unwrap().get(name).cloned();
+        g.ok_or_else(|| UnknownGroup(name.to_string()).into())
+    }
+
+    /// Bind a named group to `binding`. Only messages produced from now on
+    /// are routed by it, the group's pending messages stay queued.
+    pub fn bind(&self, group: &str, binding: &Binding) -> Result<()> {
+        if group.is_empty() {
+            return Err(anyhow::anyhow!("the default group cannot be bound"));
+        }
+        self.touch();
+        self.wal.write_binding(group, &binding.stored())?;
+        self.to_followers(ReplicaEvent::Bind {
+            group: group.to_string(),
+            binding: binding.clone(),
+        });
+        let g = self.group(group)?;
+        *g.binding.write().unwrap() = Some(binding.clone());
+        Ok(())
+    }
+
+    /// Let a named group get every message produced from now on again
+    pub fn unbind(&self, group: &str) -> Result<()> {
+        if group.is_empty() {
+            return Err(anyhow::anyhow!("the default group cannot be unbound"));
+        }
+        self.touch();
+        let g = self.group(group)?;
+        self.wal.remove_binding(group)?;
+        self.to_followers(ReplicaEvent::Unbind { group: group.to_string() });
+        *g.binding.write().unwrap() = None;
+        Ok(())
+    }
+
+    /// Drop a named group with its pending and in-flight messages, its
+    /// committed offset and binding. With `if_empty` a group that still has
+    /// messages is kept, and false returned. Consuming from it again
+    /// starts a new group at the beginning of the log.
+    pub fn delete_group(&self, group: &str, if_empty: bool) -> Result<bool> {
+        if group.is_empty() {
+            return Err(anyhow::anyhow!("the default group cannot be deleted"));
+        }
+        self.touch();
+        // no appends to it while it goes
+        let mut groups = self.groups.write().unwrap();
+        let Some(g) = groups.get(group) else {
+            return Ok(true);
+        };
+        if if_empty && (!g.mem.is_empty() || !g.inflight.lock().unwrap().msgs.is_empty() || g.is_behind()) {
+            return Ok(false);
+        }
+        groups.remove(group);
+        self.wal.remove_group(group)?;
+        self.to_followers(ReplicaEvent::DeleteGroup { group: group.to_string() });
+        Ok(true)
+    }
+
+    /// Whether `group` exists: the default one, or a named one that was
+    /// consumed from or bound
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.read().unwrap().contains_key(group)
+    }
+
+    /// Pop the next message of `group`. It stays in flight until `ack`ed,
+    /// or goes back to the queue on `nack` or after the visibility timeout.
+    /// Messages past the topic's message ttl are skipped and handed to `on_expired`.
+    pub fn dequeue(&self, group: &str, mut on_expired: impl FnMut(Message)) -> Result<Option<Delivery>> {
+        // an empty poll still counts as activity: someone is consuming
+        self.touch();
+        let g = self.group(group)?;
+        if g.mem.is_empty() && g.is_behind() {
+            self.refill(&g)?;
+        }
+        while let Some(mut e) = g.mem.pop() {
+            let mut st = g.inflight.lock().unwrap();
+            if e.seq <= st.committed {
+                // already committed past it via commit_offset
+                continue;
+            }
+            if self.is_expired(&e) {
+                drop(st);
+                let seq = e.seq;
+                on_expired(Arc::unwrap_or_clone(e.msg));
+                self.settle(&g, seq)?;
+                continue;
+            }
+            e.delivered_ms = now_ms();
+            e.deliveries += 1;
+            let out = Delivery {
+                tag: e.seq,
+                msg: Message::clone(&e.msg),
+                enqueued_ms: e.at_ms,
+                deliveries: e.deliveries,
+            };
+            st.msgs.insert(e.seq, e);
+            g.delivered.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(out));
         }
         Ok(None)
     }
 
-    pub fn read_last_n(&self, n: usize) -> Result<Vec<Vec<u8>>> {
-        let messages = self.wal.read_last_n(n)?;
-        Ok(messages)
+    /// Like `dequeue`, but waits up to `timeout` for a message to arrive
+    pub async fn dequeue_wait(
+        &self,
+        group: &str,
+        timeout: Duration,
+        mut on_expired: impl FnMut(Message),
+    ) -> Result<Option<Delivery>> {
+        let g = self.group(group)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            // register before checking, so a push in between isn't missed
+            let ready = g.mem.ready.notified();
+            if let Some(m) = self.dequeue(group, &mut on_expired)? {
+                return Ok(Some(m));
+            }
+            if tokio::time::timeout_at(deadline, ready).await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the first message, then take whatever else is
+    /// ready until `max_messages` or `max_bytes` is reached. The byte budget is
+    /// checked before each pop, so the last message may overshoot it.
+    pub async fn fetch(
+        &self,
+        group: &str,
+        timeout: Duration,
+        max_messages: usize,
+        max_bytes: usize,
+        mut on_expired: impl FnMut(Message),
+    ) -> Result<Vec<Delivery>> {
+        let mut out = Vec::new();
+        let Some(first) = self.dequeue_wait(group, timeout, &mut on_expired).await? else {
+            return Ok(out);
+        };
+        let mut bytes = first.msg.payload.len();
+        out.push(first);
+        while out.len() < max_messages && bytes < max_bytes {
+            let Some(m) = self.dequeue(group, &mut on_expired)? else {
+                break;
+            };
+            bytes += m.msg.payload.len();
+            out.push(m);
+        }
+        Ok(out)
+    }
+
+    /// Queue what fits of the records `g` left in the log
+    fn refill(&self, g: &Group) -> Result<()> {
+        // no appends until it's known whether the group caught up
+        let _groups = self.groups.write().unwrap();
+        let mut st = g.inflight.lock().unwrap();
+        if st.backlog.is_some() {
+            self.reload(g, &mut st)?;
+        }
+        Ok(())
+    }
+
+    /// Queue the records of `g` past its committed offset, or past what was
+    /// read of its backlog. The caller holds the groups' write lock.
+    fn reload(&self, g: &Group, st: &mut Inflight) -> Result<()> {
+        let binding = g.binding.read().unwrap();
+        load_unacked(&self.wal, &g.mem, st, |seq, key, headers| {
+            routes(self.cfg.kind, binding.as_ref(), key, headers) && binding.as_ref().is_none_or(|b| b.samples(seq))
+        })
+    }
+
+    fn is_expired(&self, e: &Entry) -> bool {
+        let Some(ttl) = self.cfg.message_ttl else {
+            return false;
+        };
+        now_ms().saturating_sub(e.at_ms) >= ttl.as_millis() as u64
+    }
+
+    pub fn dead_letter(&self) -> Option<&str> {
+        self.cfg.dead_letter.as_deref()
+    }
+
+    /// Returns false if `tag` is not in flight
+    pub fn ack(&self, group: &str, tag: u64) -> Result<bool> {
+        self.touch();
+        // nothing is in flight for a group that isn't there, it's not created
+        let Some(g) = self.groups.read().unwrap().get(group).cloned() else {
+            return Ok(false);
+        };
+        let mut st = g.inflight.lock().unwrap();
+        if st.msgs.remove(&tag).is_none() {
+            return Ok(false);
+        }
+        if st.settle(tag) {
+            self.write_acked(&g.name, st.committed)?;
+        }
+        Ok(true)
+    }
+
+    /// Put an in-flight message back on the queue for redelivery.
+    /// Returns false if `tag` is not in flight.
+    pub fn nack(&self, group: &str, tag: u64) -> Result<bool> {
+        self.touch();
+        let Some(g) = self.groups.read().unwrap().get(group).cloned() else {
+            return Ok(false);
+        };
+        let mut st = g.inflight.lock().unwrap();
+        let Some(e) = st.msgs.remove(&tag) else {
+            return Ok(false);
+        };
+        // keeps its original enqueue time, the ttl is not restarted
+        g.mem.requeue(e);
+        Ok(true)
+    }
+
+    /// Queue again the messages of every group that were in flight for
+    /// longer than the topic's visibility timeout, ahead of those pending.
+    /// Returns how many were queued again.
+    pub fn redeliver_unacked(&self) -> usize {
+        let Some(timeout) = self.cfg.visibility_timeout else {
+            return 0;
+        };
+        let cutoff = now_ms().saturating_sub(timeout.as_millis() as u64);
+        let groups: Vec<Arc<Group>> = self.groups.read().unwrap().values().cloned().collect();
+        let mut n = 0;
+        for g in groups {
+            let mut st = g.inflight.lock().unwrap();
+            let due: Vec<u64> = st
+                .msgs
+                .iter()
+                .filter(|(_, e)| e.delivered_ms <= cutoff)
+                .map(|(&seq, _)| seq)
+                .collect();
+            // newest first, so the oldest ends up at the head
+            for seq in due.into_iter().rev() {
+                let e = st.msgs.remove(&seq).unwrap();
+                g.mem.requeue(e);
+                n += 1;
+            }
+        }
+        n
     }
 
+    /// Mark every message up to and including `seq` as processed by `group`,
+    /// whether it was delivered yet or not
+    pub fn commit_offset(&self, group: &str, seq: u64) -> Result<()> {
+        self.touch();
+        let g = self.existing_group(group)?;
+        let mut st = g.inflight.lock().unwrap();
+        if st.settle_through(seq.min(self.wal.last_seq())) {
+            self.write_acked(&g.name, st.committed)?;
+        }
+        Ok(())
+    }
+
+    fn settle(&self, g: &Group, seq: u64) -> Result<()> {
+        let mut st = g.inflight.lock().unwrap();
+        if st.settle(seq) {
+            self.write_acked(&g.name, st.committed)?;
+        }
+        Ok(())
+    }
+
+    /// Move the committed position of `group` to the start or end of the log,
+    /// or to just before a seq or a time, and reload its queue from there.
+    /// Returns pending message count, records left in the log for lack of
+    /// room counted whether the group's binding takes them or not.
+    pub fn reset_offset(&self, group: &str, to: OffsetReset) -> Result<usize> {
+        self.touch();
+        let g = self.existing_group(group)?;
+        // no appends while the queue is rebuilt from the log
+        let _groups = self.groups.write().unwrap();
+        let mut st = g.inflight.lock().unwrap();
+        while g.mem.pop().is_some() {}
+        let committed = match to {
+            OffsetReset::Earliest => 0,
+            OffsetReset::Latest => self.wal.last_seq(),
+            OffsetReset::Seq(seq) => seq.saturating_sub(1).min(self.wal.last_seq()),
+            OffsetReset::At(ms) => self.wal.seq_at(ms)? - 1,
+        };
+        self.write_acked(&g.name, committed)?;
+        st.reset(committed);
+        if to != OffsetReset::Latest {
+            self.reload(&g, &mut st)?;
+        }
+        let behind = st.backlog.map_or(0, |seq| self.wal.last_seq() - seq);
+        Ok(g.mem.len() + behind as usize)
+    }
+
+    /// Drop every pending message of `group` and return how many were dropped.
+    /// In-flight messages are left to be acked or redelivered.
+    pub fn purge(&self, group: &str) -> Result<usize> {
+        self.touch();
+        let g = self.existing_group(group)?;
+        // no appends while draining, so the purge is a clean cut
+        let _groups = self.groups.write().unwrap();
+        let mut st = g.inflight.lock().unwrap();
+        let before = st.committed;
+        let mut purged = 0;
+        loop {
+            while let Some(e) = g.mem.pop() {
+                if e.seq > st.committed {
+                    purged += 1;
+                    st.settle(e.seq);
+                }
+            }
+            if st.backlog.is_none() {
+                break;
+            }
+            // what's left in the log is pending too, read it a queue at a time
+            self.reload(&g, &mut st)?;
+            if g.mem.is_empty() && st.backlog.is_some() {
+                break;
+            }
+        }
+        if st.committed != before {
+            self.write_acked(&g.name, st.committed)?;
+        }
+        Ok(purged)
+    }
+
+    /// The last `n` records of the log, acked or not, oldest first
+    pub fn read_last_n(&self, n: usize) -> Result<Vec<LogEntry>> {
+        self.wal.read_last_n(n)
+    }
+
+    fn default_group(&self) -> Arc<Group> {
+        self.groups.read().unwrap()[""].clone()
+    }
+
+    /// Pending messages of the default group
     pub fn len(&self) -> usize {
-        self.mem.len()
+        self.default_group().mem.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default_group().mem.is_empty()
+    }
+
+    /// Seq of the last message in the log, 0 if none was ever written
+    pub fn last_seq(&self) -> u64 {
+        self.wal.last_seq()
     }
 
     pub fn capacity(&self) -> usize {
-        self.mem.capacity()
+        self.cfg.capacity
+    }
+
+    pub fn config(&self) -> &TopicConfig {
+        &self.cfg
+    }
+
+    /// Delivered but unacked messages of the default group
+    pub fn in_flight(&self) -> usize {
+        self.default_group().inflight.lock().unwrap().msgs.len()
+    }
+
+    /// Committed offset of every loaded group, the default group included
+    pub fn offsets(&self) -> Vec<(String, u64)> {
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .map(|g| (g.name.clone(), g.inflight.lock().unwrap().committed))
+            .collect()
+    }
+
+    /// Committed offset of `group`, None if it doesn't exist
+    pub fn committed_offset(&self, group: &str) -> Option<u64> {
+        let g = self.groups.read().unwrap().get(group)?.clone();
+        let committed = g.inflight.lock().unwrap().committed;
+        Some(committed)
+    }
+
+    /// (group, binding) of every bound group
+    pub fn bindings(&self) -> Vec<(String, Binding)> {
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|g| Some((g.name.clone(), g.binding.read().unwrap().clone()?)))
+            .collect()
+    }
+
+    /// Log records with a seq above `seq`
+    pub fn read_after(&self, seq: u64) -> Result<Vec<LogEntry>> {
+        self.wal.read_after(seq)
+    }
+
+    /// Named consumer groups loaded on this topic
+    pub fn group_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .groups
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|n| !n.is_empty())
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Counters and depth of one consumer group
+    pub fn stats(&self, group: &str) -> Result<TopicStats> {
+        let g = self.existing_group(group)?;
+        let st = g.inflight.lock().unwrap();
+        let oldest = g
+            .mem
+            .oldest_at_ms()
+            .into_iter()
+            .chain(st.msgs.values().map(|e| e.at_ms))
+            .min();
+        Ok(TopicStats {
+            enqueued: self.enqueued.load(Ordering::Relaxed),
+            delivered: g.delivered.load(Ordering::Relaxed),
+            depth: g.mem.len(),
+            depth_bytes: g.mem.bytes.load(Ordering::Acquire),
+            peak_depth: g.mem.peak.load(Ordering::Relaxed),
+            in_flight: st.msgs.len(),
+            oldest_age_ms: oldest.map(|at| now_ms().saturating_sub(at)).unwrap_or(0),
+        })
+    }
+
+    /// True if no group has a pending or in-flight message
+    pub fn is_drained(&self) -> bool {
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .all(|g| g.mem.is_empty() && !g.is_behind() && g.inflight.lock().unwrap().msgs.is_empty())
+    }
+
+    /// Delete old log segments per the topic's retention, or `default`
+    /// (age, bytes) for what it doesn't set. Segments still holding
+    /// messages some group hasn't committed are kept.
+    pub fn enforce_retention(&self, default: (Option<Duration>, Option<u64>)) -> Result<usize> {
+        let retention = self.cfg.retention.or(default.0);
+        let retention_bytes = self.cfg.retention_bytes.or(default.1);
+        if retention.is_none() && retention_bytes.is_none() {
+            return Ok(0);
+        }
+        let committed = self
+            .groups
+            .read()
+            .unwrap()
+            .values()
+            .map(|g| g.inflight.lock().unwrap().committed)
+            .min()
+            .unwrap_or(0);
+        self.wal.trim(committed, retention, retention_bytes)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if let Some(a) = &self.audit {
+            a.sync()?;
+        }
+        self.wal.sync()
+    }
+
+    /// Note `event` of the message at `seq` on connection `conn` in the
+    /// topic's audit trail, if it keeps one. A failed write is logged, the
+    /// request goes on.
+    pub fn audit(&self, event: AuditEvent, seq: u64, message_id: &str, conn: u64, identity: &str, group: &str) {
+        let Some(a) = &self.audit else {
+            return;
+        };
+        let r = AuditRecord {
+            at_ms: now_ms(),
+            event,
+            seq,
+            conn,
+            identity: identity.to_string(),
+            group: group.to_string(),
+            message_id: message_id.to_string(),
+        };
+        if let Err(e) = a.append(&r) {
+            tracing::warn!("failed to audit {:?} of {}#{}: {}", event, self.name, seq, e);
+        }
+    }
+
+    /// Everything the audit trail kept, oldest first, None if the topic
+    /// keeps none
+    pub fn audit_trail(&self) -> Option<Result<Vec<AuditRecord>>> {
+        self.audit.as_ref().map(AuditLog::read)
+    }
+
+    /// Remove the on-disk log of this topic, and its followers' copies
+    pub fn destroy(&self) -> Result<()> {
+        // the last event, followers stop after it
+        if let Some(tx) = self.followers.lock().unwrap().take() {
+            let _ = tx.send(ReplicaEvent::Drop);
+        }
+        self.wal.remove()
+    }
+
+    fn touch(&self) {
+        self.last_active_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    /// Count a session consuming from the topic until it calls `detach`
+    pub fn attach(&self) {
+        self.consumers.fetch_add(1, Ordering::AcqRel);
+        self.abandoned.store(false, Ordering::Release);
+    }
+
+    /// A session that `attach`ed is gone. The last one leaves an
+    /// `auto_delete` topic to be deleted by `TopicRegistry::expire_idle`.
+    pub fn detach(&self) {
+        if self.consumers.fetch_sub(1, Ordering::AcqRel) == 1 && self.cfg.auto_delete {
+            self.abandoned.store(true, Ordering::Release);
+        }
+    }
+
+    /// Make session `owner` the only one to consume from an `exclusive` topic
+    pub fn claim(&self, owner: u64) {
+        self.owner.store(owner, Ordering::Release);
+    }
+
+    /// The owner of an `exclusive` topic is gone, or was before a restart.
+    /// Leaves it to be deleted by `TopicRegistry::expire_idle`.
+    pub fn disown(&self) {
+        self.owner.store(0, Ordering::Release);
+        self.abandoned.store(true, Ordering::Release);
+    }
+
+    /// Whether session `id` may consume from the topic
+    pub fn may_consume(&self, id: u64) -> bool {
+        !self.cfg.exclusive || self.owner.load(Ordering::Acquire) == id
+    }
+
+    /// Whether the idle ttl has passed, or the last consumer of an
+    /// `auto_delete` topic left
+    pub fn is_idle(&self, now_ms: u64) -> bool {
+        if self.abandoned.load(Ordering::Acquire) && self.consumers.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        let Some(ttl) = self.cfg.idle_ttl else {
+            return false;
+        };
+        let last = self.last_active_ms.load(Ordering::Relaxed);
+        now_ms.saturating_sub(last) >= ttl.as_millis() as u64
+    }
+}
+
+/// Log of topic `name`, transient or not as `cfg` says
+fn open_log(data_dir: &str, name: &str, cfg: &TopicConfig) -> Result<DiskLog> {
+    if cfg.transient {
+        DiskLog::open_transient(data_dir, name)
+    } else {
+        DiskLog::open(data_dir, name)
+    }
+}
+
+/// Follower copy of a topic led by another node: the log, bindings and
+/// committed offsets, no queues. Becomes a `Topic` if this node takes over.
+pub struct Replica {
+    pub name: String,
+    wal: DiskLog,
+    cfg: TopicConfig,
+    /// set while the topic is handed over to this node, which must not
+    /// take over from a half copied log
+    receiving_until: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    pub fn open(data_dir: &str, name: &str, cfg: TopicConfig) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            wal: open_log(data_dir, name, &cfg)?,
+            cfg,
+            receiving_until: Mutex::new(None),
+        })
+    }
+
+    /// Hand a topic this node led over to another node, keeping its log
+    pub fn demote(topic: &Topic) -> Self {
+        Self {
+            name: topic.name.clone(),
+            wal: (*topic.wal).clone(),
+            cfg: topic.cfg.clone(),
+            receiving_until: Mutex::new(None),
+        }
+    }
+
+    /// Hold off taking over for up to `max`, or until `stop_receiving`
+    pub fn start_receiving(&self, max: Duration) {
+        *self.receiving_until.lock().unwrap() = Some(Instant::now() + max);
+    }
+
+    pub fn stop_receiving(&self) {
+        *self.receiving_until.lock().unwrap() = None;
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.receiving_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Returns false if the record was already copied
+    pub fn append(&self, e: &LogEntry) -> Result<bool> {
+        self.wal
+            .append_at(e.seq, e.at_ms, e.priority, &e.routing_key, &e.envelope, &e.payload)
+    }
+
+    pub fn commit(&self, group: &str, seq: u64) -> Result<()> {
+        self.wal.write_acked(group, seq)
+    }
+
+    pub fn bind(&self, group: &str, binding: &Binding) -> Result<()> {
+        self.wal.write_binding(group, &binding.stored())
+    }
+
+    pub fn unbind(&self, group: &str) -> Result<()> {
+        self.wal.remove_binding(group)
+    }
+
+    pub fn delete_group(&self, group: &str) -> Result<()> {
+        self.wal.remove_group(group)
+    }
+
+    pub fn config(&self) -> &TopicConfig {
+        &self.cfg
+    }
+
+    /// Same as `Topic::enforce_retention`, by the offsets copied from the leader
+    pub fn enforce_retention(&self, default: (Option<Duration>, Option<u64>)) -> Result<usize> {
+        let retention = self.cfg.retention.or(default.0);
+        let retention_bytes = self.cfg.retention_bytes.or(default.1);
+        if retention.is_none() && retention_bytes.is_none() {
+            return Ok(0);
+        }
+        let mut committed = self.wal.read_acked("")?;
+        for g in self.wal.group_names()? {
+            committed = committed.min(self.wal.read_acked(&g)?);
+        }
+        self.wal.trim(committed, retention, retention_bytes)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.wal.sync()
+    }
+
+    pub fn destroy(&self) -> Result<()> {
+        self.wal.remove()
     }
+}
+
+/// Give back the slots `Topic::reserve_room` took in `targets`
+fn release_room(targets: &[&Arc<Group>], size: u64) {
+    for g in targets {
+        g.mem.release(size);
+    }
+}
+
+/// Queue the records past `st.committed`, or past `st.backlog` if set, that
+/// `accept` takes by seq, routing key and headers, the rest are settled in
+/// memory only. Reads until `mem` is full, leaving `st.backlog` set if the
+/// log goes on past that.
+fn load_unacked(
+    wal: &DiskLog,
+    mem: &Levels,
+    st: &mut Inflight,
+    accept: impl Fn(u64, &str, &BTreeMap<String, String>) -> bool,
+) -> Result<()> {
+    let now = now_ms();
+    // seqs cut off by a corrupt tail or trimmed by retention are never
+    // delivered, settle them so they can't hold back the committed offset
+    let mut next = st.backlog.take().unwrap_or(0).max(st.committed) + 1;
+    loop {
+        // a capacity's worth at a time, however long the log
+        let mut entries = wal.read_some_after(next - 1, mem.cap.max(1))?;
+        if entries.is_empty() {
+            break;
+        }
+        entries.sort_by_key(|e| e.seq);
+        for LogEntry {
+            seq,
+            at_ms,
+            priority,
+            routing_key,
+            envelope,
+            payload,
+        } in entries
+        {
+            st.settle_gap(next, seq);
+            next = seq + 1;
+            let mut envelope = get_envelope(&mut &envelope[..]).unwrap_or_default();
+            if !accept(seq, &routing_key, &envelope.headers) {
+                st.settle(seq);
+                continue;
+            }
+            // records written before timestamps were logged count from load time
+            let at_ms = if at_ms == 0 { now } else { at_ms };
+            if envelope.timestamp_ms == 0 {
+                envelope.timestamp_ms = at_ms;
+            }
+            let e = Entry {
+                seq,
+                at_ms,
+                priority,
+                msg: Arc::new(Message { payload, envelope }),
+                delivered_ms: 0,
+                deliveries: 0,
+            };
+            if mem.push(e).is_err() {
+                // the rest of the log waits until the queue drained
+                st.backlog = Some(seq - 1);
+                return Ok(());
+            }
+        }
+    }
+    st.settle_gap(next, wal.last_seq() + 1);
+    Ok(())
+}
 
+/// Message produced in a transaction, see `enqueue_all`
+pub struct Staged {
+    pub topic: Arc<Topic>,
+    pub msg: Message,
+    pub priority: u8,
+    pub routing_key: String,
+}
+
+/// Enqueue `staged` as one, in order: every message is written to its
+/// topic's log before any is queued, so none is delivered unless all were
+/// written. Fails with `QueueFull` and writes none if a group that would
+/// get one has no room under `Overflow::Reject`. Messages that overflowed
+/// a dead lettering topic go to `on_overflow` once all are queued.
+/// Returns the seqs they got.
+pub fn enqueue_all(staged: Vec<Staged>, mut on_overflow: impl FnMut(&Topic, Message)) -> Result<Vec<u64>> {
+    // read locks of every topic, as `Topic::push` holds one, taken in name
+    // order so commits can't deadlock each other
+    let mut topics: Vec<Arc<Topic>> = staged.iter().map(|s| s.topic.clone()).collect();
+    topics.sort_by(|a, b| a.name.cmp(&b.name));
+    topics.dedup_by(|a, b| Arc::ptr_eq(a, b));
+    let locked: Vec<_> = topics.iter().map(|t| (Arc::as_ptr(t), t.groups.read().unwrap())).collect();
+    let groups_of = |t: &Arc<Topic>| &*locked.iter().find(|(p, _)| *p == Arc::as_ptr(t)).unwrap().1;
+
+    // room first, nothing is written if a group is full
+    let mut routed = Vec::with_capacity(staged.len());
+    for s in &staged {
+        s.topic.touch();
+        let (targets, skipped) = s.topic.route(groups_of(&s.topic), &s.routing_key, &s.msg.envelope.headers);
+        let size = s.msg.payload.len() as u64;
+        match s.topic.reserve_room(&targets, size) {
+            Ok(reserved) => routed.push((targets, skipped, reserved, size)),
+            Err(e) => {
+                for (targets, _, reserved, size) in &routed {
+                    if *reserved {
+                        release_room(targets, *size);
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    let mut written = Vec::with_capacity(staged.len());
+    for s in staged {
+        match s.topic.write(s.msg, s.priority, &s.routing_key, None) {
+            Ok(w) => written.push((s.topic, w)),
+            Err(e) => {
+                // nothing is queued yet, what was written is given up on
+                for ((t, w), (targets, skipped, reserved, _)) in written.iter().zip(&routed) {
+                    t.discard(w, targets, skipped, *reserved)?;
+                }
+                for (targets, _, reserved, size) in &routed[written.len()..] {
+                    if *reserved {
+                        release_room(targets, *size);
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    let mut seqs = Vec::with_capacity(written.len());
+    let mut overflowed = Vec::new();
+    for ((t, w), (targets, skipped, reserved, _)) in written.into_iter().zip(routed) {
+        seqs.push(w.seq);
+        if let Some(m) = t.queue(w, &targets, &skipped, reserved)? {
+            overflowed.push((t, m));
+        }
+    }
+    // dead letter topics may be among these
+    drop(locked);
+    for (t, m) in overflowed {
+        on_overflow(&t, m);
+    }
+    Ok(seqs)
+}
+
+/// How often in-flight messages are checked against their topic's visibility timeout
+const REDELIVER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queue again the messages not acked within their topic's visibility
+/// timeout, see `Topic::redeliver_unacked`. Runs until aborted.
+pub async fn redeliver_unacked(topics: Arc<TopicRegistry>) {
+    let mut tick = tokio::time::interval(REDELIVER_INTERVAL);
+    loop {
+        tick.tick().await;
+        for t in topics.list() {
+            let n = t.redeliver_unacked();
+            if n > 0 {
+                tracing::debug!("{} messages of {} not acked in time, queued again", n, t.name);
+            }
+        }
+    }
+}
 
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
-pub struct TopicRegistry(pub DashMap<String, Arc<Topic>>);
+#[derive(Default)]
+pub struct TopicRegistry {
+    topics: DashMap<String, Arc<Topic>>,
+    /// topics led by other nodes that this node keeps a copy of
+    replicas: DashMap<String, Arc<Replica>>,
+    /// topic -> patterns of topic names it gets copies of messages from
+    subscriptions: DashMap<String, BTreeSet<String>>,
+    /// the other way around for patterns without wildcards, which name a
+    /// single topic: topic -> topics that get copies of its messages
+    copied_to: DashMap<String, BTreeSet<String>>,
+    /// topic -> schedule name -> what it publishes when
+    schedules: DashMap<String, BTreeMap<String, Schedule>>,
+}
 impl TopicRegistry {
     pub fn new() -> Self {
-        Self(DashMap::new())
+        Self::default()
     }
     pub fn get(&self, t: &str) -> Option<Arc<Topic>> {
-        self.0.get(t).map(|v| v.value().clone())
+        self.topics.get(t).map(|v| v.value().clone())
     }
     pub fn insert(&self, t: Arc<Topic>) {
-        self.0.insert(t.name.clone(), t);
+        self.topics.insert(t.name.clone(), t);
+    }
+    /// All topics, sorted by name
+    pub fn list(&self) -> Vec<Arc<Topic>> {
+        let mut all: Vec<Arc<Topic>> = self.topics.iter().map(|e| e.value().clone()).collect();
+        all.sort_by(|a, b| a.name.cmp(&b.name));
+        all
+    }
+    pub fn remove(&self, t: &str) -> Option<Arc<Topic>> {
+        self.topics.remove(t).map(|(_, v)| v)
+    }
+    pub fn remove_if(&self, t: &str, f: impl FnOnce(&Topic) -> bool) -> Option<Arc<Topic>> {
+        self.topics.remove_if(t, |_, v| f(v)).map(|(_, v)| v)
+    }
+
+    pub fn get_replica(&self, t: &str) -> Option<Arc<Replica>> {
+        self.replicas.get(t).map(|v| v.value().clone())
+    }
+    pub fn insert_replica(&self, r: Arc<Replica>) {
+        self.replicas.insert(r.name.clone(), r);
+    }
+    /// All replicas, sorted by name
+    pub fn list_replicas(&self) -> Vec<Arc<Replica>> {
+        let mut all: Vec<Arc<Replica>> = self.replicas.iter().map(|e| e.value().clone()).collect();
+        all.sort_by(|a, b| a.name.cmp(&b.name));
+        all
+    }
+    pub fn remove_replica(&self, t: &str) -> Option<Arc<Replica>> {
+        self.replicas.remove(t).map(|(_, v)| v)
+    }
+
+    /// Get the logs of every topic and replica onto disk, all of them even
+    /// if one fails, which is reported
+    pub fn sync(&self) -> Result<()> {
+        let mut res = Ok(());
+        let topics = self.list().into_iter().map(|t| (t.name.clone(), t.sync()));
+        let replicas = self.list_replicas().into_iter().map(|r| (r.name.clone(), r.sync()));
+        for (name, r) in topics.chain(replicas) {
+            if let Err(e) = r {
+                tracing::warn!("failed to sync log of {}: {}", name, e);
+                res = Err(e);
+            }
+        }
+        res
+    }
+
+    /// Configs of every topic and replica held here
+    pub fn metadata(&self) -> BrokerMetadata {
+        // transient topics don't outlive a restart
+        let topics = self
+            .topics
+            .iter()
+            .filter(|e| !e.value().config().transient)
+            .map(|e| (e.key().clone(), e.value().config().clone()))
+            .collect();
+        let replicas = self
+            .replicas
+            .iter()
+            .filter(|e| !e.value().config().transient)
+            .map(|e| (e.key().clone(), e.value().config().clone()))
+            .collect();
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .filter(|e| self.get(e.key()).is_none_or(|t| !t.config().transient))
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        let schedules = self
+            .schedules
+            .iter()
+            .filter(|e| self.get(e.key()).is_none_or(|t| !t.config().transient))
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        BrokerMetadata {
+            topics,
+            replicas,
+            subscriptions,
+            schedules,
+        }
+    }
+
+    /// Copy messages produced from now on to topics whose name matches
+    /// `pattern` into `topic`. Words of a pattern are separated by dots,
+    /// `*` matches one word and `#` any number, as for `TopicKind::Pattern`.
+    /// False if it already was subscribed to it.
+    pub fn subscribe(&self, topic: &str, pattern: &str) -> bool {
+        if !self.subscriptions.entry(topic.to_string()).or_default().insert(pattern.to_string()) {
+            return false;
+        }
+        if let Some(source) = single_topic(topic, pattern) {
+            self.copied_to.entry(source).or_default().insert(topic.to_string());
+        }
+        true
+    }
+
+    /// False if `topic` wasn't subscribed to `pattern`
+    pub fn unsubscribe(&self, topic: &str, pattern: &str) -> bool {
+        let removed = self.subscriptions.get_mut(topic).is_some_and(|mut p| p.remove(pattern));
+        self.subscriptions.remove_if(topic, |_, p| p.is_empty());
+        if removed && let Some(source) = single_topic(topic, pattern) {
+            if let Some(mut t) = self.copied_to.get_mut(&source) {
+                t.remove(topic);
+            }
+            self.copied_to.remove_if(&source, |_, t| t.is_empty());
+        }
+        removed
+    }
+
+    /// Drop every subscription and schedule of `topic`, once it's deleted
+    pub fn unsubscribe_all(&self, topic: &str) {
+        for p in self.subscriptions(topic) {
+            self.unsubscribe(topic, &p);
+        }
+        self.schedules.remove(topic);
+    }
+
+    /// Publish `s` to `topic` as schedule `name`, in place of one of that
+    /// name. False if it was already there as it is.
+    pub fn schedule(&self, topic: &str, name: &str, s: Schedule) -> bool {
+        self.schedules.entry(topic.to_string()).or_default().insert(name.to_string(), s.clone()) != Some(s)
+    }
+
+    /// False if `topic` had no schedule `name`
+    pub fn unschedule(&self, topic: &str, name: &str) -> bool {
+        let removed = self.schedules.get_mut(topic).is_some_and(|mut s| s.remove(name).is_some());
+        self.schedules.remove_if(topic, |_, s| s.is_empty());
+        removed
+    }
+
+    /// Schedules of `topic` by name, sorted
+    pub fn schedules(&self, topic: &str) -> Vec<(String, Schedule)> {
+        self.schedules.get(topic).map(|s| s.iter().map(|(n, s)| (n.clone(), s.clone())).collect()).unwrap_or_default()
+    }
+
+    /// (topic, name, schedule) of every schedule here
+    pub fn all_schedules(&self) -> Vec<(String, String, Schedule)> {
+        self.schedules
+            .iter()
+            .flat_map(|e| e.value().iter().map(|(n, s)| (e.key().clone(), n.clone(), s.clone())).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Patterns `topic` is subscribed to, sorted
+    pub fn subscriptions(&self, topic: &str) -> Vec<String> {
+        self.subscriptions.get(topic).map(|p| p.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Topics here that get a copy of messages produced to `topic`: those
+    /// subscribed to a pattern matching its name, without the partition, in
+    /// the same namespace. Each once, however many of its patterns match,
+    /// and never `topic` itself.
+    pub fn subscribers(&self, topic: &str) -> Vec<Arc<Topic>> {
+        let base = split_partition(topic).0;
+        let (ns, name) = split_namespace(base);
+        let words: Vec<&str> = name.split('.').collect();
+        let mut targets = self.copied_to.get(base).map(|t| t.clone()).unwrap_or_default();
+        for e in self.subscriptions.iter() {
+            if split_namespace(e.key()).0 == ns
+                && e.value().iter().any(|p| is_wildcard(p) && pattern_matches(&p.split('.').collect::<Vec<_>>(), &words))
+            {
+                targets.insert(e.key().clone());
+            }
+        }
+        targets.remove(topic);
+        targets.iter().filter_map(|t| self.get(t)).collect()
+    }
+
+    /// Drop every topic whose idle ttl has passed or whose last consumer
+    /// left, returning removed names
+    pub fn expire_idle(&self) -> Vec<String> {
+        let now = now_ms();
+        let idle: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|e| e.value().is_idle(now))
+            .map(|e| e.key().clone())
+            .collect();
+
+        let mut expired = Vec::new();
+        for name in idle {
+            // re-check under the shard lock, activity may have raced the scan
+            if let Some((_, t)) = self.topics.remove_if(&name, |_, t| t.is_idle(now)) {
+                if let Err(e) = t.destroy() {
+                    tracing::warn!("failed to remove log of expired topic {}: {}", name, e);
+                }
+                self.unsubscribe_all(&name);
+                expired.push(name);
+            }
+        }
+        expired
+    }
+}
+
+/// Whether a group bound to `binding` gets a message sent with `key` and `headers`
+fn routes(kind: TopicKind, binding: Option<&Binding>, key: &str, headers: &BTreeMap<String, String>) -> bool {
+    let Some(binding) = binding else {
+        return true;
+    };
+    let by_key = match kind {
+        TopicKind::Fanout => true,
+        TopicKind::Direct => binding.key == key,
+        TopicKind::Pattern => {
+            let pat: Vec<&str> = binding.key.split('.').collect();
+            let words: Vec<&str> = key.split('.').collect();
+            pattern_matches(&pat, &words)
+        }
+    };
+    by_key && binding.selects(headers)
+}
+
+fn is_wildcard(pattern: &str) -> bool {
+    pattern.split('.').any(|w| w == "*" || w == "#")
+}
+
+/// Topic `pattern` names for `topic` subscribed to it, if it has no wildcards
+fn single_topic(topic: &str, pattern: &str) -> Option<String> {
+    if is_wildcard(pattern) {
+        return None;
+    }
+    Some(match split_namespace(topic).0 {
+        Some(ns) => namespaced(ns, pattern),
+        None => pattern.to_string(),
+    })
+}
+
+/// Whether `words` match `pat`, `*` standing for one word and `#` for any
+/// number of them. Goes over the pattern a word at a time, so a pattern
+/// full of `#`s takes no longer than any other of its length.
+fn pattern_matches(pat: &[&str], words: &[&str]) -> bool {
+    // matched[j]: the pattern so far matches the first j words
+    let mut matched = vec![false; words.len() + 1];
+    matched[0] = true;
+    for p in pat {
+        if *p == "#" {
+            for j in 1..matched.len() {
+                matched[j] |= matched[j - 1];
+            }
+        } else {
+            for j in (1..matched.len()).rev() {
+                matched[j] = matched[j - 1] && (*p == "*" || *p == words[j - 1]);
+            }
+            matched[0] = false;
+        }
+    }
+    matched[words.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Topic `name` in a fresh directory under the system's temp dir
+    fn topic(name: &str, cfg: TopicConfig) -> Topic {
+        let dir = std::env::temp_dir().join(format!("quique-queue-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Topic::open(dir.to_str().unwrap(), name, cfg, || true).unwrap()
+    }
+
+    fn produce(t: &Topic, payload: &str) {
+        let msg = Message {
+            payload: Bytes::copy_from_slice(payload.as_bytes()),
+            envelope: Envelope::default(),
+        };
+        t.enqueue(msg, 0, "", None, |_| {}).unwrap();
+    }
+
+    /// Dequeue and ack everything `group` has, payloads in delivery order
+    fn drain(t: &Topic, group: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        while let Some(d) = t.dequeue(group, |_| {}).unwrap() {
+            assert!(t.ack(group, d.tag).unwrap());
+            out.push(String::from_utf8(d.msg.payload.to_vec()).unwrap());
+        }
+        out
+    }
+
+    fn cfg(capacity: usize) -> TopicConfig {
+        TopicConfig {
+            capacity,
+            overflow: Overflow::DropHead,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn group_drains_a_log_longer_than_its_capacity() {
+        let t = topic("backlog", cfg(4));
+        for i in 0..10 {
+            produce(&t, &i.to_string());
+        }
+        let mut got = Vec::new();
+        for _ in 0..5 {
+            let d = t.dequeue("g", |_| {}).unwrap().unwrap();
+            t.ack("g", d.tag).unwrap();
+            got.push(String::from_utf8(d.msg.payload.to_vec()).unwrap());
+        }
+        // produced while the group is behind, delivered after the backlog
+        produce(&t, "10");
+        produce(&t, "11");
+        got.extend(drain(&t, "g"));
+        let want: Vec<String> = (0..12).map(|i| i.to_string()).collect();
+        assert_eq!(got, want);
+        assert_eq!(t.committed_offset("g"), Some(12));
+        t.destroy().unwrap();
+    }
+
+    #[test]
+    fn caught_up_group_gets_new_messages_in_memory() {
+        let t = topic("caught-up", cfg(4));
+        for i in 0..6 {
+            produce(&t, &i.to_string());
+        }
+        assert_eq!(drain(&t, "g").len(), 6);
+        produce(&t, "6");
+        assert_eq!(t.stats("g").unwrap().depth, 1);
+        assert_eq!(drain(&t, "g"), ["6"]);
+        t.destroy().unwrap();
+    }
+
+    #[test]
+    fn reset_replays_a_log_longer_than_capacity() {
+        let t = topic("reset", cfg(4));
+        for i in 0..10 {
+            produce(&t, &i.to_string());
+        }
+        assert_eq!(drain(&t, "g").len(), 10);
+        assert_eq!(t.reset_offset("g", OffsetReset::Earliest).unwrap(), 10);
+        let want: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(drain(&t, "g"), want);
+        assert_eq!(t.reset_offset("g", OffsetReset::Seq(3)).unwrap(), 8);
+        assert_eq!(drain(&t, "g"), want[2..]);
+        assert_eq!(t.reset_offset("g", OffsetReset::Latest).unwrap(), 0);
+        assert!(drain(&t, "g").is_empty());
+        t.destroy().unwrap();
+    }
+
+    #[test]
+    fn unknown_group_is_not_created() {
+        let t = topic("unknown", cfg(4));
+        produce(&t, "0");
+        assert!(t.stats("typo").unwrap_err().is::<UnknownGroup>());
+        assert!(t.purge("typo").unwrap_err().is::<UnknownGroup>());
+        assert!(t.commit_offset("typo", 1).unwrap_err().is::<UnknownGroup>());
+        assert!(t.reset_offset("typo", OffsetReset::Earliest).unwrap_err().is::<UnknownGroup>());
+        assert!(!t.has_group("typo"));
+        assert_eq!(t.stats("").unwrap().depth, 1);
+        t.destroy().unwrap();
+    }
+
+    fn matches(pat: &str, topic: &str) -> bool {
+        pattern_matches(&pat.split('.').collect::<Vec<_>>(), &topic.split('.').collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn patterns_match_words() {
+        assert!(matches("orders.*", "orders.eu"));
+        assert!(!matches("orders.*", "orders.eu.paid"));
+        assert!(!matches("orders.*", "orders"));
+        assert!(matches("orders.#", "orders"));
+        assert!(matches("orders.#", "orders.eu.paid"));
+        assert!(matches("#.paid", "orders.eu.paid"));
+        assert!(matches("orders.#.paid", "orders.paid"));
+        assert!(matches("*.#.*", "a.b"));
+        assert!(!matches("*.#.*", "a"));
+        assert!(matches("#", "a.b.c"));
+        assert!(!matches("orders.eu", "orders.us"));
+    }
+
+    #[test]
+    fn many_hashes_match_quickly() {
+        let pat = vec!["#"; 40].join(".") + ".x";
+        let topic = vec!["a"; 40].join(".");
+        assert!(!matches(&pat, &topic));
+        assert!(matches(&pat, &(topic + ".x")));
+    }
+
+    #[test]
+    fn purge_drops_the_backlog_too() {
+        let t = topic("purge", cfg(4));
+        for i in 0..10 {
+            produce(&t, &i.to_string());
+        }
+        t.dequeue("g", |_| {}).unwrap();
+        assert_eq!(t.purge("g").unwrap(), 9);
+        assert!(drain(&t, "g").is_empty());
+        t.destroy().unwrap();
     }
 }
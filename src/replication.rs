@@ -0,0 +1,186 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::cluster::{Cluster, Node};
+use crate::protocol::*;
+use crate::queue::{Replica, ReplicaEvent, Topic, TopicConfig, TopicRegistry};
+use crate::storage::disk_log::LogEntry;
+
+/// A handoff that hasn't finished by then no longer holds off a takeover
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Ship the log changes of `topic` to its followers from now on, if its
+/// config asks for more than one copy. Runs until the topic is dropped.
+pub fn start(cluster: &Cluster, topic: &Topic) {
+    let cfg = topic.config().clone();
+    if cfg.replicas < 2 {
+        return;
+    }
+    let events = topic.replicate();
+    tokio::spawn(ship(cluster.clone(), topic.name.clone(), cfg, events));
+}
+
+/// Followers are whoever ranks right below this node at the time of each
+/// change, so a node that comes up or goes down is picked up on the next one
+async fn ship(cluster: Cluster, topic: String, cfg: TopicConfig, mut events: UnboundedReceiver<ReplicaEvent>) {
+    // followers learn about the topic before its first change
+    for node in followers(&cluster, &topic, &cfg) {
+        if let Err(e) = open(&cluster, &node, &topic, &cfg).await {
+            tracing::warn!("failed to open replica of {} on {}: {}", topic, node.id, e);
+        }
+    }
+    while let Some(mut ev) = events.recv().await {
+        let mut body = BytesMut::new();
+        put_str(&mut body, &topic);
+        encode(&mut body, &ev);
+        let mut copies = 0;
+        for node in followers(&cluster, &topic, &cfg) {
+            match send(&cluster, &node, &topic, &cfg, &body).await {
+                Ok(()) => copies += 1,
+                Err(e) => tracing::warn!("failed to replicate {} to {}: {}", topic, node.id, e),
+            }
+        }
+        if let ReplicaEvent::Append(_, waiting) = &mut ev
+            && let Some(tx) = waiting.take()
+        {
+            let _ = tx.send(copies);
+        }
+        if matches!(ev, ReplicaEvent::Drop) {
+            return;
+        }
+    }
+}
+
+/// Turn a replica into the topic it copies, leading it from here on
+pub fn promote(cluster: &Cluster, topics: &TopicRegistry, replica: &Replica) -> Result<Arc<Topic>> {
+    let t = Arc::new(Topic::promote(replica)?);
+    topics.remove_replica(&replica.name);
+    start(cluster, &t);
+    topics.insert(t.clone());
+    Ok(t)
+}
+
+/// Move `topic` to `to`, its new leader: everything some group hasn't
+/// committed yet, the committed offsets and bindings, then let it take
+/// over. Returns false if `to` already holds the topic, nothing is sent then.
+/// The topic must not change meanwhile, which holds once this node isn't
+/// its leader: requests for it are redirected.
+pub async fn hand_off(cluster: &Cluster, topic: &Topic, to: &Node) -> Result<bool> {
+    let cfg = topic.config();
+    let mut body = BytesMut::new();
+    put_str(&mut body, &topic.name);
+    put_u8(&mut body, ReplicaOp::Open as u8);
+    put_bytes(&mut body, &serde_json::to_vec(cfg)?);
+    put_u8(&mut body, 1);
+    match cluster.peers().call(&to.addr, Op::Replicate, &body).await? {
+        (Status::Ok, _) => {}
+        (Status::TopicExists, _) => return Ok(false),
+        (st, _) => return Err(anyhow::anyhow!("open answered {:?}", st)),
+    }
+
+    let offsets = topic.offsets();
+    let from = offsets.iter().map(|(_, seq)| *seq).min().unwrap_or(0);
+    let mut events: Vec<ReplicaEvent> = topic.read_after(from)?.into_iter().map(|e| ReplicaEvent::Append(e, None)).collect();
+    events.extend(offsets.into_iter().map(|(group, seq)| ReplicaEvent::Commit { group, seq }));
+    events.extend(topic.bindings().into_iter().map(|(group, binding)| ReplicaEvent::Bind { group, binding }));
+    for ev in &events {
+        let mut body = BytesMut::new();
+        put_str(&mut body, &topic.name);
+        encode(&mut body, ev);
+        match cluster.peers().call(&to.addr, Op::Replicate, &body).await? {
+            (Status::Ok, _) => {}
+            (st, _) => return Err(anyhow::anyhow!("answered {:?}", st)),
+        }
+    }
+
+    let mut body = BytesMut::new();
+    put_str(&mut body, &topic.name);
+    put_u8(&mut body, ReplicaOp::Promote as u8);
+    match cluster.peers().call(&to.addr, Op::Replicate, &body).await? {
+        (Status::Ok, _) => Ok(true),
+        (st, _) => Err(anyhow::anyhow!("promote answered {:?}", st)),
+    }
+}
+
+fn followers(cluster: &Cluster, topic: &str, cfg: &TopicConfig) -> Vec<Node> {
+    let mut nodes = cluster.replicas_of(topic, cfg.replicas as usize);
+    nodes.retain(|n| n.id != cluster.me.id);
+    nodes
+}
+
+/// req: topic(str) | op(u8) | op specific fields, see ReplicaOp
+fn encode(body: &mut BytesMut, ev: &ReplicaEvent) {
+    match ev {
+        ReplicaEvent::Append(
+            LogEntry {
+                seq,
+                at_ms,
+                priority,
+                routing_key,
+                envelope,
+                payload,
+            },
+            _,
+        ) => {
+            put_u8(body, ReplicaOp::Append as u8);
+            put_u64(body, *seq);
+            put_u64(body, *at_ms);
+            put_u8(body, *priority);
+            put_str(body, routing_key);
+            put_bytes(body, envelope);
+            put_bytes(body, payload);
+        }
+        ReplicaEvent::Commit { group, seq } => {
+            put_u8(body, ReplicaOp::Commit as u8);
+            put_str(body, group);
+            put_u64(body, *seq);
+        }
+        ReplicaEvent::Bind { group, binding } => {
+            put_u8(body, ReplicaOp::Bind as u8);
+            put_str(body, group);
+            put_str(body, &binding.key);
+            put_str(body, &binding.headers.to_string());
+            put_str(body, &binding.filter.as_ref().map(|f| f.to_string()).unwrap_or_default());
+            put_u32(body, binding.sample.unwrap_or(0));
+        }
+        ReplicaEvent::Unbind { group } => {
+            put_u8(body, ReplicaOp::Unbind as u8);
+            put_str(body, group);
+        }
+        ReplicaEvent::DeleteGroup { group } => {
+            put_u8(body, ReplicaOp::DeleteGroup as u8);
+            put_str(body, group);
+        }
+        ReplicaEvent::Drop => put_u8(body, ReplicaOp::Drop as u8),
+    }
+}
+
+async fn open(cluster: &Cluster, node: &Node, topic: &str, cfg: &TopicConfig) -> Result<()> {
+    let mut body = BytesMut::new();
+    put_str(&mut body, topic);
+    put_u8(&mut body, ReplicaOp::Open as u8);
+    put_bytes(&mut body, &serde_json::to_vec(cfg)?);
+    match cluster.peers().call(&node.addr, Op::Replicate, &body).await? {
+        (Status::Ok, _) => Ok(()),
+        (st, _) => Err(anyhow::anyhow!("open answered {:?}", st)),
+    }
+}
+
+/// A follower that doesn't know the topic, say one that just joined, is
+/// opened and gets the change again. It only has the log from there on.
+async fn send(cluster: &Cluster, node: &Node, topic: &str, cfg: &TopicConfig, body: &[u8]) -> Result<()> {
+    let (st, _) = cluster.peers().call(&node.addr, Op::Replicate, body).await?;
+    let st = if st == Status::NotFound {
+        open(cluster, node, topic, cfg).await?;
+        cluster.peers().call(&node.addr, Op::Replicate, body).await?.0
+    } else {
+        st
+    };
+    match st {
+        Status::Ok => Ok(()),
+        st => Err(anyhow::anyhow!("answered {:?}", st)),
+    }
+}
@@ -0,0 +1,444 @@
+use anyhow::{Result, bail};
+use bytes::{Buf, BytesMut};
+use futures_util::FutureExt;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+
+use crate::gateway::Gateway;
+use crate::handler::Session;
+use crate::protocol::*;
+use crate::queue::{Message, now_ms};
+
+/// Field an entry's payload goes in, the others are message headers
+pub const PAYLOAD_FIELD: &str = "payload";
+
+/// Entries a read returns per stream when it doesn't give a COUNT
+const DEFAULT_COUNT: usize = 128;
+
+/// How long a blocked read waits on a stream before checking the client is
+/// still there and trying the next one
+const BLOCK_SLICE_MS: u32 = 1_000;
+
+/// Arguments a command has at most, as in Redis
+const MAX_ARGS: usize = 1 << 20;
+
+/// Bytes an inline command, a line without `*`, has at most, as in Redis
+const MAX_INLINE: usize = 64 << 10;
+
+/// A RESP reply
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    /// null array, what a read that timed out answers
+    Nil,
+}
+
+impl Reply {
+    fn err(msg: impl Into<String>) -> Self {
+        Reply::Error(format!("ERR {}", msg.into()))
+    }
+
+    fn bulk(s: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(s.into())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+            Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(b) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+            Reply::Nil => out.extend_from_slice(b"*-1\r\n"),
+        }
+    }
+}
+
+/// One client speaking RESP2, Redis streams style: a stream is a topic.
+/// XADD produces an entry, its `payload` field as the payload and the other
+/// fields as headers. XREADGROUP consumes from the named consumer group and
+/// leaves entries pending until XACKed, or redelivered once the connection
+/// closes; XREAD consumes from the default group, acking as it goes. Entry
+/// IDs are the message's timestamp and delivery tag, `<ms>-<tag>`.
+pub async fn serve<S>(sock: S, gateway: Gateway, mut drain: watch::Receiver<bool>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (rd, mut wr) = tokio::io::split(sock);
+    let mut client = Client {
+        session: gateway.session(),
+        gateway,
+        rd,
+        buf: BytesMut::with_capacity(4096),
+    };
+    let mut out = Vec::new();
+    loop {
+        let args = tokio::select! {
+            args = client.read_command() => args?,
+            _ = drain.wait_for(|d| *d) => return Ok(()),
+        };
+        let Some(args) = args else {
+            return Ok(());
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case("QUIT");
+        out.clear();
+        client.dispatch(&args).await.encode(&mut out);
+        wr.write_all(&out).await?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+struct Client<R> {
+    gateway: Gateway,
+    session: Session,
+    rd: R,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> Client<R> {
+    /// Next command as its arguments, None once the connection is closed.
+    /// Whatever was read is kept in `buf`, so it can be cancelled and called
+    /// again. A command of more than `MAX_FRAME` bytes fails the connection.
+    async fn read_command(&mut self) -> Result<Option<Vec<String>>> {
+        loop {
+            if let Some(args) = decode(&mut self.buf)? {
+                return Ok(Some(args));
+            }
+            if self.buf.len() > MAX_FRAME as usize {
+                bail!("command of more than {} bytes", MAX_FRAME);
+            }
+            if self.rd.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Whether the client hung up, without waiting for it to send anything.
+    /// Commands it pipelined meanwhile stay buffered.
+    fn hung_up(&mut self) -> bool {
+        match self.rd.read_buf(&mut self.buf).now_or_never() {
+            Some(Ok(0)) | Some(Err(_)) => true,
+            Some(Ok(_)) | None => false,
+        }
+    }
+
+    async fn dispatch(&mut self, args: &[String]) -> Reply {
+        let cmd = args[0].to_ascii_uppercase();
+        let args = &args[1..];
+        match cmd.as_str() {
+            "PING" => match args.first() {
+                Some(msg) => Reply::bulk(msg.as_str()),
+                None => Reply::Simple("PONG"),
+            },
+            "ECHO" if args.len() == 1 => Reply::bulk(args[0].as_str()),
+            "QUIT" => Reply::Simple("OK"),
+            "AUTH" => {
+                let (user, secret) = match args {
+                    [secret] => ("", secret.as_str()),
+                    [user, secret] => (user.as_str(), secret.as_str()),
+                    _ => return Reply::err("wrong number of arguments for 'auth' command"),
+                };
+                match self.gateway.authenticate(&mut self.session, user, secret).await {
+                    Status::Ok => Reply::Simple("OK"),
+                    _ => Reply::Error("WRONGPASS invalid username-password pair".to_string()),
+                }
+            }
+            // RESP3 isn't spoken, clients fall back to RESP2 on this
+            "HELLO" => Reply::Error("NOPROTO this server speaks RESP2 only".to_string()),
+            "SELECT" if args.first().is_some_and(|db| db == "0") => Reply::Simple("OK"),
+            "CLIENT" => Reply::Simple("OK"),
+            "COMMAND" => Reply::Array(Vec::new()),
+            _ if self.gateway.auth_required() && self.session.identity.is_none() => {
+                Reply::Error("NOAUTH Authentication required.".to_string())
+            }
+            "XADD" => self.xadd(args).await,
+            "XREAD" => self.xread(args, None).await,
+            "XREADGROUP" => match args {
+                [group, g, _consumer, rest @ ..] if group.eq_ignore_ascii_case("GROUP") => {
+                    self.xread(rest, Some(g.as_str())).await
+                }
+                _ => Reply::err("syntax error"),
+            },
+            "XACK" => self.xack(args).await,
+            // groups come into being as they're first read from
+            "XGROUP" => match args.first().map(|a| a.to_ascii_uppercase()).as_deref() {
+                Some("CREATE") => Reply::Simple("OK"),
+                Some("CREATECONSUMER") => Reply::Int(1),
+                _ => Reply::err("only XGROUP CREATE and CREATECONSUMER are supported"),
+            },
+            _ => Reply::err(format!("unknown command '{}'", cmd)),
+        }
+    }
+
+    /// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] * field value [field value ...]
+    async fn xadd(&mut self, args: &[String]) -> Reply {
+        let Some((key, mut rest)) = args.split_first() else {
+            return Reply::err("wrong number of arguments for 'xadd' command");
+        };
+        // trimming is left to the topic's retention
+        loop {
+            match rest.first().map(|a| a.to_ascii_uppercase()).as_deref() {
+                Some("NOMKSTREAM") => rest = &rest[1..],
+                Some("MAXLEN" | "MINID") => {
+                    let skip = if matches!(rest.get(1).map(String::as_str), Some("=" | "~")) { 3 } else { 2 };
+                    rest = rest.get(skip..).unwrap_or_default();
+                    if rest.first().is_some_and(|a| a.eq_ignore_ascii_case("LIMIT")) {
+                        rest = rest.get(2..).unwrap_or_default();
+                    }
+                }
+                _ => break,
+            }
+        }
+        let Some((id, fields)) = rest.split_first() else {
+            return Reply::err("wrong number of arguments for 'xadd' command");
+        };
+        if id != "*" {
+            return Reply::err("only * IDs are supported, the broker picks them");
+        }
+        if fields.is_empty() || !fields.len().is_multiple_of(2) {
+            return Reply::err("wrong number of arguments for 'xadd' command");
+        }
+        let mut payload = Vec::new();
+        let mut headers = BTreeMap::new();
+        for kv in fields.chunks(2) {
+            if kv[0] == PAYLOAD_FIELD {
+                payload = kv[1].clone().into_bytes();
+            } else {
+                headers.insert(kv[0].clone(), kv[1].clone());
+            }
+        }
+        let timestamp_ms = now_ms();
+        let msg = Message {
+            payload: payload.into(),
+            envelope: Envelope {
+                timestamp_ms,
+                headers,
+                ..Default::default()
+            },
+        };
+        match self.gateway.produce(&mut self.session, key, &msg, 0, "", Acks::Leader).await {
+            // the seq is the delivery tag XREADGROUP answers it with
+            Ok(seq) => Reply::bulk(format!("{}-{}", timestamp_ms, seq)),
+            Err(st) => Reply::err(format!("{:?}", st)),
+        }
+    }
+
+    /// XREAD [COUNT count] [BLOCK ms] STREAMS key [key ...] id [id ...], and
+    /// the rest of XREADGROUP after its GROUP, which may also say NOACK. The
+    /// IDs don't matter, entries come in queue order, except that asking a
+    /// group for its pending ones (any ID but `>`) answers none: they were
+    /// requeued when the connection that read them closed.
+    async fn xread(&mut self, mut args: &[String], group: Option<&str>) -> Reply {
+        let mut count = DEFAULT_COUNT;
+        let mut block = None;
+        let mut ack = group.is_none();
+        loop {
+            match args.first().map(|a| a.to_ascii_uppercase()).as_deref() {
+                Some("COUNT") => match args.get(1).map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => {
+                        count = n;
+                        args = &args[2..];
+                    }
+                    _ => return Reply::err("value is not an integer or out of range"),
+                },
+                Some("BLOCK") => match args.get(1).map(|n| n.parse::<u64>()) {
+                    // 0 = forever
+                    Some(Ok(ms)) => {
+                        block = Some((ms > 0).then(|| Instant::now() + Duration::from_millis(ms)));
+                        args = &args[2..];
+                    }
+                    _ => return Reply::err("timeout is not an integer or out of range"),
+                },
+                Some("NOACK") if group.is_some() => {
+                    ack = true;
+                    args = &args[1..];
+                }
+                Some("STREAMS") => {
+                    args = &args[1..];
+                    break;
+                }
+                _ => return Reply::err("syntax error"),
+            }
+        }
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Reply::err("Unbalanced 'xread' list of streams: for each stream key an ID must be specified.");
+        }
+        let (keys, ids) = args.split_at(args.len() / 2);
+        let group = group.unwrap_or_default();
+        let mut streams = Vec::new();
+        let mut live = Vec::new();
+        for (key, id) in keys.iter().zip(ids) {
+            if group.is_empty() || id == ">" {
+                live.push(key.as_str());
+            } else {
+                streams.push(stream(key, Vec::new()));
+            }
+        }
+
+        for key in &live {
+            match self.take(key, group, count, 0, ack).await {
+                Ok(entries) if entries.is_empty() => {}
+                Ok(entries) => streams.push(stream(key, entries)),
+                Err(reply) => return reply,
+            }
+        }
+        if let Some(deadline) = block {
+            while streams.is_empty() && !live.is_empty() && !self.hung_up() {
+                let left = match deadline {
+                    Some(d) => d.saturating_duration_since(Instant::now()),
+                    None => Duration::MAX,
+                };
+                if left.is_zero() {
+                    break;
+                }
+                // a slice at a time, shared by the streams
+                let wait = left.min(Duration::from_millis(BLOCK_SLICE_MS as u64)).as_millis() as u32 / live.len() as u32;
+                for key in &live {
+                    match self.take(key, group, count, wait.max(1), ack).await {
+                        Ok(entries) if entries.is_empty() => {}
+                        Ok(entries) => streams.push(stream(key, entries)),
+                        Err(reply) => return reply,
+                    }
+                    if !streams.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+        if streams.is_empty() && block.is_some() {
+            return Reply::Nil;
+        }
+        Reply::Array(streams)
+    }
+
+    /// Up to `count` entries of `key` for `group`, waiting up to `wait_ms`
+    /// for the first one
+    async fn take(&mut self, key: &str, group: &str, count: usize, wait_ms: u32, ack: bool) -> Result<Vec<Reply>, Reply> {
+        let mut entries = Vec::new();
+        let mut wait = wait_ms;
+        while entries.len() < count {
+            let (tag, msg) = match self.gateway.next(&mut self.session, key, group, wait).await {
+                Ok(Some(m)) => m,
+                Ok(None) => break,
+                Err(st) if entries.is_empty() => return Err(Reply::err(format!("{:?}", st))),
+                Err(_) => break,
+            };
+            wait = 0;
+            if ack {
+                let st = self.gateway.settle(&mut self.session, key, group, tag, false).await;
+                if st != Status::Ok {
+                    tracing::debug!("ack of {}#{} for {:?} failed: {:?}", key, tag, group, st);
+                }
+            }
+            let mut fields = vec![Reply::bulk(PAYLOAD_FIELD), Reply::Bulk(msg.payload.to_vec())];
+            let id = format!("{}-{}", msg.envelope.timestamp_ms, tag);
+            for (k, v) in msg.envelope.into_headers() {
+                fields.push(Reply::Bulk(k.into_bytes()));
+                fields.push(Reply::Bulk(v.into_bytes()));
+            }
+            entries.push(Reply::Array(vec![Reply::bulk(id), Reply::Array(fields)]));
+        }
+        Ok(entries)
+    }
+
+    /// XACK key group id [id ...]
+    async fn xack(&mut self, args: &[String]) -> Reply {
+        let [key, group, ids @ ..] = args else {
+            return Reply::err("wrong number of arguments for 'xack' command");
+        };
+        if ids.is_empty() {
+            return Reply::err("wrong number of arguments for 'xack' command");
+        }
+        let mut acked = 0;
+        for id in ids {
+            let tag = id.rsplit_once('-').map_or(id.as_str(), |(_, tag)| tag);
+            let Ok(tag) = tag.parse::<u64>() else {
+                return Reply::err("Invalid stream ID specified as stream command argument");
+            };
+            if self.gateway.settle(&mut self.session, key, group, tag, false).await == Status::Ok {
+                acked += 1;
+            }
+        }
+        Reply::Int(acked)
+    }
+}
+
+/// A stream of a read's answer: its key and entries
+fn stream(key: &str, entries: Vec<Reply>) -> Reply {
+    Reply::Array(vec![Reply::bulk(key), Reply::Array(entries)])
+}
+
+/// Take a whole command off `buf`: an array of bulk strings, or an inline
+/// command, a line of words. Counts and lengths past the limits fail it.
+fn decode(buf: &mut BytesMut) -> Result<Option<Vec<String>>> {
+    if buf.first() != Some(&b'*') {
+        let Some(end) = buf.iter().position(|b| *b == b'\n') else {
+            if buf.len() > MAX_INLINE {
+                bail!("inline command of more than {} bytes", MAX_INLINE);
+            }
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+        buf.advance(end + 1);
+        return Ok(Some(line.split_whitespace().map(String::from).collect()));
+    }
+    let mut at = 0;
+    let Some(n) = read_len(buf, &mut at, b'*')? else {
+        return Ok(None);
+    };
+    if n > MAX_ARGS {
+        bail!("command of {} arguments, at most {}", n, MAX_ARGS);
+    }
+    // grown as they arrive, not as many as the client claims
+    let mut args = Vec::with_capacity(n.min(16));
+    for _ in 0..n {
+        let Some(len) = read_len(buf, &mut at, b'$')? else {
+            return Ok(None);
+        };
+        if len > MAX_FRAME as usize {
+            bail!("argument of {} bytes, at most {}", len, MAX_FRAME);
+        }
+        if buf.len() < at + len + 2 {
+            return Ok(None);
+        }
+        args.push(String::from_utf8_lossy(&buf[at..at + len]).into_owned());
+        at += len + 2;
+    }
+    buf.advance(at);
+    Ok(Some(args))
+}
+
+/// The number on a `*<n>\r\n` or `$<n>\r\n` line starting at `at`, moving
+/// `at` past it
+fn read_len(buf: &[u8], at: &mut usize, kind: u8) -> Result<Option<usize>> {
+    let Some(end) = buf[*at..].windows(2).position(|w| w == b"\r\n") else {
+        return Ok(None);
+    };
+    let line = &buf[*at..*at + end];
+    if line.first() != Some(&kind) {
+        bail!("expected '{}', got {:?}", kind as char, String::from_utf8_lossy(line));
+    }
+    let n = std::str::from_utf8(&line[1..])?.parse::<i64>()?;
+    *at += end + 2;
+    Ok(Some(n.max(0) as usize))
+}
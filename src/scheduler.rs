@@ -0,0 +1,375 @@
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::cluster::Cluster;
+use crate::handler::{self, Session};
+use crate::hints::Hints;
+use crate::protocol::*;
+use crate::queue::{TopicRegistry, now_ms};
+
+/// Years looked ahead for the next time a cron expression names, past the
+/// eight a February 29th can be apart
+const LOOKAHEAD_YEARS: i64 = 9;
+
+/// A message published to a topic at the times `cron` names, see
+/// `Op::Schedule`. Kept by the node leading the topic in its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// as written, see `Cron`
+    pub cron: String,
+    pub payload: Vec<u8>,
+    #[serde(default)]
+    pub routing_key: String,
+}
+
+/// When a schedule fires, as cron has it: `minute hour day-of-month month
+/// day-of-week`, or with a leading `second` field if there are six, in
+/// UTC. A field is `*` or a comma separated list of values, `a-b` ranges
+/// and steps, `*/n`, `a-b/n` or `a/n` for `a` up to the field's last value.
+/// Day of week is 0-7, Sunday being both 0 and 7. If both day fields are
+/// restricted a day matching either one fires, as in cron. `@yearly`,
+/// `@monthly`, `@weekly`, `@daily` and `@hourly` stand for their usual
+/// expressions.
+#[derive(Debug, Clone)]
+pub struct Cron {
+    /// as written, what's stored and shown
+    text: String,
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// day of month given as `*`, only the day of week decides
+    any_day: bool,
+    /// and the other way around
+    any_weekday: bool,
+}
+
+/// Why a cron expression was refused
+#[derive(Debug, Clone, Error)]
+#[error("{0}")]
+pub struct CronError(String);
+
+impl Cron {
+    pub fn parse(text: &str) -> Result<Self, CronError> {
+        let expanded = match text.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            s => s,
+        };
+        let mut fields: Vec<&str> = expanded.split_whitespace().collect();
+        match fields.len() {
+            5 => fields.insert(0, "0"),
+            6 => {}
+            n => return Err(CronError(format!("expected 5 or 6 fields, got {}", n))),
+        }
+        let cron = Self {
+            text: text.to_string(),
+            seconds: field(fields[0], "second", 0, 59)?,
+            minutes: field(fields[1], "minute", 0, 59)?,
+            hours: field(fields[2], "hour", 0, 23)?,
+            days: field(fields[3], "day of month", 1, 31)?,
+            months: field(fields[4], "month", 1, 12)?,
+            // Sunday is 0, and 7 as well
+            weekdays: field(fields[5], "day of week", 0, 7).map(|w| (w | w >> 7) & 0x7f)?,
+            any_day: fields[3].starts_with('*'),
+            any_weekday: fields[5].starts_with('*'),
+        };
+        if cron.next_after(now_ms()).is_none() {
+            return Err(CronError(format!("{} names no time in the years ahead", text)));
+        }
+        Ok(cron)
+    }
+
+    /// First time, in unix ms on a whole second, it names after `ms`. None
+    /// if there is none in the years ahead, like February 30th.
+    pub fn next_after(&self, ms: u64) -> Option<u64> {
+        let mut t = (ms / 1000 + 1) as i64;
+        let (start_year, _, _) = civil_from_days(t.div_euclid(86_400));
+        loop {
+            let (days, secs) = (t.div_euclid(86_400), t.rem_euclid(86_400));
+            let (y, m, d) = civil_from_days(days);
+            if y > start_year + LOOKAHEAD_YEARS {
+                return None;
+            }
+            if !has(self.months, m) {
+                t = if m == 12 { days_from_civil(y + 1, 1, 1) } else { days_from_civil(y, m + 1, 1) } * 86_400;
+            } else if !self.day_matches(d, (days + 4).rem_euclid(7)) {
+                t = (days + 1) * 86_400;
+            } else if !has(self.hours, secs / 3600) {
+                t = days * 86_400 + (secs / 3600 + 1) * 3600;
+            } else if !has(self.minutes, secs / 60 % 60) {
+                t = days * 86_400 + (secs / 60 + 1) * 60;
+            } else if !has(self.seconds, secs % 60) {
+                t += 1;
+            } else {
+                return Some(t as u64 * 1000);
+            }
+        }
+    }
+
+    /// Whether day of month `d` that's day of week `wd` fires
+    fn day_matches(&self, d: i64, wd: i64) -> bool {
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => has(self.weekdays, wd),
+            (false, true) => has(self.days, d),
+            (false, false) => has(self.days, d) || has(self.weekdays, wd),
+        }
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn has(set: u64, v: i64) -> bool {
+    set & (1 << v) != 0
+}
+
+/// Bit set of the values field `s` of a cron expression names, each in
+/// `min..=max`
+fn field(s: &str, name: &str, min: u64, max: u64) -> Result<u64, CronError> {
+    let bad = || CronError(format!("bad {} field {:?}, values are {}-{}", name, s, min, max));
+    let num = |v: &str| v.parse::<u64>().ok().filter(|v| (min..=max).contains(v)).ok_or_else(bad);
+    let mut set = 0;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().ok().filter(|&s| s > 0).ok_or_else(bad)?),
+            None => (part, 1),
+        };
+        let (lo, hi) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((lo, hi)) => (num(lo)?, num(hi)?),
+            // a lone value with a step runs to the end of the field
+            None if part.contains('/') => (num(range)?, max),
+            None => {
+                let v = num(range)?;
+                (v, v)
+            }
+        };
+        if lo > hi {
+            return Err(bad());
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+/// (year, month, day) of days since 1970-01-01, after Howard Hinnant's
+/// civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Days since 1970-01-01 of `y`-`m`-`d`, the other way around
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Fire the schedules of topics this node leads, once a second, until the
+/// process ends. A schedule first fires at the next time it names after
+/// it's seen here; times missed while the node was down or didn't lead the
+/// topic aren't made up for. Each message is produced as a client's would
+/// be, with a `quique-schedule` header naming the schedule. Nothing fires
+/// while the node is in `maintenance` mode, those times are skipped too.
+pub async fn run(cluster: Cluster, topics: Arc<TopicRegistry>, hints: Arc<Hints>, maintenance: Arc<AtomicBool>) {
+    // (topic, name) -> its cron expression and when it fires next
+    let mut due: HashMap<(String, String), (Cron, Option<u64>)> = HashMap::new();
+    let mut session = Session::new(topics.clone());
+    loop {
+        tokio::time::sleep(Duration::from_millis(1000 - now_ms() % 1000)).await;
+        let now = now_ms();
+        let mut seen = HashSet::new();
+        for (topic, name, s) in topics.all_schedules() {
+            if cluster.leader_of(&topic).id != cluster.me.id {
+                continue;
+            }
+            let key = (topic, name);
+            seen.insert(key.clone());
+            // new, or replaced since
+            if due.get(&key).is_none_or(|(cron, _)| cron.text != s.cron) {
+                let Ok(cron) = Cron::parse(&s.cron) else {
+                    continue;
+                };
+                let next = cron.next_after(now);
+                due.insert(key.clone(), (cron, next));
+            }
+            let (cron, next) = due.get_mut(&key).unwrap();
+            if next.is_none_or(|at| at > now) {
+                continue;
+            }
+            *next = cron.next_after(now);
+            if maintenance.load(Ordering::SeqCst) {
+                continue;
+            }
+            fire(&cluster, &topics, &hints, &mut session, &key.0, &key.1, &s).await;
+        }
+        due.retain(|k, _| seen.contains(k));
+    }
+}
+
+/// Produce the message of schedule `name` to `topic`
+async fn fire(cluster: &Cluster, topics: &TopicRegistry, hints: &Hints, session: &mut Session, topic: &str, name: &str, s: &Schedule) {
+    let envelope = Envelope {
+        headers: [(SCHEDULE_HEADER.to_string(), name.to_string())].into(),
+        ..Default::default()
+    };
+    let mut body = BytesMut::new();
+    put_str(&mut body, topic);
+    put_bytes(&mut body, &s.payload);
+    put_u8(&mut body, 0);
+    put_str(&mut body, &s.routing_key);
+    put_envelope(&mut body, &envelope);
+    put_u8(&mut body, Acks::Leader as u8);
+    let mut out = BytesMut::new();
+    if let Err(e) = handler::handle_produce(&body.freeze(), cluster, topics, hints, session, &mut out).await {
+        tracing::warn!("schedule {} of {} failed: {}", name, topic, e);
+        return;
+    }
+    match Status::try_from(u16::from_be_bytes([out[0], out[1]])) {
+        Ok(Status::Ok) => tracing::debug!("schedule {} of {} fired", name, topic),
+        st => tracing::warn!("schedule {} of {} failed: {:?}", name, topic, st),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// unix ms of a UTC time
+    fn at(y: i64, m: i64, d: i64, hh: i64, mm: i64, ss: i64) -> u64 {
+        ((days_from_civil(y, m, d) * 86_400 + hh * 3600 + mm * 60 + ss) * 1000) as u64
+    }
+
+    fn next(cron: &str, after: u64) -> Option<u64> {
+        Cron::parse(cron).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(at(2026, 10, 1, 0, 0, 0), 1_790_812_800_000);
+        assert_eq!(at(2104, 2, 29, 0, 0, 0), 4_233_686_400_000);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29) + 1), (2024, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2100, 2, 28) + 1), (2100, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2000, 2, 28) + 1), (2000, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(1999, 12, 31) + 1), (2000, 1, 1));
+        for days in (-800_000..800_000).step_by(37) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days, "{}-{}-{}", y, m, d);
+        }
+    }
+
+    #[test]
+    fn fields() {
+        let c = Cron::parse("5/20 1-3,7 */6 1 1 *").unwrap();
+        assert_eq!(c.seconds, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(c.minutes, 0b1000_1110);
+        assert_eq!(c.hours, 1 | 1 << 6 | 1 << 12 | 1 << 18);
+        assert_eq!(c.months, 1 << 1);
+        // five fields fire on the whole minute
+        assert_eq!(Cron::parse("* * * * *").unwrap().seconds, 1);
+        for bad in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "*/0 * * * *", "5-1 * * * *", "a * * * *", "1-2-3 * * * *"] {
+            assert!(Cron::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn steps_from_a_value_run_to_the_end_of_the_field() {
+        let t = at(2026, 10, 1, 0, 0, 0);
+        assert_eq!(next("5/20 * * * * *", t), Some(t + 5_000));
+        assert_eq!(next("5/20 * * * * *", t + 5_000), Some(t + 25_000));
+        assert_eq!(next("5/20 * * * * *", t + 45_000), Some(t + 65_000));
+        assert_eq!(next("50/20 * * * *", t), Some(t + 50 * 60_000));
+        assert_eq!(next("50/20 * * * *", t + 50 * 60_000), Some(t + 110 * 60_000));
+        assert_eq!(next("*/15 * * * *", t + 1), Some(t + 15 * 60_000));
+    }
+
+    #[test]
+    fn next_is_strictly_after() {
+        let t = at(2026, 10, 1, 12, 0, 0);
+        assert_eq!(next("0 12 * * *", t), Some(at(2026, 10, 2, 12, 0, 0)));
+        assert_eq!(next("0 12 * * *", t - 1), Some(t));
+        assert_eq!(next("* * * * * *", t + 999), Some(t + 1000));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // 2026-10-01 is a Thursday: the 13th or any Friday
+        let t = at(2026, 10, 1, 0, 0, 0);
+        let mut fired = Vec::new();
+        let mut ms = t;
+        while let Some(n) = next("0 0 13 * 5", ms).filter(|&n| n < at(2026, 10, 20, 0, 0, 0)) {
+            fired.push(civil_from_days((n / 86_400_000) as i64));
+            ms = n;
+        }
+        assert_eq!(fired, [(2026, 10, 2), (2026, 10, 9), (2026, 10, 13), (2026, 10, 16)]);
+        // with either one `*` only the other decides
+        assert_eq!(next("0 0 * * 5", t), Some(at(2026, 10, 2, 0, 0, 0)));
+        assert_eq!(next("0 0 13 * *", t), Some(at(2026, 10, 13, 0, 0, 0)));
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        let t = at(2026, 12, 31, 0, 0, 0);
+        let sunday = Some(at(2027, 1, 3, 0, 0, 0));
+        assert_eq!(next("0 0 * * 0", t), sunday);
+        assert_eq!(next("0 0 * * 7", t), sunday);
+        assert_eq!(next("0 0 * * 6-7", t), Some(at(2027, 1, 2, 0, 0, 0)));
+        assert_eq!(next("0 0 * * 7", at(2027, 1, 2, 0, 0, 0)), sunday);
+        assert_eq!(next("@weekly", t), sunday);
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(Cron::parse("0 0 * * *").unwrap().weekdays, 0x7f);
+    }
+
+    #[test]
+    fn february_29th() {
+        assert_eq!(next("0 0 29 2 *", at(2025, 1, 1, 0, 0, 0)), Some(at(2028, 2, 29, 0, 0, 0)));
+        // 2100 isn't a leap year, the one after 2096 is eight years on
+        assert_eq!(next("0 0 29 2 *", at(2096, 3, 1, 0, 0, 0)), Some(at(2104, 2, 29, 0, 0, 0)));
+        assert!(Cron::parse("0 0 30 2 *").is_err());
+        assert!(Cron::parse("0 0 31 4,6,9,11 *").is_err());
+        // unless the day of week can fire instead
+        assert!(Cron::parse("0 0 30 2 1").is_ok());
+    }
+
+    #[test]
+    fn rolls_over_the_year() {
+        let last = at(2026, 12, 31, 23, 59, 59);
+        assert_eq!(next("* * * * * *", last), Some(at(2027, 1, 1, 0, 0, 0)));
+        assert_eq!(next("@yearly", at(2026, 1, 1, 0, 0, 0)), Some(at(2027, 1, 1, 0, 0, 0)));
+        assert_eq!(next("59 23 31 12 *", at(2026, 12, 31, 23, 59, 0)), Some(at(2027, 12, 31, 23, 59, 0)));
+        assert_eq!(next("0 0 1 3 *", at(2026, 12, 31, 0, 0, 0)), Some(at(2027, 3, 1, 0, 0, 0)));
+        assert_eq!(next("@monthly", at(2026, 12, 15, 0, 0, 0)), Some(at(2027, 1, 1, 0, 0, 0)));
+    }
+}
@@ -0,0 +1,854 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Longest filter accepted, in bytes
+pub const MAX_SELECTOR_LEN: usize = 4096;
+
+/// Deepest nesting of a filter, parentheses, `NOT`s and signs included
+const MAX_DEPTH: usize = 64;
+
+/// Filter of a consumer group binding: a condition on a message's headers
+/// in the subset of SQL-92 JMS message selectors use, like
+/// `region = 'eu' AND (amount > 100 OR type IN ('refund', 'chargeback'))`.
+///
+/// Words name headers, `"double quoted"` for names that aren't plain words
+/// like `"quique-origin-topic"`, and strings are single quoted, `''` for a
+/// quote. There are `AND`, `OR`, `NOT`, comparisons `= <> < <= > >=`,
+/// arithmetic `+ - * /`, `[NOT] BETWEEN a AND b`, `[NOT] IN ('a', ...)`,
+/// `[NOT] LIKE 'pattern' [ESCAPE 'c']` with `%` for any run of characters
+/// and `_` for one, `IS [NOT] NULL`, `TRUE` and `FALSE`. Keywords are case
+/// insensitive.
+///
+/// Header values are strings, read as numbers where one is expected. As in
+/// SQL a missing header is NULL and any comparison with it is unknown, as
+/// is one with a value that isn't a number where one is expected; a message
+/// is selected only if the whole condition is true.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// as written, what's stored and shown
+    text: String,
+    expr: Expr,
+}
+
+/// Why a filter was refused, and the byte offset into it where
+#[derive(Debug, Clone, Error)]
+#[error("{msg} at offset {pos}")]
+pub struct SelectorError {
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> Result<Self, SelectorError> {
+        if text.len() > MAX_SELECTOR_LEN {
+            return Err(err(MAX_SELECTOR_LEN, format!("filter longer than {} bytes", MAX_SELECTOR_LEN)));
+        }
+        let toks = lex(text)?;
+        let mut p = Parser { toks: &toks, at: 0, depth: 0 };
+        let e = p.or()?;
+        if p.peek() != &Tok::End {
+            return Err(err(p.pos(), format!("unexpected {}", p.peek())));
+        }
+        e.want(Ty::Bool, "the filter has to be a condition")?;
+        Ok(Self {
+            text: text.to_string(),
+            expr: e.expr,
+        })
+    }
+
+    /// Whether a message with `headers` is selected
+    pub fn matches(&self, headers: &BTreeMap<String, String>) -> bool {
+        matches!(self.expr.eval(headers), Value::Bool(true))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn err(pos: usize, msg: impl Into<String>) -> SelectorError {
+    SelectorError { pos, msg: msg.into() }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    /// unquoted, a keyword or a header name
+    Word(String),
+    /// double quoted header name
+    Name(String),
+    Str(String),
+    Num(f64),
+    Sym(&'static str),
+    End,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Word(w) => write!(f, "{}", w),
+            Tok::Name(n) => write!(f, "\"{}\"", n),
+            Tok::Str(s) => write!(f, "'{}'", s),
+            Tok::Num(n) => write!(f, "{}", n),
+            Tok::Sym(s) => write!(f, "'{}'", s),
+            Tok::End => f.write_str("end of filter"),
+        }
+    }
+}
+
+const KEYWORDS: [&str; 11] = ["AND", "OR", "NOT", "BETWEEN", "IN", "LIKE", "ESCAPE", "IS", "NULL", "TRUE", "FALSE"];
+
+/// Longer symbols first, so `<=` isn't taken for `<`
+const SYMBOLS: [&str; 13] = ["<>", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "(", ")", ","];
+
+fn lex(s: &str) -> Result<Vec<(usize, Tok)>, SelectorError> {
+    let b = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let tok = if c == b'\'' || c == b'"' {
+            // a doubled quote stands for itself
+            let mut v = String::new();
+            i += 1;
+            loop {
+                let Some(end) = s[i..].find(c as char) else {
+                    let what = if c == b'\'' { "string" } else { "header name" };
+                    return Err(err(start, format!("unterminated {}", what)));
+                };
+                v.push_str(&s[i..i + end]);
+                i += end + 1;
+                if b.get(i) != Some(&c) {
+                    break;
+                }
+                v.push(c as char);
+                i += 1;
+            }
+            match c {
+                b'\'' => Tok::Str(v),
+                _ if v.is_empty() => return Err(err(start, "empty header name")),
+                _ => Tok::Name(v),
+            }
+        } else if c.is_ascii_digit() || (c == b'.' && b.get(i + 1).is_some_and(u8::is_ascii_digit)) {
+            while i < b.len() && (b[i].is_ascii_digit() || b[i] == b'.') {
+                i += 1;
+            }
+            if i < b.len() && (b[i] == b'e' || b[i] == b'E') {
+                i += 1;
+                if i < b.len() && (b[i] == b'+' || b[i] == b'-') {
+                    i += 1;
+                }
+                while i < b.len() && b[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            match s[start..i].parse() {
+                Ok(n) => Tok::Num(n),
+                Err(_) => return Err(err(start, format!("bad number {}", &s[start..i]))),
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' || c == b'$' {
+            while i < b.len() && (b[i].is_ascii_alphanumeric() || matches!(b[i], b'_' | b'$' | b'.')) {
+                i += 1;
+            }
+            Tok::Word(s[start..i].to_string())
+        } else if let Some(sym) = SYMBOLS.iter().find(|sym| s[i..].starts_with(**sym)) {
+            i += sym.len();
+            Tok::Sym(sym)
+        } else if s[i..].starts_with("!=") {
+            return Err(err(start, "use <> for not equal"));
+        } else {
+            let c = s[i..].chars().next().unwrap_or_default();
+            return Err(err(start, format!("unexpected character '{}'", c)));
+        };
+        out.push((start, tok));
+    }
+    out.push((s.len(), Tok::End));
+    Ok(out)
+}
+
+/// What an expression gives, checked while parsing so a filter that can
+/// never work is refused at bind time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Bool,
+    Num,
+    Str,
+    /// a header's value, a string that may be read as a number
+    Header,
+}
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Bool => "a condition",
+            Ty::Num => "a number",
+            Ty::Str => "a string",
+            Ty::Header => "a header",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Header(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(Cmp, Box<Expr>, Box<Expr>),
+    Arith(Arith, Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Between { e: Box<Expr>, lo: Box<Expr>, hi: Box<Expr>, not: bool },
+    In { e: Box<Expr>, list: Vec<String>, not: bool },
+    Like { e: Box<Expr>, pattern: Vec<Like>, not: bool },
+    IsNull { e: Box<Expr>, not: bool },
+}
+
+/// Piece of a `LIKE` pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Like {
+    Char(char),
+    /// `_`
+    One,
+    /// `%`
+    Any,
+}
+
+/// Expression with its type and where it starts, for errors
+struct Typed {
+    expr: Expr,
+    ty: Ty,
+    pos: usize,
+}
+
+impl Typed {
+    fn want(&self, ty: Ty, why: &str) -> Result<(), SelectorError> {
+        self.want_any(&[ty], why)
+    }
+
+    fn want_any(&self, tys: &[Ty], why: &str) -> Result<(), SelectorError> {
+        if tys.contains(&self.ty) {
+            return Ok(());
+        }
+        Err(err(self.pos, format!("{}, found {}", why, self.ty.name())))
+    }
+
+    fn boxed(self) -> Box<Expr> {
+        Box::new(self.expr)
+    }
+}
+
+struct Parser<'t> {
+    toks: &'t [(usize, Tok)],
+    at: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.toks[self.at].1
+    }
+
+    fn pos(&self) -> usize {
+        self.toks[self.at].0
+    }
+
+    fn bump(&mut self) -> Tok {
+        let t = self.toks[self.at].1.clone();
+        if t != Tok::End {
+            self.at += 1;
+        }
+        t
+    }
+
+    fn kw(&mut self, kw: &str) -> bool {
+        let is = matches!(self.peek(), Tok::Word(w) if w.eq_ignore_ascii_case(kw));
+        if is {
+            self.at += 1;
+        }
+        is
+    }
+
+    fn sym(&mut self, sym: &str) -> bool {
+        let is = matches!(self.peek(), Tok::Sym(s) if *s == sym);
+        if is {
+            self.at += 1;
+        }
+        is
+    }
+
+    fn expect_kw(&mut self, kw: &str) -> Result<(), SelectorError> {
+        match self.kw(kw) {
+            true => Ok(()),
+            false => Err(err(self.pos(), format!("expected {}, found {}", kw, self.peek()))),
+        }
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), SelectorError> {
+        match self.sym(sym) {
+            true => Ok(()),
+            false => Err(err(self.pos(), format!("expected '{}', found {}", sym, self.peek()))),
+        }
+    }
+
+    fn expect_str(&mut self) -> Result<String, SelectorError> {
+        let pos = self.pos();
+        match self.bump() {
+            Tok::Str(s) => Ok(s),
+            t => Err(err(pos, format!("expected a string, found {}", t))),
+        }
+    }
+
+    /// Parse one level deeper with `f`
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, SelectorError>) -> Result<T, SelectorError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(err(self.pos(), "filter nested too deeply"));
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn or(&mut self) -> Result<Typed, SelectorError> {
+        let mut l = self.and()?;
+        while self.kw("OR") {
+            let r = self.and()?;
+            l.want(Ty::Bool, "OR joins conditions")?;
+            r.want(Ty::Bool, "OR joins conditions")?;
+            l = Typed {
+                pos: l.pos,
+                expr: Expr::Or(l.boxed(), r.boxed()),
+                ty: Ty::Bool,
+            };
+        }
+        Ok(l)
+    }
+
+    fn and(&mut self) -> Result<Typed, SelectorError> {
+        let mut l = self.not()?;
+        while self.kw("AND") {
+            let r = self.not()?;
+            l.want(Ty::Bool, "AND joins conditions")?;
+            r.want(Ty::Bool, "AND joins conditions")?;
+            l = Typed {
+                pos: l.pos,
+                expr: Expr::And(l.boxed(), r.boxed()),
+                ty: Ty::Bool,
+            };
+        }
+        Ok(l)
+    }
+
+    fn not(&mut self) -> Result<Typed, SelectorError> {
+        let pos = self.pos();
+        if !self.kw("NOT") {
+            return self.predicate();
+        }
+        let e = self.nested(Self::not)?;
+        e.want(Ty::Bool, "NOT applies to a condition")?;
+        Ok(Typed {
+            expr: Expr::Not(e.boxed()),
+            ty: Ty::Bool,
+            pos,
+        })
+    }
+
+    fn predicate(&mut self) -> Result<Typed, SelectorError> {
+        let l = self.additive()?;
+        let pos = l.pos;
+        let cmp = match self.peek() {
+            Tok::Sym("=") => Some(Cmp::Eq),
+            Tok::Sym("<>") => Some(Cmp::Ne),
+            Tok::Sym("<") => Some(Cmp::Lt),
+            Tok::Sym("<=") => Some(Cmp::Le),
+            Tok::Sym(">") => Some(Cmp::Gt),
+            Tok::Sym(">=") => Some(Cmp::Ge),
+            _ => None,
+        };
+        if let Some(cmp) = cmp {
+            self.bump();
+            let r = self.additive()?;
+            let values = [Ty::Num, Ty::Str, Ty::Header];
+            l.want_any(&values, "comparisons are between values")?;
+            r.want_any(&values, "comparisons are between values")?;
+            if l.ty != r.ty && l.ty != Ty::Header && r.ty != Ty::Header {
+                return Err(err(r.pos, format!("{} can't be compared with {}", l.ty.name(), r.ty.name())));
+            }
+            return Ok(Typed {
+                expr: Expr::Cmp(cmp, l.boxed(), r.boxed()),
+                ty: Ty::Bool,
+                pos,
+            });
+        }
+        if self.kw("IS") {
+            let not = self.kw("NOT");
+            self.expect_kw("NULL")?;
+            l.want(Ty::Header, "IS NULL applies to a header")?;
+            return Ok(Typed {
+                expr: Expr::IsNull { e: l.boxed(), not },
+                ty: Ty::Bool,
+                pos,
+            });
+        }
+        let not_at = self.pos();
+        let not = self.kw("NOT");
+        let expr = if self.kw("BETWEEN") {
+            let lo = self.additive()?;
+            self.expect_kw("AND")?;
+            let hi = self.additive()?;
+            for e in [&l, &lo, &hi] {
+                e.want_any(&[Ty::Num, Ty::Header], "BETWEEN compares numbers")?;
+            }
+            Expr::Between {
+                e: l.boxed(),
+                lo: lo.boxed(),
+                hi: hi.boxed(),
+                not,
+            }
+        } else if self.kw("IN") {
+            l.want_any(&[Ty::Str, Ty::Header], "IN compares strings")?;
+            self.expect_sym("(")?;
+            let mut list = vec![self.expect_str()?];
+            while self.sym(",") {
+                list.push(self.expect_str()?);
+            }
+            self.expect_sym(")")?;
+            Expr::In { e: l.boxed(), list, not }
+        } else if self.kw("LIKE") {
+            l.want_any(&[Ty::Str, Ty::Header], "LIKE compares strings")?;
+            let pattern_at = self.pos();
+            let pattern = self.expect_str()?;
+            let escape = if self.kw("ESCAPE") {
+                let at = self.pos();
+                let e = self.expect_str()?;
+                let mut chars = e.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => return Err(err(at, "ESCAPE takes a single character")),
+                }
+            } else {
+                None
+            };
+            let pattern = like_pattern(&pattern, escape).ok_or_else(|| err(pattern_at, "pattern ends with its escape character"))?;
+            Expr::Like { e: l.boxed(), pattern, not }
+        } else if not {
+            return Err(err(not_at, "expected BETWEEN, IN or LIKE after NOT"));
+        } else {
+            return Ok(l);
+        };
+        Ok(Typed { expr, ty: Ty::Bool, pos })
+    }
+
+    fn additive(&mut self) -> Result<Typed, SelectorError> {
+        let mut l = self.multiplicative()?;
+        loop {
+            let op = if self.sym("+") {
+                Arith::Add
+            } else if self.sym("-") {
+                Arith::Sub
+            } else {
+                return Ok(l);
+            };
+            let r = self.multiplicative()?;
+            l = arith(op, l, r)?;
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Typed, SelectorError> {
+        let mut l = self.unary()?;
+        loop {
+            let op = if self.sym("*") {
+                Arith::Mul
+            } else if self.sym("/") {
+                Arith::Div
+            } else {
+                return Ok(l);
+            };
+            let r = self.unary()?;
+            l = arith(op, l, r)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<Typed, SelectorError> {
+        let pos = self.pos();
+        let neg = if self.sym("-") {
+            true
+        } else if self.sym("+") {
+            false
+        } else {
+            return self.primary();
+        };
+        let e = self.nested(Self::unary)?;
+        e.want_any(&[Ty::Num, Ty::Header], "a sign applies to a number")?;
+        let expr = match (neg, e.expr) {
+            (true, Expr::Num(n)) => Expr::Num(-n),
+            (true, e) => Expr::Neg(Box::new(e)),
+            (false, e) => e,
+        };
+        Ok(Typed { expr, ty: Ty::Num, pos })
+    }
+
+    fn primary(&mut self) -> Result<Typed, SelectorError> {
+        let pos = self.pos();
+        let (expr, ty) = match self.bump() {
+            Tok::Sym("(") => {
+                let e = self.nested(Self::or)?;
+                self.expect_sym(")")?;
+                return Ok(Typed { pos, ..e });
+            }
+            Tok::Num(n) => (Expr::Num(n), Ty::Num),
+            Tok::Str(s) => (Expr::Str(s), Ty::Str),
+            Tok::Name(n) => (Expr::Header(n), Ty::Header),
+            Tok::Word(w) if w.eq_ignore_ascii_case("TRUE") => (Expr::Bool(true), Ty::Bool),
+            Tok::Word(w) if w.eq_ignore_ascii_case("FALSE") => (Expr::Bool(false), Ty::Bool),
+            Tok::Word(w) if w.eq_ignore_ascii_case("NULL") => return Err(err(pos, "use IS NULL to check for a missing header")),
+            Tok::Word(w) if KEYWORDS.iter().any(|k| w.eq_ignore_ascii_case(k)) => {
+                return Err(err(pos, format!("unexpected {}, quote it as \"{}\" for a header of that name", w, w)));
+            }
+            Tok::Word(w) => (Expr::Header(w), Ty::Header),
+            t => return Err(err(pos, format!("unexpected {}", t))),
+        };
+        Ok(Typed { expr, ty, pos })
+    }
+}
+
+fn arith(op: Arith, l: Typed, r: Typed) -> Result<Typed, SelectorError> {
+    l.want_any(&[Ty::Num, Ty::Header], "arithmetic is on numbers")?;
+    r.want_any(&[Ty::Num, Ty::Header], "arithmetic is on numbers")?;
+    Ok(Typed {
+        pos: l.pos,
+        expr: Expr::Arith(op, l.boxed(), r.boxed()),
+        ty: Ty::Num,
+    })
+}
+
+/// Pieces of a `LIKE` pattern, None if it ends with a lone `escape`
+fn like_pattern(s: &str, escape: Option<char>) -> Option<Vec<Like>> {
+    let mut out = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        out.push(match c {
+            c if Some(c) == escape => Like::Char(chars.next()?),
+            '%' => Like::Any,
+            '_' => Like::One,
+            c => Like::Char(c),
+        });
+    }
+    Some(out)
+}
+
+/// Whether `s` matches `pattern`, going back to the last `%` on a mismatch
+fn like(pattern: &[Like], s: &str) -> bool {
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(Like::Any) => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(Like::One) => {
+                p += 1;
+                i += 1;
+            }
+            Some(Like::Char(c)) if *c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((sp, si)) => {
+                    star = Some((sp, si + 1));
+                    p = sp + 1;
+                    i = si + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|l| *l == Like::Any)
+}
+
+/// Value of an expression for a message, NULL where SQL would have it
+#[derive(Debug, Clone, Copy)]
+enum Value<'a> {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(&'a str),
+}
+
+impl Value<'_> {
+    fn num(self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(n),
+            Value::Str(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn truth(self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+fn truth(b: Option<bool>) -> Value<'static> {
+    b.map_or(Value::Null, Value::Bool)
+}
+
+impl Expr {
+    fn eval<'a>(&'a self, headers: &'a BTreeMap<String, String>) -> Value<'a> {
+        match self {
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Num(n) => Value::Num(*n),
+            Expr::Str(s) => Value::Str(s),
+            Expr::Header(h) => headers.get(h).map_or(Value::Null, |v| Value::Str(v)),
+            Expr::Not(e) => truth(e.eval(headers).truth().map(|b| !b)),
+            Expr::And(l, r) => match (l.eval(headers).truth(), r.eval(headers).truth()) {
+                (Some(false), _) | (_, Some(false)) => Value::Bool(false),
+                (Some(true), Some(true)) => Value::Bool(true),
+                _ => Value::Null,
+            },
+            Expr::Or(l, r) => match (l.eval(headers).truth(), r.eval(headers).truth()) {
+                (Some(true), _) | (_, Some(true)) => Value::Bool(true),
+                (Some(false), Some(false)) => Value::Bool(false),
+                _ => Value::Null,
+            },
+            Expr::Cmp(op, l, r) => truth(compare(*op, l.eval(headers), r.eval(headers))),
+            Expr::Arith(op, l, r) => {
+                let (Some(a), Some(b)) = (l.eval(headers).num(), r.eval(headers).num()) else {
+                    return Value::Null;
+                };
+                let n = match op {
+                    Arith::Add => a + b,
+                    Arith::Sub => a - b,
+                    Arith::Mul => a * b,
+                    Arith::Div => a / b,
+                };
+                if n.is_finite() { Value::Num(n) } else { Value::Null }
+            }
+            Expr::Neg(e) => e.eval(headers).num().map_or(Value::Null, |n| Value::Num(-n)),
+            Expr::Between { e, lo, hi, not } => {
+                let (Some(v), Some(lo), Some(hi)) = (e.eval(headers).num(), lo.eval(headers).num(), hi.eval(headers).num()) else {
+                    return Value::Null;
+                };
+                Value::Bool((lo <= v && v <= hi) != *not)
+            }
+            Expr::In { e, list, not } => match e.eval(headers) {
+                Value::Str(s) => Value::Bool(list.iter().any(|l| l == s) != *not),
+                _ => Value::Null,
+            },
+            Expr::Like { e, pattern, not } => match e.eval(headers) {
+                Value::Str(s) => Value::Bool(like(pattern, s) != *not),
+                _ => Value::Null,
+            },
+            Expr::IsNull { e, not } => Value::Bool(matches!(e.eval(headers), Value::Null) != *not),
+        }
+    }
+}
+
+/// Strings compare as strings, anything with a number as numbers
+fn compare(op: Cmp, l: Value, r: Value) -> Option<bool> {
+    let ord = match (l, r) {
+        (Value::Str(a), Value::Str(b)) => a.cmp(b),
+        (Value::Null, _) | (_, Value::Null) => return None,
+        (a, b) => a.num()?.partial_cmp(&b.num()?)?,
+    };
+    Some(match op {
+        Cmp::Eq => ord.is_eq(),
+        Cmp::Ne => ord.is_ne(),
+        Cmp::Lt => ord.is_lt(),
+        Cmp::Le => ord.is_le(),
+        Cmp::Gt => ord.is_gt(),
+        Cmp::Ge => ord.is_ge(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(kv: &[(&str, &str)]) -> BTreeMap<String, String> {
+        kv.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn selects(filter: &str, kv: &[(&str, &str)]) -> bool {
+        Selector::parse(filter).unwrap().matches(&headers(kv))
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_token() {
+        let cases = [
+            ("a = 'x", 4, "unterminated string"),
+            ("\"a = 1", 0, "unterminated header name"),
+            ("\"\" = 1", 0, "empty header name"),
+            ("a != 1", 2, "use <> for not equal"),
+            ("a = #", 4, "unexpected character '#'"),
+            ("a = 1 b", 6, "unexpected b"),
+            ("(a = 1", 6, "expected ')', found end of filter"),
+            ("a = NULL", 4, "use IS NULL"),
+            ("and = 1", 0, "quote it as \"and\""),
+            ("a", 0, "the filter has to be a condition, found a header"),
+            ("a = 'x' AND 1", 12, "AND joins conditions, found a number"),
+            ("'a' = 1", 6, "a string can't be compared with a number"),
+            ("a NOT = 1", 2, "expected BETWEEN, IN or LIKE after NOT"),
+            ("a BETWEEN 'x' AND 2", 10, "BETWEEN compares numbers"),
+            ("a IN (1)", 6, "expected a string, found 1"),
+            ("a LIKE 'x' ESCAPE 'ab'", 18, "ESCAPE takes a single character"),
+            ("a LIKE 'x!' ESCAPE '!'", 7, "pattern ends with its escape character"),
+            ("a IS NOT 1", 9, "expected NULL"),
+            ("-'x' = 1", 1, "a sign applies to a number"),
+        ];
+        for (filter, pos, msg) in cases {
+            let e = Selector::parse(filter).unwrap_err();
+            assert_eq!(e.pos, pos, "{}: {}", filter, e);
+            assert!(e.msg.contains(msg), "{}: {}", filter, e);
+        }
+    }
+
+    #[test]
+    fn missing_header_is_unknown_not_false() {
+        // unknown isn't selected, and neither is its negation
+        assert!(!selects("a = 1", &[]));
+        assert!(!selects("NOT (a = 1)", &[]));
+        assert!(!selects("a <> 1", &[]));
+        assert!(!selects("a + 1 > 0", &[]));
+        assert!(!selects("-a < 0", &[]));
+        assert!(!selects("a NOT BETWEEN 1 AND 2", &[]));
+        assert!(!selects("a NOT IN ('x')", &[]));
+        assert!(!selects("a NOT LIKE '%'", &[]));
+        // unless AND or OR settle it either way
+        assert!(selects("a = 1 OR TRUE", &[]));
+        assert!(!selects("a = 1 OR FALSE", &[]));
+        assert!(selects("NOT (a = 1 AND FALSE)", &[]));
+        assert!(!selects("NOT (a = 1 AND TRUE)", &[]));
+        assert!(selects("a IS NULL", &[]));
+        assert!(!selects("a IS NOT NULL", &[]));
+        assert!(selects("a IS NOT NULL", &[("a", "")]));
+    }
+
+    #[test]
+    fn values_that_are_not_numbers_are_unknown_where_one_is_expected() {
+        let h = [("a", "x")];
+        assert!(!selects("a > 1", &h));
+        assert!(!selects("NOT (a > 1)", &h));
+        assert!(!selects("a BETWEEN 1 AND 2", &h));
+        assert!(!selects("NOT (a BETWEEN 1 AND 2)", &h));
+        // division by zero too
+        assert!(!selects("1 / 0 = 1", &[]));
+        assert!(!selects("NOT (1 / 0 = 1)", &[]));
+        // a header against a string compares strings, against a number numbers
+        assert!(selects("a > 9", &[("a", "10")]));
+        assert!(!selects("a > '9'", &[("a", "10")]));
+        assert!(selects("a = 1.0", &[("a", " 1 ")]));
+    }
+
+    #[test]
+    fn between_is_inclusive() {
+        let f = "amount BETWEEN 100 AND 200";
+        assert!(!selects(f, &[("amount", "99.5")]));
+        assert!(selects(f, &[("amount", "100")]));
+        assert!(selects(f, &[("amount", "200")]));
+        assert!(!selects(f, &[("amount", "201")]));
+        assert!(selects("amount NOT BETWEEN 100 AND 200", &[("amount", "201")]));
+        assert!(selects("amount * 2 BETWEEN 2 * 100 AND 400 - -1", &[("amount", "200")]));
+    }
+
+    #[test]
+    fn in_lists() {
+        let f = "type IN ('refund', 'chargeback')";
+        assert!(selects(f, &[("type", "refund")]));
+        assert!(selects(f, &[("type", "chargeback")]));
+        assert!(!selects(f, &[("type", "Refund")]));
+        assert!(selects("type NOT IN ('refund')", &[("type", "sale")]));
+        assert!(selects("type in ('it''s')", &[("type", "it's")]));
+    }
+
+    #[test]
+    fn like_patterns() {
+        let h = |v| [("code", v)];
+        assert!(selects("code LIKE 'a%'", &h("abc")));
+        assert!(selects("code LIKE 'a%'", &h("a")));
+        assert!(!selects("code LIKE 'a%'", &h("ba")));
+        assert!(selects("code LIKE '_b_'", &h("abc")));
+        assert!(!selects("code LIKE '_b_'", &h("abcd")));
+        assert!(selects("code LIKE '_'", &h("é")));
+        // a % that matched too little is retried with more
+        assert!(selects("code LIKE 'a%b%c'", &h("axbxxbyc")));
+        assert!(!selects("code LIKE 'a%b%c'", &h("axbxxbyd")));
+        assert!(selects("code LIKE '%%'", &h("")));
+        assert!(selects("code LIKE 'a!_%' ESCAPE '!'", &h("a_1")));
+        assert!(!selects("code LIKE 'a!_%' ESCAPE '!'", &h("ab1")));
+        assert!(selects("code LIKE '100!%' ESCAPE '!'", &h("100%")));
+        assert!(selects("code NOT LIKE 'a%'", &h("ba")));
+    }
+
+    #[test]
+    fn keywords_and_quoted_names() {
+        assert!(selects("a = 1 and not b = 2", &[("a", "1"), ("b", "3")]));
+        assert!(selects("\"quique-origin-topic\" = 'orders'", &[("quique-origin-topic", "orders")]));
+        assert!(selects("\"AND\" = 'x'", &[("AND", "x")]));
+        assert!(selects("\"a\"\"b\" = 'x'", &[("a\"b", "x")]));
+        let text = "region = 'eu' AND (amount > 100 OR type IN ('refund'))";
+        assert_eq!(Selector::parse(text).unwrap().to_string(), text);
+    }
+
+    #[test]
+    fn length_is_limited() {
+        let fits = format!("a = '{}'", "x".repeat(MAX_SELECTOR_LEN - 6));
+        assert_eq!(fits.len(), MAX_SELECTOR_LEN);
+        assert!(Selector::parse(&fits).is_ok());
+        let e = Selector::parse(&format!("{} ", fits)).unwrap_err();
+        assert_eq!(e.pos, MAX_SELECTOR_LEN);
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let parens = |n| format!("{}a = 1{}", "(".repeat(n), ")".repeat(n));
+        assert!(Selector::parse(&parens(MAX_DEPTH)).is_ok());
+        let e = Selector::parse(&parens(MAX_DEPTH + 1)).unwrap_err();
+        assert!(e.msg.contains("nested too deeply"), "{}", e);
+        assert_eq!(e.pos, MAX_DEPTH + 1);
+
+        let nots = |n| format!("{}a = 1", "NOT ".repeat(n));
+        assert!(Selector::parse(&nots(MAX_DEPTH)).is_ok());
+        assert!(Selector::parse(&nots(MAX_DEPTH + 1)).is_err());
+
+        let signs = |n| format!("{}a = 1", "-".repeat(n));
+        assert!(Selector::parse(&signs(MAX_DEPTH)).is_ok());
+        assert!(Selector::parse(&signs(MAX_DEPTH + 1)).is_err());
+        // deep input fails cleanly rather than overflowing the stack
+        assert!(Selector::parse(&"(".repeat(MAX_SELECTOR_LEN)).is_err());
+    }
+}
@@ -1,91 +1,741 @@
 use anyhow::Result;
-use bytes::BytesMut;
+use arc_swap::ArcSwap;
+use bytes::{Buf, Bytes, BytesMut};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use std::future::Future;
+use std::io::IoSlice;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, watch},
+    task::JoinSet,
 };
-use tracing::{info, warn};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::TcpListenerStream;
+use tracing::{Instrument, Span, field, info, warn};
  
 use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::gateway::Gateway;
+use crate::grpc::{self, pb::broker_server::BrokerServer};
+use crate::hints::Hints;
+use crate::limit::Limiter;
+use crate::pool;
 use crate::protocol::*;
-use crate::queue::TopicRegistry;
+use crate::queue::{Replica, Topic, TopicConfig, TopicRegistry, redeliver_unacked};
+use crate::replication;
+use crate::storage::disk_log::{DiskLog, TRANSIENT_DIR};
+use crate::storage::metadata::{LocalMetadataStorage, MetadataStorage, save_topics};
+use crate::{mqtt, resp, scheduler, stomp, webhook, ws};
  
-use crate::handler;
+use crate::handler::{self, Session};
  
 pub struct Server {
     addr: String,
     data_dir: String,
     cluster: Cluster,
     topics: Arc<TopicRegistry>,
+    metadata: Arc<dyn MetadataStorage>,
+    /// reject produces while set, so queues can drain before shutdown
+    maintenance: Arc<AtomicBool>,
+    /// bind with SO_REUSEPORT so a new process can take over the port
+    reuse_port: bool,
+    /// forward requests for topics led elsewhere instead of redirecting
+    proxy: bool,
+    /// set TCP_NODELAY on accepted connections, so a small answer goes out
+    /// without waiting for the last one to be acked
+    nodelay: bool,
+    /// terminate TLS on accepted connections
+    tls: Option<TlsAcceptor>,
+    /// settings that can change while running, see `live_config`
+    config: Arc<ArcSwap<Config>>,
+    /// most connections served at once, 0 = unlimited
+    max_connections: usize,
+    /// at the limit, answer new connections with TooManyConnections
+    /// instead of leaving them in the listen backlog
+    reject_when_full: bool,
+    /// also serve the gRPC service here, see proto/quique.proto
+    grpc_addr: Option<String>,
+    /// also serve WebSocket clients here, see `ws::serve`
+    ws_addr: Option<String>,
+    /// also serve MQTT clients here, see `mqtt::serve`
+    mqtt_addr: Option<String>,
+    /// also serve STOMP clients here, see `stomp::serve`
+    stomp_addr: Option<String>,
+    /// also serve Redis streams commands here, see `resp::serve`
+    resp_addr: Option<String>,
 }
 
+/// How long a draining server waits for open connections before exiting
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection refused at the limit gets to send the request
+/// its refusal answers
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often topic logs are checked against their retention
+const RETENTION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Capacity of topics reopened from a log that has no saved config
+const RECOVERED_CAPACITY: usize = 1024;
+
+/// How often replicas and topics are checked against current leadership
+const ROLES_INTERVAL: Duration = Duration::from_secs(1);
+
+/// File in the data dir its process holds a lock on, see `lock_data_dir`
+const LOCK_FILE: &str = ".lock";
+
 /// Central server application for messaging
 impl Server {
     pub fn new(addr: String, data_dir: String, cluster: Cluster) -> Self {
+        let metadata = Arc::new(LocalMetadataStorage::new(&data_dir));
         Self {
             addr,
             data_dir,
             cluster,
             topics: Arc::new(TopicRegistry::new()),
+            metadata,
+            maintenance: Arc::new(AtomicBool::new(false)),
+            reuse_port: false,
+            proxy: false,
+            nodelay: true,
+            tls: None,
+            config: Arc::new(ArcSwap::from_pointee(Config::default())),
+            max_connections: 0,
+            reject_when_full: false,
+            grpc_addr: None,
+            ws_addr: None,
+            mqtt_addr: None,
+            stomp_addr: None,
+            resp_addr: None,
         }
     } 
 
+    /// Bind with SO_REUSEPORT, so a new server can start next to a draining
+    /// one. The new one waits for the old one to let go of the data dir
+    /// before it opens any log, see `lock_data_dir`.
+    pub fn reuse_port(mut self, on: bool) -> Self {
+        self.reuse_port = on;
+        self
+    }
+
+    pub fn proxy(mut self, on: bool) -> Self {
+        self.proxy = on;
+        self
+    }
+
+    /// Whether to send answers as soon as they're written (the default), or
+    /// let the kernel hold small ones back to fill segments, for throughput
+    /// over latency
+    pub fn nodelay(mut self, on: bool) -> Self {
+        self.nodelay = on;
+        self
+    }
+
+    /// Settings to start with. With `config.auth` only connections that
+    /// authenticate first are served, other nodes present its `peer_token`.
+    pub fn config(self, config: Config) -> Self {
+        self.config.store(Arc::new(config));
+        self
+    }
+
+    /// Where the running server reads its settings from: a new `Config`
+    /// stored there applies to the next request on every connection.
+    /// Connections keep the identity they authenticated as.
+    pub fn live_config(&self) -> Arc<ArcSwap<Config>> {
+        self.config.clone()
+    }
+
+    /// Serve at most `max` connections at once (0 = unlimited). Beyond that
+    /// new ones wait to be accepted, or with `reject` are answered
+    /// TooManyConnections and closed.
+    pub fn max_connections(mut self, max: usize, reject: bool) -> Self {
+        self.max_connections = max;
+        self.reject_when_full = reject;
+        self
+    }
+
+    /// Serve the gRPC service on `addr` too, in plain text
+    pub fn grpc(mut self, addr: String) -> Self {
+        self.grpc_addr = Some(addr);
+        self
+    }
+
+    /// Serve WebSocket clients on `addr` too, over TLS (wss) if `tls` is set
+    pub fn websocket(mut self, addr: String) -> Self {
+        self.ws_addr = Some(addr);
+        self
+    }
+
+    /// Serve MQTT 3.1.1 clients on `addr` too, over TLS if `tls` is set
+    pub fn mqtt(mut self, addr: String) -> Self {
+        self.mqtt_addr = Some(addr);
+        self
+    }
+
+    /// Serve STOMP 1.2 clients on `addr` too, over TLS if `tls` is set
+    pub fn stomp(mut self, addr: String) -> Self {
+        self.stomp_addr = Some(addr);
+        self
+    }
+
+    /// Serve Redis streams style commands over RESP on `addr` too, over TLS
+    /// if `tls` is set
+    pub fn resp(mut self, addr: String) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// Serve TLS only. Other nodes have to connect with TLS too, see `Cluster::tls`.
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
     pub async fn run(self) -> Result<()> {
-        let listener = TcpListener::bind(&self.addr).await?;
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Serve until `shutdown` resolves, then stop accepting and drain:
+    /// open connections finish their current request and are closed.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        // bound first: next to a draining server, connections the kernel
+        // hands this one wait in its backlog until the data dir is free
+        let listener = self.bind().await?;
+        let data_dir = self.data_dir.clone();
+        let _lock = tokio::task::spawn_blocking(move || lock_data_dir(&data_dir, true)).await??;
+        recover_topics(&self.data_dir, &self.cluster, &self.topics, self.metadata.as_ref())?;
+        let hints = Arc::new(Hints::open(&self.data_dir)?);
         info!("quique server listening on {}", self.addr);
 
-        loop {
-            let (sock, _) = listener.accept().await?;
-            let me = self.cluster.clone();
-            let topics = self.topics.clone();
-            let data_dir = self.data_dir.clone();
+        tokio::spawn(expire_idle_topics(self.topics.clone(), self.metadata.clone()));
+        tokio::spawn(enforce_retention(self.topics.clone(), self.config.clone()));
+        tokio::spawn(webhook::run(self.topics.clone()));
+        tokio::spawn(scheduler::run(self.cluster.clone(), self.topics.clone(), hints.clone(), self.maintenance.clone()));
+        tokio::spawn(redeliver_unacked(self.topics.clone()));
+        tokio::spawn(self.cluster.clone().gossip());
+        tokio::spawn(self.cluster.clone().probe());
+        tokio::spawn(hints.clone().replay(self.cluster.clone(), self.topics.clone()));
+        tokio::spawn(sync_roles(self.cluster.clone(), self.topics.clone(), self.metadata.clone()));
+
+        let max = match self.max_connections {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        let slots = Arc::new(Semaphore::new(max));
+        let (drain_tx, drain_rx) = watch::channel(false);
+        if let Some(addr) = &self.grpc_addr {
+            let listener = TcpListener::bind(addr).await?;
+            info!("gRPC service listening on {}", addr);
+            let svc = grpc::Service::new(self.gateway(&hints));
+            let mut drain = drain_rx.clone();
             tokio::spawn(async move {
-                // info!("New connection on {:?}", sock.peer_addr());
-                if let Err(e) = handle_conn(sock, me, topics, data_dir).await {
-                    warn!("conn closed: {}", e);
+                let res = tonic::transport::Server::builder()
+                    .add_service(BrokerServer::new(svc))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+                        let _ = drain.wait_for(|d| *d).await;
+                    })
+                    .await;
+                if let Err(e) = res {
+                    warn!("gRPC service failed: {}", e);
+                }
+            });
+        }
+        let mut frontends = Vec::new();
+        let addrs = [
+            (Frontend::WebSocket, &self.ws_addr),
+            (Frontend::Mqtt, &self.mqtt_addr),
+            (Frontend::Stomp, &self.stomp_addr),
+            (Frontend::Resp, &self.resp_addr),
+        ];
+        for (frontend, addr) in addrs {
+            let Some(addr) = addr else {
+                continue;
+            };
+            let listener = TcpListener::bind(addr).await?;
+            info!("{:?} listening on {}", frontend, addr);
+            let gateway = self.gateway(&hints);
+            frontends.push(tokio::spawn(frontend.serve(listener, gateway, self.tls.clone(), drain_rx.clone())));
+        }
+        let mut conns = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                res = accept(&listener, &slots, self.reject_when_full) => {
+                    let (sock, permit) = res?;
+                    if permit.is_none() {
+                        warn!("{} connections open, refusing a new one", max);
+                    }
+                    sock.set_nodelay(self.nodelay)?;
+                    let me = self.cluster.clone();
+                    let topics = self.topics.clone();
+                    let data_dir = self.data_dir.clone();
+                    let metadata = self.metadata.clone();
+                    let maintenance = self.maintenance.clone();
+                    let drain = drain_rx.clone();
+                    let hints = hints.clone();
+                    let proxy = self.proxy;
+                    let tls = self.tls.clone();
+                    let config = self.config.clone();
+                    conns.spawn(async move {
+                        // info!("New connection on {:?}", sock.peer_addr());
+                        let res = match tls {
+                            Some(tls) => match tls.accept(sock).await {
+                                Ok(sock) if permit.is_none() => refuse(sock).await,
+                                Ok(sock) => handle_conn(sock, me, topics, metadata, hints, config, data_dir, maintenance, proxy, drain).await,
+                                Err(e) => Err(anyhow::anyhow!("TLS handshake failed: {}", e)),
+                            },
+                            None if permit.is_none() => refuse(sock).await,
+                            None => handle_conn(sock, me, topics, metadata, hints, config, data_dir, maintenance, proxy, drain).await,
+                        };
+                        if let Err(e) = res {
+                            warn!("conn closed: {}", e);
+                        }
+                        drop(permit);
+                    });
+                }
+                // reap finished connections so the set doesn't grow forever
+                Some(_) = conns.join_next() => {}
+                _ = &mut shutdown => break,
+            }
+        }
+
+        // hand the port over: a process bound with SO_REUSEPORT keeps accepting
+        drop(listener);
+        info!("draining {} connections", conns.len());
+        let _ = drain_tx.send(true);
+        let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            while conns.join_next().await.is_some() {}
+            for frontend in frontends {
+                let _ = frontend.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("drain timed out, dropping {} connections", conns.len());
+        }
+        let synced = self.topics.sync().and(hints.sync());
+        save_topics(self.metadata.as_ref(), &self.topics)?;
+        synced?;
+        info!("logs and metadata saved, stopped");
+        Ok(())
+    }
+
+    fn gateway(&self, hints: &Arc<Hints>) -> Gateway {
+        Gateway::new(
+            self.cluster.clone(),
+            self.topics.clone(),
+            hints.clone(),
+            self.config.clone(),
+            self.maintenance.clone(),
+        )
+    }
+
+    async fn bind(&self) -> Result<TcpListener> {
+        let addr = tokio::net::lookup_host(&self.addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("cannot resolve {}", self.addr))?;
+        let sock = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        sock.set_reuseaddr(true)?;
+        if self.reuse_port {
+            sock.set_reuseport(true)?;
+        }
+        sock.bind(addr)?;
+        Ok(sock.listen(1024)?)
+    }
+}
+
+/// Take `data_dir` for this process: an exclusive lock on its `.lock` file,
+/// held until the returned file is dropped, so two processes never append
+/// to the same logs. With `wait` a process holding it, like a server
+/// draining after a new one started with `--reuse-port`, is waited for,
+/// otherwise it's an error.
+pub(crate) fn lock_data_dir(data_dir: &str, wait: bool) -> Result<File> {
+    std::fs::create_dir_all(data_dir)?;
+    let f = OpenOptions::new().create(true).truncate(false).write(true).open(Path::new(data_dir).join(LOCK_FILE))?;
+    match f.try_lock() {
+        Ok(()) => return Ok(f),
+        Err(TryLockError::WouldBlock) if wait => {}
+        Err(TryLockError::WouldBlock) => anyhow::bail!("data dir {} is in use by another process", data_dir),
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    info!("data dir {} is in use, waiting for the process using it to stop", data_dir);
+    f.lock()?;
+    info!("data dir {} is free", data_dir);
+    Ok(f)
+}
+
+/// Reopen the topics this node leads with their saved configs, plus any
+/// log in `data_dir` without one, so messages accepted before a crash
+/// or restart are delivered again. Saved replicas of topics led
+/// elsewhere keep following.
+pub(crate) fn recover_topics(data_dir: &str, cluster: &Cluster, topics: &TopicRegistry, metadata: &dyn MetadataStorage) -> Result<()> {
+    // transient topics went away with the previous run
+    match std::fs::remove_dir_all(Path::new(data_dir).join(TRANSIENT_DIR)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let meta = metadata.load()?;
+    for (topic, patterns) in &meta.subscriptions {
+        for p in patterns {
+            topics.subscribe(topic, p);
+        }
+    }
+    for (topic, schedules) in &meta.schedules {
+        for (name, s) in schedules {
+            topics.schedule(topic, name, s.clone());
+        }
+    }
+    let mut saved = meta.topics;
+    let mut replicas = meta.replicas;
+    let mut names: Vec<String> = saved.keys().chain(replicas.keys()).cloned().collect();
+    names.extend(DiskLog::list(data_dir)?);
+    names.sort();
+    names.dedup();
+    for name in names {
+        if topics.get(&name).is_some() {
+            continue;
+        }
+        let cfg = saved
+            .remove(&name)
+            .or_else(|| replicas.remove(&name))
+            .unwrap_or_else(|| TopicConfig {
+                capacity: RECOVERED_CAPACITY,
+                ..Default::default()
+            });
+        if !cluster.is_leader(&name) {
+            if cfg.replicas > 1 {
+                topics.insert_replica(Arc::new(Replica::open(data_dir, &name, cfg)?));
+            }
+            continue;
+        }
+        let t = Topic::open(data_dir, &name, cfg, || true)?;
+        info!("recovered topic {} with {} pending messages", name, t.len());
+        if t.config().exclusive {
+            // its connection didn't survive the restart
+            t.disown();
+        }
+        replication::start(cluster, &t);
+        topics.insert(Arc::new(t));
+    }
+    Ok(())
+}
+
+/// Next connection and the slot it takes, once one is free. With `reject`
+/// connections are taken right away, without a slot if none is free.
+async fn accept(listener: &TcpListener, slots: &Arc<Semaphore>, reject: bool) -> Result<(TcpStream, Option<OwnedSemaphorePermit>)> {
+    if reject {
+        let (sock, _) = listener.accept().await?;
+        return Ok((sock, slots.clone().try_acquire_owned().ok()));
+    }
+    // until a slot frees up, connections queue in the listen backlog
+    let permit = slots.clone().acquire_owned().await?;
+    let (sock, _) = listener.accept().await?;
+    Ok((sock, Some(permit)))
+}
+
+/// Answer the first request of a connection over the limit with
+/// TooManyConnections, then hang up
+async fn refuse<S: AsyncRead + AsyncWrite + Unpin>(mut sock: S) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(1024);
+    let read = tokio::time::timeout(REFUSE_TIMEOUT, async {
+        while !frame_ready(&buf) {
+            if sock.read_buf(&mut buf).await? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok::<_, std::io::Error>(true)
+    });
+    if !matches!(read.await, Ok(Ok(true))) {
+        return Ok(());
+    }
+    let Some(hdr) = Header::decode(&mut buf)? else {
+        unreachable!("a whole frame is buffered");
+    };
+    let rh = Header {
+        magic: 0,
+        version: 0,
+        op: hdr.op,
+        flags: 0,
+        stream_id: hdr.stream_id,
+        body_len: 0,
+    };
+    write_err(&mut sock, rh, Status::TooManyConnections, 0).await?;
+    let _ = sock.shutdown().await;
+    Ok(())
+}
+
+/// Protocols served next to QBUS on listeners of their own, through a `Gateway`
+#[derive(Debug, Clone, Copy)]
+enum Frontend {
+    WebSocket,
+    Mqtt,
+    Stomp,
+    Resp,
+}
+
+impl Frontend {
+    /// Accept clients until drained, then wait for the open ones to close:
+    /// they stop at the next thing they get
+    async fn serve(self, listener: TcpListener, gateway: Gateway, tls: Option<TlsAcceptor>, mut drain: watch::Receiver<bool>) {
+        let mut conns = JoinSet::new();
+        loop {
+            let sock = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((sock, _)) => sock,
+                    Err(e) => {
+                        warn!("{:?} accept failed: {}", self, e);
+                        continue;
+                    }
+                },
+                Some(_) = conns.join_next() => continue,
+                _ = drain.wait_for(|d| *d) => break,
+            };
+            let _ = sock.set_nodelay(true);
+            let gateway = gateway.clone();
+            let tls = tls.clone();
+            let drain = drain.clone();
+            conns.spawn(async move {
+                let res = match tls {
+                    Some(tls) => match tls.accept(sock).await {
+                        Ok(sock) => self.serve_conn(sock, gateway, drain).await,
+                        Err(e) => Err(anyhow::anyhow!("TLS handshake failed: {}", e)),
+                    },
+                    None => self.serve_conn(sock, gateway, drain).await,
+                };
+                if let Err(e) = res {
+                    warn!("{:?} conn closed: {}", self, e);
                 }
             });
         }
+        drop(listener);
+        while conns.join_next().await.is_some() {}
+    }
+
+    async fn serve_conn<S>(self, sock: S, gateway: Gateway, drain: watch::Receiver<bool>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            Frontend::WebSocket => ws::serve(sock, gateway, drain).await,
+            Frontend::Mqtt => mqtt::serve(sock, gateway, drain).await,
+            Frontend::Stomp => stomp::serve(sock, gateway, drain).await,
+            Frontend::Resp => resp::serve(sock, gateway, drain).await,
+        }
+    }
+}
+
+/// Periodically drop topics that outlived their idle ttl
+pub(crate) async fn expire_idle_topics(topics: Arc<TopicRegistry>, metadata: Arc<dyn MetadataStorage>) {
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        let expired = topics.expire_idle();
+        for name in &expired {
+            info!("topic {} expired after idle ttl or its last consumer left", name);
+        }
+        if !expired.is_empty()
+            && let Err(e) = save_topics(metadata.as_ref(), &topics)
+        {
+            warn!("failed to save metadata after expiring topics: {}", e);
+        }
     }
 }
 
-async fn handle_conn(
-    mut sock: TcpStream,
+pub(crate) async fn enforce_retention(topics: Arc<TopicRegistry>, config: Arc<ArcSwap<Config>>) {
+    let mut tick = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        tick.tick().await;
+        let cfg = config.load();
+        let default = (cfg.retention, cfg.retention_bytes);
+        for t in topics.list() {
+            match t.enforce_retention(default) {
+                Ok(0) => {}
+                Ok(n) => info!("deleted {} log segments of {}", n, t.name),
+                Err(e) => warn!("retention of {} failed: {}", t.name, e),
+            }
+        }
+        for r in topics.list_replicas() {
+            match r.enforce_retention(default) {
+                Ok(0) => {}
+                Ok(n) => info!("deleted {} log segments of replica {}", n, r.name),
+                Err(e) => warn!("retention of replica {} failed: {}", r.name, e),
+            }
+        }
+    }
+}
+
+/// Follow leadership as membership changes: take over the replicas this
+/// node now leads, and hand replicated topics it no longer leads back to
+/// being replicas of the new leader
+async fn sync_roles(cluster: Cluster, topics: Arc<TopicRegistry>, metadata: Arc<dyn MetadataStorage>) {
+    let mut tick = tokio::time::interval(ROLES_INTERVAL);
+    loop {
+        tick.tick().await;
+        let mut changed = false;
+        for r in topics.list_replicas() {
+            // a handoff in progress promotes the replica once it is complete
+            if !cluster.is_leader(&r.name) || r.is_receiving() {
+                continue;
+            }
+            match replication::promote(&cluster, &topics, &r) {
+                Ok(t) => {
+                    info!("took over topic {} with {} pending messages", r.name, t.len());
+                    changed = true;
+                }
+                Err(e) => warn!("failed to take over topic {}: {}", r.name, e),
+            }
+        }
+        for t in topics.list() {
+            if cluster.is_leader(&t.name) {
+                continue;
+            }
+            // the new leader gets the queue as it is here before this node lets go
+            let leader = cluster.leader_of(&t.name);
+            let handed = match replication::hand_off(&cluster, &t, &leader).await {
+                Ok(handed) => handed,
+                Err(e) => {
+                    warn!("failed to hand topic {} off to {}, retrying: {}", t.name, leader.id, e);
+                    continue;
+                }
+            };
+            if handed {
+                info!("handed topic {} off to {}", t.name, leader.id);
+            } else {
+                warn!("topic {} is already led by {}, keeping the local log only", t.name, leader.id);
+            }
+            topics.remove(&t.name);
+            if t.config().replicas > 1 || !handed {
+                topics.insert_replica(Arc::new(Replica::demote(&t)));
+            } else if let Err(e) = t.destroy() {
+                warn!("failed to drop handed off topic {}: {}", t.name, e);
+            }
+            changed = true;
+        }
+        if changed && cluster.has_left() && topics.list().is_empty() {
+            info!("left the cluster and handed off every topic, safe to stop");
+        }
+        if changed && let Err(e) = save_topics(metadata.as_ref(), &topics) {
+            warn!("failed to save metadata after leadership change: {}", e);
+        }
+    }
+}
+
+/// Requests of one connection handled at once. No more frames are read
+/// while this many are running.
+const MAX_INFLIGHT: usize = 64;
+
+/// Frames with a body of at least this many bytes that aren't in yet are
+/// read into a buffer of their own, sized to them, which their payload
+/// then keeps. Smaller ones are copied out of the pooled read buffer, so a
+/// queued message never holds on to one.
+const OWN_BUFFER: usize = 32 << 10;
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_conn<S: AsyncRead + AsyncWrite + Unpin>(
+    mut sock: S,
     cluster: Cluster,
     topics: Arc<TopicRegistry>,
+    metadata: Arc<dyn MetadataStorage>,
+    hints: Arc<Hints>,
+    config: Arc<ArcSwap<Config>>,
     data_dir: String,
+    maintenance: Arc<AtomicBool>,
+    proxy: bool,
+    mut drain: watch::Receiver<bool>,
 ) -> Result<()> {
-
-    // initialize memory space: 64kb
-    // make space for memory buffer, to avoid assigining additional memory too often
-    // TODO: setup value as config
-    // - Make it bigger to avoid frequent memory assigning
-    // - Make it smaller to avoid waste of memory if data traffic is small
-    let mut buf = BytesMut::with_capacity(64 * 1024);
+    // taken from the pool as frames come in, given back whenever it's empty,
+    // so an idle connection doesn't hold on to one
+    let mut buf = BytesMut::new();
+    // `buf` is one of OWN_BUFFER's, not the pool's
+    let mut own = false;
+    let mut session = Session::new(topics.clone());
+    let mut limiter = Limiter::new(config.load().rate_limit);
+    // requests that had to wait, answered as they complete: a long poll
+    // doesn't hold up the requests behind it
+    let mut inflight = FuturesUnordered::new();
+    // the client is done sending, what's in flight still gets answered
+    let mut eof = false;
 
     loop {
-        // assign additional memory if buffer is <1kb
-        // TODO: setup value as config
-        buf.reserve(1024);
-        let n = sock.read_buf(&mut buf).await?;
-        if n == 0 {
-            return Ok(());
+        // frames already buffered go first: a producer that doesn't wait
+        // for answers sends several in one go
+        while !frame_ready(&buf) || inflight.len() >= MAX_INFLIGHT {
+            if let Some(len) = body_len(&buf)
+                && len > MAX_FRAME
+            {
+                return Err(ProtoError::TooLarge(len).into());
+            }
+            if let Some(len) = body_len(&buf)
+                && len as usize >= OWN_BUFFER
+                && !own
+                && !frame_ready(&buf)
+            {
+                // room for the start of the next frame, so it isn't grown
+                let mut b = BytesMut::with_capacity(Header::LEN + len as usize + 1024);
+                b.extend_from_slice(&buf);
+                pool::READS.give(std::mem::replace(&mut buf, b));
+                own = true;
+            }
+            if eof && inflight.is_empty() {
+                return Ok(());
+            }
+            let done = tokio::select! {
+                done = inflight.next(), if !inflight.is_empty() => done,
+                n = fill(&mut sock, &mut buf), if !eof && inflight.len() < MAX_INFLIGHT => {
+                    let n = match n {
+                        Ok(n) => n,
+                        // a TLS client that hung up without saying goodbye
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+                        Err(e) => return Err(e.into()),
+                    };
+                    eof = n == 0;
+                    None
+                }
+                // only leave between frames, never with half a request buffered
+                // or one unanswered
+                _ = drain.wait_for(|d| *d), if buf.is_empty() && inflight.is_empty() => return Ok(()),
+            };
+            if let Some((rh, req, out)) = done {
+                answer(&mut sock, rh, &req, out?, session.features).await?;
+            }
         }
 
-        let hdr = match Header::decode(&mut buf)? {
-            Some(h) => h,
-            None => continue,  // if header is not fully arrived...
+        let Some(hdr) = Header::decode(&mut buf)? else {
+            unreachable!("a whole frame is buffered");
         };
-        if buf.len() < hdr.body_len as usize {
-            // keep going loop if body is not fully arrived
-            continue;
+        let len = hdr.body_len as usize;
+        let mut body = if own {
+            own = false;
+            buf.split_to(len).freeze()
+        } else {
+            let body = Bytes::copy_from_slice(&buf[..len]);
+            buf.advance(len);
+            body
+        };
+        if hdr.flags & FLAG_CHECKSUM != 0 {
+            body.truncate(verify_checksum(&body)?);
         }
-        let body = buf.split_to(hdr.body_len as usize).freeze();
-        let mut body_slice = &body[..];
+        let body = match hdr.flags & FLAG_COMPRESSED {
+            0 => body,
+            _ => Bytes::from(decompress(&body)?),
+        };
+        let req = RequestSpan::new(hdr.op);
 
-        let mut out = BytesMut::with_capacity(1024);
-        let mut rh = Header {
+        let rh = Header {
             magic: 0,
             version: 0,
             op: hdr.op,
@@ -94,33 +744,298 @@ async fn handle_conn(
             body_len: 0,
         };
 
-        match hdr.op {
-            Op::Metadata => handler::handle_metadata(&mut body_slice, &cluster, &mut out).await?,
-            Op::CreateTopic => handler::handle_create_topic(&mut body_slice, &cluster, &topics, &data_dir, &mut out).await?,
-            Op::Produce => handler::handle_produce(&mut body_slice, &cluster, &topics, &mut out).await?,
-            Op::Consume => handler::handle_consume(&mut body_slice, &cluster, &topics, &mut out).await?,
-            Op::Read => handler::handle_read(&mut body_slice, &cluster, &topics, &mut out).await?,
+        let cfg = config.load_full();
+        let auth = cfg.auth.as_deref();
+        // other nodes keep the cluster going, they aren't held back once
+        // they proved they're nodes (or admins), anyone else is
+        let cluster_op = matches!(hdr.op, Op::Gossip | Op::Replicate | Op::Ping)
+            && auth.is_some_and(|auth| session.identity.as_deref().is_some_and(|id| auth.is_admin(id)));
+        if !cluster_op && !limiter.admit(cfg.rate_limit, Header::LEN + body.len()) {
+            req.status(Status::Throttled);
+            if hdr.op != Op::Produce || handler::produce_acks(&body) != Acks::None {
+                write_err(&mut sock, rh, Status::Throttled, session.features).await?;
+            }
+            continue;
+        }
+
+        if auth.is_some() && session.identity.is_none() && !matches!(hdr.op, Op::Auth | Op::Hello) {
+            req.status(Status::Unauthorized);
+            if hdr.op != Op::Produce || handler::produce_acks(&body) != Acks::None {
+                write_err(&mut sock, rh, Status::Unauthorized, session.features).await?;
+            }
+            continue;
+        }
+
+        // running the cluster is for the nodes and admins
+        if let Some(auth) = auth
+            && matches!(hdr.op, Op::Gossip | Op::Replicate | Op::AddNode | Op::RemoveNode | Op::Maintenance)
+            && !session.identity.as_deref().is_some_and(|id| auth.is_admin(id))
+        {
+            req.status(Status::Unauthorized);
+            write_err(&mut sock, rh, Status::Unauthorized, session.features).await?;
+            continue;
+        }
+
+        // a tenant's topics are its namespace's, the cluster isn't its to run
+        let body = match session.namespace.as_deref() {
+            Some(_) if matches!(hdr.op, Op::Gossip | Op::Replicate | Op::AddNode | Op::RemoveNode | Op::Maintenance | Op::ClusterInfo) => {
+                req.status(Status::Unauthorized);
+                write_err(&mut sock, rh, Status::Unauthorized, session.features).await?;
+                continue;
+            }
+            Some(ns) if !matches!(hdr.op, Op::ListTopics | Op::Auth | Op::Hello | Op::Ping | Op::Begin | Op::Commit | Op::Abort) => match handler::scope_request(&body, ns) {
+                Some(body) => body,
+                None => {
+                    req.status(Status::BadRequest);
+                    if hdr.op != Op::Produce || handler::produce_acks(&body) != Acks::None {
+                        write_err(&mut sock, rh, Status::BadRequest, session.features).await?;
+                    }
+                    continue;
+                }
+            },
+            _ => body,
+        };
+        if !matches!(
+            hdr.op,
+            Op::ListTopics | Op::Gossip | Op::Auth | Op::Hello | Op::Ping | Op::ClusterInfo | Op::AddNode | Op::RemoveNode | Op::Maintenance | Op::Begin | Op::Commit | Op::Abort
+        ) && let Some(topic) = get_str(&mut &body[..])
+        {
+            req.span.record("topic", topic.as_str());
+        }
+
+        if hdr.op.is_write() && maintenance.load(Ordering::SeqCst) {
+            req.status(Status::Maintenance);
+            if hdr.op != Op::Produce || handler::produce_acks(&body) != Acks::None {
+                write_err(&mut sock, rh, Status::Maintenance, session.features).await?;
+            }
+            continue;
+        }
+
+        // every proxied request starts with the topic. Produces for a leader
+        // that can't be reached are kept as hints instead.
+        let upstream = match get_str(&mut &body[..]).map(|topic| cluster.leader_of(&topic)) {
+            Some(leader)
+                if proxy
+                    && matches!(hdr.op, Op::Produce | Op::Consume | Op::Fetch | Op::Ack | Op::Nack)
+                    && leader.id != cluster.me.id
+                    && (hdr.op != Op::Produce || cluster.is_reachable(&leader.id)) =>
+            {
+                Some(leader.addr)
+            }
+            _ => None,
+        };
+
+        // later requests are let in, or not, or read, by how it went
+        if matches!(hdr.op, Op::Auth | Op::Hello) {
+            let out = dispatch(hdr.op, body, upstream, &mut session, &cluster, &topics, metadata.as_ref(), &hints, &cfg, &data_dir, &maintenance)
+                .instrument(req.span.clone())
+                .await?;
+            answer(&mut sock, rh, &req, out, session.features).await?;
+            continue;
+        }
+
+        let mut fork = session.fork();
+        let (cluster, topics, metadata, hints, data_dir, maintenance) = (&cluster, &topics, metadata.as_ref(), &hints, &data_dir, &maintenance);
+        let mut request = Box::pin(async move {
+            let out = dispatch(hdr.op, body, upstream, &mut fork, cluster, topics, metadata, hints, &cfg, data_dir, maintenance)
+                .instrument(req.span.clone())
+                .await;
+            (rh, req, out)
+        });
+        // it runs until it first waits before the next frame is read, so
+        // requests that don't wait, and the appends of produces, happen in
+        // the order sent
+        match std::future::poll_fn(|cx| Poll::Ready(request.as_mut().poll(cx))).await {
+            Poll::Ready((rh, req, out)) => answer(&mut sock, rh, &req, out?, session.features).await?,
+            Poll::Pending => inflight.push(request),
         }
- 
-        rh.body_len = out.len() as u32;
-        rh.magic = MAGIC;
-        rh.version = VERSION;
-        let mut hb = BytesMut::with_capacity(16);
-        rh.encode(&mut hb);
-        sock.write_all(&hb).await?;
-        sock.write_all(&out).await?;
     }
 }
 
-async fn write_err(sock: &mut TcpStream, mut rh: Header, st: Status) -> Result<()> {
-    let mut out = BytesMut::new();
-    put_status(&mut out, st);
-    rh.body_len = out.len() as u32;
+/// Handle one request on `session`: what to answer it with, nothing for a
+/// request that isn't answered. Proxied ones are forwarded to `upstream`.
+#[allow(clippy::too_many_arguments)]
+async fn dispatch(
+    op: Op,
+    body: Bytes,
+    upstream: Option<String>,
+    session: &mut Session,
+    cluster: &Cluster,
+    topics: &TopicRegistry,
+    metadata: &dyn MetadataStorage,
+    hints: &Hints,
+    cfg: &Config,
+    data_dir: &str,
+    maintenance: &AtomicBool,
+) -> Result<BytesMut> {
+    let mut out = pool::ANSWERS.take();
+    let auth = cfg.auth.as_deref();
+    let mut body_slice = &body[..];
+    match op {
+        // staged in the session's transaction, never forwarded
+        Op::Produce if session.in_transaction() => handler::handle_produce(&body, cluster, topics, hints, session, &mut out).await?,
+        _ if let Some(addr) = &upstream => handler::forward(session, cluster.peers(), addr, op, &body, &mut out).await?,
+        Op::ListTopics => handler::handle_list_topics(topics, session.namespace.as_deref(), &mut out).await?,
+        Op::Metadata => handler::handle_metadata(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::CreateTopic => handler::handle_create_topic(&mut body_slice, cluster, topics, metadata, auth, Some(session), data_dir, &mut out).await?,
+        Op::DeleteTopic => handler::handle_delete_topic(&mut body_slice, cluster, topics, metadata, &mut out).await?,
+        Op::Produce => handler::handle_produce(&body, cluster, topics, hints, session, &mut out).await?,
+        Op::Begin => handler::handle_begin(session, &mut out).await?,
+        Op::Commit => handler::handle_commit(topics, session, &mut out).await?,
+        Op::Abort => handler::handle_abort(session, &mut out).await?,
+        Op::Consume => handler::handle_consume(&mut body_slice, cluster, topics, session, &mut out).await?,
+        Op::Read => handler::handle_read(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::ResetOffset => handler::handle_reset_offset(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::Fetch => handler::handle_fetch(&mut body_slice, cluster, topics, session, &mut out).await?,
+        Op::Ack | Op::Nack => handler::handle_settle(&mut body_slice, op, cluster, topics, session, &mut out).await?,
+        Op::Stats => handler::handle_stats(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::Bind => handler::handle_bind(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::Unbind => handler::handle_unbind(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::BindTopic => handler::handle_bind_topic(&mut body_slice, cluster, topics, metadata, &mut out).await?,
+        Op::UnbindTopic => handler::handle_unbind_topic(&mut body_slice, cluster, topics, metadata, &mut out).await?,
+        Op::Schedule => handler::handle_schedule(&mut body_slice, cluster, topics, metadata, &mut out).await?,
+        Op::Unschedule => handler::handle_unschedule(&mut body_slice, cluster, topics, metadata, &mut out).await?,
+        Op::Audit => handler::handle_audit(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::DeleteGroup => handler::handle_delete_group(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::Purge => handler::handle_purge(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::CommitOffset => handler::handle_commit_offset(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::FetchOffset => handler::handle_fetch_offset(&mut body_slice, cluster, topics, &mut out).await?,
+        Op::Maintenance => handler::handle_maintenance(&mut body_slice, maintenance, &mut out).await?,
+        Op::Gossip => handler::handle_gossip(&mut body_slice, cluster, &mut out).await?,
+        Op::Auth => handler::handle_auth(&mut body_slice, auth, session, &mut out).await?,
+        Op::Hello => handler::handle_hello(&mut body_slice, session, &mut out).await?,
+        Op::Ping => handler::handle_ping(cluster, &mut out).await?,
+        Op::ClusterInfo => handler::handle_cluster_info(cluster, topics, &mut out).await?,
+        Op::AddNode => handler::handle_add_node(&mut body_slice, cluster, &mut out).await?,
+        Op::RemoveNode => handler::handle_remove_node(&mut body_slice, cluster, &mut out).await?,
+        Op::Replicate => handler::handle_replicate(&mut body_slice, cluster, topics, metadata, data_dir, &mut out).await?,
+    }
+    Ok(out)
+}
+
+/// Write the answer `out` to the request of `rh`, none if it's empty: a fire
+/// and forget produce
+async fn answer<S: AsyncWrite + Unpin>(sock: &mut S, rh: Header, req: &RequestSpan, out: BytesMut, features: u32) -> Result<()> {
+    if out.is_empty() {
+        pool::ANSWERS.give(out);
+        return Ok(());
+    }
+    if let Ok(st) = Status::try_from(u16::from_be_bytes([out[0], out[1]])) {
+        req.status(st);
+    }
+    write_frame(sock, rh, &out, features).await?;
+    pool::ANSWERS.give(out);
+    Ok(())
+}
+
+/// Write `body` as the answer to the request of `rh`, the way the client
+/// agreed to in its `features`: messages consumed compressed, every body
+/// checksummed
+async fn write_frame<S: AsyncWrite + Unpin>(sock: &mut S, mut rh: Header, body: &[u8], features: u32) -> Result<()> {
+    let packed = match rh.op {
+        Op::Consume | Op::Fetch if features & FEATURE_COMPRESSION != 0 => compress(body),
+        _ => None,
+    };
+    let body = match &packed {
+        Some(packed) => {
+            rh.flags |= FLAG_COMPRESSED;
+            &packed[..]
+        }
+        None => body,
+    };
+    let crc = match features & FEATURE_CHECKSUM {
+        0 => None,
+        _ => Some(checksum(body)),
+    };
+    let crc = crc.as_ref().map_or(&[][..], |crc| &crc[..]);
+    let mut frame = pool::ANSWERS.take();
+    rh.body_len = (body.len() + crc.len()) as u32;
+    if !crc.is_empty() {
+        rh.flags |= FLAG_CHECKSUM;
+    }
     rh.magic = MAGIC;
     rh.version = VERSION;
-    let mut hdr = BytesMut::with_capacity(16);
-    rh.encode(&mut hdr);
-    sock.write_all(&hdr).await?;
-    sock.write_all(&out).await?;
+    rh.encode(&mut frame);
+    // one write either way, so header and body leave in the same segment
+    if sock.is_write_vectored() {
+        write_all_vectored(sock, &mut [IoSlice::new(&frame), IoSlice::new(body), IoSlice::new(crc)]).await?;
+    } else {
+        frame.extend_from_slice(body);
+        frame.extend_from_slice(crc);
+        sock.write_all(&frame).await?;
+    }
+    pool::ANSWERS.give(frame);
     Ok(())
 }
+
+/// Write all of `bufs`, without copying them into one first
+async fn write_all_vectored<S: AsyncWrite + Unpin>(sock: &mut S, mut bufs: &mut [IoSlice<'_>]) -> std::io::Result<()> {
+    while !bufs.is_empty() {
+        let n = sock.write_vectored(bufs).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, n);
+    }
+    Ok(())
+}
+
+/// Read more of the connection into `buf`. An empty `buf` goes back to the
+/// pool while waiting, what comes in first is read into a small buffer of
+/// its own and moved into one taken from the pool.
+async fn fill<S: AsyncRead + Unpin>(sock: &mut S, buf: &mut BytesMut) -> std::io::Result<usize> {
+    if !buf.is_empty() {
+        buf.reserve(1024);
+        return sock.read_buf(buf).await;
+    }
+    pool::READS.give(std::mem::take(buf));
+    let mut first = [0u8; 512];
+    let n = sock.read(&mut first).await?;
+    if n > 0 {
+        *buf = pool::READS.take();
+        buf.extend_from_slice(&first[..n]);
+    }
+    Ok(n)
+}
+
+/// Span of one request, with what it was about and how it went, exported
+/// with OTLP if configured. Ends with its latency once dropped.
+struct RequestSpan {
+    span: Span,
+    started: Instant,
+}
+
+impl RequestSpan {
+    fn new(op: Op) -> Self {
+        Self {
+            span: tracing::info_span!("request", op = ?op, topic = field::Empty, status = field::Empty, latency_us = field::Empty),
+            started: Instant::now(),
+        }
+    }
+
+    fn status(&self, st: Status) {
+        self.span.record("status", field::debug(st));
+    }
+}
+
+impl Drop for RequestSpan {
+    fn drop(&mut self) {
+        self.span.record("latency_us", self.started.elapsed().as_micros() as u64);
+    }
+}
+
+/// Whether `buf` starts with a whole frame, header and body
+fn frame_ready(buf: &[u8]) -> bool {
+    body_len(buf).is_some_and(|len| buf.len() >= Header::LEN + len as usize)
+}
+
+/// Body length of the frame `buf` starts with, once its header is in
+fn body_len(buf: &[u8]) -> Option<u32> {
+    (buf.len() >= Header::LEN).then(|| u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]))
+}
+
+async fn write_err<S: AsyncWrite + Unpin>(sock: &mut S, rh: Header, st: Status, features: u32) -> Result<()> {
+    let mut out = BytesMut::new();
+    put_status(&mut out, st);
+    write_frame(sock, rh, &out, features).await
+}
@@ -0,0 +1,616 @@
+use anyhow::{Result, bail};
+use bytes::{Buf, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::gateway::Gateway;
+use crate::handler::Session;
+use crate::protocol::*;
+use crate::queue::Message;
+
+/// How long a new connection has to send CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a subscription waits for a message before settling what the
+/// client acked in the meantime
+const SUBSCRIBE_POLL_MS: u32 = 1_000;
+
+/// Messages a `client` or `client-individual` subscription sends before
+/// waiting for ACKs
+const MAX_INFLIGHT: usize = 32;
+
+/// Frames waiting to be written to a client
+const OUTBOX: usize = 64;
+
+/// Bytes of a frame's command and headers at most
+const MAX_HEADERS: usize = 64 << 10;
+
+/// Headers of SEND that aren't passed on as message headers
+const RESERVED: [&str; 9] = [
+    "destination",
+    "content-length",
+    "content-type",
+    "receipt",
+    "priority",
+    "routing-key",
+    "message-id",
+    "reply-to",
+    "correlation-id",
+];
+
+/// One STOMP 1.2 client. A destination names a topic, `/queue/` and
+/// `/topic/` prefixes are dropped. SEND takes `routing-key`, `priority`,
+/// `reply-to` and `correlation-id` headers, and passes on the others, bar the frame's own, as message
+/// headers. SUBSCRIBE consumes from the default group, or the one in its
+/// `group` header, acking each message as it's sent unless `ack` is
+/// `client` or `client-individual`. What isn't acked when the client goes
+/// away is redelivered.
+pub async fn serve<S>(sock: S, gateway: Gateway, mut drain: watch::Receiver<bool>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut rd, mut wr) = tokio::io::split(sock);
+    let mut buf = Incoming::default();
+    let Ok(first) = tokio::time::timeout(CONNECT_TIMEOUT, read_frame(&mut rd, &mut buf)).await else {
+        return Ok(());
+    };
+    let Some(f) = first? else {
+        return Ok(());
+    };
+    if f.command != "CONNECT" && f.command != "STOMP" {
+        wr.write_all(&error("expected CONNECT", &f)).await?;
+        return Ok(());
+    }
+    let mut session = gateway.session();
+    if let Err(msg) = connect(&gateway, &mut session, &f).await {
+        wr.write_all(&error(msg, &f)).await?;
+        return Ok(());
+    }
+    let connected = Frame::new("CONNECTED")
+        .header("version", "1.2")
+        .header("heart-beat", "0,0")
+        .header("server", concat!("quique/", env!("CARGO_PKG_VERSION")));
+    wr.write_all(&connected.encode()).await?;
+
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(OUTBOX);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if wr.write_all(&frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = wr.shutdown().await;
+    });
+
+    let mut client = Client {
+        gateway,
+        session,
+        out: tx,
+        next_sub: 0,
+        subs: HashMap::new(),
+    };
+    let res = client.run(&mut rd, &mut buf, &mut drain).await;
+    let subs: Vec<Sub> = client.subs.drain().map(|(_, sub)| sub).collect();
+    drop(client);
+    for sub in subs {
+        sub.stop().await;
+    }
+    let _ = writer.await;
+    res
+}
+
+/// Authenticate `session` with the `login` and `passcode` of a CONNECT. A
+/// passcode without a login is taken as a token.
+async fn connect(gateway: &Gateway, session: &mut Session, f: &Frame) -> Result<(), &'static str> {
+    if !f.get("accept-version").unwrap_or("1.0").split(',').any(|v| v == "1.2") {
+        return Err("only STOMP 1.2 is supported");
+    }
+    if !gateway.auth_required() {
+        return Ok(());
+    }
+    let Some(passcode) = f.get("passcode") else {
+        return Err("login and passcode required");
+    };
+    match gateway.authenticate(session, f.get("login").unwrap_or(""), passcode).await {
+        Status::Ok => Ok(()),
+        _ => Err("invalid credentials"),
+    }
+}
+
+/// How a subscription's messages are acked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckMode {
+    /// as they're sent
+    Auto,
+    /// an ACK or NACK settles that message and every one sent before it
+    Client,
+    /// an ACK or NACK settles only that message
+    ClientIndividual,
+}
+
+/// A subscription as the connection knows it
+struct Sub {
+    /// names it in the `ack` headers of its messages
+    seq: u64,
+    /// ACK (false) or NACK (true) of a delivery tag
+    acks: mpsc::UnboundedSender<(u64, bool)>,
+    task: JoinHandle<()>,
+}
+
+impl Sub {
+    /// Stop delivering once the acks already in are settled, the rest is
+    /// requeued
+    async fn stop(self) {
+        drop(self.acks);
+        let _ = self.task.await;
+    }
+}
+
+/// A connected client
+struct Client {
+    gateway: Gateway,
+    session: Session,
+    out: mpsc::Sender<Vec<u8>>,
+    next_sub: u64,
+    /// by the client's subscription id
+    subs: HashMap<String, Sub>,
+}
+
+impl Client {
+    async fn run<R: AsyncRead + Unpin>(
+        &mut self,
+        rd: &mut R,
+        buf: &mut Incoming,
+        drain: &mut watch::Receiver<bool>,
+    ) -> Result<()> {
+        loop {
+            let f = tokio::select! {
+                f = read_frame(rd, buf) => f?,
+                _ = drain.wait_for(|d| *d) => return Ok(()),
+            };
+            let Some(f) = f else {
+                return Ok(());
+            };
+            let res = match f.command.as_str() {
+                "SEND" => self.send(&f).await,
+                "SUBSCRIBE" => self.subscribe(&f),
+                "UNSUBSCRIBE" => match f.get("id").and_then(|id| self.subs.remove(id)) {
+                    Some(sub) => {
+                        tokio::spawn(sub.stop());
+                        Ok(())
+                    }
+                    None => Err("no such subscription".to_string()),
+                },
+                "ACK" | "NACK" => self.settle(&f),
+                "DISCONNECT" => {
+                    if let Some(receipt) = f.get("receipt") {
+                        let _ = self.out.send(Frame::new("RECEIPT").header("receipt-id", receipt).encode()).await;
+                    }
+                    return Ok(());
+                }
+                "BEGIN" | "COMMIT" | "ABORT" => Err("transactions are not supported".to_string()),
+                _ => Err(format!("unknown command {}", f.command)),
+            };
+            let reply = match res {
+                Ok(()) => match f.get("receipt") {
+                    Some(receipt) => Frame::new("RECEIPT").header("receipt-id", receipt).encode(),
+                    None => continue,
+                },
+                Err(msg) => {
+                    // the connection ends with an ERROR
+                    let _ = self.out.send(error(&msg, &f)).await;
+                    return Ok(());
+                }
+            };
+            if self.out.send(reply).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn send(&mut self, f: &Frame) -> Result<(), String> {
+        let Some(dest) = f.get("destination") else {
+            return Err("SEND without destination".to_string());
+        };
+        if f.get("transaction").is_some() {
+            return Err("transactions are not supported".to_string());
+        }
+        let priority = match f.get("priority").map(str::parse) {
+            None => 0,
+            Some(Ok(p)) => p,
+            Some(Err(_)) => return Err("priority is 0-255".to_string()),
+        };
+        let msg = Message {
+            payload: f.body.clone().into(),
+            envelope: Envelope {
+                message_id: f.get("message-id").unwrap_or_default().to_string(),
+                content_type: f.get("content-type").unwrap_or_default().to_string(),
+                timestamp_ms: 0,
+                headers: f
+                    .headers
+                    .iter()
+                    .filter(|(k, _)| !RESERVED.contains(&k.as_str()))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                reply_to: f.get("reply-to").map(topic_of).unwrap_or_default().to_string(),
+                correlation_id: f.get("correlation-id").unwrap_or_default().to_string(),
+            },
+        };
+        let key = f.get("routing-key").unwrap_or_default();
+        match self.gateway.produce(&mut self.session, topic_of(dest), &msg, priority, key, Acks::Leader).await {
+            Ok(_seq) => Ok(()),
+            Err(st) => Err(format!("send to {} failed: {:?}", dest, st)),
+        }
+    }
+
+    fn subscribe(&mut self, f: &Frame) -> Result<(), String> {
+        let (Some(id), Some(dest)) = (f.get("id"), f.get("destination")) else {
+            return Err("SUBSCRIBE needs id and destination".to_string());
+        };
+        if self.subs.get(id).is_some_and(|s| !s.task.is_finished()) {
+            return Err(format!("subscription {} exists", id));
+        }
+        let mode = match f.get("ack").unwrap_or("auto") {
+            "auto" => AckMode::Auto,
+            "client" => AckMode::Client,
+            "client-individual" => AckMode::ClientIndividual,
+            other => return Err(format!("unknown ack mode {}", other)),
+        };
+        self.next_sub += 1;
+        let (acks, rx) = mpsc::unbounded_channel();
+        let sub = Subscription {
+            gateway: self.gateway.clone(),
+            session: self.session.sibling(),
+            id: id.to_string(),
+            seq: self.next_sub,
+            destination: dest.to_string(),
+            topic: topic_of(dest).to_string(),
+            group: f.get("group").unwrap_or_default().to_string(),
+            mode,
+            out: self.out.clone(),
+        };
+        let sub = Sub {
+            seq: self.next_sub,
+            acks,
+            task: tokio::spawn(sub.run(rx)),
+        };
+        self.subs.insert(id.to_string(), sub);
+        Ok(())
+    }
+
+    /// Hand an ACK or NACK to the subscription whose message it names
+    fn settle(&mut self, f: &Frame) -> Result<(), String> {
+        if f.get("transaction").is_some() {
+            return Err("transactions are not supported".to_string());
+        }
+        let ack = f.get("id").and_then(|id| id.split_once('-'));
+        let Some((Ok(seq), Ok(tag))) = ack.map(|(seq, tag)| (seq.parse::<u64>(), tag.parse::<u64>())) else {
+            return Err(format!("{} without a valid id", f.command));
+        };
+        // the subscription may be gone, and with it what it didn't get acked
+        if let Some(sub) = self.subs.values().find(|s| s.seq == seq) {
+            let _ = sub.acks.send((tag, f.command == "NACK"));
+        }
+        Ok(())
+    }
+}
+
+/// Deliveries of one SUBSCRIBE, on a session of their own
+struct Subscription {
+    gateway: Gateway,
+    session: Session,
+    id: String,
+    seq: u64,
+    destination: String,
+    topic: String,
+    group: String,
+    mode: AckMode,
+    out: mpsc::Sender<Vec<u8>>,
+}
+
+impl Subscription {
+    async fn run(mut self, mut acks: mpsc::UnboundedReceiver<(u64, bool)>) {
+        // delivery tags sent and not yet settled, in the order they went out
+        let mut unacked = VecDeque::new();
+        let out = self.out.clone();
+        loop {
+            // settle what's in before stopping, see `Sub::stop`
+            loop {
+                match acks.try_recv() {
+                    Ok((tag, requeue)) => self.settle(&mut unacked, tag, requeue).await,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            if unacked.len() >= MAX_INFLIGHT {
+                let Some((tag, requeue)) = acks.recv().await else {
+                    return;
+                };
+                self.settle(&mut unacked, tag, requeue).await;
+                continue;
+            }
+            // a permit first: nothing is taken off the topic for a client that's gone
+            let Ok(permit) = out.reserve().await else {
+                return;
+            };
+            let (tag, msg) = match self.gateway.next(&mut self.session, &self.topic, &self.group, SUBSCRIBE_POLL_MS).await {
+                // stopped or gone while waiting: the message goes back with the session
+                Ok(Some(_)) if out.is_closed() || acks.is_closed() => return,
+                Ok(Some(m)) => m,
+                Ok(None) => continue,
+                Err(st) => {
+                    let f = Frame::new("ERROR")
+                        .header("subscription", &self.id)
+                        .header("message", &format!("delivery from {} failed: {:?}", self.destination, st));
+                    permit.send(f.encode());
+                    return;
+                }
+            };
+            let message_id = match msg.envelope.message_id.as_str() {
+                "" => tag.to_string(),
+                id => id.to_string(),
+            };
+            let mut f = Frame::new("MESSAGE")
+                .header("subscription", &self.id)
+                .header("destination", &self.destination)
+                .header("message-id", &message_id)
+                .header("timestamp", &msg.envelope.timestamp_ms.to_string());
+            if !msg.envelope.content_type.is_empty() {
+                f = f.header("content-type", &msg.envelope.content_type);
+            }
+            if !msg.envelope.reply_to.is_empty() {
+                f = f.header("reply-to", &format!("/queue/{}", msg.envelope.reply_to));
+            }
+            if !msg.envelope.correlation_id.is_empty() {
+                f = f.header("correlation-id", &msg.envelope.correlation_id);
+            }
+            if self.mode == AckMode::Auto {
+                let st = self.gateway.settle(&mut self.session, &self.topic, &self.group, tag, false).await;
+                if st != Status::Ok {
+                    tracing::debug!("ack of {}#{} for {:?} failed: {:?}", self.topic, tag, self.group, st);
+                }
+            } else {
+                f = f.header("ack", &format!("{}-{}", self.seq, tag));
+                unacked.push_back(tag);
+            }
+            for (k, v) in &msg.envelope.headers {
+                // the frame's own headers come first and win, leave out the ones it would add
+                if k != "content-length" && f.get(k).is_none() {
+                    f = f.header(k, v);
+                }
+            }
+            f.body = msg.payload.to_vec();
+            permit.send(f.encode());
+        }
+    }
+
+    /// Ack, or requeue, `tag`, and in `client` mode everything sent before it
+    async fn settle(&mut self, unacked: &mut VecDeque<u64>, tag: u64, requeue: bool) {
+        let Some(at) = unacked.iter().position(|t| *t == tag) else {
+            return;
+        };
+        let tags: Vec<u64> = match self.mode {
+            AckMode::Client => unacked.drain(..=at).collect(),
+            _ => unacked.remove(at).into_iter().collect(),
+        };
+        for tag in tags {
+            let st = self.gateway.settle(&mut self.session, &self.topic, &self.group, tag, requeue).await;
+            if st != Status::Ok {
+                tracing::debug!("settling {}#{} for {:?} failed: {:?}", self.topic, tag, self.group, st);
+            }
+        }
+    }
+}
+
+/// The topic a destination names
+fn topic_of(dest: &str) -> &str {
+    dest.strip_prefix("/queue/").or_else(|| dest.strip_prefix("/topic/")).unwrap_or(dest)
+}
+
+/// A STOMP frame: command line, `name:value` header lines, a blank line,
+/// then the body, ended by a NUL
+#[derive(Debug)]
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The first value of header `name`, later repeats don't count
+    fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // CONNECTED is the one frame sent with its headers unescaped
+        let raw = self.command == "CONNECTED";
+        let mut out = Vec::with_capacity(64 + self.body.len());
+        out.extend_from_slice(self.command.as_bytes());
+        out.push(b'\n');
+        for (k, v) in &self.headers {
+            out.extend_from_slice(if raw { k.clone() } else { escape(k) }.as_bytes());
+            out.push(b':');
+            out.extend_from_slice(if raw { v.clone() } else { escape(v) }.as_bytes());
+            out.push(b'\n');
+        }
+        if !self.body.is_empty() {
+            out.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+        out
+    }
+}
+
+/// ERROR answering `to`, after which the connection is closed
+fn error(msg: &str, to: &Frame) -> Vec<u8> {
+    let mut f = Frame::new("ERROR").header("message", msg);
+    if let Some(receipt) = to.get("receipt") {
+        f = f.header("receipt-id", receipt);
+    }
+    f.encode()
+}
+
+/// What was read of a connection and isn't a whole frame yet
+#[derive(Default)]
+struct Incoming {
+    buf: BytesMut,
+    /// the frame at the start of `buf` without its body, once its headers
+    /// are in, and where the body starts
+    head: Option<(Frame, usize)>,
+    /// bytes of `buf` already searched for the end of the headers, or for
+    /// the NUL ending a body without content-length, so each read only
+    /// searches what it added
+    scanned: usize,
+}
+
+/// Next frame from `rd`, None once it's closed. Whatever was read is kept
+/// in `buf`, so it can be cancelled and called again.
+async fn read_frame<R: AsyncRead + Unpin>(rd: &mut R, buf: &mut Incoming) -> Result<Option<Frame>> {
+    loop {
+        if let Some(f) = buf.decode()? {
+            return Ok(Some(f));
+        }
+        if rd.read_buf(&mut buf.buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+impl Incoming {
+    /// Take a whole frame off `buf`. The body runs for `content-length`
+    /// bytes if the frame says, up to the first NUL if not. End of lines
+    /// between frames are heart-beats and skipped. Headers of more than
+    /// `MAX_HEADERS` bytes or a body of more than `MAX_FRAME` fail it.
+    fn decode(&mut self) -> Result<Option<Frame>> {
+        if self.head.is_none() {
+            let Some(head) = self.decode_head()? else {
+                return Ok(None);
+            };
+            self.head = Some(head);
+        }
+        let Some((f, start)) = &self.head else {
+            return Ok(None);
+        };
+        let (buf, start) = (&self.buf, *start);
+        let len = f.get("content-length").map(|v| v.parse::<usize>());
+        let body_len = match len {
+            Some(Ok(n)) if n > MAX_FRAME as usize => bail!("frame body of {} bytes, at most {}", n, MAX_FRAME),
+            Some(Ok(n)) => n,
+            Some(Err(_)) => bail!("malformed content-length"),
+            None => match buf[self.scanned.max(start)..].iter().position(|b| *b == 0) {
+                Some(n) => self.scanned.max(start) - start + n,
+                None if buf.len() - start > MAX_FRAME as usize => bail!("frame body of more than {} bytes", MAX_FRAME),
+                None => {
+                    self.scanned = buf.len();
+                    return Ok(None);
+                }
+            },
+        };
+        if buf.len() < start + body_len + 1 {
+            return Ok(None);
+        }
+        if buf[start + body_len] != 0 {
+            bail!("frame body not followed by NUL");
+        }
+        let Some((mut f, _)) = self.head.take() else {
+            return Ok(None);
+        };
+        f.body = self.buf[start..start + body_len].to_vec();
+        self.buf.advance(start + body_len + 1);
+        self.scanned = 0;
+        Ok(Some(f))
+    }
+
+    /// The command and headers of the frame at the start of `buf`, and
+    /// where its body starts, once they're all in
+    fn decode_head(&mut self) -> Result<Option<(Frame, usize)>> {
+        let buf = &mut self.buf;
+        let skip = buf.iter().take_while(|b| matches!(b, b'\r' | b'\n')).count();
+        buf.advance(skip);
+        self.scanned = self.scanned.saturating_sub(skip);
+        // the blank line after the headers, lines end with \n or \r\n. The
+        // search picks up two bytes back, it may straddle the last read.
+        let end = (self.scanned.saturating_sub(2)..buf.len()).find_map(|i| match &buf[i..] {
+            [b'\n', b'\n', ..] => Some((i, i + 2)),
+            [b'\n', b'\r', b'\n', ..] => Some((i, i + 3)),
+            _ => None,
+        });
+        let Some((head_len, start)) = end.filter(|(head_len, _)| *head_len <= MAX_HEADERS) else {
+            if buf.len() > MAX_HEADERS {
+                bail!("frame headers of more than {} bytes", MAX_HEADERS);
+            }
+            self.scanned = buf.len();
+            return Ok(None);
+        };
+        let head = std::str::from_utf8(&buf[..head_len])?;
+        let mut lines = head.lines();
+        let command = lines.next().unwrap_or_default().to_string();
+        let raw = command == "CONNECT" || command == "STOMP";
+        let mut headers = Vec::new();
+        for line in lines {
+            let Some((k, v)) = line.split_once(':') else {
+                bail!("malformed header {:?}", line);
+            };
+            if raw {
+                headers.push((k.to_string(), v.to_string()));
+            } else {
+                headers.push((unescape(k)?, unescape(v)?));
+            }
+        }
+        self.scanned = start;
+        Ok(Some((Frame { command, headers, body: Vec::new() }, start)))
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            ':' => out.push_str("\\c"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('c') => out.push(':'),
+            other => bail!("undefined escape \\{}", other.map(String::from).unwrap_or_default()),
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Size past which an audit log is rotated, see `AuditLog`
+pub const AUDIT_MAX_BYTES: u64 = 64 << 20;
+
+/// What happened to a message, as its topic's audit trail tells
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// written to the topic's log
+    Produce = 1,
+    /// handed out to a consumer, again if it's redelivered
+    Deliver = 2,
+    Ack = 3,
+    /// given back to the topic by its consumer
+    Nack = 4,
+}
+
+impl TryFrom<u8> for AuditEvent {
+    type Error = u8;
+    fn try_from(v: u8) -> Result<Self, u8> {
+        Ok(match v {
+            1 => AuditEvent::Produce,
+            2 => AuditEvent::Deliver,
+            3 => AuditEvent::Ack,
+            4 => AuditEvent::Nack,
+            v => return Err(v),
+        })
+    }
+}
+
+/// One entry of an audit trail
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// unix ms
+    pub at_ms: u64,
+    pub event: AuditEvent,
+    /// the message's seq in the topic's log, its delivery tag
+    pub seq: u64,
+    /// id of the connection the request came on, see `Session`, 0 for the
+    /// node's own deliveries like webhooks
+    pub conn: u64,
+    /// who authenticated on it, "" if no one did
+    pub identity: String,
+    /// consumer group, "" for the default one and for produces
+    pub group: String,
+    /// as the producer set it, "" if it didn't or the event doesn't carry it
+    pub message_id: String,
+}
+
+/// Audit trail of a topic: records appended to `{topic}.audit` next to its
+/// log. Each is `[u32 len][u64 at_ms][u8 event][u64 seq][u64 conn]` then
+/// identity, group and message id, each as `[u16 len][bytes]`. Records are
+/// flushed as they're appended and synced with the log. Past
+/// `AUDIT_MAX_BYTES` the file becomes `{topic}.audit.old`, replacing the
+/// one before, so a trail keeps the last one to two times that.
+pub struct AuditLog {
+    path: PathBuf,
+    old: PathBuf,
+    file: Mutex<(BufWriter<File>, u64)>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let f = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = f.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            old: old_path(path),
+            file: Mutex::new((BufWriter::new(f), len)),
+        })
+    }
+
+    pub fn append(&self, r: &AuditRecord) -> Result<()> {
+        let mut body = Vec::with_capacity(31 + r.identity.len() + r.group.len() + r.message_id.len());
+        body.extend_from_slice(&r.at_ms.to_be_bytes());
+        body.push(r.event as u8);
+        body.extend_from_slice(&r.seq.to_be_bytes());
+        body.extend_from_slice(&r.conn.to_be_bytes());
+        for s in [&r.identity, &r.group, &r.message_id] {
+            let s = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+            body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            body.extend_from_slice(s);
+        }
+        let mut file = self.file.lock().unwrap();
+        if file.1 >= AUDIT_MAX_BYTES {
+            file.0.flush()?;
+            std::fs::rename(&self.path, &self.old)?;
+            *file = (BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?), 0);
+        }
+        file.0.write_all(&(body.len() as u32).to_be_bytes())?;
+        file.0.write_all(&body)?;
+        file.0.flush()?;
+        file.1 += 4 + body.len() as u64;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.0.flush()?;
+        file.0.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Every record kept, oldest first. A torn record at the end, from a
+    /// crash mid-append, and anything unreadable after it is left out.
+    pub fn read(&self) -> Result<Vec<AuditRecord>> {
+        let _file = self.file.lock().unwrap();
+        let mut out = Vec::new();
+        for path in [&self.old, &self.path] {
+            let buf = match std::fs::read(path) {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut b = &buf[..];
+            while let Some((len, rest)) = b.split_first_chunk::<4>() {
+                let Some((rec, rest)) = rest.split_at_checked(u32::from_be_bytes(*len) as usize) else {
+                    break;
+                };
+                let Some(r) = decode(rec) else {
+                    break;
+                };
+                out.push(r);
+                b = rest;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Delete the audit files of the log at `path`, see `DiskLog::remove`
+    pub fn remove(path: &Path) -> Result<()> {
+        for p in [path.to_path_buf(), old_path(path)] {
+            match std::fs::remove_file(p) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn old_path(path: &Path) -> PathBuf {
+    let mut old = path.as_os_str().to_owned();
+    old.push(".old");
+    old.into()
+}
+
+fn decode(mut b: &[u8]) -> Option<AuditRecord> {
+    let (at_ms, rest) = b.split_first_chunk::<8>()?;
+    let (&event, rest) = rest.split_first()?;
+    let (seq, rest) = rest.split_first_chunk::<8>()?;
+    let (conn, rest) = rest.split_first_chunk::<8>()?;
+    b = rest;
+    let mut str = || {
+        let (len, rest) = b.split_first_chunk::<2>()?;
+        let (s, rest) = rest.split_at_checked(u16::from_be_bytes(*len) as usize)?;
+        b = rest;
+        Some(String::from_utf8_lossy(s).into_owned())
+    };
+    Some(AuditRecord {
+        at_ms: u64::from_be_bytes(*at_ms),
+        event: AuditEvent::try_from(event).ok()?,
+        seq: u64::from_be_bytes(*seq),
+        conn: u64::from_be_bytes(*conn),
+        identity: str()?,
+        group: str()?,
+        message_id: str()?,
+    })
+}
@@ -1,76 +1,440 @@
 use anyhow::Result;
+use bytes::Bytes;
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-/// Record: [u8 type=1][u64 seq][u32 len][bytes]
+use crate::protocol::{namespaced, split_namespace, valid_namespace};
+use crate::storage::audit_log::AuditLog;
+
+/// Size at which the active segment is sealed and a new one started
+pub const SEGMENT_BYTES: u64 = 128 * 1024 * 1024;
+
+/// Log bytes between two entries of a segment's sparse index
+const INDEX_INTERVAL: u64 = 4096;
+
+/// Directory of the data dir holding logs of transient topics, emptied on
+/// startup. Not a valid namespace, so `list` skips it.
+pub const TRANSIENT_DIR: &str = ".transient";
+
+/// Binding of a named group as kept in its files, "" where there's none
+#[derive(Debug, Clone, Default)]
+pub struct StoredBinding {
+    pub key: String,
+    pub headers: String,
+    pub filter: String,
+    pub sample: String,
+}
+
+/// Record: [u8 type][u64 seq][u32 len][body]
+/// type 1 body: [bytes]
+/// type 2 body: [u64 enqueue unix ms][bytes]
+/// type 3 body: [u64 enqueue unix ms][u8 priority][bytes]
+/// type 4 body: [u64 enqueue unix ms][u8 priority][u16 len][routing key][bytes]
+/// type 5 body: [u64 enqueue unix ms][u8 priority][u16 len][routing key][u32 len][envelope][bytes]
+/// type 6 body: [u32 crc32 of header + rest of body][type 5 body]
+///
+/// Records live in segments `{topic}.segments/{base seq:020}.log`, appends
+/// go to the last one until it reaches `SEGMENT_BYTES`.
+///
+/// Each segment has a sparse `{base seq:020}.index` of [u64 seq][u64 offset]
+/// entries, about one per `INDEX_INTERVAL` bytes of log. It is only a hint:
+/// lost entries make reads scan further, so it is not fsynced.
 #[derive(Clone)]
 pub struct DiskLog {
-    path: PathBuf,
-    writer: Arc<Mutex<BufWriter<File>>>,
+    seg_dir: PathBuf,
+    segments: Arc<Mutex<Segments>>,
     seq: Arc<AtomicU64>,
     ack_path: PathBuf,
+    /// holds `{group}.ack`, `{group}.bind`, `{group}.headers`,
+    /// `{group}.filter` and `{group}.sample` of each named consumer group
+    groups_dir: PathBuf,
+    /// `{topic}.audit`, see `AuditLog`
+    audit_path: PathBuf,
+    /// sync records and acks to disk as they're written, off for transient logs
+    fsync: bool,
+}
+
+/// In-memory segment index plus the writer of the active (last) segment
+struct Segments {
+    /// base seqs in ascending order
+    bases: Vec<u64>,
+    writer: BufWriter<File>,
+    active_len: u64,
+    index: BufWriter<File>,
+    /// offset of the active segment's last index entry
+    indexed_at: Option<u64>,
 }
 
 impl DiskLog {
     pub fn open<P: AsRef<Path>>(dir: P, topic: &str) -> Result<Self> {
         let dir = dir.as_ref();
-        std::fs::create_dir_all(dir)?;
-        let path = dir.join(format!("{}.log", topic));
+        let seg_dir = dir.join(format!("{}.segments", topic));
         let ack_path = dir.join(format!("{}.ack", topic));
+        let groups_dir = dir.join(format!("{}.groups", topic));
+        let audit_path = dir.join(format!("{}.audit", topic));
+        std::fs::create_dir_all(&seg_dir)?;
+
+        let mut bases = list_segments(&seg_dir)?;
+        // logs from before segmenting become the first segment
+        let legacy = dir.join(format!("{}.log", topic));
+        if bases.is_empty() && legacy.exists() {
+            std::fs::rename(&legacy, segment_path(&seg_dir, 1))?;
+            bases.push(1);
+        }
+        if bases.is_empty() {
+            bases.push(1);
+        }
+        let base = *bases.last().unwrap();
+        let path = segment_path(&seg_dir, base);
         let f = OpenOptions::new()
             .create(true)
             .append(true)
             .read(true)
             .open(&path)?;
-        // scan last seq
-        let mut last = 0u64;
+        // scan last seq of the active segment
+        let mut last = base - 1;
         if f.metadata()?.len() > 0 {
             let mut r = &f;
             r.seek(SeekFrom::Start(0))?;
             let mut buf = Vec::new();
             r.read_to_end(&mut buf)?;
-            let mut off = 0usize;
-            while off + 13 <= buf.len() {
-                let _t = buf[off];
-                let seq = u64::from_be_bytes(buf[off + 1..off + 9].try_into().unwrap());
-                let n = u32::from_be_bytes(buf[off + 9..off + 13].try_into().unwrap()) as usize;
-                off += 13 + n;
-                last = seq;
-            }
-        }
-        let writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
-        Ok(Self {
-            path,
-            writer: Arc::new(Mutex::new(writer)),
+            let (off, corrupt) = scan_records(&buf, |_, seq, _| last = seq);
+            // a crash mid-append leaves a partial record, drop it so new
+            // records don't land behind garbage. Past a corrupt record the
+            // lengths can't be trusted either, so everything after it goes,
+            // kept aside in `{base:020}.log.corrupt` for whoever wants to dig.
+            if off < buf.len() && corrupt {
+                let aside = path.with_extension("log.corrupt");
+                // appended to, an earlier one's tail is kept too
+                let mut w = OpenOptions::new().create(true).append(true).open(&aside)?;
+                w.write_all(&buf[off..])?;
+                w.sync_all()?;
+                let (lost, max_seq) = resync(&buf[off..]);
+                // seqs of what was lost aren't handed out again
+                last = last.max(max_seq);
+                tracing::error!(
+                    "corrupt record in {}, truncating {} bytes holding {} records (at least), moved to {}",
+                    path.display(),
+                    buf.len() - off,
+                    lost,
+                    aside.display()
+                );
+            } else if off < buf.len() {
+                tracing::warn!("truncating {} torn bytes at the end of {}", buf.len() - off, path.display());
+            }
+            if off < buf.len() {
+                f.set_len(off as u64)?;
+                f.sync_all()?;
+            }
+        }
+        let active_len = f.metadata()?.len();
+        let writer = BufWriter::new(f);
+        // entries past a truncated tail point at nothing
+        let mut entries = read_index(&index_path(&seg_dir, base))?;
+        let n = entries.len();
+        entries.retain(|&(_, off)| off < active_len);
+        if entries.len() < n {
+            let mut b = Vec::with_capacity(entries.len() * 16);
+            for (seq, off) in &entries {
+                b.extend_from_slice(&seq.to_be_bytes());
+                b.extend_from_slice(&off.to_be_bytes());
+            }
+            std::fs::write(index_path(&seg_dir, base), b)?;
+        }
+        let index = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(index_path(&seg_dir, base))?,
+        );
+        let indexed_at = entries.last().map(|&(_, off)| off);
+        let log = Self {
+            seg_dir,
+            segments: Arc::new(Mutex::new(Segments {
+                bases,
+                writer,
+                active_len,
+                index,
+                indexed_at,
+            })),
             seq: Arc::new(AtomicU64::new(last)),
             ack_path,
-        })
+            groups_dir,
+            audit_path,
+            fsync: true,
+        };
+        // records cut off above a committed offset must not have their seqs
+        // reused, consumers would take the new ones as already acked
+        let mut committed = log.read_acked("")?;
+        for g in log.group_names()? {
+            committed = committed.max(log.read_acked(&g)?);
+        }
+        log.seq.fetch_max(committed, Ordering::SeqCst);
+        Ok(log)
     }
 
-    pub fn append(&self, payload: &[u8]) -> Result<u64> {
-        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
-        let mut rec = Vec::with_capacity(13 + payload.len());
-        rec.push(1u8);
+    /// Log of a transient topic, in `TRANSIENT_DIR` of `dir`. Nothing is
+    /// synced to disk, and the log is gone after a restart.
+    pub fn open_transient<P: AsRef<Path>>(dir: P, topic: &str) -> Result<Self> {
+        let mut log = Self::open(dir.as_ref().join(TRANSIENT_DIR), topic)?;
+        log.fsync = false;
+        Ok(log)
+    }
+
+    /// `envelope` is stored as is, the log does not look inside it
+    pub fn append(
+        &self,
+        at_ms: u64,
+        priority: u8,
+        routing_key: &str,
+        envelope: &[u8],
+        payload: &[u8],
+    ) -> Result<u64> {
+        // seqs are assigned under the lock so segments stay in seq order
+        let mut segs = self.segments.lock().unwrap();
+        let seq = self.seq.load(Ordering::SeqCst) + 1;
+        self.write_record(&mut segs, seq, at_ms, priority, routing_key, envelope, payload)?;
+        Ok(seq)
+    }
+
+    /// Append a record under a seq assigned elsewhere, as a follower copying
+    /// its leader's log. Seqs at or below the last one are skipped, returns
+    /// false for those.
+    pub fn append_at(
+        &self,
+        seq: u64,
+        at_ms: u64,
+        priority: u8,
+        routing_key: &str,
+        envelope: &[u8],
+        payload: &[u8],
+    ) -> Result<bool> {
+        let mut segs = self.segments.lock().unwrap();
+        if seq <= self.seq.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        self.write_record(&mut segs, seq, at_ms, priority, routing_key, envelope, payload)?;
+        Ok(true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_record(
+        &self,
+        segs: &mut Segments,
+        seq: u64,
+        at_ms: u64,
+        priority: u8,
+        routing_key: &str,
+        envelope: &[u8],
+        payload: &[u8],
+    ) -> Result<()> {
+        let key = routing_key.as_bytes();
+        let n = 19 + key.len() + envelope.len() + payload.len();
+        let mut rec = Vec::with_capacity(13 + n);
+        rec.push(6u8);
         rec.extend_from_slice(&seq.to_be_bytes());
-        rec.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        rec.extend_from_slice(&(n as u32).to_be_bytes());
+        rec.extend_from_slice(&[0; 4]); // crc, filled in below
+        rec.extend_from_slice(&at_ms.to_be_bytes());
+        rec.push(priority);
+        rec.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        rec.extend_from_slice(key);
+        rec.extend_from_slice(&(envelope.len() as u32).to_be_bytes());
+        rec.extend_from_slice(envelope);
         rec.extend_from_slice(payload);
-        let mut w = self.writer.lock().unwrap();
+        let crc = record_crc(&rec[..13], &rec[17..]);
+        rec[13..17].copy_from_slice(&crc.to_be_bytes());
+        if segs.active_len > 0 && segs.active_len + rec.len() as u64 > SEGMENT_BYTES {
+            self.roll(segs, seq)?;
+        }
+        let w = &mut segs.writer;
         w.write_all(&rec)?;
         w.flush()?;
-        if let Ok(f) = w.get_ref().try_clone() {
-            f.sync_all()?;
+        if self.fsync {
+            w.get_ref().sync_all()?;
+        }
+        let pos = segs.active_len;
+        if segs.indexed_at.is_none_or(|at| pos - at >= INDEX_INTERVAL) {
+            segs.index.write_all(&seq.to_be_bytes())?;
+            segs.index.write_all(&pos.to_be_bytes())?;
+            segs.index.flush()?;
+            segs.indexed_at = Some(pos);
+        }
+        segs.active_len += rec.len() as u64;
+        self.seq.store(seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Seal the active segment and start a new one at `base`
+    fn roll(&self, segs: &mut Segments, base: u64) -> Result<()> {
+        let path = segment_path(&self.seg_dir, base);
+        let f = OpenOptions::new().create(true).append(true).open(&path)?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(index_path(&self.seg_dir, base))?;
+        segs.writer = BufWriter::new(f);
+        segs.active_len = 0;
+        segs.index = BufWriter::new(index);
+        segs.indexed_at = None;
+        segs.bases.push(base);
+        tracing::debug!("rolled {} at #{}", self.seg_dir.display(), base);
+        Ok(())
+    }
+
+    /// Delete sealed segments, oldest first, while the oldest is older than
+    /// `max_age` or the log is larger than `max_bytes`. A segment is only
+    /// deleted once every record in it is at or below `committed`.
+    /// Returns the number of segments deleted.
+    pub fn trim(&self, committed: u64, max_age: Option<Duration>, max_bytes: Option<u64>) -> Result<usize> {
+        let mut segs = self.segments.lock().unwrap();
+        let mut total = segs.active_len;
+        for &b in &segs.bases[..segs.bases.len() - 1] {
+            total += std::fs::metadata(segment_path(&self.seg_dir, b))?.len();
+        }
+        let mut removed = 0;
+        // the active segment is never deleted
+        while segs.bases.len() > 1 {
+            if segs.bases[1] - 1 > committed {
+                break;
+            }
+            let path = segment_path(&self.seg_dir, segs.bases[0]);
+            let meta = std::fs::metadata(&path)?;
+            // sealed segments are not written again, mtime is their last append
+            let too_old = match max_age {
+                Some(age) => meta.modified()?.elapsed().is_ok_and(|e| e >= age),
+                None => false,
+            };
+            let too_big = max_bytes.is_some_and(|b| total > b);
+            if !too_old && !too_big {
+                break;
+            }
+            std::fs::remove_file(&path)?;
+            match std::fs::remove_file(index_path(&self.seg_dir, segs.bases[0])) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            total -= meta.len();
+            segs.bases.remove(0);
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Segment files with their base seq, oldest first
+    fn segment_files(&self) -> Vec<(u64, PathBuf)> {
+        let segs = self.segments.lock().unwrap();
+        segs.bases
+            .iter()
+            .map(|&b| (b, segment_path(&self.seg_dir, b)))
+            .collect()
+    }
+
+    /// Get the active segment and its index onto disk. Records are synced
+    /// as they're appended, index entries only flushed.
+    pub fn sync(&self) -> Result<()> {
+        let mut segs = self.segments.lock().unwrap();
+        segs.writer.flush()?;
+        segs.writer.get_ref().sync_all()?;
+        segs.index.flush()?;
+        segs.index.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Audit trail of the topic, kept next to its log
+    pub fn open_audit(&self) -> Result<AuditLog> {
+        AuditLog::open(&self.audit_path)
+    }
+
+    /// Delete segments, ack and audit files of this topic, including consumer groups
+    pub fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.ack_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        AuditLog::remove(&self.audit_path)?;
+        for d in [&self.seg_dir, &self.groups_dir] {
+            match std::fs::remove_dir_all(d) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Topics with a log in `dir`
+    pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+        let rd = match std::fs::read_dir(dir) {
+            Ok(rd) => rd,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(out),
+            Err(e) => return Err(e.into()),
+        };
+        for ent in rd {
+            let ent = ent?;
+            let name = ent.file_name();
+            let Some(name) = name.to_str() else { continue };
+            // `.log` is the unsegmented layout, migrated on open
+            if let Some(topic) = name.strip_suffix(".segments").or_else(|| name.strip_suffix(".log")) {
+                out.push(topic.to_string());
+            } else if valid_namespace(name) && ent.file_type()?.is_dir() {
+                // topics of a namespace live in a directory named after it
+                for t in Self::list(ent.path())? {
+                    if split_namespace(&t).0.is_none() {
+                        out.push(namespaced(name, &t));
+                    }
+                }
+            }
+        }
+        out.sort();
+        out.dedup();
+        Ok(out)
+    }
+
+    /// Named groups that have an ack or binding file
+    pub fn group_names(&self) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+        let rd = match std::fs::read_dir(&self.groups_dir) {
+            Ok(rd) => rd,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(out),
+            Err(e) => return Err(e.into()),
+        };
+        for ent in rd {
+            let name = ent?.file_name();
+            let Some(name) = name.to_str() else { continue };
+            if let Some(g) = name.strip_suffix(".ack").or_else(|| name.strip_suffix(".bind")) {
+                out.push(g.to_string());
+            }
+        }
+        out.sort();
+        out.dedup();
+        Ok(out)
+    }
+
+    /// "" is the default group, stored as `{topic}.ack`
+    fn group_ack_path(&self, group: &str) -> PathBuf {
+        if group.is_empty() {
+            self.ack_path.clone()
+        } else {
+            self.groups_dir.join(format!("{}.ack", group))
         }
-        Ok(seq)
     }
 
-    pub fn read_acked(&self) -> Result<u64> {
-        if !self.ack_path.exists() {
+    /// seq of the last appended record (0 if empty)
+    pub fn last_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub fn read_acked(&self, group: &str) -> Result<u64> {
+        let path = self.group_ack_path(group);
+        if !path.exists() {
             return Ok(0);
         }
-        let mut f = File::open(&self.ack_path)?;
+        let mut f = File::open(&path)?;
         let mut b = [0u8; 8];
         if f.read(&mut b)? < 8 {
             return Ok(0);
@@ -78,68 +442,329 @@ impl DiskLog {
         Ok(u64::from_be_bytes(b))
     }
 
-    pub fn write_acked(&self, s: u64) -> Result<()> {
+    pub fn write_acked(&self, group: &str, s: u64) -> Result<()> {
+        if !group.is_empty() {
+            std::fs::create_dir_all(&self.groups_dir)?;
+        }
         let mut f = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(&self.ack_path)?;
+            .open(self.group_ack_path(group))?;
         f.write_all(&s.to_be_bytes())?;
+        if self.fsync {
+            f.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Binding of a named group, None if it was never bound
+    pub fn read_binding(&self, group: &str) -> Result<Option<StoredBinding>> {
+        let Some(key) = self.read_group_file(group, "bind")? else {
+            return Ok(None);
+        };
+        Ok(Some(StoredBinding {
+            key,
+            headers: self.read_group_file(group, "headers")?.unwrap_or_default(),
+            filter: self.read_group_file(group, "filter")?.unwrap_or_default(),
+            sample: self.read_group_file(group, "sample")?.unwrap_or_default(),
+        }))
+    }
+
+    /// The conditions go first, so a crash in between leaves the key of
+    /// the previous binding with them at worst, never the key alone
+    pub fn write_binding(&self, group: &str, b: &StoredBinding) -> Result<()> {
+        std::fs::create_dir_all(&self.groups_dir)?;
+        for (ext, v) in [("headers", &b.headers), ("filter", &b.filter), ("sample", &b.sample)] {
+            let path = self.groups_dir.join(format!("{}.{}", group, ext));
+            if v.is_empty() {
+                remove_if_exists(&path)?;
+                continue;
+            }
+            let mut f = File::create(path)?;
+            f.write_all(v.as_bytes())?;
+            f.sync_all()?;
+        }
+        let mut f = File::create(self.groups_dir.join(format!("{}.bind", group)))?;
+        f.write_all(b.key.as_bytes())?;
         f.sync_all()?;
         Ok(())
     }
 
-    /// (seq,payload) of unacked
-    pub fn replay_unacked(&self) -> Result<Vec<(u64, Vec<u8>)>> {
-        let acked = self.read_acked()?;
-        let mut f = File::open(&self.path)?;
-        f.seek(SeekFrom::Start(0))?;
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf)?;
-        let mut off = 0usize;
+    /// Forget the binding of a named group, it gets everything again
+    pub fn remove_binding(&self, group: &str) -> Result<()> {
+        for ext in ["bind", "headers", "filter", "sample"] {
+            remove_if_exists(&self.groups_dir.join(format!("{}.{}", group, ext)))?;
+        }
+        Ok(())
+    }
+
+    /// `{group}.{ext}` of the groups dir, None if there's none
+    fn read_group_file(&self, group: &str, ext: &str) -> Result<Option<String>> {
+        match std::fs::read_to_string(self.groups_dir.join(format!("{}.{}", group, ext))) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Forget a named group: its committed offset and binding
+    pub fn remove_group(&self, group: &str) -> Result<()> {
+        self.remove_binding(group)?;
+        match std::fs::remove_file(self.group_ack_path(group)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// records not yet acked by `group`, in log order
+    pub fn replay_unacked(&self, group: &str) -> Result<Vec<LogEntry>> {
+        self.read_after(self.read_acked(group)?)
+    }
+
+    /// Every record with a seq above `acked`
+    pub fn read_after(&self, acked: u64) -> Result<Vec<LogEntry>> {
+        self.read_some_after(acked, usize::MAX)
+    }
+
+    /// The first `max` records with a seq above `acked`
+    pub fn read_some_after(&self, acked: u64, max: usize) -> Result<Vec<LogEntry>> {
+        let files = self.segment_files();
         let mut out = Vec::new();
-        while off + 13 <= buf.len() {
-            let t = buf[off];
-            let seq = u64::from_be_bytes(buf[off + 1..off + 9].try_into().unwrap());
-            let n = u32::from_be_bytes(buf[off + 9..off + 13].try_into().unwrap()) as usize;
-            let s = off + 13;
-            let e = s + n;
-            if e > buf.len() {
+        for (i, (base, path)) in files.iter().enumerate() {
+            if out.len() >= max {
                 break;
             }
-            if t == 1 && seq > acked {
-                out.push((seq, buf[s..e].to_vec()));
+            // every seq in a segment is below the next segment's base
+            if files.get(i + 1).is_some_and(|(next, _)| *next <= acked + 1) {
+                continue;
             }
-            off = e;
+            // start at the last indexed record not past the first unacked one
+            let start = read_index(&index_path(&self.seg_dir, *base))?
+                .into_iter()
+                .take_while(|&(seq, _)| seq <= acked + 1)
+                .last()
+                .map_or(0, |(_, off)| off);
+            read_segment(path, start, |seq, entry| {
+                if seq > acked && out.len() < max {
+                    out.push(entry);
+                }
+            })?;
         }
         Ok(out)
     }
 
-    pub fn read_last_n(&self, n: usize) -> Result<Vec<Vec<u8>>> {
-        let mut f = File::open(&self.path)?;
-        f.seek(SeekFrom::Start(0))?;
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf)?;
-        if buf.is_empty() {
-            return Ok(Vec::new());
+    /// Seq of the first record still in the log that was enqueued at or
+    /// after `at_ms`, the one after the last if there's none. Records of
+    /// type 1 carry no time and count as older than any.
+    pub fn seq_at(&self, at_ms: u64) -> Result<u64> {
+        // times come from the leader's clock on replicas, segment mtimes
+        // can't rule a segment out
+        for (_, path) in self.segment_files() {
+            let mut found = None;
+            read_segment(&path, 0, |seq, entry| {
+                if found.is_none() && entry.at_ms >= at_ms {
+                    found = Some(seq);
+                }
+            })?;
+            if let Some(seq) = found {
+                return Ok(seq);
+            }
         }
-        let mut off = 0usize;
-        let mut out = Vec::new();
-        while off + 13 <= buf.len() {
-            let t = buf[off];
-            let seq = u64::from_be_bytes(buf[off + 1..off + 9].try_into().unwrap());
-            let len = u32::from_be_bytes(buf[off + 9..off + 13].try_into().unwrap()) as usize;
-            let s = off + 13;
-            let e = s + len;
-            if e > buf.len() {
+        Ok(self.last_seq() + 1)
+    }
+
+    /// The last `n` records still in the log, oldest first
+    pub fn read_last_n(&self, n: usize) -> Result<Vec<LogEntry>> {
+        // newest segments first, stop once there are enough records
+        let mut chunks = Vec::new();
+        let mut count = 0;
+        for (_, path) in self.segment_files().iter().rev() {
+            let mut chunk = Vec::new();
+            read_segment(path, 0, |_, entry| chunk.push(entry))?;
+            count += chunk.len();
+            chunks.push(chunk);
+            if count >= n {
                 break;
             }
-            if t == 1 {
-                out.push(buf[s..e].to_vec());
-            }
-            off = e;
         }
+        let mut out: Vec<LogEntry> = chunks.into_iter().rev().flatten().collect();
         let start = out.len().saturating_sub(n);
         Ok(out.split_off(start))
     }
 }
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn segment_path(seg_dir: &Path, base: u64) -> PathBuf {
+    seg_dir.join(format!("{:020}.log", base))
+}
+
+fn index_path(seg_dir: &Path, base: u64) -> PathBuf {
+    seg_dir.join(format!("{:020}.index", base))
+}
+
+/// (seq, offset) entries of a segment index, empty if there is none
+fn read_index(path: &Path) -> Result<Vec<(u64, u64)>> {
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(buf
+        .chunks_exact(16)
+        .map(|c| {
+            let seq = u64::from_be_bytes(c[..8].try_into().unwrap());
+            let off = u64::from_be_bytes(c[8..].try_into().unwrap());
+            (seq, off)
+        })
+        .collect())
+}
+
+/// Base seqs of the segments in `seg_dir`, ascending
+fn list_segments(seg_dir: &Path) -> Result<Vec<u64>> {
+    let mut bases = Vec::new();
+    for ent in std::fs::read_dir(seg_dir)? {
+        let name = ent?.file_name();
+        if let Some(base) = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".log"))
+            .and_then(|n| n.parse().ok())
+        {
+            bases.push(base);
+        }
+    }
+    bases.sort_unstable();
+    Ok(bases)
+}
+
+/// Decode every complete record of a segment from byte `start`, in file order
+fn read_segment(path: &Path, start: u64, mut f: impl FnMut(u64, LogEntry)) -> Result<()> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        // removed by a concurrent delete
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let (_, corrupt) = scan_records(&buf, |t, seq, body| {
+        if let Some(mut entry) = decode_body(t, body) {
+            entry.seq = seq;
+            f(seq, entry);
+        }
+    });
+    if corrupt {
+        tracing::warn!("corrupt record in {}, skipping the rest of the segment", path.display());
+    }
+    Ok(())
+}
+
+/// Walk the complete records in `buf`, calling `f(type, seq, body)` for each.
+/// Checksummed records are verified and passed on as their unchecked type.
+/// Returns where the valid records end and whether a bad checksum stopped the walk.
+fn scan_records(buf: &[u8], mut f: impl FnMut(u8, u64, &[u8])) -> (usize, bool) {
+    let mut off = 0usize;
+    while off + 13 <= buf.len() {
+        let t = buf[off];
+        let seq = u64::from_be_bytes(buf[off + 1..off + 9].try_into().unwrap());
+        let n = u32::from_be_bytes(buf[off + 9..off + 13].try_into().unwrap()) as usize;
+        let s = off + 13;
+        let e = s + n;
+        if e > buf.len() {
+            break;
+        }
+        let body = &buf[s..e];
+        if t == 6 {
+            let Some((crc, rest)) = body.split_first_chunk::<4>() else {
+                return (off, true);
+            };
+            if u32::from_be_bytes(*crc) != record_crc(&buf[off..s], rest) {
+                return (off, true);
+            }
+            f(5, seq, rest);
+        } else {
+            f(t, seq, body);
+        }
+        off = e;
+    }
+    (off, false)
+}
+
+/// Records in `buf`, which starts with a corrupt one: that one, and each
+/// checksummed record found past it whose checksum checks out, with the
+/// highest seq among them
+fn resync(buf: &[u8]) -> (usize, u64) {
+    let (mut lost, mut max_seq) = (1, 0);
+    let mut off = 1;
+    while off + 17 <= buf.len() {
+        let n = u32::from_be_bytes(buf[off + 9..off + 13].try_into().unwrap()) as usize;
+        let e = off + 13 + n;
+        if buf[off] == 6 && n >= 4 && e <= buf.len() {
+            let crc = u32::from_be_bytes(buf[off + 13..off + 17].try_into().unwrap());
+            if crc == record_crc(&buf[off..off + 13], &buf[off + 17..e]) {
+                lost += 1;
+                max_seq = max_seq.max(u64::from_be_bytes(buf[off + 1..off + 9].try_into().unwrap()));
+                off = e;
+                continue;
+            }
+        }
+        off += 1;
+    }
+    (lost, max_seq)
+}
+
+fn record_crc(header: &[u8], rest: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(header);
+    h.update(rest);
+    h.finalize()
+}
+
+/// Decoded record, `seq` is filled in by the caller
+pub struct LogEntry {
+    pub seq: u64,
+    /// enqueue unix ms, 0 for type 1 records
+    pub at_ms: u64,
+    pub priority: u8,
+    /// empty for records older than type 4
+    pub routing_key: String,
+    /// encoded envelope, empty for records older than type 5
+    pub envelope: Vec<u8>,
+    pub payload: Bytes,
+}
+
+/// None for unknown types or truncated bodies
+fn decode_body(t: u8, body: &[u8]) -> Option<LogEntry> {
+    let at_ms = |b: &[u8]| u64::from_be_bytes(b[..8].try_into().unwrap());
+    let (at_ms, priority, key, envelope, payload) = match t {
+        1 => (0, 0, &[][..], &[][..], body),
+        2 if body.len() >= 8 => (at_ms(body), 0, &[][..], &[][..], &body[8..]),
+        3 if body.len() >= 9 => (at_ms(body), body[8], &[][..], &[][..], &body[9..]),
+        4 | 5 if body.len() >= 11 => {
+            let n = u16::from_be_bytes([body[9], body[10]]) as usize;
+            let (key, mut rest) = body[11..].split_at_checked(n)?;
+            let mut envelope = &[][..];
+            if t == 5 {
+                let (len, tail) = rest.split_first_chunk::<4>()?;
+                (envelope, rest) = tail.split_at_checked(u32::from_be_bytes(*len) as usize)?;
+            }
+            (at_ms(body), body[8], key, envelope, rest)
+        }
+        _ => return None,
+    };
+    Some(LogEntry {
+        seq: 0,
+        at_ms,
+        priority,
+        routing_key: String::from_utf8_lossy(key).into_owned(),
+        envelope: envelope.to_vec(),
+        payload: Bytes::copy_from_slice(payload),
+    })
+}
@@ -0,0 +1,217 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::queue::{TopicConfig, TopicRegistry};
+use crate::scheduler::Schedule;
+
+/// Changes journaled before the next save writes a snapshot instead
+const SNAPSHOT_EVERY: usize = 1000;
+
+/// Broker configuration that has to survive a restart. Messages and
+/// consumer offsets live in the topic logs, not here.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrokerMetadata {
+    /// topic name -> settings it was created with
+    #[serde(default)]
+    pub topics: BTreeMap<String, TopicConfig>,
+    /// topics led elsewhere that this node follows
+    #[serde(default)]
+    pub replicas: BTreeMap<String, TopicConfig>,
+    /// topic name -> patterns of topic names it gets copies of messages
+    /// from, see `TopicRegistry::subscribe`
+    #[serde(default)]
+    pub subscriptions: BTreeMap<String, BTreeSet<String>>,
+    /// topic name -> schedule name -> what it publishes when, see
+    /// `Op::Schedule`
+    #[serde(default)]
+    pub schedules: BTreeMap<String, BTreeMap<String, Schedule>>,
+}
+
+/// One change of `BrokerMetadata`, what the journal is made of. Each sets
+/// a topic's entry as a whole, None or empty removing it, so replaying one
+/// that's already in the snapshot changes nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum MetadataOp {
+    Topic { name: String, config: Option<TopicConfig> },
+    Replica { name: String, config: Option<TopicConfig> },
+    Subscriptions { topic: String, patterns: BTreeSet<String> },
+    Schedules { topic: String, schedules: BTreeMap<String, Schedule> },
+}
+
+impl BrokerMetadata {
+    /// What turns this into `newer`
+    pub fn diff(&self, newer: &BrokerMetadata) -> Vec<MetadataOp> {
+        let mut ops = Vec::new();
+        diff_map(&self.topics, &newer.topics, |name, config| ops.push(MetadataOp::Topic { name, config }));
+        diff_map(&self.replicas, &newer.replicas, |name, config| ops.push(MetadataOp::Replica { name, config }));
+        diff_map(&self.subscriptions, &newer.subscriptions, |topic, patterns| {
+            ops.push(MetadataOp::Subscriptions { topic, patterns: patterns.unwrap_or_default() })
+        });
+        diff_map(&self.schedules, &newer.schedules, |topic, schedules| {
+            ops.push(MetadataOp::Schedules { topic, schedules: schedules.unwrap_or_default() })
+        });
+        ops
+    }
+
+    pub fn apply(&mut self, op: MetadataOp) {
+        match op {
+            MetadataOp::Topic { name, config } => set(&mut self.topics, name, config),
+            MetadataOp::Replica { name, config } => set(&mut self.replicas, name, config),
+            MetadataOp::Subscriptions { topic, patterns } => {
+                set(&mut self.subscriptions, topic, Some(patterns).filter(|p| !p.is_empty()))
+            }
+            MetadataOp::Schedules { topic, schedules } => {
+                set(&mut self.schedules, topic, Some(schedules).filter(|s| !s.is_empty()))
+            }
+        }
+    }
+}
+
+/// Call `changed` with each key whose value differs between `old` and
+/// `new`, and its new value, None if it's gone
+fn diff_map<V: PartialEq + Clone>(old: &BTreeMap<String, V>, new: &BTreeMap<String, V>, mut changed: impl FnMut(String, Option<V>)) {
+    for (k, v) in new {
+        if old.get(k) != Some(v) {
+            changed(k.clone(), Some(v.clone()));
+        }
+    }
+    for k in old.keys().filter(|k| !new.contains_key(*k)) {
+        changed(k.clone(), None);
+    }
+}
+
+fn set<V>(map: &mut BTreeMap<String, V>, k: String, v: Option<V>) {
+    match v {
+        Some(v) => map.insert(k, v),
+        None => map.remove(&k),
+    };
+}
+
+pub trait MetadataStorage: Send + Sync {
+    /// Empty metadata if nothing was saved yet
+    fn load(&self) -> Result<BrokerMetadata>;
+    /// Make `meta` what the next `load` returns
+    fn save(&self, meta: &BrokerMetadata) -> Result<()>;
+}
+
+/// Save the metadata of `topics`. Snapshot and save happen under one lock,
+/// so a slow save can't overwrite a newer snapshot.
+pub fn save_topics(store: &dyn MetadataStorage, topics: &TopicRegistry) -> Result<()> {
+    static SAVING: Mutex<()> = Mutex::new(());
+    let _saving = SAVING.lock().unwrap();
+    store.save(&topics.metadata())
+}
+
+/// Keeps metadata as a snapshot, `metadata.json` in the data dir, and a
+/// journal of what changed since, `metadata.journal`: a line of JSON
+/// `MetadataOp`s per save, appended and synced, so a save costs what
+/// changed rather than every topic. Every `SNAPSHOT_EVERY` saves, and on
+/// the first save before anything was loaded, the snapshot is rewritten
+/// and the journal emptied. Loading replays the journal over the snapshot.
+pub struct LocalMetadataStorage {
+    path: PathBuf,
+    journal: PathBuf,
+    /// what snapshot and journal hold together, and the saves journaled
+    /// since the snapshot. None until loaded or saved.
+    saved: Mutex<Option<(BrokerMetadata, usize)>>,
+}
+
+impl LocalMetadataStorage {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            path: PathBuf::from(data_dir).join("metadata.json"),
+            journal: PathBuf::from(data_dir).join("metadata.journal"),
+            saved: Mutex::new(None),
+        }
+    }
+
+    /// Write `meta` as the snapshot, then empty the journal. A crash in
+    /// between replays changes the snapshot already has, which is harmless.
+    fn snapshot(&self, meta: &BrokerMetadata) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // write aside and rename, a crash mid-save keeps the previous file
+        let tmp = self.path.with_extension("json.tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec_pretty(meta)?)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        File::create(&self.journal)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl MetadataStorage for LocalMetadataStorage {
+    fn load(&self) -> Result<BrokerMetadata> {
+        let mut meta: BrokerMetadata = match std::fs::read(&self.path) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BrokerMetadata::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let journal = match std::fs::read(&self.journal) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = 0;
+        let mut torn = false;
+        for line in journal.split_inclusive(|&b| b == b'\n') {
+            // a save cut short by a crash, and never answered
+            let Some(ops) = line.strip_suffix(b"\n").and_then(|l| serde_json::from_slice::<Vec<MetadataOp>>(l).ok()) else {
+                tracing::warn!("ignoring a torn change at the end of {}", self.journal.display());
+                torn = true;
+                break;
+            };
+            for op in ops {
+                meta.apply(op);
+            }
+            entries += 1;
+        }
+        // later changes would be appended after the torn one and lost with it
+        if torn {
+            self.snapshot(&meta)?;
+            entries = 0;
+        }
+        *self.saved.lock().unwrap() = Some((meta.clone(), entries));
+        Ok(meta)
+    }
+
+    fn save(&self, meta: &BrokerMetadata) -> Result<()> {
+        let mut saved = self.saved.lock().unwrap();
+        match saved.as_mut() {
+            Some((last, entries)) if *entries < SNAPSHOT_EVERY => {
+                let ops = last.diff(meta);
+                if ops.is_empty() {
+                    return Ok(());
+                }
+                let mut line = serde_json::to_vec(&ops)?;
+                line.push(b'\n');
+                let append = || -> Result<()> {
+                    let mut f = OpenOptions::new().create(true).append(true).open(&self.journal)?;
+                    f.write_all(&line)?;
+                    f.sync_data()?;
+                    Ok(())
+                };
+                if let Err(e) = append() {
+                    // part of the line may be in, the next save starts over
+                    *saved = None;
+                    return Err(e);
+                }
+                *last = meta.clone();
+                *entries += 1;
+            }
+            _ => {
+                self.snapshot(meta)?;
+                *saved = Some((meta.clone(), 0));
+            }
+        }
+        Ok(())
+    }
+}
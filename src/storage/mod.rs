@@ -1 +1,3 @@
+pub mod audit_log;
 pub mod disk_log;
+pub mod metadata;
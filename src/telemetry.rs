@@ -0,0 +1,15 @@
+use anyhow::Result;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+/// Spans exported in batches over OTLP/HTTP to `endpoint`, say
+/// "http://localhost:4318/v1/traces". Shut it down before exiting so the
+/// last batch goes out.
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("quique").build())
+        .build())
+}
@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, client};
+
+/// Terminates TLS on the listener with the certificate chain and private
+/// key in the PEM files at `cert` and `key`
+pub fn acceptor(cert: &str, key: &str) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", cert))?;
+    let key = PrivateKeyDer::from_pem_file(key).with_context(|| format!("reading private key from {}", key))?;
+    let cfg = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(cfg)))
+}
+
+/// Connects over TLS to servers whose certificate is signed by one in the
+/// PEM file at `ca`
+pub fn connector(ca: &str) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).with_context(|| format!("reading CA certificates from {}", ca))? {
+        roots.add(cert?)?;
+    }
+    let cfg = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(cfg)))
+}
+
+/// Connect to `addr` ("host:port"), over TLS if `tls` is set. The server's
+/// certificate has to be valid for `host`, a name or an IP address.
+pub async fn connect(addr: &str, tls: Option<&TlsConnector>) -> Result<Stream> {
+    let s = TcpStream::connect(addr).await?;
+    // requests go out as header + body, don't let the body wait for an ack
+    s.set_nodelay(true)?;
+    let Some(tls) = tls else {
+        return Ok(Stream::Plain(s));
+    };
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())?;
+    Ok(Stream::Client(Box::new(tls.connect(name, s).await?)))
+}
+
+/// A connection to a node, with TLS on top or not
+pub enum Stream {
+    Plain(TcpStream),
+    Client(Box<client::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Client(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Client(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Client(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Client(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
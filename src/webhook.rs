@@ -0,0 +1,174 @@
+use reqwest::header::{CONTENT_TYPE, HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::handler;
+use crate::protocol::{CORRELATION_ID_HEADER, REPLY_TO_HEADER};
+use crate::queue::{Message, Topic, TopicRegistry};
+use crate::storage::audit_log::AuditEvent;
+
+/// How often topics are checked for a webhook to start delivering to
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a pusher waits for a message before checking its topic is
+/// still led here
+const POLL: Duration = Duration::from_secs(1);
+
+/// POSTs of a message before it's dead lettered
+const ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled for each one after it
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Time allowed for one POST, response included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefix of the request headers carrying the message's own headers
+const HEADER_PREFIX: &str = "quique-header-";
+
+/// Whether `url` can be a topic's webhook: http or https
+pub fn valid_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.has_host())
+}
+
+/// Push the messages of every topic led here that has a webhook to it,
+/// one pusher per topic, taking from the default group. Pushers start as
+/// topics are created or taken over, and stop once they're gone.
+pub async fn run(topics: Arc<TopicRegistry>) {
+    // rustls needs a provider, the one the listeners use
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("webhooks disabled, failed to build HTTP client: {}", e);
+            return;
+        }
+    };
+    let mut pushers: HashMap<String, JoinHandle<()>> = HashMap::new();
+    let mut tick = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        tick.tick().await;
+        pushers.retain(|_, p| !p.is_finished());
+        for t in topics.list() {
+            if t.config().webhook.is_none() || pushers.contains_key(&t.name) {
+                continue;
+            }
+            let pusher = tokio::spawn(push(client.clone(), topics.clone(), t.clone()));
+            pushers.insert(t.name.clone(), pusher);
+        }
+    }
+}
+
+/// Deliver `t`'s messages in order until it's no longer the topic led
+/// here. A message the endpoint doesn't take after `ATTEMPTS` goes to
+/// the topic's dead letter topic, or is dropped without one.
+async fn push(client: reqwest::Client, topics: Arc<TopicRegistry>, t: Arc<Topic>) {
+    let Some(url) = t.config().webhook.clone() else {
+        return;
+    };
+    info!("delivering {} to webhook {}", t.name, url);
+    while current(&topics, &t) {
+        let (tag, msg) = match t.dequeue_wait("", POLL, |m| handler::dead_letter(&topics, &t, m)).await {
+            Ok(Some(d)) => (d.tag, d.msg),
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("webhook of {} failed to dequeue: {}", t.name, e);
+                tokio::time::sleep(POLL).await;
+                continue;
+            }
+        };
+        t.audit(AuditEvent::Deliver, tag, &msg.envelope.message_id, 0, "", "");
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        let delivered = loop {
+            match post(&client, &url, &t.name, tag, &msg, attempt).await {
+                Ok(()) => break true,
+                Err(Failure::Rejected(e)) => {
+                    warn!("webhook of {} rejected message {}: {}", t.name, tag, e);
+                    break false;
+                }
+                Err(Failure::Retry(e)) if attempt >= ATTEMPTS => {
+                    warn!("webhook of {} failed message {} {} times: {}", t.name, tag, attempt, e);
+                    break false;
+                }
+                Err(Failure::Retry(_)) => {}
+            }
+            tokio::time::sleep(backoff).await;
+            // left unacked, the message is the next leader's to deliver
+            if !current(&topics, &t) {
+                return;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        };
+        if !delivered {
+            match t.dead_letter() {
+                Some(_) => handler::dead_letter(&topics, &t, msg),
+                None => warn!("dropping message {} of {}, it has no dead letter topic", tag, t.name),
+            }
+        }
+        match t.ack("", tag) {
+            Ok(true) => t.audit(AuditEvent::Ack, tag, "", 0, "", ""),
+            Ok(false) => {}
+            Err(e) => warn!("webhook of {} failed to ack {}: {}", t.name, tag, e),
+        }
+    }
+    info!("stopped delivering {} to webhook {}", t.name, url);
+}
+
+/// Whether `t` is still the topic of its name led here, not deleted,
+/// expired or handed off
+fn current(topics: &TopicRegistry, t: &Arc<Topic>) -> bool {
+    topics.get(&t.name).is_some_and(|cur| Arc::ptr_eq(&cur, t))
+}
+
+enum Failure {
+    /// worth another try: no answer, a server error, 408 or 429
+    Retry(String),
+    /// any other 4xx, the same request would fail again
+    Rejected(String),
+}
+
+/// POST the payload as the body, its envelope as headers
+async fn post(client: &reqwest::Client, url: &str, topic: &str, tag: u64, msg: &Message, attempt: u32) -> Result<(), Failure> {
+    let content_type = match msg.envelope.content_type.as_str() {
+        "" => "application/octet-stream",
+        ct => ct,
+    };
+    let mut req = client
+        .post(url)
+        .header(CONTENT_TYPE, content_type)
+        .header("quique-topic", topic)
+        .header("quique-delivery-tag", tag)
+        .header("quique-timestamp-ms", msg.envelope.timestamp_ms)
+        .header("quique-attempt", attempt);
+    if !msg.envelope.message_id.is_empty() {
+        req = req.header("quique-message-id", &msg.envelope.message_id);
+    }
+    if !msg.envelope.reply_to.is_empty() {
+        req = req.header(REPLY_TO_HEADER, &msg.envelope.reply_to);
+    }
+    if !msg.envelope.correlation_id.is_empty() {
+        req = req.header(CORRELATION_ID_HEADER, &msg.envelope.correlation_id);
+    }
+    for (k, v) in &msg.envelope.headers {
+        // headers HTTP can't carry are left out
+        let name = HeaderName::try_from(format!("{}{}", HEADER_PREFIX, k.to_ascii_lowercase()));
+        if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(v)) {
+            req = req.header(name, value);
+        }
+    }
+    let res = req.body(msg.payload.clone()).send().await.map_err(|e| Failure::Retry(e.to_string()))?;
+    let st = res.status();
+    if st.is_success() {
+        Ok(())
+    } else if st.is_client_error() && st.as_u16() != 408 && st.as_u16() != 429 {
+        Err(Failure::Rejected(st.to_string()))
+    } else {
+        Err(Failure::Retry(st.to_string()))
+    }
+}
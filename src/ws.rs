@@ -0,0 +1,315 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as Frame;
+
+use crate::gateway::Gateway;
+use crate::handler::{self, Session};
+use crate::protocol::*;
+use crate::queue::Message;
+
+/// How long a subscription waits for a message before checking the client
+/// is still there
+const SUBSCRIBE_POLL_MS: u32 = 30_000;
+
+/// Frames waiting to be written to a client
+const OUTBOX: usize = 64;
+
+/// A request in a text frame, JSON with the op as `op`. `id`, if any, is
+/// echoed in its answer: {"id": .., "status": "Ok", ..}.
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    op: JsonOp,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JsonOp {
+    /// {"op": "auth", "user": "alice", "password": ".."} or {"op": "auth", "token": ".."}
+    Auth {
+        #[serde(default)]
+        user: String,
+        #[serde(default)]
+        password: String,
+        #[serde(default)]
+        token: String,
+    },
+    /// {"op": "produce", "topic": "t", "data": "text payload", ...}
+    Produce {
+        topic: String,
+        data: String,
+        #[serde(default)]
+        priority: u8,
+        #[serde(default)]
+        routing_key: String,
+        #[serde(default)]
+        message_id: String,
+        #[serde(default)]
+        content_type: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        /// topic an answer goes to
+        #[serde(default)]
+        reply_to: String,
+        #[serde(default)]
+        correlation_id: String,
+        /// "none", "leader" (default) or "quorum"
+        #[serde(default)]
+        acks: Option<String>,
+    },
+    /// answered with "message", settle its "tag" with ack/nack
+    Consume {
+        topic: String,
+        #[serde(default)]
+        group: String,
+        #[serde(default)]
+        timeout_ms: u32,
+    },
+    Ack {
+        topic: String,
+        #[serde(default)]
+        group: String,
+        tag: u64,
+    },
+    Nack {
+        topic: String,
+        #[serde(default)]
+        group: String,
+        tag: u64,
+    },
+    /// messages are pushed as {"event": "message", "topic", "group", "message"},
+    /// acked as they're sent
+    Subscribe {
+        topic: String,
+        #[serde(default)]
+        group: String,
+    },
+    Unsubscribe {
+        topic: String,
+        #[serde(default)]
+        group: String,
+    },
+}
+
+/// A message as sent to clients, the payload as text
+#[derive(Debug, Serialize)]
+struct JsonMessage {
+    tag: u64,
+    data: String,
+    message_id: String,
+    content_type: String,
+    timestamp_ms: u64,
+    headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    reply_to: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    correlation_id: String,
+}
+
+impl JsonMessage {
+    fn new(tag: u64, m: Message) -> Self {
+        Self {
+            tag,
+            data: String::from_utf8_lossy(&m.payload).into_owned(),
+            message_id: m.envelope.message_id,
+            content_type: m.envelope.content_type,
+            timestamp_ms: m.envelope.timestamp_ms,
+            headers: m.envelope.headers,
+            reply_to: m.envelope.reply_to,
+            correlation_id: m.envelope.correlation_id,
+        }
+    }
+}
+
+/// One WebSocket client. Text frames carry JSON requests, see `JsonOp`,
+/// binary frames a QBUS frame (header and body) for Produce, Consume, Ack,
+/// Nack, Bind or Auth, answered with one. The connection is a session like a
+/// QBUS one: what it consumed and didn't ack is redelivered once it closes.
+pub async fn serve<S>(sock: S, gateway: Gateway, mut drain: watch::Receiver<bool>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ws = tokio_tungstenite::accept_async(sock).await?;
+    let (mut sink, mut stream) = ws.split();
+    let (tx, mut rx) = mpsc::channel::<Frame>(OUTBOX);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if sink.send(frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let mut session = gateway.session();
+    let mut subs: HashMap<(String, String), JoinHandle<()>> = HashMap::new();
+    loop {
+        let frame = tokio::select! {
+            frame = stream.next() => frame,
+            _ = drain.wait_for(|d| *d) => None,
+        };
+        let reply = match frame.transpose()? {
+            None | Some(Frame::Close(_)) => break,
+            Some(Frame::Text(text)) => {
+                let reply = match serde_json::from_str::<Request>(&text) {
+                    Ok(req) => {
+                        let mut reply = handle_json(&gateway, &mut session, &mut subs, &tx, req.op).await;
+                        reply["id"] = req.id;
+                        reply
+                    }
+                    Err(e) => json!({"status": "BadRequest", "error": e.to_string()}),
+                };
+                Some(Frame::Text(reply.to_string().into()))
+            }
+            Some(Frame::Binary(data)) => handle_frame(&gateway, &mut session, data).await?.map(Frame::Binary),
+            // pings are answered by tungstenite
+            Some(_) => None,
+        };
+        if let Some(reply) = reply
+            && tx.send(reply).await.is_err()
+        {
+            break;
+        }
+    }
+    for (_, sub) in subs.drain() {
+        sub.abort();
+    }
+    drop(tx);
+    let _ = writer.await;
+    Ok(())
+}
+
+async fn handle_json(
+    gateway: &Gateway,
+    session: &mut Session,
+    subs: &mut HashMap<(String, String), JoinHandle<()>>,
+    tx: &mpsc::Sender<Frame>,
+    op: JsonOp,
+) -> Value {
+    let st = match op {
+        JsonOp::Auth { user, password, token } => {
+            let (user, secret) = if token.is_empty() { (user, password) } else { (String::new(), token) };
+            gateway.authenticate(session, &user, &secret).await
+        }
+        JsonOp::Produce {
+            topic,
+            data,
+            priority,
+            routing_key,
+            message_id,
+            content_type,
+            headers,
+            reply_to,
+            correlation_id,
+            acks,
+        } => {
+            let acks = match acks.as_deref() {
+                None | Some("leader") => Acks::Leader,
+                Some("quorum") => Acks::Quorum,
+                Some("none") => Acks::None,
+                Some(_) => return json!({"status": "BadRequest", "error": "acks is none, leader or quorum"}),
+            };
+            let msg = Message {
+                payload: data.into_bytes().into(),
+                envelope: Envelope {
+                    message_id,
+                    content_type,
+                    timestamp_ms: 0,
+                    headers,
+                    reply_to,
+                    correlation_id,
+                },
+            };
+            match gateway.produce(session, &topic, &msg, priority, &routing_key, acks).await {
+                Ok(seq) => return json!({"status": "Ok", "seq": seq}),
+                Err(st) => st,
+            }
+        }
+        JsonOp::Consume { topic, group, timeout_ms } => match gateway.next(session, &topic, &group, timeout_ms).await {
+            Ok(Some((tag, m))) => return json!({"status": "Ok", "message": JsonMessage::new(tag, m)}),
+            Ok(None) => Status::Empty,
+            Err(st) => st,
+        },
+        JsonOp::Ack { topic, group, tag } => gateway.settle(session, &topic, &group, tag, false).await,
+        JsonOp::Nack { topic, group, tag } => gateway.settle(session, &topic, &group, tag, true).await,
+        JsonOp::Subscribe { topic, group } => {
+            if gateway.auth_required() && session.identity.is_none() {
+                Status::Unauthorized
+            } else if subs.get(&(topic.clone(), group.clone())).is_some_and(|s| !s.is_finished()) {
+                Status::BadRequest
+            } else {
+                let sub = tokio::spawn(subscription(gateway.clone(), session.sibling(), topic.clone(), group.clone(), tx.clone()));
+                subs.insert((topic, group), sub);
+                Status::Ok
+            }
+        }
+        JsonOp::Unsubscribe { topic, group } => match subs.remove(&(topic, group)) {
+            Some(sub) => {
+                // whatever it took and didn't send is requeued with its session
+                sub.abort();
+                Status::Ok
+            }
+            None => Status::NotFound,
+        },
+    };
+    json!({ "status": format!("{:?}", st) })
+}
+
+/// Push messages of `topic` for `group` to the client until it goes away,
+/// one at a time: the next is taken once the last one is queued for sending
+async fn subscription(gateway: Gateway, mut session: Session, topic: String, group: String, tx: mpsc::Sender<Frame>) {
+    while let Ok(permit) = tx.reserve().await {
+        let event = match gateway.next(&mut session, &topic, &group, SUBSCRIBE_POLL_MS).await {
+            Ok(Some(_)) if tx.is_closed() => return,
+            Ok(Some((tag, m))) => match gateway.settle(&mut session, &topic, &group, tag, false).await {
+                Status::Ok => json!({"event": "message", "topic": topic, "group": group, "message": JsonMessage::new(tag, m)}),
+                st => json!({"event": "error", "topic": topic, "group": group, "status": format!("{:?}", st)}),
+            },
+            Ok(None) => continue,
+            Err(st) => json!({"event": "error", "topic": topic, "group": group, "status": format!("{:?}", st)}),
+        };
+        let done = event["event"] == "error";
+        permit.send(Frame::Text(event.to_string().into()));
+        if done {
+            return;
+        }
+    }
+}
+
+/// Answer a binary frame, a QBUS request, with one. None for a fire and
+/// forget produce.
+async fn handle_frame(gateway: &Gateway, session: &mut Session, data: Bytes) -> Result<Option<Bytes>> {
+    let mut buf = BytesMut::from(&data[..]);
+    let Some(hdr) = Header::decode(&mut buf)? else {
+        return Err(anyhow::anyhow!("truncated frame"));
+    };
+    let body = buf.split_to((hdr.body_len as usize).min(buf.len())).freeze();
+    let (st, rest) = gateway.call(session, hdr.op, body.clone()).await;
+    if hdr.op == Op::Produce && handler::produce_acks(&body) == Acks::None {
+        return Ok(None);
+    }
+    let mut out = BytesMut::new();
+    put_status(&mut out, st);
+    out.extend_from_slice(&rest);
+    let rh = Header {
+        magic: MAGIC,
+        version: VERSION,
+        op: hdr.op,
+        flags: 0,
+        stream_id: hdr.stream_id,
+        body_len: out.len() as u32,
+    };
+    let mut frame = BytesMut::with_capacity(Header::LEN + out.len());
+    rh.encode(&mut frame);
+    frame.extend_from_slice(&out);
+    Ok(Some(frame.freeze()))
+}